@@ -0,0 +1,113 @@
+//! Where hubs and spokes read the current time from.
+//!
+//! Hubs and spokes use the [`SystemClock`] unless given another [`Clock`]. Tests hand them a
+//! [`MockClock`] instead, so they can move time forward without sleeping.
+//!
+//! The time a hub reads never goes back, even when its clock does: hubs read their clock through
+//! a [`MonotonicClock`], and the [`SystemClock`] holds the time still when the system clock is
+//! stepped back. Spokes that expired stay expired, and jobs that were ready stay ready.
+
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use times;
+
+/// A source of the current time, in ms since the epoch
+pub trait Clock: Debug + Send + Sync {
+    fn now_ms(&self) -> u64;
+}
+
+/// Reads the time off the system clock
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now_ms(&self) -> u64 {
+        times::current_time_ms()
+    }
+}
+
+/// A clock that only moves when told to. Share it behind an `Arc` to keep moving the time seen
+/// by the hub it was given to.
+#[derive(Debug, Default)]
+pub struct MockClock {
+    now_ms: AtomicU64,
+}
+
+impl MockClock {
+    pub fn new(now_ms: u64) -> MockClock {
+        MockClock {
+            now_ms: AtomicU64::new(now_ms),
+        }
+    }
+
+    /// Moves the clock `ms` forward
+    pub fn advance(&self, ms: u64) {
+        self.now_ms.fetch_add(ms, Ordering::SeqCst);
+    }
+
+    /// Moves the clock to `now_ms`, which may be earlier than its current time
+    pub fn set(&self, now_ms: u64) {
+        self.now_ms.store(now_ms, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    #[inline]
+    fn now_ms(&self) -> u64 {
+        self.now_ms.load(Ordering::SeqCst)
+    }
+}
+
+/// Reads the time off another clock, but never returns less than it did before. When the other
+/// clock steps back, the time stays where it was until that clock catches up again.
+#[derive(Debug)]
+pub struct MonotonicClock {
+    clock: Arc<dyn Clock>,
+    latest_ms: AtomicU64,
+}
+
+impl MonotonicClock {
+    pub fn new(clock: Arc<dyn Clock>) -> MonotonicClock {
+        MonotonicClock {
+            clock,
+            latest_ms: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Clock for MonotonicClock {
+    #[inline]
+    fn now_ms(&self) -> u64 {
+        times::not_before_latest(&self.latest_ms, self.clock.now_ms())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_moves_when_told() {
+        let clock = MockClock::new(1_000);
+        assert_eq!(clock.now_ms(), 1_000);
+        clock.advance(250);
+        assert_eq!(clock.now_ms(), 1_250);
+        clock.set(10);
+        assert_eq!(clock.now_ms(), 10);
+    }
+
+    #[test]
+    fn monotonic_clock_holds_still_while_its_clock_steps_back() {
+        let mock = Arc::new(MockClock::new(1_000));
+        let clock = MonotonicClock::new(Arc::clone(&mock) as Arc<dyn Clock>);
+        assert_eq!(clock.now_ms(), 1_000);
+        mock.set(700);
+        assert_eq!(clock.now_ms(), 1_000);
+        mock.advance(200);
+        assert_eq!(clock.now_ms(), 1_000);
+        mock.advance(150);
+        assert_eq!(clock.now_ms(), 1_050, "Moves again once its clock catches up");
+    }
+}
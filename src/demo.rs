@@ -1,111 +1,702 @@
+//! Demo mode: producers put jobs on a shared hub while consumers drain it, and every job is
+//! reconciled at the end.
+//!
+//! The run doubles as a load generator for benchmarking the hub. Producers put `count` jobs of
+//! `body_bytes` each, pacing themselves with sleeps to add `jobs_per_second` between them, and
+//! schedule each job a delay drawn from a [`DelayDistribution`] ahead. Once every job was
+//! consumed the run prints a [`RunSummary`] of insert and walk throughput and delivery lag
+//! percentiles.
+
 use colored::*;
-use hub::Hub;
-use job::Job;
+use metrics::Metrics;
 use rand::{thread_rng, Rng};
 use settings;
-use statsd::Client;
-use std::ops::Index;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use times;
-
-pub fn demo(conf: settings::Settings) {
-    println!("Running in demo mode. This will infinitely create a stream of jobs");
-
-    let hub_mutex_rc = Arc::new(Mutex::new(Hub::new(10_000)));
-    let hub_producer = Arc::clone(&hub_mutex_rc);
-    let hub_consumer = Arc::clone(&hub_mutex_rc);
-
-    let max_jobs = conf.count.unwrap_or(50);
-
-    println!("starting producer thread");
-    let producer_thread = thread::Builder::new()
-        .name("producer".into())
-        .spawn(move || {
-            let client = Client::new("127.0.0.1:8125", "yaad.").unwrap();
-            println!("{} {}", "Producing total jobs: ".green(), max_jobs);
-            let job_sample_bodies = vec!["Hello ", "Hey ", "Hi "];
-            let mut r = thread_rng();
-            for job_counter in 0..max_jobs {
-                let delay = match Some(r.next_f32()) {
-                    Some(x) if x <= 0.1 => x * -10.0,
-                    Some(x) if x <= 0.3 => x * 100.0,
-                    Some(x) if x <= 0.5 => x * 10.0,
-                    Some(x) => x,
-                    _ => 1.0,
-                } as u64
-                    * 1_000;
-                let mut j = Job::new_auto_id(
-                    times::current_time_ms() + delay,
-                    job_sample_bodies.index((r.next_u32() % 3) as usize),
-                );
-                client.incr("demojob.produced.count");
-                let trigger_at_from_now =
-                    j.trigger_at_ms() as i64 - times::current_time_ms() as i64;
-                let log = format!(
-                    "\nAdding demo job: {:?} :: \
-                     Trigger at {:?} ms Current time: {:?}.  \
-                     Trigger at {:?} ms from now \
-                     Count: {}",
-                    j.get_body(),
-                    times::to_string(j.trigger_at_ms()),
-                    times::to_string(times::current_time_ms()),
-                    trigger_at_from_now,
-                    job_counter
-                );
-                println!("{}", log.green());
-                let mut h = hub_producer.lock().unwrap();
-                client.time("demojob.addjob.duration", || {
-                    h.add_job(j);
-                });
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use yaad::errors::{RejectReason, YaadError};
+use yaad::gauges::HubMetrics;
+use yaad::hub::{HubStats, DEFAULT_TICK_INTERVAL_MS};
+use yaad::ids::{self, IdSource};
+use yaad::job::Job;
+use yaad::shared::{self, SharedHub};
+use yaad::sink::ChannelSink;
+use yaad::times;
+
+/// Default time the consumers wait without seeing a job before declaring the rest lost.
+/// Comfortably longer than the default delay distribution schedules jobs apart.
+pub const DEFAULT_WATCHDOG_QUIET_MS: u64 = 60_000;
+/// Spoke duration of the demo hub unless configured otherwise
+pub const DEFAULT_SPOKE_DURATION_MS: u64 = 10_000;
+/// Jobs produced unless configured otherwise
+pub const DEFAULT_COUNT: usize = 50;
+/// Jobs the producers add per second between them unless configured otherwise
+pub const DEFAULT_JOBS_PER_SECOND: u64 = 100;
+/// Size of every job body unless configured otherwise
+pub const DEFAULT_BODY_BYTES: usize = 16;
+/// How far ahead jobs are scheduled unless configured otherwise, see
+/// [`DelayDistribution::from_setting`]
+pub const DEFAULT_DELAY_DISTRIBUTION: &str = "uniform:0:10000";
+/// Longest a consumer sleeps between walks, so jobs added meanwhile with an earlier trigger time
+/// aren't picked up late
+const MAX_CONSUMER_SLEEP_MS: u64 = 100;
+/// How often the first consumer prints how many jobs and body bytes each spoke holds
+const SUMMARY_INTERVAL_MS: u64 = 5_000;
+/// Most jobs a consumer's channel holds - the hub keeps any further ready jobs until the
+/// consumer caught up
+const CONSUMER_CHANNEL_CAPACITY: usize = 1_024;
+
+/// How far ahead of now a produced job is scheduled
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DelayDistribution {
+    /// Every job `delay_ms` ahead
+    Fixed { delay_ms: u64 },
+    /// Anywhere from `min_ms` up to but not including `max_ms` ahead, all equally likely
+    Uniform { min_ms: u64, max_ms: u64 },
+    /// Exponentially distributed delays averaging `mean_ms`, so most jobs are due soon and a few
+    /// much later
+    Exponential { mean_ms: u64 },
+}
+
+impl DelayDistribution {
+    /// Parses the `delay_distribution` setting: `fixed:<delay_ms>`,
+    /// `uniform:<min_ms>:<max_ms>` or `exponential:<mean_ms>`
+    pub fn from_setting(setting: &str) -> Result<DelayDistribution, String> {
+        let bad = || {
+            format!(
+                "Bad delay_distribution: {}. Expected fixed:<delay_ms>, \
+                 uniform:<min_ms>:<max_ms> or exponential:<mean_ms>",
+                setting
+            )
+        };
+        let mut parts = setting.split(':');
+        let kind = parts.next().unwrap_or("");
+        let params: Vec<u64> = parts
+            .map(|p| p.parse().map_err(|_| bad()))
+            .collect::<Result<_, _>>()?;
+        match (kind, params.as_slice()) {
+            ("fixed", &[delay_ms]) => Ok(DelayDistribution::Fixed { delay_ms }),
+            ("uniform", &[min_ms, max_ms]) if min_ms < max_ms => {
+                Ok(DelayDistribution::Uniform { min_ms, max_ms })
             }
-        }).unwrap();
-
-    let consumer_thread = thread::Builder::new()
-        .name("consumer".into())
-        .spawn(move || {
-            let client = Client::new("127.0.0.1:8125", "yaad.").unwrap();
-            println!("-----------------------------------------------");
-            // Switch into drain mode...
-            println!("Job drain mode",);
-            println!("-----------------------------------------------");
-            let mut job_counter = 0;
-            loop {
-                let mut h = hub_consumer.lock().unwrap();
-
-                h.walk_jobs().iter().for_each(|j| {
-                    println!(
-                        "{}",
-                        format!(
-                            "Ready job: {:?} to be triggered at: {} current time: {}",
-                            j.get_body(),
-                            times::to_string(j.trigger_at_ms()),
-                            times::to_string(times::current_time_ms())
-                        ).blue()
-                    );
-                    job_counter += 1;
-                    println!(
-                        "{}",
-                        format!("Read total jobs so far: {}", job_counter).blue()
-                    );
-                    client.incr("demojob.consumed.count");
-                });
-
-                if job_counter.eq(&max_jobs) {
-                    println!("Consumer done with max jobs");
-                    break;
-                }
-                // thread::sleep(time::Duration::from_millis(100));
-                thread::yield_now();
+            ("exponential", &[mean_ms]) => Ok(DelayDistribution::Exponential { mean_ms }),
+            _ => Err(bad()),
+        }
+    }
+
+    /// Draws the delay of the next job
+    pub fn sample<R: Rng>(&self, r: &mut R) -> u64 {
+        match *self {
+            DelayDistribution::Fixed { delay_ms } => delay_ms,
+            DelayDistribution::Uniform { min_ms, max_ms } => r.gen_range(min_ms, max_ms),
+            DelayDistribution::Exponential { mean_ms } => {
+                (-(1.0 - r.next_f64()).ln() * mean_ms as f64) as u64
             }
-        }).unwrap();
+        }
+    }
+}
 
-    match producer_thread.join() {
-        Result::Ok(_) => println!("{}", "Producer thread finished ok".yellow()),
-        Result::Err(e) => println!("{} {:?}", "Producer thread errored".red(), e),
+/// What a demo run puts on the hub and how it consumes it
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadConfig {
+    pub count: usize,
+    pub jobs_per_second: u64,
+    pub body_bytes: usize,
+    pub delays: DelayDistribution,
+    pub producer_threads: usize,
+    pub consumer_threads: usize,
+    pub tick_interval_ms: u64,
+    /// How long the consumers wait without seeing a job before declaring the rest lost
+    pub quiet_ms: u64,
+}
+
+impl LoadConfig {
+    /// Reads the load from the demo settings, with the defaults for anything not set
+    pub fn from_settings(conf: &settings::Settings) -> Result<LoadConfig, String> {
+        let load = LoadConfig {
+            count: conf.count.unwrap_or(DEFAULT_COUNT),
+            jobs_per_second: conf.jobs_per_second.unwrap_or(DEFAULT_JOBS_PER_SECOND),
+            body_bytes: conf.body_bytes.unwrap_or(DEFAULT_BODY_BYTES),
+            delays: DelayDistribution::from_setting(
+                conf.delay_distribution
+                    .as_deref()
+                    .unwrap_or(DEFAULT_DELAY_DISTRIBUTION),
+            )?,
+            producer_threads: conf.producer_threads.unwrap_or(1),
+            consumer_threads: conf.consumer_threads.unwrap_or(1),
+            tick_interval_ms: conf.tick_interval_ms.unwrap_or(DEFAULT_TICK_INTERVAL_MS),
+            quiet_ms: conf.watchdog_quiet_ms.unwrap_or(DEFAULT_WATCHDOG_QUIET_MS),
+        };
+        if load.jobs_per_second == 0 {
+            return Err("jobs_per_second must be at least 1".to_owned());
+        }
+        if load.producer_threads == 0 || load.consumer_threads == 0 {
+            return Err("producer_threads and consumer_threads must be at least 1".to_owned());
+        }
+        Ok(load)
     }
-    match consumer_thread.join() {
-        Result::Ok(_) => println!("{}", "Consumer thread finished ok".yellow()),
-        Result::Err(e) => println!("{} {:?}", "Consumer thread errored".red(), e),
+}
+
+/// Runs producers and consumers against a shared hub as the settings describe and reports how
+/// the run ended - only `Outcome::Reconciled` means every produced job was consumed exactly once.
+pub fn demo(conf: settings::Settings) -> Outcome {
+    let load = match LoadConfig::from_settings(&conf) {
+        Ok(load) => load,
+        Err(e) => {
+            println!("{}", e.red());
+            return Outcome::Stalled;
+        }
+    };
+    let id_sources: Result<Vec<Box<dyn IdSource>>, String> = (0..load.producer_threads)
+        .map(|_| ids::from_setting(conf.id_generation.as_deref()))
+        .collect();
+    let id_sources = match id_sources {
+        Ok(sources) => sources,
+        Err(e) => {
+            println!("{}", e.red());
+            return Outcome::Stalled;
+        }
+    };
+
+    let spoke_duration_ms = conf.spoke_duration_ms.unwrap_or(DEFAULT_SPOKE_DURATION_MS);
+    let shards = conf.hub_shards.unwrap_or(shared::DEFAULT_SHARDS);
+    let mut hub = SharedHub::with_shards(spoke_duration_ms, shards);
+    if let Some(ratio) = conf.stale_compaction_ratio {
+        hub.set_stale_compaction_ratio(ratio);
+    }
+    // Metrics are only sent if a statsd_addr is configured
+    let metrics = Arc::new(Metrics::from_setting(conf.statsd_addr.as_deref()));
+    if metrics.is_enabled() {
+        hub.set_metrics(Arc::clone(&metrics) as Arc<dyn HubMetrics>);
+    }
+
+    println!(
+        "Running in demo mode: {} jobs of {} bytes at {} jobs/s, delays {:?}, {} producers, \
+         {} consumers",
+        load.count,
+        load.body_bytes,
+        load.jobs_per_second,
+        load.delays,
+        load.producer_threads,
+        load.consumer_threads
+    );
+    let (outcome, run) = run(Arc::new(hub), &load, id_sources, metrics);
+    print!("{}", run.to_string().yellow());
+    outcome
+}
+
+/// Runs `load` against `hub`, one producer per id source, and returns how the run ended along
+/// with what it measured
+pub fn run(
+    hub: Arc<SharedHub>,
+    load: &LoadConfig,
+    id_sources: Vec<Box<dyn IdSource>>,
+    metrics: Arc<Metrics>,
+) -> (Outcome, RunSummary) {
+    let ledger = Arc::new(Mutex::new(Ledger::default()));
+    let started = Instant::now();
+    let producers = id_sources.len();
+    let producer_threads: Vec<_> = id_sources
+        .into_iter()
+        .enumerate()
+        .map(|(p, id_source)| {
+            let (hub, ledger) = (Arc::clone(&hub), Arc::clone(&ledger));
+            let metrics = Arc::clone(&metrics);
+            let load = load.clone();
+            thread::Builder::new()
+                .name(format!("producer-{}", p))
+                .spawn(move || {
+                    produce(&hub, &ledger, &metrics, &load, id_source, (p, producers), started)
+                })
+                .unwrap()
+        })
+        .collect();
+
+    let consumer_threads: Vec<_> = (0..load.consumer_threads)
+        .map(|c| {
+            let (hub, ledger) = (Arc::clone(&hub), Arc::clone(&ledger));
+            let metrics = Arc::clone(&metrics);
+            let (count, quiet_ms, tick_interval_ms) =
+                (load.count, load.quiet_ms, load.tick_interval_ms);
+            thread::Builder::new()
+                .name(format!("consumer-{}", c))
+                .spawn(move || {
+                    let mut last_summary_ms = times::current_time_ms();
+                    let mut last_tick_ms = last_summary_ms;
+                    consume(&hub, &ledger, count, quiet_ms, |jobs| {
+                        let walked_at_ms = times::current_time_ms();
+                        for j in &jobs {
+                            metrics.timing("demojob.delivery_lag", j.delivery_lag_ms(walked_at_ms));
+                            metrics.incr("demojob.consumed.count");
+                        }
+                        // Housekeeping and progress are left to the first consumer
+                        if c == 0 && walked_at_ms - last_tick_ms >= tick_interval_ms {
+                            hub.tick();
+                            last_tick_ms = walked_at_ms;
+                        }
+                        if c == 0 && walked_at_ms - last_summary_ms >= SUMMARY_INTERVAL_MS {
+                            print!("{}", summary(&hub.stats()).yellow());
+                            last_summary_ms = walked_at_ms;
+                        }
+                        jobs
+                    })
+                })
+                .unwrap()
+        })
+        .collect();
+
+    let mut inserted_in = Duration::from_millis(0);
+    for producer in producer_threads {
+        match producer.join() {
+            Ok(took) => inserted_in = inserted_in.max(took),
+            Err(e) => println!("{} {:?}", "Producer thread errored".red(), e),
+        }
+    }
+    println!("{}", "Producers finished".yellow());
+    let mut outcome = Outcome::Reconciled;
+    for consumer in consumer_threads {
+        match consumer.join() {
+            // The worst outcome any consumer saw is the run's
+            Ok(o) if o != Outcome::Reconciled => outcome = o,
+            Ok(_) => {}
+            Err(e) => {
+                println!("{} {:?}", "Consumer thread errored".red(), e);
+                outcome = Outcome::Stalled;
+            }
+        }
+    }
+
+    let ledger = ledger.lock().unwrap();
+    if outcome == Outcome::Reconciled {
+        println!("Consumers done with all {} jobs", load.count);
+    } else {
+        println!("{:?}\n{}", outcome, ledger.report(&hub).red());
+    }
+    (outcome, RunSummary::new(&ledger, inserted_in))
+}
+
+/// Adds producer `p`'s `share` of `load.count` jobs - every `producers`th job - to `hub`, each
+/// once its turn at `load.jobs_per_second` since `started` came. Returns how long after
+/// `started` the last job was added.
+fn produce(
+    hub: &SharedHub,
+    ledger: &Mutex<Ledger>,
+    metrics: &Metrics,
+    load: &LoadConfig,
+    mut id_source: Box<dyn IdSource>,
+    (p, producers): (usize, usize),
+    started: Instant,
+) -> Duration {
+    let mut r = thread_rng();
+    for n in (p..load.count).step_by(producers) {
+        let due = started + Duration::from_nanos(n as u64 * 1_000_000_000 / load.jobs_per_second);
+        let now = Instant::now();
+        if now < due {
+            thread::sleep(due - now);
+        }
+        let body: Vec<u8> = (0..load.body_bytes)
+            .map(|_| b'a' + (r.next_u32() % 26) as u8)
+            .collect();
+        let trigger_at_ms = times::current_time_ms() + load.delays.sample(&mut r);
+        let j = Job::new(id_source.next_id(), trigger_at_ms, body);
+        metrics.incr("demojob.produced.count");
+        ledger.lock().unwrap().record_produced(&j);
+        let added = metrics.time("demojob.addjob.duration", || hub.add_job(j));
+        match added {
+            Ok(()) => {}
+            // The id source handed out an id twice, the hub keeps the job added first
+            Err(YaadError::JobRejected {
+                reason: RejectReason::Duplicate(id),
+            }) => {
+                metrics.incr("demojob.refused.count");
+                println!("{}", format!("Hub refused duplicate job id {}", id).yellow());
+            }
+            Err(e @ YaadError::Internal(_)) => {
+                metrics.incr("demojob.refused.count");
+                println!("{}", format!("Hub refused job, this is a bug: {}", e).red());
+            }
+            Err(e) => {
+                metrics.incr("demojob.refused.count");
+                println!("{}", format!("Hub refused job: {}", e).red());
+            }
+        }
+    }
+    started.elapsed()
+}
+
+/// What a demo run measured
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunSummary {
+    pub produced: usize,
+    pub consumed: usize,
+    /// Jobs added per second, from the start of the run until the last one was added
+    pub insert_per_sec: f64,
+    /// Jobs consumed per second, from the first job consumed to the last
+    pub walk_per_sec: f64,
+    /// How long after their trigger time jobs were consumed, None if none was
+    pub delivery_lag_ms: Option<LagPercentiles>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LagPercentiles {
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+}
+
+impl RunSummary {
+    fn new(ledger: &Ledger, inserted_in: Duration) -> RunSummary {
+        let per_sec = |jobs: usize, secs: f64| jobs as f64 / secs.max(0.001);
+        let consumed_for_ms = match (ledger.first_consumed_ms, ledger.last_consumed_ms) {
+            (Some(first), Some(last)) => last - first,
+            _ => 0,
+        };
+        let mut lags = ledger.delivery_lags_ms.clone();
+        lags.sort_unstable();
+        // Nearest rank: the smallest lag at least `pct` percent of jobs had
+        let percentile = |pct: usize| lags[(lags.len() * pct).div_ceil(100).max(1) - 1];
+        RunSummary {
+            produced: ledger.produced_count,
+            consumed: ledger.consumed_count,
+            insert_per_sec: per_sec(ledger.produced_count, inserted_in.as_secs_f64()),
+            walk_per_sec: per_sec(ledger.consumed_count, consumed_for_ms as f64 / 1_000.0),
+            delivery_lag_ms: if lags.is_empty() {
+                None
+            } else {
+                Some(LagPercentiles {
+                    p50: percentile(50),
+                    p95: percentile(95),
+                    p99: percentile(99),
+                })
+            },
+        }
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Produced {} jobs, consumed {}", self.produced, self.consumed)?;
+        writeln!(f, "  insert throughput: {:.1} jobs/s", self.insert_per_sec)?;
+        writeln!(f, "  walk throughput: {:.1} jobs/s", self.walk_per_sec)?;
+        match self.delivery_lag_ms {
+            Some(lag) => writeln!(
+                f,
+                "  delivery lag: p50 {}ms p95 {}ms p99 {}ms",
+                lag.p50, lag.p95, lag.p99
+            ),
+            None => writeln!(f, "  delivery lag: no jobs consumed"),
+        }
+    }
+}
+
+/// Why the demo consumer stopped
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Outcome {
+    /// Every produced job was consumed exactly once
+    Reconciled,
+    /// Nothing was consumed for the watchdog's quiet period while jobs were still missing
+    Stalled,
+    /// A job was consumed more than once, or one that was never produced showed up
+    Duplicated,
+}
+
+impl Outcome {
+    /// Process exit code for this outcome - anything but a clean reconciliation fails the run
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Outcome::Reconciled => 0,
+            _ => 1,
+        }
+    }
+}
+
+/// Tracks produced job ids so lost and duplicated deliveries can be named in a report
+#[derive(Debug, Default)]
+pub struct Ledger {
+    outstanding: HashMap<Uuid, String>,
+    consumed: HashSet<Uuid>,
+    duplicates: Vec<Uuid>,
+    produced_count: usize,
+    consumed_count: usize,
+    /// How long after its trigger time each job was consumed
+    delivery_lags_ms: Vec<u64>,
+    first_consumed_ms: Option<u64>,
+    last_consumed_ms: Option<u64>,
+}
+
+impl Ledger {
+    pub fn record_produced(&mut self, job: &Job) {
+        let id = job.get_metadata().get_id();
+        self.outstanding
+            .insert(id, job.get_body().to_string_lossy().into_owned());
+        self.produced_count += 1;
+    }
+
+    pub fn record_consumed(&mut self, job: &Job, consumed_at_ms: u64) {
+        let id = job.get_metadata().get_id();
+        self.consumed_count += 1;
+        self.delivery_lags_ms.push(job.delivery_lag_ms(consumed_at_ms));
+        self.first_consumed_ms.get_or_insert(consumed_at_ms);
+        self.last_consumed_ms = Some(consumed_at_ms);
+        if self.outstanding.remove(&id).is_none() || !self.consumed.insert(id) {
+            self.duplicates.push(id);
+        }
+    }
+
+    /// Returns the ids and bodies of jobs that were produced but never consumed, sorted by id
+    pub fn missing(&self) -> Vec<(Uuid, &str)> {
+        let mut missing: Vec<(Uuid, &str)> = self
+            .outstanding
+            .iter()
+            .map(|(id, body)| (*id, body.as_str()))
+            .collect();
+        missing.sort_by_key(|m| m.0);
+        missing
+    }
+
+    /// Describes produced vs consumed counts, every missing and duplicated job and where the
+    /// hub thinks the missing jobs are.
+    pub fn report(&self, hub: &SharedHub) -> String {
+        let mut report = format!(
+            "Reconciliation report: produced {} consumed {}\n",
+            self.produced_count, self.consumed_count
+        );
+        let missing = self.missing();
+        report.push_str(&format!("Missing jobs ({}):\n", missing.len()));
+        for (id, body) in missing {
+            report.push_str(&format!(
+                "  {} body: {} owner: {:?}\n",
+                id,
+                body,
+                hub.find_job_owner_bst(id)
+            ));
+        }
+        report.push_str(&format!("Duplicated jobs ({}):\n", self.duplicates.len()));
+        for id in &self.duplicates {
+            report.push_str(&format!("  {}\n", id));
+        }
+        report.push_str(&format!("Hub: {:?}\n", hub.stale_stats()));
+        report
+    }
+}
+
+/// Describes how many jobs and job body bytes the hub holds in total and in each spoke
+fn summary(stats: &HubStats) -> String {
+    let mut summary = format!(
+        "Hub holds {} jobs, {} body bytes\n  past spoke: {} jobs, {} bytes\n",
+        stats.total_jobs, stats.total_body_bytes, stats.past.job_count, stats.past.body_bytes
+    );
+    for s in &stats.spokes {
+        summary.push_str(&format!(
+            "  spoke [{}, {}): {} jobs, {} bytes\n",
+            times::to_string(s.bounds.get_start_time_ms()),
+            times::to_string(s.bounds.get_end_time_ms()),
+            s.job_count,
+            s.body_bytes
+        ));
+    }
+    summary
+}
+
+/// Drains the hub into a channel and consumes the jobs coming out of it until `max_jobs` have
+/// been consumed, a duplicate delivery is seen, or the watchdog notices nothing was consumed for
+/// `quiet_ms`. `on_walk` sees every batch received before it is counted. Several consumers can
+/// share a ledger, each stopping once the jobs they consumed between them add up.
+fn consume<F>(
+    hub: &SharedHub,
+    ledger: &Mutex<Ledger>,
+    max_jobs: usize,
+    quiet_ms: u64,
+    mut on_walk: F,
+) -> Outcome
+where
+    F: FnMut(Vec<Job>) -> Vec<Job>,
+{
+    let (sender, receiver) = mpsc::sync_channel(CONSUMER_CHANNEL_CAPACITY);
+    let sink = ChannelSink::new(sender);
+    let started_ms = times::current_time_ms();
+    loop {
+        hub.drain_into(&sink, CONSUMER_CHANNEL_CAPACITY);
+        let jobs: Vec<Job> = receiver.try_iter().collect();
+        let next_trigger_ms = hub.next_trigger_time_ms();
+        let jobs = on_walk(jobs);
+        let now = times::current_time_ms();
+
+        let mut l = ledger.lock().unwrap();
+        jobs.iter().for_each(|j| l.record_consumed(j, now));
+        // Whichever consumer consumed last, the jobs are still flowing
+        let last_consumed_ms = l.last_consumed_ms.map_or(started_ms, |ms| ms.max(started_ms));
+
+        if !l.duplicates.is_empty() {
+            return Outcome::Duplicated;
+        }
+        if l.consumed_count >= max_jobs {
+            return Outcome::Reconciled;
+        }
+        if now.saturating_sub(last_consumed_ms) > quiet_ms {
+            return Outcome::Stalled;
+        }
+        drop(l);
+        let watchdog_ms = (last_consumed_ms + quiet_ms + 1).saturating_sub(now);
+        let sleep_ms = next_trigger_ms
+            .map_or(MAX_CONSUMER_SLEEP_MS, |t| t.saturating_sub(now))
+            .min(MAX_CONSUMER_SLEEP_MS)
+            .min(watchdog_ms);
+        thread::sleep(Duration::from_millis(sleep_ms));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUIET_MS: u64 = 30;
+
+    /// Returns a hub and ledger holding `count` jobs that are ready right away
+    fn produced(count: usize) -> (SharedHub, Mutex<Ledger>) {
+        let hub = SharedHub::new(10);
+        let mut ledger = Ledger::default();
+        for i in 0..count {
+            let j = Job::new_auto_id(times::current_time_ms() - 100 + i as u64, "demo");
+            ledger.record_produced(&j);
+            hub.add_job(j).unwrap();
+        }
+        (hub, Mutex::new(ledger))
+    }
+
+    #[test]
+    fn reconciles_when_every_job_is_consumed() {
+        let (hub, ledger) = produced(3);
+        let outcome = consume(&hub, &ledger, 3, QUIET_MS, |jobs| jobs);
+        assert_eq!(outcome, Outcome::Reconciled);
+        assert_eq!(outcome.exit_code(), 0);
+        assert!(ledger.lock().unwrap().missing().is_empty());
+    }
+
+    #[test]
+    fn watchdog_fires_on_lost_job() {
+        let (hub, ledger) = produced(3);
+        let mut lost = None;
+        let outcome = consume(&hub, &ledger, 3, QUIET_MS, |mut jobs| {
+            if lost.is_none() && !jobs.is_empty() {
+                lost = Some(jobs.remove(0).get_metadata().get_id());
+            }
+            jobs
+        });
+        assert_eq!(outcome, Outcome::Stalled);
+        assert_eq!(outcome.exit_code(), 1);
+
+        let ledger = ledger.lock().unwrap();
+        let lost = lost.unwrap();
+        let missing = ledger.missing();
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].0, lost);
+
+        let report = ledger.report(&hub);
+        assert!(report.contains("produced 3 consumed 2"), "{}", report);
+        assert!(report.contains(&format!("  {} body: ", lost)), "{}", report);
+        assert!(report.contains("owner: None"), "{}", report);
+    }
+
+    #[test]
+    fn summarizes_spoke_contents() {
+        let (hub, _) = produced(2);
+        hub.add_job(Job::new_auto_id(times::current_time_ms() + 60_000, "later"))
+            .unwrap();
+        let summary = summary(&hub.stats());
+        assert!(summary.starts_with("Hub holds 3 jobs, 13 body bytes\n"), "{}", summary);
+        assert!(summary.contains("past spoke: 2 jobs, 8 bytes"), "{}", summary);
+        assert!(summary.contains("): 1 jobs, 5 bytes"), "{}", summary);
+    }
+
+    #[test]
+    fn parses_delay_distributions() {
+        let parse = DelayDistribution::from_setting;
+        assert_eq!(parse("fixed:250"), Ok(DelayDistribution::Fixed { delay_ms: 250 }));
+        assert_eq!(
+            parse("uniform:10:20"),
+            Ok(DelayDistribution::Uniform {
+                min_ms: 10,
+                max_ms: 20,
+            })
+        );
+        assert_eq!(
+            parse("exponential:1000"),
+            Ok(DelayDistribution::Exponential { mean_ms: 1_000 })
+        );
+        for bad in &["", "fixed", "fixed:soon", "uniform:20:10", "normal:5", "fixed:1:2"] {
+            assert!(parse(bad).is_err(), "Parsed {:?}", bad);
+        }
+
+        let mut r = thread_rng();
+        for _ in 0..1_000 {
+            let delay_ms = parse("uniform:10:20").unwrap().sample(&mut r);
+            assert!((10..20).contains(&delay_ms), "Drew {}", delay_ms);
+        }
+    }
+
+    #[test]
+    fn paced_run_consumes_every_job_and_summarizes_it() {
+        let load = LoadConfig {
+            count: 100,
+            jobs_per_second: 1_000,
+            body_bytes: 32,
+            delays: DelayDistribution::Uniform {
+                min_ms: 0,
+                max_ms: 50,
+            },
+            producer_threads: 2,
+            consumer_threads: 2,
+            tick_interval_ms: 10,
+            quiet_ms: 5_000,
+        };
+        let id_sources = vec![ids::from_setting(None).unwrap(), ids::from_setting(None).unwrap()];
+        let hub = Arc::new(SharedHub::new(10));
+        let started = Instant::now();
+        let metrics = Arc::new(Metrics::disabled());
+        let (outcome, run) = run(Arc::clone(&hub), &load, id_sources, metrics);
+
+        assert_eq!(outcome, Outcome::Reconciled);
+        assert!(
+            started.elapsed() >= Duration::from_millis(99),
+            "Producers pace themselves to 1000 jobs/s"
+        );
+        assert_eq!((run.produced, run.consumed), (100, 100));
+        assert_eq!(hub.stats().total_jobs, 0);
+        assert!(run.insert_per_sec > 0.0 && run.insert_per_sec <= 1_100.0, "{:?}", run);
+        assert!(run.walk_per_sec > 0.0, "{:?}", run);
+        let lag = run.delivery_lag_ms.unwrap();
+        assert!(lag.p50 <= lag.p95 && lag.p95 <= lag.p99, "{:?}", lag);
+        let summary = run.to_string();
+        assert!(summary.starts_with("Produced 100 jobs, consumed 100\n"), "{}", summary);
+        assert!(summary.contains("delivery lag: p50 "), "{}", summary);
+    }
+
+    #[test]
+    fn names_duplicated_jobs() {
+        let (hub, ledger) = produced(3);
+        let mut duplicated = None;
+        let outcome = consume(&hub, &ledger, 3, QUIET_MS, |mut jobs| {
+            if duplicated.is_none() && !jobs.is_empty() {
+                let twin = Job::new_from_metadata(jobs[0].get_metadata(), jobs[0].get_body());
+                duplicated = Some(twin.get_metadata().get_id());
+                jobs.push(twin);
+            }
+            jobs
+        });
+        assert_eq!(outcome, Outcome::Duplicated);
+        assert_eq!(outcome.exit_code(), 1);
+
+        let report = ledger.lock().unwrap().report(&hub);
+        assert!(report.contains("Duplicated jobs (1):"), "{}", report);
+        assert!(
+            report.contains(&format!("  {}\n", duplicated.unwrap())),
+            "{}",
+            report
+        );
     }
 }
@@ -0,0 +1,155 @@
+//! The errors the scheduling core reports.
+//!
+//! Hubs and spokes report a failed operation with a [`YaadError`] instead of an empty `Option`,
+//! so a caller can tell a job that was refused from one that isn't there. A refused job is left
+//! out and the hub or spoke stays as it was. Inconsistencies a hub finds in its own state are bugs:
+//! they are logged, counted in the hub's [`Stats`](::stats::Stats) as internal errors, and
+//! reported as [`YaadError::Internal`] where a caller is waiting for an answer.
+//!
+//! Reservations and reschedules keep their own [`TouchError`](::hub::TouchError) and
+//! [`RescheduleError`](::hub::RescheduleError), the latter wrapping the [`YaadError`] of a job
+//! refused at its new trigger time.
+
+use std::error::Error;
+use std::fmt;
+
+use spoke::BoundingSpokeTime;
+use uuid::Uuid;
+
+/// Why an operation on a hub or spoke failed
+#[derive(Debug, Clone, PartialEq)]
+pub enum YaadError {
+    /// The job was refused, see [`RejectReason`]
+    JobRejected { reason: RejectReason },
+    /// No job with this id is where it was looked for - it was never added, or is done with
+    NotFound(Uuid),
+    /// The spoke's window has passed, so it takes no more jobs
+    Expired { bounds: BoundingSpokeTime },
+    /// The hub holds [`HubConfig::max_pending_jobs`](::hub::HubConfig::max_pending_jobs) jobs
+    /// already
+    Capacity { max_pending_jobs: usize },
+    /// A delay of `delay_ms` from the clock's `now_ms` ends past the last millisecond a u64 holds
+    Clock { now_ms: u64, delay_ms: u64 },
+    /// The hub or a spoke found its own state inconsistent - a bug
+    Internal(&'static str),
+}
+
+/// Why a job was refused
+#[derive(Debug, Clone, PartialEq)]
+pub enum RejectReason {
+    /// The end of the spoke that would own the job doesn't fit in a u64
+    Overflow { trigger_at_ms: u64 },
+    /// The job triggers after `horizon_ms`, further ahead than the hub's `max_future_ms`
+    TooFarInFuture { trigger_at_ms: u64, horizon_ms: u64 },
+    /// The job triggers outside the bounds of the spoke it was offered to
+    OutOfBounds {
+        trigger_at_ms: u64,
+        bounds: BoundingSpokeTime,
+    },
+    /// A job with this id is held already - by the hub, scheduled, reserved or buried, or by
+    /// the spoke it was offered to
+    Duplicate(Uuid),
+    /// The hub is draining and takes no new jobs
+    Draining,
+    /// The job's id isn't a uuid v4
+    InvalidId(Uuid),
+}
+
+impl fmt::Display for YaadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            YaadError::JobRejected { ref reason } => write!(f, "{}", reason),
+            YaadError::NotFound(id) => write!(f, "Job {} is unknown", id),
+            YaadError::Expired { ref bounds } => write!(
+                f,
+                "Spoke [{}, {}) has expired and takes no more jobs",
+                bounds.get_start_time_ms(),
+                bounds.get_end_time_ms()
+            ),
+            YaadError::Capacity { max_pending_jobs } => {
+                write!(f, "Hub holds its limit of {} jobs already", max_pending_jobs)
+            }
+            YaadError::Clock { now_ms, delay_ms } => write!(
+                f,
+                "A delay of {}ms from {} overflows the clock",
+                delay_ms, now_ms
+            ),
+            YaadError::Internal(reason) => write!(f, "Hub is inconsistent: {}", reason),
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RejectReason::Overflow { trigger_at_ms } => write!(
+                f,
+                "No spoke can own a job triggering at {}: its bounds overflow",
+                trigger_at_ms
+            ),
+            RejectReason::TooFarInFuture {
+                trigger_at_ms,
+                horizon_ms,
+            } => write!(
+                f,
+                "Job triggering at {} is past the hub's horizon at {}",
+                trigger_at_ms, horizon_ms
+            ),
+            RejectReason::OutOfBounds {
+                trigger_at_ms,
+                ref bounds,
+            } => write!(
+                f,
+                "Spoke [{}, {}) doesn't cover a job triggering at {}",
+                bounds.get_start_time_ms(),
+                bounds.get_end_time_ms(),
+                trigger_at_ms
+            ),
+            RejectReason::Duplicate(id) => write!(f, "Job {} is held already", id),
+            RejectReason::Draining => write!(f, "Hub is draining and takes no new jobs"),
+            RejectReason::InvalidId(id) => write!(f, "Job id {} isn't a uuid v4", id),
+        }
+    }
+}
+
+impl Error for YaadError {}
+
+impl Error for RejectReason {}
+
+impl From<RejectReason> for YaadError {
+    fn from(reason: RejectReason) -> YaadError {
+        YaadError::JobRejected { reason }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejections_read_like_their_reason() {
+        let id = Uuid::new_v4();
+        let e = YaadError::from(RejectReason::Duplicate(id));
+        assert_eq!(
+            e,
+            YaadError::JobRejected {
+                reason: RejectReason::Duplicate(id)
+            }
+        );
+        assert_eq!(e.to_string(), format!("Job {} is held already", id));
+
+        let bounds = BoundingSpokeTime::new(1_000, 2_000);
+        assert_eq!(
+            YaadError::Expired { bounds }.to_string(),
+            "Spoke [1000, 2000) has expired and takes no more jobs"
+        );
+        assert_eq!(
+            YaadError::Clock {
+                now_ms: 10,
+                delay_ms: u64::MAX
+            }
+            .to_string(),
+            format!("A delay of {}ms from 10 overflows the clock", u64::MAX)
+        );
+    }
+}
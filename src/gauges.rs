@@ -0,0 +1,64 @@
+//! Gauges describing the health of a hub's spoke map, for embedding services to send wherever
+//! their metrics go.
+//!
+//! [`Hub::tick`](::hub::Hub::tick) samples the hub's [`HubGauges`] and hands them to the
+//! [`HubMetrics`] set with [`Hub::set_metrics`](::hub::Hub::set_metrics). A spoke map that keeps
+//! growing, or expired spokes that never get pruned, show up in the gauges long before they show
+//! up in the process' memory. The hub also tells its [`HubMetrics`] about every job it adds,
+//! walks and cancels, for backends that keep counters and histograms of their own.
+
+use std::fmt::Debug;
+
+use spoke::BoundingSpokeTime;
+
+/// A hub's spoke map at one point in time
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct HubGauges {
+    /// Spokes in the spoke map, not counting the past spoke
+    pub spokes: usize,
+    /// How long ago the oldest expired spoke still in the map ended, or 0 if no spoke expired
+    pub oldest_expired_spoke_age_ms: u64,
+    /// Jobs waiting in the past spoke
+    pub past_jobs: usize,
+    /// Jobs handed to consumers that haven't been acknowledged yet
+    pub reserved_jobs: usize,
+}
+
+impl HubGauges {
+    /// Returns every gauge with its name, e.g. to send each one to a metrics backend
+    pub fn named(&self) -> [(&'static str, u64); 4] {
+        [
+            ("spokes", self.spokes as u64),
+            (
+                "oldest_expired_spoke_age_ms",
+                self.oldest_expired_spoke_age_ms,
+            ),
+            ("past_jobs", self.past_jobs as u64),
+            ("reserved_jobs", self.reserved_jobs as u64),
+        ]
+    }
+}
+
+/// Takes the gauges a hub samples on every tick, and the jobs it adds, walks and cancels as it
+/// goes. Only the gauges have to be taken, the rest is dropped unless overridden.
+pub trait HubMetrics: Debug + Send + Sync {
+    fn record_gauges(&self, gauges: &HubGauges);
+
+    /// Takes the number of jobs one add or batch of adds took in. Jobs put back after a
+    /// reservation, release or kick are not added again.
+    fn record_jobs_added(&self, _jobs: usize) {}
+
+    /// Takes a job walked `lag_ms` after its trigger time
+    fn record_delivery(&self, _lag_ms: u64) {}
+
+    /// Takes a job cancelled before it was walked, or while buried
+    fn record_job_cancelled(&self) {}
+}
+
+/// Returns how long ago the spoke bounded by `oldest` ended, or 0 if it hasn't yet. The spoke
+/// map is ordered by start time, so its first spoke is the one that expired first.
+pub(crate) fn expired_age_ms(oldest: Option<&BoundingSpokeTime>, now_ms: u64) -> u64 {
+    oldest
+        .filter(|bst| bst.is_expired_at(now_ms))
+        .map_or(0, |bst| now_ms - bst.get_end_time_ms())
+}
@@ -1,15 +1,359 @@
-use std::collections::BTreeMap;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::sync::Arc;
 
-use job::Job;
-use spoke::{BoundingSpokeTime, Spoke};
+use clock::{Clock, MonotonicClock, SystemClock};
+use errors::{RejectReason, YaadError};
+use gauges::{self, HubGauges, HubMetrics};
+use job::{Job, JobBody, JobMetadata, TemporalState};
+use layout::{self, LayoutFormat, SpokeRow};
+use persistence::{self, SavedState};
+use sink::{JobSink, SINK_RETRY_DELAY_MS};
+use spoke::{self, BoundingSpokeTime, Spoke, SpokeStats};
+use stats::Stats;
 use times;
 use uuid::Uuid;
+use watchdog::{StallReport, Watchdog, WatchdogThresholds};
+
+/// Spokes whose stale heap entry ratio is above this are compacted unless configured otherwise
+pub const DEFAULT_STALE_COMPACTION_RATIO: f64 = 0.5;
+/// Number of upcoming spokes [`Hub::tick`] keeps created ahead of time
+pub const PREALLOCATED_SPOKES: u64 = 6;
+/// How often protocol runners call [`Hub::tick`] unless configured otherwise
+pub const DEFAULT_TICK_INTERVAL_MS: u64 = 1_000;
+/// How far ahead of now jobs may trigger unless configured otherwise - a year
+pub const DEFAULT_MAX_FUTURE_MS: u64 = 365 * 24 * 60 * 60 * 1_000;
+
+/// The past spoke covers all of time and never expires, so it only refuses a job by mistake
+const PAST_SPOKE_REFUSED: &str = "Past spoke refused a job";
+
+/// How a hub is set up. Start from [`HubConfig::new`] and override what differs from the
+/// defaults.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HubConfig {
+    /// How long a time window each spoke covers
+    pub spoke_duration_ms: u64,
+    /// How far ahead of now a job may trigger. Jobs triggering later are refused, so a buggy
+    /// producer can't create spokes that live for years.
+    pub max_future_ms: u64,
+    /// How far past now a walked job's trigger time may be. Walks check that no job is handed
+    /// out earlier than this in debug builds.
+    pub early_walk_tolerance_ms: u64,
+    /// Bodies longer than this are kept compressed while their jobs are scheduled. None keeps
+    /// every body as it was put.
+    pub compress_bodies_over_bytes: Option<usize>,
+    /// A reserved job whose TTR runs out more often than this is buried instead of scheduled
+    /// again, so a job that keeps crashing its consumers stops being handed out. None schedules
+    /// it again however often it timed out.
+    pub max_timeouts: Option<u32>,
+    /// When [`Hub::tick`] reports the hub as stalled, see [`watchdog`](::watchdog)
+    pub watchdog: WatchdogThresholds,
+    /// Most jobs the hub holds at once, scheduled, reserved and buried ones alike. New jobs past
+    /// it are refused with [`YaadError::Capacity`] until some are acknowledged or cancelled.
+    /// None takes as many as fit in memory.
+    pub max_pending_jobs: Option<usize>,
+}
+
+impl HubConfig {
+    /// Returns the default config for spokes of `spoke_duration_ms`
+    pub fn new(spoke_duration_ms: u64) -> HubConfig {
+        HubConfig {
+            spoke_duration_ms,
+            max_future_ms: DEFAULT_MAX_FUTURE_MS,
+            early_walk_tolerance_ms: 0,
+            compress_bodies_over_bytes: None,
+            max_timeouts: None,
+            watchdog: WatchdogThresholds::default(),
+            max_pending_jobs: None,
+        }
+    }
+
+    /// Returns this config refusing jobs that trigger more than `max_future_ms` from now
+    pub fn with_max_future_ms(mut self, max_future_ms: u64) -> HubConfig {
+        self.max_future_ms = max_future_ms;
+        self
+    }
+
+    /// Returns this config letting walks hand out jobs up to `tolerance_ms` before they trigger
+    pub fn with_early_walk_tolerance_ms(mut self, tolerance_ms: u64) -> HubConfig {
+        self.early_walk_tolerance_ms = tolerance_ms;
+        self
+    }
+
+    /// Returns this config compressing bodies longer than `threshold_bytes`, see
+    /// [`JobBody::compressed_over`]. Without the `compression` feature this changes nothing.
+    pub fn with_compress_bodies_over_bytes(mut self, threshold_bytes: usize) -> HubConfig {
+        self.compress_bodies_over_bytes = Some(threshold_bytes);
+        self
+    }
+
+    /// Returns this config burying reserved jobs whose TTR ran out more than `max_timeouts`
+    /// times, see [`Hub::expire_reservations`]
+    pub fn with_max_timeouts(mut self, max_timeouts: u32) -> HubConfig {
+        self.max_timeouts = Some(max_timeouts);
+        self
+    }
+
+    /// Returns this config checking the hub against `thresholds` on every tick
+    pub fn with_watchdog(mut self, thresholds: WatchdogThresholds) -> HubConfig {
+        self.watchdog = thresholds;
+        self
+    }
+
+    /// Returns this config refusing new jobs while the hub holds `max_pending_jobs`
+    pub fn with_max_pending_jobs(mut self, max_pending_jobs: usize) -> HubConfig {
+        self.max_pending_jobs = Some(max_pending_jobs);
+        self
+    }
+}
 
 #[derive(Debug)]
 pub struct Hub {
     spoke_duration_ms: u64,
+    max_future_ms: u64,
+    early_walk_tolerance_ms: u64,
+    compress_bodies_over_bytes: Option<usize>,
+    max_timeouts: Option<u32>,
     bst_spoke_map: BTreeMap<BoundingSpokeTime, Spoke>,
     past_spoke: Spoke,
+    stale_compaction_ratio: f64,
+    namespace: Uuid,
+    reserved: HashMap<Uuid, Reservation>,
+    /// Jobs shelved by a consumer, out of every spoke until kicked back
+    buried: HashMap<Uuid, Buried>,
+    buried_seq: u64,
+    /// Jobs of the spokes taken out by [`Hub::checkout_ready_spoke`], by the bounds of their spoke
+    checked_out: HashMap<BoundingSpokeTime, CheckedOut>,
+    counters: Arc<Stats>,
+    /// Where [`Hub::tick`] reports the hub's gauges, if anywhere
+    metrics: Option<Arc<dyn HubMetrics>>,
+    /// Checked for stalls on every tick
+    watchdog: Watchdog,
+    /// Read for the current time by the hub and every spoke it creates
+    clock: Arc<dyn Clock>,
+    /// Set while the hub refuses new jobs, see [`Hub::set_draining`]
+    draining: bool,
+    max_pending_jobs: Option<usize>,
+    /// Jobs scheduled, reserved or buried, counted as they come and go so puts needn't count them
+    held_jobs: usize,
+}
+
+/// A job handed to a consumer that goes back into the hub unless acknowledged by `deadline_ms`
+#[derive(Debug)]
+struct Reservation {
+    job: Job,
+    deadline_ms: u64,
+}
+
+/// A job shelved until it is kicked. `seq` is the order it was buried in - kicks go oldest first.
+#[derive(Debug)]
+struct Buried {
+    job: Job,
+    seq: u64,
+}
+
+/// The jobs of the spokes with one set of bounds that are checked out. The past spoke can be out
+/// more than once, so the jobs are only let go of once every spoke with these bounds is back.
+#[derive(Debug, Default)]
+struct CheckedOut {
+    spokes: usize,
+    /// Copies of the jobs as they were checked out, walked ones included
+    jobs: HashMap<Uuid, Job>,
+    /// Jobs cancelled while checked out, dropped from their spoke when it is checked in
+    cancelled: HashSet<Uuid>,
+}
+
+/// Where a job held by the hub currently is
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum JobState {
+    /// Scheduled and due, waiting to be walked
+    Ready,
+    /// Scheduled to trigger later
+    Delayed,
+    /// Walked and handed to a consumer that hasn't acknowledged it yet
+    Reserved,
+    /// Shelved until kicked
+    Buried,
+}
+
+/// A read-only look at a job held by the hub. The body is borrowed from wherever the job is, as
+/// stored there - possibly compressed, see [`JobBody::to_bytes`]. `body_len` is its length as put.
+#[derive(Debug, Copy, Clone)]
+pub struct JobView<'a> {
+    pub id: Uuid,
+    pub trigger_at_ms: u64,
+    pub state: JobState,
+    pub body_len: usize,
+    pub body: &'a JobBody,
+    /// How often the job was reserved, released and timed out so far
+    pub reserves: u32,
+    pub releases: u32,
+    pub timeouts: u32,
+}
+
+impl<'a> JobView<'a> {
+    fn new(jm: JobMetadata, body: &'a JobBody, state: JobState) -> JobView<'a> {
+        JobView {
+            id: jm.get_id(),
+            trigger_at_ms: jm.trigger_at_ms(),
+            state,
+            body_len: body.raw_len(),
+            body,
+            reserves: jm.reserves(),
+            releases: jm.releases(),
+            timeouts: jm.timeouts(),
+        }
+    }
+}
+
+/// Reasons a reservation can't be touched
+#[derive(Debug, Clone, PartialEq)]
+pub enum TouchError {
+    /// The hub doesn't hold the job - it was never added, or already acknowledged or cancelled
+    UnknownJob(Uuid),
+    /// The hub holds the job but it isn't reserved, e.g. because its TTR ran out
+    NotReserved(Uuid),
+}
+
+impl fmt::Display for TouchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TouchError::UnknownJob(id) => write!(f, "Job {} is unknown", id),
+            TouchError::NotReserved(id) => write!(f, "Job {} isn't reserved", id),
+        }
+    }
+}
+
+impl Error for TouchError {}
+
+/// Reasons a job can't be rescheduled. The job is left where it was.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RescheduleError {
+    /// The hub doesn't hold the job - it was never added, or already walked or cancelled
+    UnknownJob(Uuid),
+    /// The job is reserved by a consumer, release it to reschedule it
+    Reserved(Uuid),
+    /// The job is buried, kick it to schedule it again
+    Buried(Uuid),
+    /// The hub refused the job at its new trigger time
+    Refused(YaadError),
+}
+
+impl fmt::Display for RescheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RescheduleError::UnknownJob(id) => write!(f, "Job {} is unknown", id),
+            RescheduleError::Reserved(id) => write!(f, "Job {} is reserved", id),
+            RescheduleError::Buried(id) => write!(f, "Job {} is buried", id),
+            RescheduleError::Refused(ref e) => write!(f, "Job can't be rescheduled: {}", e),
+        }
+    }
+}
+
+impl Error for RescheduleError {}
+
+/// A job a walk handed out before its trigger time, less the config's
+/// [`early_walk_tolerance_ms`](HubConfig::early_walk_tolerance_ms)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct EarlyWalk {
+    pub id: Uuid,
+    pub trigger_at_ms: u64,
+    pub walked_at_ms: u64,
+}
+
+impl fmt::Display for EarlyWalk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Job {} triggering at {} was walked {}ms early",
+            self.id,
+            self.trigger_at_ms,
+            self.trigger_at_ms - self.walked_at_ms
+        )
+    }
+}
+
+impl Error for EarlyWalk {}
+
+/// Aggregate view of heap entries left behind by cancelled jobs across all spokes
+#[derive(Debug, Clone, PartialEq)]
+pub struct StaleStats {
+    pub total_stale_entries: usize,
+    pub worst_ratio: f64,
+    pub worst_bounds: Option<BoundingSpokeTime>,
+    pub worst_spoke_id: Option<Uuid>,
+    pub spokes_above_threshold: usize,
+}
+
+/// Jobs and job body bytes held in each of a hub's time windows, for capacity planning.
+/// Reserved and buried jobs aren't held by any spoke, so they aren't counted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HubStats {
+    pub past: SpokeStats,
+    /// Every other spoke, in time order
+    pub spokes: Vec<SpokeStats>,
+    pub total_jobs: usize,
+    pub total_body_bytes: usize,
+    /// What the bodies take up as stored, less than `total_body_bytes` if some are compressed
+    pub total_stored_body_bytes: usize,
+}
+
+/// Returns the bounds of the spoke owning `time_ms`. Spokes are aligned to multiples of their
+/// duration, so every time has exactly one owner and neighbouring spokes don't overlap. Returns
+/// None if the spoke's end doesn't fit in a u64.
+fn spoke_bounds_at(time_ms: u64, spoke_duration_ms: u64) -> Option<BoundingSpokeTime> {
+    let spoke_start = times::floor_to(time_ms, spoke_duration_ms);
+    spoke_start
+        .checked_add(spoke_duration_ms)
+        .map(|spoke_end| BoundingSpokeTime::new(spoke_start, spoke_end))
+}
+
+/// Sums up the stale heap entries of `spokes`, counting the ones above `compaction_ratio`
+pub(crate) fn stale_stats_of<'a, I: IntoIterator<Item = &'a Spoke>>(
+    spokes: I,
+    compaction_ratio: f64,
+) -> StaleStats {
+    let mut stats = StaleStats {
+        total_stale_entries: 0,
+        worst_ratio: 0.0,
+        worst_bounds: None,
+        worst_spoke_id: None,
+        spokes_above_threshold: 0,
+    };
+    for s in spokes {
+        let ratio = s.stale_ratio();
+        stats.total_stale_entries += s.stale_entry_len();
+        if ratio > stats.worst_ratio {
+            stats.worst_ratio = ratio;
+            stats.worst_bounds = Some(s.get_bounds());
+            stats.worst_spoke_id = Some(s.get_id());
+        }
+        if ratio > compaction_ratio {
+            stats.spokes_above_threshold += 1;
+        }
+    }
+    stats
+}
+
+/// Collects the stats of the past spoke and of `spokes`, which are in time order
+pub(crate) fn hub_stats_of<'a, I: IntoIterator<Item = &'a Spoke>>(
+    past: &Spoke,
+    spokes: I,
+) -> HubStats {
+    let past = past.stats();
+    let spokes: Vec<SpokeStats> = spokes.into_iter().map(|s| s.stats()).collect();
+    let all = || Some(&past).into_iter().chain(spokes.iter());
+    HubStats {
+        total_jobs: all().map(|s| s.job_count).sum(),
+        total_body_bytes: all().map(|s| s.body_bytes).sum(),
+        total_stored_body_bytes: all().map(|s| s.stored_body_bytes).sum(),
+        past,
+        spokes,
+    }
 }
 
 impl Hub {
@@ -18,373 +362,3949 @@ impl Hub {
     ///
     /// A Hub comes with a default `past` spoke which accepts any job whose trigger time is in the
     /// past. The hub will always try to walk this spoke first.
+    ///
+    /// The hub is set up with the defaults of [`HubConfig::new`].
     pub fn new(spoke_duration_ms: u64) -> Hub {
+        Hub::from_config(HubConfig::new(spoke_duration_ms))
+    }
+
+    /// Creates a new Hub set up by `config`, see [`Hub::new`]
+    pub fn from_config(config: HubConfig) -> Hub {
+        Hub::from_config_with_clock(config, Arc::new(SystemClock))
+    }
+
+    /// Creates a new Hub whose spoke ids are derived in the given namespace. Two hubs sharing a
+    /// namespace and spoke duration give the same time window the same spoke id.
+    pub fn new_in_namespace(spoke_duration_ms: u64, namespace: Uuid) -> Hub {
+        let config = HubConfig::new(spoke_duration_ms);
+        Hub::new_in_namespace_with_clock(config, namespace, Arc::new(SystemClock))
+    }
+
+    /// Creates a new Hub that reads the current time off `clock` instead of the system clock,
+    /// e.g. a [`MockClock`](::clock::MockClock) a test moves forward instead of sleeping
+    pub fn new_with_clock(spoke_duration_ms: u64, clock: Arc<dyn Clock>) -> Hub {
+        Hub::from_config_with_clock(HubConfig::new(spoke_duration_ms), clock)
+    }
+
+    /// Creates a new Hub set up by `config` that reads the current time off `clock`. The hub reads
+    /// it through a [`MonotonicClock`], so it never sees the time go back even if `clock` does.
+    pub fn from_config_with_clock(config: HubConfig, clock: Arc<dyn Clock>) -> Hub {
+        Hub::new_in_namespace_with_clock(config, spoke::default_spoke_namespace(), clock)
+    }
+
+    fn new_in_namespace_with_clock(
+        config: HubConfig,
+        namespace: Uuid,
+        clock: Arc<dyn Clock>,
+    ) -> Hub {
+        let clock: Arc<dyn Clock> = Arc::new(MonotonicClock::new(clock));
+        let past_spoke = Spoke::new_in_namespace(&namespace, BoundingSpokeTime::new(0, u64::MAX))
+            .with_clock(Arc::clone(&clock))
+            .with_compress_bodies_over_bytes(config.compress_bodies_over_bytes);
         Hub {
-            spoke_duration_ms,
+            spoke_duration_ms: config.spoke_duration_ms,
+            max_future_ms: config.max_future_ms,
+            early_walk_tolerance_ms: config.early_walk_tolerance_ms,
+            compress_bodies_over_bytes: config.compress_bodies_over_bytes,
+            max_timeouts: config.max_timeouts,
             bst_spoke_map: BTreeMap::new(),
-            past_spoke: Spoke::new(0, <u64>::max_value()),
+            past_spoke,
+            stale_compaction_ratio: DEFAULT_STALE_COMPACTION_RATIO,
+            namespace,
+            reserved: HashMap::new(),
+            buried: HashMap::new(),
+            buried_seq: 0,
+            checked_out: HashMap::new(),
+            counters: Arc::new(Stats::new()),
+            metrics: None,
+            watchdog: Watchdog::new(config.watchdog, clock.now_ms()),
+            clock,
+            draining: false,
+            max_pending_jobs: config.max_pending_jobs,
+            held_jobs: 0,
+        }
+    }
+
+    /// Sets the stale entry ratio above which a spoke becomes a compaction candidate.
+    pub fn set_stale_compaction_ratio(&mut self, ratio: f64) -> &mut Hub {
+        self.stale_compaction_ratio = ratio;
+        self
+    }
+
+    /// Makes the hub refuse new jobs with [`RejectReason::Draining`] while `draining` is set, e.g.
+    /// during a deploy. Jobs already held are walked, released and kicked as usual.
+    pub fn set_draining(&mut self, draining: bool) -> &mut Hub {
+        self.draining = draining;
+        self
+    }
+
+    /// Returns true while the hub refuses new jobs, see [`Hub::set_draining`]
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /// Makes the hub count into `counters`, e.g. to share one collector between several hubs.
+    /// The hub's current spokes are counted into it, the counts of the collector it used before
+    /// are left as they are.
+    pub fn set_counters(&mut self, counters: Arc<Stats>) -> &mut Hub {
+        counters.record_spokes_created(self.bst_spoke_map.len());
+        self.counters = counters;
+        self
+    }
+
+    /// Makes [`Hub::tick`] report the hub's gauges to `metrics`, and the hub report every job it
+    /// adds, walks and cancels from now on
+    pub fn set_metrics(&mut self, metrics: Arc<dyn HubMetrics>) -> &mut Hub {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Makes [`Hub::tick`] hand every [`StallReport`] to `on_stall` besides logging it, e.g. to
+    /// page someone
+    pub fn set_on_stall(&mut self, on_stall: Box<dyn Fn(StallReport) + Send>) -> &mut Hub {
+        self.watchdog.set_on_stall(on_stall);
+        self
+    }
+
+    /// Returns how many spokes, past jobs and reservations the hub holds right now
+    pub fn gauges(&self) -> HubGauges {
+        let now_ms = self.clock.now_ms();
+        HubGauges {
+            spokes: self.bst_spoke_map.len(),
+            oldest_expired_spoke_age_ms: gauges::expired_age_ms(
+                self.bst_spoke_map.keys().next(),
+                now_ms,
+            ),
+            past_jobs: self.past_spoke.pending_job_len(),
+            reserved_jobs: self.reserved.len(),
+        }
+    }
+
+    /// Returns how long a time window each of the hub's spokes covers
+    pub fn spoke_duration_ms(&self) -> u64 {
+        self.spoke_duration_ms
+    }
+
+    /// Returns the clock the hub reads the current time off, e.g. to set up more hubs that agree
+    /// with it on the time
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Returns the config this hub was set up with
+    pub fn config(&self) -> HubConfig {
+        HubConfig {
+            spoke_duration_ms: self.spoke_duration_ms,
+            max_future_ms: self.max_future_ms,
+            early_walk_tolerance_ms: self.early_walk_tolerance_ms,
+            compress_bodies_over_bytes: self.compress_bodies_over_bytes,
+            max_timeouts: self.max_timeouts,
+            watchdog: self.watchdog.thresholds(),
+            max_pending_jobs: self.max_pending_jobs,
+        }
+    }
+
+    /// Returns the collector this hub counts into
+    pub fn counters(&self) -> &Arc<Stats> {
+        &self.counters
+    }
+
+    /// Returns how many jobs and job body bytes each spoke holds, with totals. Spokes keep their
+    /// counts up to date as jobs come and go, so this doesn't look at the jobs themselves.
+    pub fn stats(&self) -> HubStats {
+        hub_stats_of(&self.past_spoke, self.bst_spoke_map.values())
+    }
+
+    /// Returns the number of jobs scheduled in the hub, the past spoke included. Reserved and
+    /// buried jobs aren't scheduled, so they aren't counted.
+    pub fn pending_job_count(&self) -> usize {
+        self.all_spokes().map(|s| s.pending_job_len()).sum()
+    }
+
+    /// Returns the number of scheduled jobs that are due and would be handed out by a walk now
+    pub fn ready_job_count(&self) -> usize {
+        let now_ms = self.clock.now_ms();
+        self.all_spokes().map(|s| s.due_job_len(now_ms)).sum()
+    }
+
+    /// Returns the number of jobs the hub holds, scheduled, reserved and buried ones alike - what
+    /// [`HubConfig::max_pending_jobs`] limits. Jobs walked off the hub and not reserved aren't
+    /// held anymore.
+    pub fn held_job_count(&self) -> usize {
+        self.held_jobs
+    }
+
+    /// Returns the number of spokes the hub keeps, not counting the past spoke
+    pub fn spoke_count(&self) -> usize {
+        self.bst_spoke_map.len()
+    }
+
+    /// Returns a copy of a job held by the hub along with where it is, or None if the hub doesn't
+    /// hold it
+    pub fn find_job(&self, id: Uuid) -> Option<(Job, JobState)> {
+        if let Some(r) = self.reserved.get(&id) {
+            return Some((r.job.clone(), JobState::Reserved));
+        }
+        if let Some(b) = self.buried.get(&id) {
+            return Some((b.job.clone(), JobState::Buried));
+        }
+        if let Some(job) = self.checked_out_job(id) {
+            return Some((job.clone(), JobState::Ready));
+        }
+        let job = self
+            .peek_job(id)
+            .map(|(jm, body)| Job::new_from_metadata(jm, body.clone()))?;
+        let state = if job.is_ready_at(self.clock.now_ms()) {
+            JobState::Ready
+        } else {
+            JobState::Delayed
+        };
+        Some((job, state))
+    }
+
+    /// Returns a view of a job held by the hub wherever it is - scheduled, the past spoke included,
+    /// reserved or buried - or None if the hub doesn't hold it. Unlike [`Hub::find_job`] nothing is
+    /// copied. A job in a checked out spoke is ready, and looks like it did when checked out.
+    pub fn get_job(&self, id: Uuid) -> Option<JobView<'_>> {
+        let (jm, body, state) = if let Some(r) = self.reserved.get(&id) {
+            (r.job.get_metadata(), r.job.body(), JobState::Reserved)
+        } else if let Some(b) = self.buried.get(&id) {
+            (b.job.get_metadata(), b.job.body(), JobState::Buried)
+        } else if let Some(job) = self.checked_out_job(id) {
+            (job.get_metadata(), job.body(), JobState::Ready)
+        } else {
+            let (jm, body) = self.peek_job(id)?;
+            let state = if jm.is_ready_at(self.clock.now_ms()) {
+                JobState::Ready
+            } else {
+                JobState::Delayed
+            };
+            (jm, body, state)
+        };
+        Some(JobView::new(jm, body, state))
+    }
+
+    /// Returns a view of every scheduled job triggering within `range`, from its start up to but
+    /// excluding its end, or of every scheduled job if `range` is None. Nothing is walked or
+    /// copied, so a walk afterwards hands out the same jobs as without the look.
+    ///
+    /// Only the spokes whose bounds meet the range are looked at, and the past spoke only if the
+    /// range starts by now. Jobs come spoke by spoke, the past spoke first and then in time order,
+    /// but in no particular order within a spoke. Reserved and buried jobs aren't scheduled, so
+    /// they aren't included.
+    pub fn iter_scheduled(
+        &self,
+        range: Option<(u64, u64)>,
+    ) -> impl Iterator<Item = JobView<'_>> + '_ {
+        let now_ms = self.clock.now_ms();
+        let (start_ms, end_ms) = range.unwrap_or((0, u64::MAX));
+        let past = Some(&self.past_spoke).filter(|_| start_ms <= now_ms);
+        // Spokes don't overlap, so the ones ending by the start of the range are a prefix
+        let spokes = self
+            .bst_spoke_map
+            .range(spoke::started_before(end_ms))
+            .skip_while(move |(bst, _)| bst.get_end_time_ms() <= start_ms)
+            .map(|(_, s)| s);
+        past.into_iter()
+            .chain(spokes)
+            .flat_map(|s| s.live_jobs())
+            .filter(move |(jm, _)| {
+                range.is_none_or(|(start_ms, end_ms)| {
+                    (start_ms..end_ms).contains(&jm.trigger_at_ms())
+                })
+            })
+            .map(move |(jm, body)| {
+                let state = if jm.is_ready_at(now_ms) {
+                    JobState::Ready
+                } else {
+                    JobState::Delayed
+                };
+                JobView::new(jm, body, state)
+            })
+    }
+
+    /// Returns the metadata and a reference to the body of a job scheduled in the hub, the past
+    /// spoke included, or None if no spoke holds it. Nothing is walked.
+    pub fn peek_job(&self, id: Uuid) -> Option<(JobMetadata, &JobBody)> {
+        let bst = self.find_job_owner_bst(id)?;
+        let spoke = if bst == self.past_spoke.get_bounds() {
+            &self.past_spoke
+        } else {
+            self.bst_spoke_map.get(&bst)?
+        };
+        spoke.peek_job(id)
+    }
+
+    /// Returns the ready job the next walk would hand out first, without walking it. The body is
+    /// a copy as it was put.
+    pub fn peek_next_ready(&self) -> Option<(JobMetadata, JobBody)> {
+        let now_ms = self.clock.now_ms();
+        self.peek_next_where(|jm| jm.is_ready_at(now_ms))
+    }
+
+    /// Returns the delayed job that falls due first, without walking it
+    pub fn peek_next_delayed(&self) -> Option<(JobMetadata, JobBody)> {
+        let now_ms = self.clock.now_ms();
+        self.peek_next_where(|jm| !jm.is_ready_at(now_ms))
+    }
+
+    /// Returns the buried job the next kick would schedule first
+    pub fn peek_next_buried(&self) -> Option<&Job> {
+        self.buried.values().min_by_key(|b| b.seq).map(|b| &b.job)
+    }
+
+    /// Returns the next scheduled job in walk order among those matching `pred`. Spokes don't
+    /// overlap and are ordered by start time, so besides the past spoke only the first spoke
+    /// holding a matching job has to be looked at.
+    fn peek_next_where<P: Fn(&JobMetadata) -> bool>(
+        &self,
+        pred: P,
+    ) -> Option<(JobMetadata, JobBody)> {
+        let past = self.past_spoke.peek_job_where(&pred);
+        let past = past.map(|jm| (jm, &self.past_spoke));
+        let next = self
+            .bst_spoke_map
+            .values()
+            .filter_map(|s| s.peek_job_where(&pred).map(|jm| (jm, s)))
+            .next();
+        let (jm, spoke) = past.into_iter().chain(next).max_by_key(|n| n.0)?;
+        spoke
+            .peek_job(jm.get_id())
+            .map(|(jm, body)| (jm, body.decompressed()))
+    }
+
+    /// Rebuilds a hub from a snapshot written by [`Hub::snapshot`]. Jobs whose trigger time passed
+    /// in the meantime land in the past spoke and are handed out on the first walk. Buried jobs
+    /// are buried again, while reserved ones are scheduled like the rest - their consumer is gone
+    /// with the process that wrote the snapshot. A job the hub refuses fails the restore with
+    /// `InvalidData`.
+    ///
+    /// The hub is set up like [`Hub::new`]; use [`Hub::restore_with_config`] for a hub that takes
+    /// jobs further ahead, or more of them, than the defaults allow.
+    pub fn restore<R: Read>(reader: R, spoke_duration_ms: u64) -> io::Result<Hub> {
+        Hub::restore_with_config(reader, HubConfig::new(spoke_duration_ms))
+    }
+
+    /// Rebuilds a hub set up by `config` from a snapshot, like [`Hub::restore`]
+    pub fn restore_with_config<R: Read>(mut reader: R, config: HubConfig) -> io::Result<Hub> {
+        let mut hub = Hub::from_config(config);
+        for (_, job, state) in persistence::read_jobs(&mut reader)? {
+            match state {
+                SavedState::Buried => hub.add_buried_job(job),
+                SavedState::Ready | SavedState::Reserved => hub.add_job(job),
+            }
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        }
+        Ok(hub)
+    }
+
+    /// Writes every job in the hub to `writer` and returns how many were written. Reserved and
+    /// buried jobs are included too - they haven't been acknowledged, so they still have to run.
+    pub fn snapshot<W: Write>(&self, mut writer: W) -> io::Result<usize> {
+        let jobs = self.saved_jobs();
+        persistence::write_jobs(&mut writer, jobs.iter().map(|j| ("", &j.0, j.1)))
+    }
+
+    /// Returns copies of every job like [`Hub::jobs`], each with the state a snapshot records
+    pub fn saved_jobs(&self) -> Vec<(Job, SavedState)> {
+        self.all_spokes()
+            .flat_map(|s| s.jobs())
+            .map(|j| (j, SavedState::Ready))
+            .chain(self.reserved.values().map(|r| (r.job.clone(), SavedState::Reserved)))
+            .chain(self.buried.values().map(|b| (b.job.clone(), SavedState::Buried)))
+            .collect()
+    }
+
+    /// Returns copies of every scheduled, reserved and buried job, in no particular order
+    pub fn jobs(&self) -> Vec<Job> {
+        self.all_spokes()
+            .flat_map(|s| s.jobs())
+            .chain(self.reserved.values().map(|r| r.job.clone()))
+            .chain(self.buried.values().map(|b| b.job.clone()))
+            .collect()
+    }
+
+    /// Returns stale heap entry totals, the worst spoke's ratio and how many spokes are above the
+    /// compaction threshold. The past spoke is included.
+    pub fn stale_stats(&self) -> StaleStats {
+        stale_stats_of(self.all_spokes(), self.stale_compaction_ratio)
+    }
+
+    /// Compacts at most `budget` spokes whose stale ratio is above the compaction threshold,
+    /// worst spokes first. Returns the number of stale entries dropped.
+    pub fn compact_stale_spokes(&mut self, budget: usize) -> usize {
+        let threshold = self.stale_compaction_ratio;
+        let mut candidates: Vec<(f64, BoundingSpokeTime)> = self
+            .all_spokes()
+            .map(|s| (s.stale_ratio(), s.get_bounds()))
+            .filter(|c| c.0 > threshold)
+            .collect();
+        candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+
+        let past_bounds = self.past_spoke.get_bounds();
+        let mut dropped = 0;
+        for (_, bst) in candidates.into_iter().take(budget) {
+            if bst == past_bounds {
+                dropped += self.past_spoke.compact();
+            } else if let Some(s) = self.bst_spoke_map.get_mut(&bst) {
+                dropped += s.compact();
+            }
         }
+        dropped
+    }
+
+    /// Renders the current spoke layout for visual debugging. See [`layout`] for the formats.
+    pub fn render_layout(&self, format: LayoutFormat) -> String {
+        self.render_layout_at(format, self.clock.now_ms())
+    }
+
+    /// Renders the spoke layout as it would look at `now_ms`.
+    pub fn render_layout_at(&self, format: LayoutFormat, now_ms: u64) -> String {
+        let row = |s: &Spoke| SpokeRow {
+            short_id: s.short_id(),
+            bounds: s.get_bounds(),
+            live: s.live_job_len(),
+            stale: s.stale_entry_len(),
+        };
+        let spokes: Vec<SpokeRow> = self.bst_spoke_map.values().map(row).collect();
+        layout::render(format, &row(&self.past_spoke), &spokes, now_ms)
+    }
+
+    fn all_spokes(&self) -> impl Iterator<Item = &Spoke> {
+        Some(&self.past_spoke)
+            .into_iter()
+            .chain(self.bst_spoke_map.values())
     }
 
     pub fn find_job_owner_bst(&self, id: Uuid) -> Option<BoundingSpokeTime> {
         if self.past_spoke.owns_job(id) {
             return Some(self.past_spoke.get_bounds().clone());
         }
-        let entry = self
-            .bst_spoke_map
+        self.bst_spoke_map
             .iter()
-            .skip_while(|e| !e.1.owns_job(id))
+            .find(|e| e.1.owns_job(id))
+            .map(|e| *e.0)
+    }
+
+    /// Returns the earliest trigger time of any job in the hub, past spoke included, or None if the
+    /// hub has no jobs. Callers can sleep until then instead of polling walk.
+    ///
+    /// Spokes don't overlap and are ordered by start time, so only the first spoke holding a job
+    /// has to be looked at.
+    pub fn next_trigger_time_ms(&mut self) -> Option<u64> {
+        let past = self.past_spoke.peek_next_trigger();
+        let next = self
+            .bst_spoke_map
+            .values_mut()
+            .filter_map(|s| s.peek_next_trigger())
             .next();
-        if entry.is_some() {
-            return Some(entry.unwrap().0.clone());
+        match (past, next) {
+            (Some(p), Some(n)) => Some(p.min(n)),
+            (p, n) => p.or(n),
+        }
+    }
+
+    /// Walks the ready jobs and reserves each of them. Reserved jobs must be acknowledged with
+    /// [`Hub::ack`] within their TTR or [`Hub::expire_reservations`] schedules them again.
+    pub fn reserve_ready_jobs(&mut self) -> Vec<Job> {
+        let jobs = self.walk_jobs();
+        jobs.into_iter().map(|j| self.reserve_job(j)).collect()
+    }
+
+    /// Tracks a job already walked off the hub as reserved until its TTR runs out from now,
+    /// counting the reserve against the job
+    pub fn reserve_job(&mut self, job: Job) -> Job {
+        let job = job.with_reserve_counted();
+        let deadline_ms = self.clock.now_ms().saturating_add(job.ttr_ms());
+        let reservation = Reservation {
+            job: job.clone(),
+            deadline_ms,
+        };
+        if self.reserved.insert(job.get_metadata().get_id(), reservation).is_none() {
+            self.held_jobs += 1;
+        }
+        job
+    }
+
+    /// Acknowledges a reserved job, removing it for good. Returns false if the job isn't
+    /// reserved - it was never reserved, already acknowledged, or its TTR ran out.
+    pub fn ack(&mut self, id: Uuid) -> bool {
+        let acked = self.reserved.remove(&id).is_some();
+        if acked {
+            self.held_jobs -= 1;
+        }
+        acked
+    }
+
+    /// Puts a reserved job back into the hub to trigger `delay_ms` from now. Fails with
+    /// [`YaadError::NotFound`] if the job isn't reserved. The job stays reserved if the hub
+    /// refuses it.
+    pub fn release(&mut self, id: Uuid, delay_ms: u64) -> Result<(), YaadError> {
+        self.release_job(id, None, delay_ms)
+    }
+
+    /// Like [`Hub::release`], also setting the job's priority to `priority`
+    pub fn release_with_priority(
+        &mut self,
+        id: Uuid,
+        priority: u32,
+        delay_ms: u64,
+    ) -> Result<(), YaadError> {
+        self.release_job(id, Some(priority), delay_ms)
+    }
+
+    fn release_job(
+        &mut self,
+        id: Uuid,
+        priority: Option<u32>,
+        delay_ms: u64,
+    ) -> Result<(), YaadError> {
+        let job = match self.reserved.get(&id) {
+            Some(r) => r.job.clone(),
+            None => return Err(YaadError::NotFound(id)),
+        };
+        let job = match priority {
+            Some(p) => job.with_priority(p),
+            None => job,
+        };
+        let now_ms = self.clock.now_ms();
+        let trigger_at_ms = now_ms
+            .checked_add(delay_ms)
+            .ok_or(YaadError::Clock { now_ms, delay_ms })?;
+        let job = job.with_trigger_at_ms(trigger_at_ms).with_release_counted();
+        self.schedule_job(job)?;
+        self.reserved.remove(&id);
+        Ok(())
+    }
+
+    /// Schedules every reserved job whose TTR ran out again, counting a timeout against each.
+    /// They land in the past spoke and are handed out on the next walk. A job that timed out more
+    /// often than [`HubConfig::max_timeouts`] allows is buried instead. Returns the number of jobs
+    /// re-queued.
+    pub fn expire_reservations(&mut self) -> usize {
+        let now_ms = self.clock.now_ms();
+        self.requeue_reservations(now_ms, true)
+    }
+
+    /// Schedules every reserved job again whether its TTR ran out or not, like
+    /// [`Hub::expire_reservations`] but without counting timeouts. Returns the number of jobs
+    /// re-queued.
+    pub fn release_reservations(&mut self) -> usize {
+        self.requeue_reservations(u64::MAX, false)
+    }
+
+    /// Schedules the reserved jobs whose deadline is at or before `deadline_ms` again at their
+    /// original trigger time. If `timed_out`, a timeout is counted against each and the ones over
+    /// the limit are buried.
+    fn requeue_reservations(&mut self, deadline_ms: u64, timed_out: bool) -> usize {
+        let due: Vec<Uuid> = self
+            .reserved
+            .iter()
+            .filter(|r| r.1.deadline_ms <= deadline_ms)
+            .map(|r| *r.0)
+            .collect();
+        let mut requeued = 0;
+        for id in &due {
+            let mut job = self.reserved[id].job.clone();
+            if timed_out {
+                job = job.with_timeout_counted();
+                if self.max_timeouts.is_some_and(|max| job.timeouts() > max) {
+                    warn!("Burying job {} after {} TTR timeouts", id, job.timeouts());
+                    self.reserved.remove(id);
+                    self.shelve(job);
+                    self.counters.record_job_auto_buried();
+                    continue;
+                }
+            }
+            match self.schedule_job(job) {
+                Ok(()) => {
+                    self.reserved.remove(id);
+                    requeued += 1;
+                }
+                // Keep the reservation so the job isn't lost, it is retried on the next call
+                Err(e) => warn!("Failed to re-queue reserved job {}: {}", id, e),
+            }
+        }
+        requeued
+    }
+
+    /// Gives a reserved job its full TTR again, counting from now, and returns the new deadline.
+    /// Workers busy with a job for long touch it so it isn't handed out again meanwhile.
+    pub fn touch(&mut self, id: Uuid) -> Result<u64, TouchError> {
+        if let Some(r) = self.reserved.get_mut(&id) {
+            r.deadline_ms = self.clock.now_ms().saturating_add(r.job.ttr_ms());
+            return Ok(r.deadline_ms);
+        }
+        match self.find_job(id) {
+            Some(_) => Err(TouchError::NotReserved(id)),
+            None => Err(TouchError::UnknownJob(id)),
+        }
+    }
+
+    /// Returns when a reserved job's TTR runs out, or None if it isn't reserved
+    pub fn reservation_deadline_ms(&self, id: Uuid) -> Option<u64> {
+        self.reserved.get(&id).map(|r| r.deadline_ms)
+    }
+
+    /// Returns the earliest TTR deadline among reserved jobs, if any
+    pub fn next_reservation_deadline_ms(&self) -> Option<u64> {
+        self.reserved.values().map(|r| r.deadline_ms).min()
+    }
+
+    /// Returns the number of jobs reserved and not yet acknowledged
+    pub fn reserved_job_len(&self) -> usize {
+        self.reserved.len()
+    }
+
+    /// Shelves a reserved job with its priority set to `priority` until it is kicked. Returns
+    /// false if the job isn't reserved.
+    pub fn bury(&mut self, id: Uuid, priority: u32) -> bool {
+        match self.reserved.remove(&id) {
+            Some(r) => {
+                self.shelve(r.job.with_priority(priority));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Adds a job buried right away, like one buried after it was reserved, e.g. to restore a job
+    /// that was buried when its snapshot was taken. The job is refused like [`Hub::add_job`]
+    /// would refuse it, but for its trigger time, which doesn't matter until it is kicked.
+    pub fn add_buried_job(&mut self, job: Job) -> Result<(), YaadError> {
+        if self.draining {
+            return Err(RejectReason::Draining.into());
+        }
+        let id = job.get_metadata().get_id();
+        if self.holds_job(id) {
+            return Err(RejectReason::Duplicate(id).into());
+        }
+        if let Some(e) = capacity_error(self.max_pending_jobs, self.held_jobs) {
+            return Err(e);
+        }
+        self.shelve(job);
+        self.held_jobs += 1;
+        let now_ms = self.clock.now_ms();
+        self.record_adds(1, now_ms);
+        Ok(())
+    }
+
+    /// Buries a job no longer reserved, behind the jobs buried before it
+    fn shelve(&mut self, job: Job) {
+        self.buried_seq += 1;
+        let buried = Buried {
+            job,
+            seq: self.buried_seq,
+        };
+        self.buried.insert(buried.job.get_metadata().get_id(), buried);
+    }
+
+    /// Schedules up to `max` buried jobs to trigger right away, in the order they were buried like
+    /// beanstalkd does. Returns the number of jobs kicked.
+    pub fn kick(&mut self, max: usize) -> usize {
+        let mut buried: Vec<(u64, Uuid)> = self.buried.iter().map(|b| (b.1.seq, *b.0)).collect();
+        buried.sort();
+        let mut kicked = 0;
+        for (_, id) in buried.into_iter().take(max) {
+            match self.kick_job(id) {
+                Ok(_) => kicked += 1,
+                // The job stays buried so it isn't lost, it can be kicked again
+                Err(e) => warn!("Failed to kick buried job {}: {}", id, e),
+            }
+        }
+        kicked
+    }
+
+    /// Schedules a buried job to trigger right away. Fails with [`YaadError::NotFound`] if the
+    /// job isn't buried. The job stays buried if the hub refuses it.
+    pub fn kick_job(&mut self, id: Uuid) -> Result<(), YaadError> {
+        let job = match self.buried.get(&id) {
+            Some(b) => b.job.clone(),
+            None => return Err(YaadError::NotFound(id)),
+        };
+        let now_ms = self.clock.now_ms();
+        self.schedule_job(job.with_trigger_at_ms(now_ms))?;
+        self.buried.remove(&id);
+        Ok(())
+    }
+
+    /// Returns the number of buried jobs waiting to be kicked
+    pub fn buried_job_len(&self) -> usize {
+        self.buried.len()
+    }
+
+    /// Cancels a job wherever it is scheduled, the past spoke included, or drops it if it is
+    /// buried. Returns false if the hub doesn't hold the job - it was never added, already
+    /// cancelled or walked, or its spoke was pruned.
+    ///
+    /// A job in a checked out spoke is dropped from it when the spoke is checked back in, unless
+    /// it was walked off the spoke by then.
+    pub fn cancel_job(&mut self, id: Uuid) -> bool {
+        if let Some(out) = self.checked_out.values_mut().find(|c| c.jobs.contains_key(&id)) {
+            out.jobs.remove(&id);
+            out.cancelled.insert(id);
+            if let Some(ref metrics) = self.metrics {
+                metrics.record_job_cancelled();
+            }
+            return true;
+        }
+        let cancelled = self.buried.remove(&id).is_some()
+            || match self.find_job_owner_bst(id).and_then(|bst| self.spoke_mut(bst)) {
+                Some(s) => s.cancel_job(id),
+                None => false,
+            };
+        if cancelled {
+            self.held_jobs -= 1;
+            if let Some(ref metrics) = self.metrics {
+                metrics.record_job_cancelled();
+            }
+        }
+        cancelled
+    }
+
+    /// Moves a scheduled job to trigger at `new_trigger_at_ms` instead, keeping its id, body,
+    /// priority and TTR. The job lands in the spoke covering its new trigger time, or in the past
+    /// spoke if that time has come already. The heap entry at the old time is left behind as a
+    /// tombstone, so the job only fires at its new time. A refused job stays where it was.
+    pub fn reschedule(&mut self, id: Uuid, new_trigger_at_ms: u64) -> Result<(), RescheduleError> {
+        if self.reserved.contains_key(&id) {
+            return Err(RescheduleError::Reserved(id));
+        }
+        if self.buried.contains_key(&id) {
+            return Err(RescheduleError::Buried(id));
+        }
+        let unknown = RescheduleError::UnknownJob(id);
+        let bst = self.find_job_owner_bst(id).ok_or_else(|| unknown.clone())?;
+        let job = self
+            .spoke_mut(bst)
+            .and_then(|s| s.find_job(id))
+            .ok_or(unknown)?;
+        let old_trigger_at_ms = job.trigger_at_ms();
+        if old_trigger_at_ms == new_trigger_at_ms {
+            return Ok(());
+        }
+        // Spokes refuse ids they hold already, so the old entry goes first
+        if let Some(s) = self.spoke_mut(bst) {
+            s.cancel_job(id);
+        }
+        if let Err(e) = self.schedule_job(job.clone().with_trigger_at_ms(new_trigger_at_ms)) {
+            // Put the job back at its old time, which its spoke or the past spoke still accepts
+            if let Err(restore) = self.schedule_job(job) {
+                error!("Lost job {} while rescheduling it: {}", id, restore);
+                self.counters.record_internal_error();
+                self.held_jobs -= 1;
+            }
+            return Err(RescheduleError::Refused(e));
+        }
+        Ok(())
+    }
+
+    /// Returns the spoke with these bounds, the past spoke included
+    fn spoke_mut(&mut self, bst: BoundingSpokeTime) -> Option<&mut Spoke> {
+        if bst == self.past_spoke.get_bounds() {
+            Some(&mut self.past_spoke)
+        } else {
+            self.bst_spoke_map.get_mut(&bst)
+        }
+    }
+
+    /// Creates an empty spoke for `bst` that reads the hub's clock and compresses bodies like the
+    /// hub is configured to
+    fn new_spoke(&self, bst: BoundingSpokeTime) -> Spoke {
+        Spoke::new_in_namespace(&self.namespace, bst)
+            .with_clock(Arc::clone(&self.clock))
+            .with_compress_bodies_over_bytes(self.compress_bodies_over_bytes)
+    }
+
+    /// Adds a spoke, which reads the hub's clock from now on so the two agree on the time, and
+    /// compresses the bodies of jobs added from now on like the hub is configured to. The jobs it
+    /// holds already are counted as held by the hub.
+    fn add_spoke(&mut self, spoke: Spoke) {
+        self.held_jobs += spoke.pending_job_len();
+        if let Some(replaced) = self.insert_spoke(spoke) {
+            self.held_jobs -= replaced.pending_job_len();
         }
-        None
     }
 
-    fn add_spoke(&mut self, spoke: Spoke) {
-        self.bst_spoke_map.insert(spoke.get_bounds(), spoke);
+    /// Adds a spoke like [`Hub::add_spoke`], leaving it to the caller to count its jobs. Returns
+    /// the spoke it replaces, if any.
+    fn insert_spoke(&mut self, spoke: Spoke) -> Option<Spoke> {
+        let spoke = spoke
+            .with_clock(Arc::clone(&self.clock))
+            .with_compress_bodies_over_bytes(self.compress_bodies_over_bytes);
+        let replaced = self.bst_spoke_map.insert(spoke.get_bounds(), spoke);
+        if replaced.is_none() {
+            self.counters.record_spokes_created(1);
+        }
+        replaced
+    }
+
+    /// Walk returns a Vector of Spokes that should be consumed next
+    /// Calls to this method can return empty vectors if no spokes are ready yet.
+    pub fn walk(&mut self) -> Vec<Job> {
+        let jobs = self.walk_unchecked();
+        self.debug_check_walked(&jobs);
+        jobs
+    }
+
+    fn walk_unchecked(&mut self) -> Vec<Job> {
+        let jobs: Vec<Job> = self.walk_spokes().into_iter().flatten().collect();
+        self.record_walk(jobs.len());
+        jobs
+    }
+
+    /// Returns the first of `jobs` that triggers later than now plus the early walk tolerance
+    fn check_walked(&self, jobs: &[Job]) -> Result<(), EarlyWalk> {
+        let now_ms = self.clock.now_ms();
+        let latest_ms = now_ms.saturating_add(self.early_walk_tolerance_ms);
+        match jobs.iter().find(|j| j.trigger_at_ms() > latest_ms) {
+            Some(j) => Err(EarlyWalk {
+                id: j.get_metadata().get_id(),
+                trigger_at_ms: j.trigger_at_ms(),
+                walked_at_ms: now_ms,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Panics in debug builds if a walk handed out one of `jobs` early - a tripwire for
+    /// scheduler changes that break the hub's one promise
+    fn debug_check_walked(&self, jobs: &[Job]) {
+        if cfg!(debug_assertions) {
+            if let Err(e) = self.check_walked(jobs) {
+                panic!("{}", e);
+            }
+        }
+    }
+
+    /// Runs `walk` and returns an error instead of the jobs if it handed any out early
+    #[cfg(test)]
+    fn walk_strictly<F: FnOnce(&mut Hub) -> Vec<Job>>(
+        &mut self,
+        walk: F,
+    ) -> Result<Vec<Job>, EarlyWalk> {
+        let jobs = walk(self);
+        self.check_walked(&jobs).map(|()| jobs)
+    }
+
+    /// Walks every ready spoke, returning each spoke's ready jobs in walk order
+    fn walk_spokes(&mut self) -> Vec<Vec<Job>> {
+        // Spokes are ordered by ascending start time, so the ready spokes are always a prefix of
+        // the map
+        let now_ms = self.clock.now_ms();
+        let counters = &self.counters;
+        let walks: Vec<Vec<Job>> = self
+            .bst_spoke_map
+            .range_mut(spoke::started_by(now_ms))
+            .map(|s| {
+                let mut jobs = vec![];
+                while let Some(walked) = s.1.next_ready_at(now_ms) {
+                    match walked {
+                        Ok(job) => jobs.push(job),
+                        Err(e) => {
+                            error!("Spoke {} skipped a job: {}", s.1.short_id(), e);
+                            counters.record_internal_error();
+                        }
+                    }
+                }
+                jobs
+            })
+            .collect();
+        for job in walks.iter().flatten() {
+            self.record_delivery(job.delivery_lag_ms(now_ms), false);
+            self.held_jobs -= 1;
+        }
+        self.prune_spokes();
+        walks
+    }
+
+    /// Moves the jobs left in expired spokes into the past spoke, so every job whose time has
+    /// passed is handed out by the next walk however late it comes. The emptied spokes are pruned
+    /// on the next walk. Returns the number of jobs moved.
+    pub fn reclaim_expired(&mut self) -> usize {
+        let now_ms = self.clock.now_ms();
+        let past_spoke = &mut self.past_spoke;
+        let mut moved = 0;
+        let mut lost = 0;
+        for (_, s) in self
+            .bst_spoke_map
+            .range_mut(spoke::started_before(now_ms))
+            .filter(|s| s.0.is_expired_at(now_ms))
+        {
+            for job in s.drain_jobs() {
+                match past_spoke.add_job(&job) {
+                    Ok(()) => moved += 1,
+                    Err(e) => {
+                        error!(
+                            "Past spoke refused job {} reclaimed from spoke {}: {}",
+                            job.get_metadata().get_id(),
+                            s.short_id(),
+                            e
+                        );
+                        self.counters.record_internal_error();
+                        lost += 1;
+                    }
+                }
+            }
+        }
+        self.held_jobs -= lost;
+        if moved > 0 {
+            debug!("Reclaimed {} jobs from expired spokes", moved);
+        }
+        moved
+    }
+
+    /// Removes every expired spoke with no pending jobs. Expired spokes are a prefix of the map,
+    /// so spokes yet to come aren't looked at. An expired spoke that still holds jobs doesn't stop
+    /// later expired spokes from being pruned.
+    pub fn prune_spokes(&mut self) -> u32 {
+        let now_ms = self.clock.now_ms();
+        let prunable: Vec<BoundingSpokeTime> = self
+            .bst_spoke_map
+            .range(spoke::started_before(now_ms))
+            .filter(|s| s.0.is_expired_at(now_ms) && s.1.pending_job_len() == 0)
+            .map(|s| *s.0)
+            .collect();
+        for bst in &prunable {
+            self.bst_spoke_map.remove(bst);
+        }
+        self.counters.record_spokes_pruned(prunable.len());
+        self.watchdog.record_prune(now_ms);
+        prunable.len() as u32
+    }
+
+    /// Add a new job to the Hub - the hub will find or create the right spoke for this job. Fails
+    /// without changing the hub if no spoke can own the job.
+    ///
+    /// Job ids are unique in the hub: a job whose id the hub holds already, wherever it is, is
+    /// refused with [`RejectReason::Duplicate`] and the held job is left untouched. Use
+    /// [`Hub::reschedule`] to move a job, or cancel it before adding it again.
+    pub fn add_job(&mut self, job: Job) -> Result<(), YaadError> {
+        if self.draining {
+            return Err(RejectReason::Draining.into());
+        }
+        let id = job.get_metadata().get_id();
+        if self.holds_job(id) {
+            return Err(RejectReason::Duplicate(id).into());
+        }
+        if let Some(e) = capacity_error(self.max_pending_jobs, self.held_jobs) {
+            return Err(e);
+        }
+        self.schedule_job(job)?;
+        self.held_jobs += 1;
+        let now_ms = self.clock.now_ms();
+        self.record_adds(1, now_ms);
+        Ok(())
+    }
+
+    /// Adds a batch of jobs like [`Hub::add_job`] would one by one, returning the jobs refused
+    /// along with the reason, in trigger order. A refused job doesn't keep the others out.
+    ///
+    /// The batch is sorted by trigger time, so jobs sharing a spoke are inserted in one run: the
+    /// spoke is looked up, or created, once per run instead of once per job. Ids the hub holds
+    /// already, or that appear twice in the batch, are refused with [`RejectReason::Duplicate`].
+    pub fn add_jobs(&mut self, mut jobs: Vec<Job>) -> Vec<(Job, YaadError)> {
+        if self.draining {
+            let draining = YaadError::from(RejectReason::Draining);
+            return jobs.into_iter().map(|j| (j, draining.clone())).collect();
+        }
+        let now_ms = self.clock.now_ms();
+        let horizon_ms = now_ms.saturating_add(self.max_future_ms);
+        let mut held = self.held_ids(&jobs);
+        let mut refused = vec![];
+        let mut added = 0;
+        jobs.sort_by_key(|j| j.trigger_at_ms());
+        let mut jobs = jobs.into_iter().peekable();
+        while let Some(job) = jobs.next() {
+            let id = job.get_metadata().get_id();
+            if held.contains(&id) {
+                refused.push((job, RejectReason::Duplicate(id).into()));
+                continue;
+            }
+            if let Some(e) = capacity_error(self.max_pending_jobs, self.held_jobs + added) {
+                refused.push((job, e));
+                continue;
+            }
+            if job.trigger_at_ms() > horizon_ms {
+                let trigger_at_ms = job.trigger_at_ms();
+                let reason = RejectReason::TooFarInFuture {
+                    trigger_at_ms,
+                    horizon_ms,
+                };
+                refused.push((job, reason.into()));
+                continue;
+            }
+            if job.temporal_state_at(now_ms) != TemporalState::Future {
+                match self.past_spoke.add_job(&job) {
+                    Ok(()) => {
+                        held.insert(id);
+                        added += 1
+                    }
+                    Err(e) => {
+                        error!("Past spoke refused job {}: {}", id, e);
+                        let e = internal_error(&self.counters, PAST_SPOKE_REFUSED);
+                        refused.push((job, e))
+                    }
+                }
+                continue;
+            }
+            let bst = match Hub::job_bounding_spoke_time(&job, self.spoke_duration_ms) {
+                Ok(bst) => bst,
+                Err(e) => {
+                    refused.push((job, e));
+                    continue;
+                }
+            };
+            // The batch is sorted, so the rest of this spoke's jobs follow right after. Jobs
+            // past the horizon are left to be refused above.
+            let run_end_ms = bst.get_end_time_ms().min(horizon_ms.saturating_add(1));
+            let mut run = vec![job];
+            while jobs.peek().is_some_and(|j| j.trigger_at_ms() < run_end_ms) {
+                run.extend(jobs.next());
+            }
+            let held_jobs = self.held_jobs + added;
+            added += self.add_run(bst, run, held_jobs, &mut held, &mut refused);
+        }
+        self.held_jobs += added;
+        self.record_adds(added, now_ms);
+        refused
+    }
+
+    /// Counts `jobs` jobs added, and lets the watchdog know if any were
+    fn record_adds(&mut self, jobs: usize, now_ms: u64) {
+        self.counters.record_jobs_added(jobs);
+        if jobs > 0 {
+            self.watchdog.record_add(now_ms);
+            if let Some(ref metrics) = self.metrics {
+                metrics.record_jobs_added(jobs);
+            }
+        }
+    }
+
+    /// Counts a walk that handed out `jobs` jobs, and lets the watchdog know the hub was walked
+    fn record_walk(&mut self, jobs: usize) {
+        self.counters.record_jobs_walked(jobs);
+        self.watchdog.record_walk(self.clock.now_ms());
+    }
+
+    /// Counts a job walked `lag_ms` after its trigger time, off the past spoke if `late`
+    fn record_delivery(&self, lag_ms: u64, late: bool) {
+        if late {
+            self.counters.record_late_delivery(lag_ms);
+        } else {
+            self.counters.record_on_time_delivery(lag_ms);
+        }
+        if let Some(ref metrics) = self.metrics {
+            metrics.record_delivery(lag_ms);
+        }
+    }
+
+    /// Adds a run of jobs owned by the spoke `bst` to it, creating the spoke if needed, while the
+    /// hub holding `held_jobs` jobs before the run has room. Returns the number of jobs added.
+    fn add_run(
+        &mut self,
+        bst: BoundingSpokeTime,
+        run: Vec<Job>,
+        held_jobs: usize,
+        held: &mut HashSet<Uuid>,
+        refused: &mut Vec<(Job, YaadError)>,
+    ) -> usize {
+        if !self.bst_spoke_map.contains_key(&bst) {
+            let spoke = self.new_spoke(bst);
+            debug!("Created spoke {} {:?} for a batch", spoke.short_id(), bst);
+            self.add_spoke(spoke);
+        }
+        let max_pending_jobs = self.max_pending_jobs;
+        let spoke = self.bst_spoke_map.get_mut(&bst).expect("Spoke was just added");
+        let mut added = 0;
+        for job in run {
+            let id = job.get_metadata().get_id();
+            if held.contains(&id) {
+                refused.push((job, RejectReason::Duplicate(id).into()));
+                continue;
+            }
+            if let Some(e) = capacity_error(max_pending_jobs, held_jobs + added) {
+                refused.push((job, e));
+                continue;
+            }
+            match spoke.add_job(&job) {
+                Ok(()) => {
+                    held.insert(id);
+                    added += 1
+                }
+                Err(e) => refused.push((job, e)),
+            }
+        }
+        added
+    }
+
+    /// Returns the ids of `jobs` the hub holds already. Each spoke is searched from whichever
+    /// side is smaller - its own jobs or the batch.
+    fn held_ids(&self, jobs: &[Job]) -> HashSet<Uuid> {
+        let ids: HashSet<Uuid> = jobs.iter().map(|j| j.get_metadata().get_id()).collect();
+        let mut held: HashSet<Uuid> = ids
+            .iter()
+            .filter(|id| {
+                self.reserved.contains_key(id)
+                    || self.buried.contains_key(id)
+                    || self.checked_out_job(**id).is_some()
+            })
+            .cloned()
+            .collect();
+        for spoke in self.all_spokes() {
+            if spoke.live_job_len() < ids.len() {
+                held.extend(spoke.job_ids().filter(|id| ids.contains(id)));
+            } else {
+                held.extend(ids.iter().filter(|id| spoke.owns_job(**id)));
+            }
+        }
+        held
+    }
+
+    /// Returns true if the hub holds a job with this id, scheduled, reserved, buried or in a
+    /// checked out spoke
+    fn holds_job(&self, id: Uuid) -> bool {
+        self.reserved.contains_key(&id)
+            || self.buried.contains_key(&id)
+            || self.checked_out_job(id).is_some()
+            || self.find_job_owner_bst(id).is_some()
+    }
+
+    /// Returns the copy of a job in a checked out spoke, if it is in one and wasn't cancelled
+    fn checked_out_job(&self, id: Uuid) -> Option<&Job> {
+        self.checked_out.values().find_map(|c| c.jobs.get(&id))
+    }
+
+    /// Adds a job like [`Hub::add_job`] without counting it as a new job - for jobs the hub held
+    /// before, e.g. released ones. The job's id isn't checked for duplicates, but jobs triggering
+    /// past the hub's horizon are refused like new ones.
+    fn schedule_job(&mut self, job: Job) -> Result<(), YaadError> {
+        let horizon_ms = self.clock.now_ms().saturating_add(self.max_future_ms);
+        if job.trigger_at_ms() > horizon_ms {
+            let reason = RejectReason::TooFarInFuture {
+                trigger_at_ms: job.trigger_at_ms(),
+                horizon_ms,
+            };
+            return Err(reason.into());
+        }
+        // If None, past spoke accepted the job, else find the right spoke for it
+        trace_job!(
+            "Adding job {} triggering at {}",
+            job.get_metadata().get_id(),
+            job.trigger_at_ms()
+        );
+        match self.maybe_add_job_to_past(job)? {
+            Some(j) => self.add_job_to_spokes(j),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds a job to the correct spoke based on the Job's trigger time
+    fn add_job_to_spokes(&mut self, job: Job) -> Result<(), YaadError> {
+        let job_bst = Hub::job_bounding_spoke_time(&job, self.spoke_duration_ms)?;
+        // Spoke bounds are aligned, so the job's spoke is found by its bounds
+        if let Some(s) = self.bst_spoke_map.get_mut(&job_bst) {
+            return s.add_job(&job);
+        }
+        // The job's spoke doesn't exist yet - create one that accepts it. Bounds that don't
+        // cover the job would be refused again on every try, so they are reported as a bug
+        // rather than retried.
+        if !job_bst.covers(job.trigger_at_ms()) {
+            error!(
+                "Spoke bounds {:?} computed for job {} don't cover its trigger time {}",
+                job_bst,
+                job.get_metadata().get_id(),
+                job.trigger_at_ms()
+            );
+            return Err(internal_error(
+                &self.counters,
+                "Spoke bounds computed for a job don't cover its trigger time",
+            ));
+        }
+        let mut spoke = self.new_spoke(job_bst);
+        let id = job.get_metadata().get_id();
+        // Fails if the trigger time passed while the spoke was created
+        spoke.add_job(&job)?;
+        debug!(
+            "Created spoke {} {:?} for job {}",
+            spoke.short_id(),
+            job_bst,
+            id
+        );
+        // The job is counted by whoever scheduled it
+        self.insert_spoke(spoke);
+        Ok(())
+    }
+
+    /// Attempts to add a job to the past spoke if the job is due already and returns None.
+    /// Otherwise, returns Some(job)
+    fn maybe_add_job_to_past(&mut self, job: Job) -> Result<Option<Job>, YaadError> {
+        // Jobs due this very millisecond are ready already, so they go to the past spoke too and
+        // are handed out by the next walk
+        let current_time_ms = self.clock.now_ms();
+        if job.temporal_state_at(current_time_ms) != TemporalState::Future {
+            // This job should be handed to the past spoke
+            trace_job!(
+                "Job {} triggering at {} is due by {}, adding it to the past spoke",
+                job.get_metadata().get_id(),
+                job.trigger_at_ms(),
+                current_time_ms
+            );
+            return match self.past_spoke.add_job(&job) {
+                Ok(()) => Ok(None),
+                Err(e) => {
+                    error!("Past spoke refused job {}: {}", job.get_metadata().get_id(), e);
+                    Err(internal_error(&self.counters, PAST_SPOKE_REFUSED))
+                }
+            };
+        }
+        // else, hand it back
+        Ok(Some(job))
+    }
+
+    /// Returns the span of a hypothetical Spoke that should own this job.
+    pub(crate) fn job_bounding_spoke_time(
+        job: &Job,
+        spoke_duration_ms: u64,
+    ) -> Result<BoundingSpokeTime, YaadError> {
+        let overflow = RejectReason::Overflow {
+            trigger_at_ms: job.trigger_at_ms(),
+        };
+        spoke_bounds_at(job.trigger_at_ms(), spoke_duration_ms).ok_or_else(|| overflow.into())
+    }
+
+    /// Creates the missing spokes so that contiguous spokes cover the time from now until
+    /// `horizon_ms` from now. Jobs falling due in that window find their spoke already in place.
+    /// Returns the number of spokes created.
+    pub fn ensure_spokes_until(&mut self, horizon_ms: u64) -> usize {
+        if self.spoke_duration_ms == 0 {
+            return 0;
+        }
+        let now_ms = self.clock.now_ms();
+        let until_ms = now_ms.saturating_add(horizon_ms);
+        let mut created = 0;
+        let mut next = spoke_bounds_at(now_ms, self.spoke_duration_ms);
+        while let Some(bst) = next {
+            if bst.get_start_time_ms() >= until_ms {
+                break;
+            }
+            if !self.bst_spoke_map.contains_key(&bst) {
+                let spoke = self.new_spoke(bst);
+                self.add_spoke(spoke);
+                created += 1;
+            }
+            next = spoke_bounds_at(bst.get_end_time_ms(), self.spoke_duration_ms);
+        }
+        created
+    }
+
+    /// Housekeeping for protocol runners to call periodically, e.g. every
+    /// [`DEFAULT_TICK_INTERVAL_MS`]: prunes spent spokes, reports the hub's gauges to its
+    /// metrics, checks them against the [`watchdog`](::watchdog) thresholds and creates the spokes
+    /// of the next [`PREALLOCATED_SPOKES`] spoke durations ahead of time
+    pub fn tick(&mut self) {
+        self.prune_spokes();
+        let gauges = self.gauges();
+        if let Some(ref metrics) = self.metrics {
+            metrics.record_gauges(&gauges);
+        }
+        if let Some(report) = self.watchdog.check(self.clock.now_ms(), &gauges) {
+            error!("Hub stalled: {}", report);
+            self.counters.record_stall();
+        }
+        let horizon_ms = self.spoke_duration_ms.saturating_mul(PREALLOCATED_SPOKES);
+        self.ensure_spokes_until(horizon_ms);
+    }
+
+    /// Takes a spoke holding ready jobs out of the hub, the past spoke first, so that it can be
+    /// walked without holding whatever lock guards the hub. Hand it back with
+    /// [`Hub::checkin_spoke`] once walked. Returns None if no spoke holds a ready job.
+    ///
+    /// Every job sits in one spoke, so consumers walking the spokes they checked out never see
+    /// the same job. Jobs are only in walk order within a spoke, not across consumers. The past
+    /// spoke's jobs are swapped into the spoke handed out, leaving the hub an empty past spoke
+    /// for jobs added in the meantime. Jobs walked off checked out spokes aren't counted in the
+    /// hub's counters.
+    ///
+    /// The hub keeps track of the jobs of a spoke while it is checked out, walked ones included:
+    /// [`Hub::add_job`] refuses their ids as duplicates, [`Hub::get_job`] finds them as they were
+    /// checked out, and [`Hub::cancel_job`] drops them from the spoke when it is checked back in.
+    /// They don't count towards the hub's `max_pending_jobs` though.
+    pub fn checkout_ready_spoke(&mut self) -> Option<Spoke> {
+        let now_ms = self.clock.now_ms();
+        self.watchdog.record_walk(now_ms);
+        let has_ready = |s: &Spoke| s.peek_job_where(|jm| jm.is_ready_at(now_ms)).is_some();
+        let spoke = if has_ready(&self.past_spoke) {
+            let empty = self.new_spoke(self.past_spoke.get_bounds());
+            mem::replace(&mut self.past_spoke, empty)
+        } else {
+            let bounds = self
+                .bst_spoke_map
+                .range(spoke::started_by(now_ms))
+                .find(|s| has_ready(s.1))
+                .map(|s| *s.0)?;
+            self.counters.record_spokes_pruned(1);
+            self.bst_spoke_map.remove(&bounds)?
+        };
+        self.held_jobs -= spoke.pending_job_len();
+        let out = self.checked_out.entry(spoke.get_bounds()).or_default();
+        out.spokes += 1;
+        out.jobs.extend(spoke.jobs().map(|j| (j.get_metadata().get_id(), j)));
+        Some(spoke)
+    }
+
+    /// Puts a spoke taken out by [`Hub::checkout_ready_spoke`] back. Its jobs are merged into
+    /// the spoke now covering its window if jobs added meanwhile created one, or into the past
+    /// spoke if it is the past spoke or its window has passed. An expired spoke is dropped once
+    /// its jobs are moved out. Jobs cancelled while the spoke was out are dropped from it.
+    pub fn checkin_spoke(&mut self, mut spoke: Spoke) {
+        let bounds = spoke.get_bounds();
+        if let Some(mut out) = self.checked_out.remove(&bounds) {
+            for id in &out.cancelled {
+                spoke.cancel_job(*id);
+            }
+            for id in spoke.job_ids() {
+                out.jobs.remove(&id);
+            }
+            out.spokes -= 1;
+            if out.spokes > 0 {
+                self.checked_out.insert(bounds, out);
+            }
+        }
+        self.held_jobs += spoke.pending_job_len();
+        let is_past = bounds == self.past_spoke.get_bounds();
+        if !is_past && !spoke.is_expired() && !self.bst_spoke_map.contains_key(&bounds) {
+            self.counters.record_spokes_created(1);
+            self.bst_spoke_map.insert(bounds, spoke);
+            return;
+        }
+        for job in spoke.drain_jobs() {
+            if let Some(s) = self.bst_spoke_map.get_mut(&bounds).filter(|_| !is_past) {
+                if s.add_job(&job).is_ok() {
+                    continue;
+                }
+            }
+            // The window's spoke refuses jobs once its time has passed, they are due by now
+            if let Err(e) = self.past_spoke.add_job(&job) {
+                error!(
+                    "Past spoke refused job {} checked in with spoke {}: {}",
+                    job.get_metadata().get_id(),
+                    spoke.short_id(),
+                    e
+                );
+                self.counters.record_internal_error();
+                self.held_jobs -= 1;
+            }
+        }
+    }
+
+    /// Returns a vec of all jobs that are ready to be consumed, sorted by ascending trigger time.
+    /// Jobs triggering at the same time are sorted by priority, most urgent first, then by id.
+    pub fn walk_jobs(&mut self) -> Vec<Job> {
+        let jobs = self.walk_jobs_unchecked();
+        self.debug_check_walked(&jobs);
+        jobs
+    }
+
+    fn walk_jobs_unchecked(&mut self) -> Vec<Job> {
+        self.reclaim_expired();
+        self.drain_ready_unchecked().collect()
+    }
+
+    /// Returns at most `max` ready jobs, in the same order as [`Hub::walk_jobs`]. Ready jobs past
+    /// the limit stay in their spokes, and a spoke that still holds ready jobs isn't pruned even
+    /// once its bounds have expired, so the next call carries on where this one stopped.
+    pub fn walk_jobs_limit(&mut self, max: usize) -> Vec<Job> {
+        let jobs = self.walk_jobs_limit_unchecked(max);
+        self.debug_check_walked(&jobs);
+        jobs
+    }
+
+    fn walk_jobs_limit_unchecked(&mut self, max: usize) -> Vec<Job> {
+        self.drain_ready_unchecked().take(max).collect()
+    }
+
+    /// Returns the ready jobs in the same order as [`Hub::walk_jobs`], taking each off its spoke
+    /// only once the iterator gets to it. Jobs the iterator doesn't get to stay scheduled, and
+    /// their spokes aren't pruned, so a caller can stop early and the next walk carries on where
+    /// it stopped - [`Hub::walk_jobs_limit`] is this, collected. Spent spokes are pruned, and the
+    /// walk counted, when the iterator is dropped. Whether a job is ready is judged by the time
+    /// the iterator was created.
+    pub fn drain_ready(&mut self) -> DrainReady<'_> {
+        let mut ready = self.drain_ready_unchecked();
+        ready.checked = true;
+        ready
+    }
+
+    fn drain_ready_unchecked(&mut self) -> DrainReady<'_> {
+        let now_ms = self.clock.now_ms();
+        DrainReady {
+            hub: self,
+            now_ms,
+            walked: 0,
+            checked: false,
+        }
+    }
+
+    /// Takes the job ready at `now_ms` that is due first off whichever spoke holds it, the past
+    /// spoke or a ready one, and counts its delivery
+    fn next_ready_at(&mut self, now_ms: u64) -> Option<Job> {
+        // None stands for the past spoke
+        let mut next: Option<(JobMetadata, Option<BoundingSpokeTime>)> = self
+            .past_spoke
+            .peek_next_job()
+            .filter(|jm| jm.is_ready_at(now_ms))
+            .map(|jm| (jm, None));
+        for (bst, s) in self.bst_spoke_map.range_mut(spoke::started_by(now_ms)) {
+            if let Some(jm) = s.peek_next_job().filter(|jm| jm.is_ready_at(now_ms)) {
+                if next.is_none_or(|n| jm > n.0) {
+                    next = Some((jm, Some(*bst)));
+                }
+            }
+        }
+        let (late, walked) = match next? {
+            (_, None) => (true, self.past_spoke.next_ready_at(now_ms)),
+            (_, Some(bst)) => {
+                let spoke = self.bst_spoke_map.get_mut(&bst);
+                (false, spoke.and_then(|s| s.next_ready_at(now_ms)))
+            }
+        };
+        let job = match walked {
+            Some(Ok(job)) => job,
+            Some(Err(e)) => {
+                // The spoke took the broken entry off, so the walk goes on past it
+                error!("Hub skipped a job: {}", e);
+                self.counters.record_internal_error();
+                return self.next_ready_at(now_ms);
+            }
+            None => {
+                internal_error(&self.counters, "Spoke didn't walk the ready job it peeked");
+                return None;
+            }
+        };
+        self.record_delivery(job.delivery_lag_ms(now_ms), late);
+        self.held_jobs -= 1;
+        Some(job)
+    }
+
+    /// Walks at most `max` ready jobs, like [`Hub::walk_jobs_limit`], and delivers them to
+    /// `sink`. Jobs the sink hands back are scheduled again [`SINK_RETRY_DELAY_MS`] from now.
+    /// Returns the number of jobs delivered.
+    pub fn drain_into<S: JobSink>(&mut self, sink: &S, max: usize) -> usize {
+        let mut delivered = 0;
+        for job in self.walk_jobs_limit(max) {
+            match sink.deliver(job) {
+                Ok(()) => delivered += 1,
+                Err(job) => {
+                    let id = job.get_metadata().get_id();
+                    let retry_at_ms = self.clock.now_ms().saturating_add(SINK_RETRY_DELAY_MS);
+                    match self.schedule_job(job.with_trigger_at_ms(retry_at_ms)) {
+                        Ok(()) => self.held_jobs += 1,
+                        Err(e) => {
+                            error!("Failed to re-queue job {} refused by the sink: {}", id, e)
+                        }
+                    }
+                }
+            }
+        }
+        delivered
+    }
+}
+
+/// Returns the refusal of a job offered to a hub holding `held_jobs` jobs, if that is its limit
+fn capacity_error(max_pending_jobs: Option<usize>, held_jobs: usize) -> Option<YaadError> {
+    max_pending_jobs
+        .filter(|max| held_jobs >= *max)
+        .map(|max_pending_jobs| YaadError::Capacity { max_pending_jobs })
+}
+
+/// Logs an inconsistency the hub found in its own state and counts it into `counters`
+fn internal_error(counters: &Stats, reason: &'static str) -> YaadError {
+    error!("Hub is inconsistent: {}", reason);
+    counters.record_internal_error();
+    YaadError::Internal(reason)
+}
+
+/// The ready jobs of a hub, taken off their spokes one by one, see [`Hub::drain_ready`]
+#[derive(Debug)]
+pub struct DrainReady<'a> {
+    hub: &'a mut Hub,
+    now_ms: u64,
+    walked: usize,
+    /// Whether jobs are checked for being handed out early in debug builds
+    checked: bool,
+}
+
+impl<'a> Iterator for DrainReady<'a> {
+    type Item = Job;
+
+    fn next(&mut self) -> Option<Job> {
+        let job = self.hub.next_ready_at(self.now_ms)?;
+        if self.checked {
+            self.hub.debug_check_walked(std::slice::from_ref(&job));
+        }
+        self.walked += 1;
+        Some(job)
+    }
+}
+
+impl<'a> Drop for DrainReady<'a> {
+    fn drop(&mut self) {
+        self.hub.past_spoke.release_memory();
+        self.hub.prune_spokes();
+        self.hub.record_walk(self.walked);
+    }
+}
+
+/// Merges spoke walks, each already in walk order, into one vec in walk order. Only the head of
+/// each walk is compared, so this doesn't sort the jobs all over again.
+pub(crate) fn merge_walks(walks: Vec<Vec<Job>>) -> Vec<Job> {
+    let mut merged = Vec::with_capacity(walks.iter().map(|w| w.len()).sum());
+    let mut walks: Vec<_> = walks.into_iter().map(|w| w.into_iter()).collect();
+    // Jobs due first are greatest, so the max heap yields the next job in walk order. The walk
+    // index breaks ties between equal jobs.
+    let mut heads = BinaryHeap::new();
+    for (i, walk) in walks.iter_mut().enumerate() {
+        if let Some(j) = walk.next() {
+            heads.push((j, Reverse(i)));
+        }
+    }
+    while let Some((j, Reverse(i))) = heads.pop() {
+        merged.push(j);
+        if let Some(next) = walks[i].next() {
+            heads.push((next, Reverse(i)));
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    const TEST_SPOKE_DURATION_MS: u64 = 10;
+    /// Where mock clocks start, on a spoke boundary for every spoke duration the tests use
+    const MOCK_START_MS: u64 = 1_500_000_000_000;
+
+    use super::*;
+    use clock::MockClock;
+    use sink::FnSink;
+    use watchdog::Stall;
+    use rand::{thread_rng, ChaChaRng, Rng, SeedableRng};
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn can_create_hub() {
+        let h: Hub = Hub::new(TEST_SPOKE_DURATION_MS);
+        assert_eq!(h.bst_spoke_map.len(), 0)
+    }
+
+    #[test]
+    fn can_add_spokes() {
+        let mut h = Hub::new(TEST_SPOKE_DURATION_MS);
+        h.add_spoke(Spoke::new(times::current_time_ms(), 10_000));
+        assert_eq!(h.bst_spoke_map.len(), 1)
+    }
+
+    #[test]
+    fn walk_empty_hub() {
+        let mut h = Hub::new(TEST_SPOKE_DURATION_MS);
+        let res = h.walk();
+        assert_eq!(h.bst_spoke_map.len(), 0);
+        assert_eq!(res.len(), 0, "Empty hub walk should return no spokes")
+    }
+
+    #[test]
+    fn can_add_job_to_past() {
+        let mut h = Hub::new(TEST_SPOKE_DURATION_MS);
+        h.add_job(Job::new_auto_id(
+            times::current_time_ms() - 10_000,
+            "I am old",
+        )).unwrap();
+        assert_eq!(
+            h.past_spoke.pending_job_len(),
+            1,
+            "Hub should put jobs triggering in the past into it's special, past spoke"
+        );
+    }
+
+    #[test]
+    fn hands_out_jobs_due_now_right_away() {
+        let mut h = Hub::new(TEST_SPOKE_DURATION_MS);
+        let j = Job::new_auto_id(times::current_time_ms(), "I am due now");
+        let id = j.get_metadata().get_id();
+        h.add_job(j).unwrap();
+        assert!(h.past_spoke.owns_job(id), "Jobs due now go to the past spoke");
+        assert_eq!(h.spoke_count(), 0);
+
+        let walked = h.walk_jobs();
+        assert_eq!(walked.len(), 1);
+        assert_eq!(walked[0].get_metadata().get_id(), id);
+    }
+
+    #[test]
+    fn walks_jobs_of_spokes_that_expired_unwalked() {
+        let (mut h, clock) = mock_hub(50);
+        let j = Job::new_auto_id(clock.now_ms() + 20, "late walk");
+        let id = j.get_metadata().get_id();
+        h.add_job(j).unwrap();
+        assert_eq!(h.reclaim_expired(), 0, "Spoke is still live");
+
+        clock.advance(200);
+        let walked = h.walk_jobs();
+        assert_eq!(walked.len(), 1, "A single walk finds the job");
+        assert_eq!(walked[0].get_metadata().get_id(), id);
+        assert_eq!(h.spoke_count(), 0, "Emptied spoke was pruned");
+        assert!(h.walk_jobs().is_empty());
+    }
+
+    #[test]
+    fn checks_spokes_out_and_back_in() {
+        let (mut hub, clock) = mock_hub(50);
+        let window_ms = MOCK_START_MS + 50;
+        hub.add_job(Job::new_auto_id(window_ms + 1, "soon")).unwrap();
+        hub.add_job(Job::new_auto_id(window_ms + 40, "later")).unwrap();
+        let now_ms = clock.now_ms();
+        hub.add_job(Job::new_auto_id(now_ms - 20, "due")).unwrap();
+        hub.add_job(Job::new_auto_id(now_ms - 10, "due")).unwrap();
+
+        let mut past = hub.checkout_ready_spoke().unwrap();
+        assert_eq!(past.get_bounds(), hub.past_spoke.get_bounds());
+        assert!(hub.checkout_ready_spoke().is_none(), "Window spoke isn't ready yet");
+        assert_eq!(past.walk_limit(1).len(), 1);
+        hub.add_job(Job::new_auto_id(now_ms - 5, "due meanwhile")).unwrap();
+        hub.checkin_spoke(past);
+        assert_eq!(hub.past_spoke.pending_job_len(), 2, "Leftovers are merged back");
+        assert_eq!(hub.walk_jobs().len(), 2);
+
+        clock.set(window_ms + 5);
+        let mut spoke = hub.checkout_ready_spoke().unwrap();
+        assert_eq!(spoke.get_bounds().get_start_time_ms(), window_ms);
+        assert_eq!(hub.spoke_count(), 0);
+        assert_eq!(spoke.walk().len(), 1);
+        hub.add_job(Job::new_auto_id(window_ms + 45, "latest")).unwrap();
+        assert_eq!(hub.spoke_count(), 1, "The window got a new spoke");
+        hub.checkin_spoke(spoke);
+        assert_eq!(hub.spoke_count(), 1);
+        assert_eq!(hub.pending_job_count(), 2, "Leftovers are merged into the new spoke");
+    }
+
+    #[test]
+    fn checked_out_jobs_are_still_known_to_the_hub() {
+        let (mut hub, clock) = mock_hub(50);
+        let now_ms = clock.now_ms();
+        let (walked, kept, cancelled) = (
+            Job::new_auto_id(now_ms - 30, "walked"),
+            Job::new_auto_id(now_ms - 20, "kept"),
+            Job::new_auto_id(now_ms - 10, "cancelled"),
+        );
+        for job in &[&walked, &kept, &cancelled] {
+            hub.add_job((*job).clone()).unwrap();
+        }
+        let id = |j: &Job| j.get_metadata().get_id();
+
+        let mut spoke = hub.checkout_ready_spoke().unwrap();
+        assert_eq!(spoke.walk_limit(1), vec![walked.clone()]);
+        for job in &[&walked, &kept] {
+            assert_eq!(
+                hub.add_job((*job).clone()),
+                Err(RejectReason::Duplicate(id(job)).into()),
+                "Checked out ids are held, walked ones too"
+            );
+            assert_eq!(hub.get_job(id(job)).unwrap().state, JobState::Ready);
+        }
+        assert!(!hub.add_jobs(vec![kept.clone()]).is_empty());
+        assert!(hub.cancel_job(id(&cancelled)));
+        assert!(hub.get_job(id(&cancelled)).is_none());
+        assert!(!hub.cancel_job(id(&cancelled)), "Cancelled already");
+
+        hub.checkin_spoke(spoke);
+        assert_eq!(hub.walk_jobs(), vec![kept.clone()], "The cancelled job is dropped");
+        assert!(hub.get_job(id(&walked)).is_none());
+        assert!(hub.add_job(walked).is_ok(), "Ids are let go of on check in");
+    }
+
+    #[test]
+    fn concurrent_consumers_see_every_job_once() {
+        const CONSUMERS: usize = 4;
+        const JOBS: u64 = 10_000;
+        let (hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let hub = Arc::new(Mutex::new(hub));
+        {
+            let mut hub = hub.lock().unwrap();
+            let now_ms = clock.now_ms();
+            for i in 0..JOBS {
+                // Spread jobs over the past spoke and the next few spokes
+                let job = Job::new_auto_id(now_ms - 20 + i % 100, "job");
+                hub.add_job(job).unwrap();
+            }
+        }
+        // Every job is due, consumers only wait on spokes checked out by others
+        clock.advance(100);
+
+        let delivered = Arc::new(AtomicUsize::new(0));
+        let consumers: Vec<_> = (0..CONSUMERS)
+            .map(|_| {
+                let (hub, delivered) = (Arc::clone(&hub), Arc::clone(&delivered));
+                thread::spawn(move || {
+                    let deadline_ms = times::current_time_ms() + 10_000;
+                    let mut seen = vec![];
+                    while delivered.load(AtomicOrdering::SeqCst) < JOBS as usize
+                        && times::current_time_ms() < deadline_ms
+                    {
+                        let spoke = hub.lock().unwrap().checkout_ready_spoke();
+                        let mut spoke = match spoke {
+                            Some(s) => s,
+                            None => {
+                                thread::sleep(Duration::from_millis(1));
+                                continue;
+                            }
+                        };
+                        // Walked without holding the hub lock
+                        let walked = spoke.walk();
+                        delivered.fetch_add(walked.len(), AtomicOrdering::SeqCst);
+                        seen.extend(walked.iter().map(|j| j.get_metadata().get_id()));
+                        hub.lock().unwrap().checkin_spoke(spoke);
+                    }
+                    seen
+                })
+            })
+            .collect();
+
+        let mut all = HashSet::new();
+        for c in consumers {
+            for id in c.join().unwrap() {
+                assert!(all.insert(id), "Job {} was delivered twice", id);
+            }
+        }
+        assert_eq!(all.len(), JOBS as usize, "Every job is delivered");
+        assert_eq!(hub.lock().unwrap().pending_job_count(), 0);
+    }
+
+    #[test]
+    fn walk_hub_with_spokes() {
+        // |
+        // |     spoke1  walk1([s1,])            walk2([])         spoke2   walk3([s2,])
+        // | s1<---------20ms--------->s1+10 .......~10ms....... s2<--------25ms--------->s2+50
+        // |---------------------------------------------------------------------------------->time
+        let (mut h, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let first_spoke_start = clock.now_ms();
+        // Create a spoke that starts now and add it to the hub
+        let mut s1 = Spoke::new(first_spoke_start, 10).with_clock(clock.clone());
+        s1.add_job(&Job::new_auto_id(first_spoke_start + 2, "job")).unwrap();
+        h.add_spoke(s1);
+
+        assert_eq!(
+            h.bst_spoke_map.len(),
+            1,
+            "Should list ownership of the newly added spoke"
+        );
+
+        // Wait for at least first spoke to be ready
+        clock.advance(15);
+
+        assert_eq!(
+            h.walk_jobs().len(),
+            1,
+            "Should have 1 job ready at: {}",
+            clock.now_ms()
+        );
+        assert_eq!(
+            h.bst_spoke_map.len(),
+            0,
+            "Should have pruned spoke after consuming it"
+        );
+
+        // Create another spoke that starts 10ms after the first spoke's starting time
+        let second_spoke_start = clock.now_ms() + 10;
+        let mut s2 = Spoke::new(second_spoke_start, 25).with_clock(clock.clone());
+        s2.add_job(&Job::new_auto_id(second_spoke_start + 17, "job")).unwrap();
+        h.add_spoke(s2);
+
+        assert_eq!(h.bst_spoke_map.len(), 1, "Should have 1 spoke");
+
+        // Wait for at least second spoke to be ready
+        clock.advance(30);
+        assert_eq!(h.walk_jobs().len(), 1, "Hub should return jobs");
+
+        assert_eq!(
+            h.walk_jobs().len(),
+            0,
+            "Hub should not return a job after all were consumed"
+        );
+
+        clock.advance(10);
+        h.prune_spokes();
+        assert_eq!(h.bst_spoke_map.len(), 0);
+    }
+
+    #[test]
+    fn prune_expired_spokes() {
+        // |
+        // |     spoke1                           spoke2         walk1([s2,])
+        // | s1<---------5ms--------->s1+5 .2ms. s2(s1+7)<--------5ms--------->s2+50
+        // |---------------------------------------------------------------------------------->time
+        let (mut h, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+
+        let first_spoke_start = clock.now_ms();
+        h.add_spoke(Spoke::new(first_spoke_start, TEST_SPOKE_DURATION_MS));
+        assert_eq!(h.bst_spoke_map.len(), 1, "Can add a spoke to a hub");
+
+        let second_spoke_start = first_spoke_start + TEST_SPOKE_DURATION_MS + 2;
+        h.add_spoke(Spoke::new(second_spoke_start, 10));
+        assert_eq!(h.bst_spoke_map.len(), 2, "Can add a spoke to a hub");
+
+        clock.advance(TEST_SPOKE_DURATION_MS * 2 + 5);
+        assert_eq!(h.bst_spoke_map.len(), 2);
+        assert_eq!(h.prune_spokes(), 2, "Expired spokes are pruned");
+    }
+
+    #[test]
+    fn prunes_expired_spokes_behind_non_empty_ones() {
+        let (mut h, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let now_ms = clock.now_ms();
+
+        // Will expire still holding a job nobody walked
+        let mut unwalked = Spoke::new(now_ms + 5, TEST_SPOKE_DURATION_MS).with_clock(clock.clone());
+        unwalked.add_job(&Job::new_auto_id(now_ms + 6, "unwalked")).unwrap();
+        h.add_spoke(unwalked);
+        // Will expire empty, ordered after the unwalked spoke
+        h.add_spoke(Spoke::new(now_ms + 20, TEST_SPOKE_DURATION_MS));
+        h.add_spoke(Spoke::new(now_ms + 35, TEST_SPOKE_DURATION_MS));
+        // Will be ready but not expired
+        let mut ready = Spoke::new(now_ms + 50, 10_000).with_clock(clock.clone());
+        ready.add_job(&Job::new_auto_id(now_ms + 51, "ready")).unwrap();
+        h.add_spoke(ready);
+        // Not ready yet
+        let mut future =
+            Spoke::new(now_ms + 20_000, TEST_SPOKE_DURATION_MS).with_clock(clock.clone());
+        future.add_job(&Job::new_auto_id(now_ms + 20_001, "future")).unwrap();
+        h.add_spoke(future);
+
+        clock.advance(70);
+        assert_eq!(h.prune_spokes(), 2, "Empty expired spokes are pruned");
+        assert_eq!(h.bst_spoke_map.len(), 3);
+
+        let walked = h.walk();
+        assert_eq!(walked.len(), 2, "Every ready spoke is walked");
+        assert_eq!(h.bst_spoke_map.len(), 2, "Walked expired spoke is pruned");
+    }
+
+    /// This test checks that we can calculate if a Spoke should own a job - a spoke should own a
+    /// job if that job's trigger time lies within the Spoke's duration.
+    #[test]
+    fn test_job_bounding_spoke_times() {
+        // Find Duration since UNIX_EPOCH
+        let dur_from_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        // Find the ms since EPOCH, floored to the nearest decimal
+        let ms_from_epoch = times::floor_ms_from_epoch(times::duration_to_ms(dur_from_epoch));
+
+        // ms from epoch down to closest 10 and then add 10ms
+        let ms_from_epoch = ms_from_epoch + 10;
+        let job_trigger_at_ms = ms_from_epoch + 7;
+        let j = Job::new_auto_id(job_trigger_at_ms, "foo");
+
+        // This job's bounds should be: ms_from_epoch -> ms_from_epoch + 10
+        let bst = self::Hub::job_bounding_spoke_time(&j, TEST_SPOKE_DURATION_MS).unwrap();
+        assert!(bst.get_start_time_ms() <= job_trigger_at_ms);
+        assert!(job_trigger_at_ms <= bst.get_end_time_ms());
+        assert_eq!(
+            bst.get_end_time_ms() - bst.get_start_time_ms(),
+            TEST_SPOKE_DURATION_MS
+        );
+    }
+
+    /// Returns a hub whose clock only moves when the test moves it, starting at [`MOCK_START_MS`]
+    fn mock_hub(spoke_duration_ms: u64) -> (Hub, Arc<MockClock>) {
+        let clock = Arc::new(MockClock::new(MOCK_START_MS));
+        (Hub::new_with_clock(spoke_duration_ms, clock.clone()), clock)
+    }
+
+    #[test]
+    fn add_job_to_hub() {
+        // Start of the next spoke window, so jobs a few ms apart share a spoke
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let start_time_ms = MOCK_START_MS + TEST_SPOKE_DURATION_MS;
+        // first spoke
+        hub.add_job(Job::new_auto_id(start_time_ms + 3, "one spoke"))
+            .unwrap();
+        hub.add_job(Job::new_auto_id(start_time_ms + 4, "one spoke"))
+            .unwrap();
+
+        // next spoke
+        hub.add_job(Job::new_auto_id(
+            start_time_ms + TEST_SPOKE_DURATION_MS * 2 + 4,
+            "foo",
+        )).unwrap();
+        hub.add_job(Job::new_auto_id(
+            start_time_ms + TEST_SPOKE_DURATION_MS * 2 + 3,
+            "foo",
+        )).unwrap();
+
+        assert_eq!(
+            hub.bst_spoke_map.len(),
+            2,
+            "Failed at time: {}",
+            clock.now_ms()
+        );
+        // wait for the first spoke's jobs to become ready
+        clock.set(start_time_ms + 4);
+
+        let mut walk_one = hub.walk_jobs();
+        assert_eq!(
+            walk_one.len(),
+            2,
+            "Failed at time: {}",
+            clock.now_ms()
+        );
+    }
+
+    #[test]
+    fn can_find_jobs() {
+        let (mut hub, _clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let start_time_ms = MOCK_START_MS + TEST_SPOKE_DURATION_MS;
+        let job_one_spoke = Job::new_auto_id(start_time_ms + 3, "one spoke");
+        let job_other_spoke =
+            Job::new_auto_id(start_time_ms + TEST_SPOKE_DURATION_MS * 2 + 4, "foo");
+
+        let job_one_id = job_one_spoke.get_metadata().get_id();
+        let job_other_id = job_other_spoke.get_metadata().get_id();
+
+        hub.add_job(job_one_spoke).unwrap();
+        hub.add_job(Job::new_auto_id(start_time_ms + 4, "one spoke"))
+            .unwrap();
+        hub.add_job(job_other_spoke).unwrap();
+        hub.add_job(Job::new_auto_id(
+            start_time_ms + TEST_SPOKE_DURATION_MS * 2 + 3,
+            "foo",
+        )).unwrap();
+        assert_eq!(hub.bst_spoke_map.len(), 2);
+
+        assert!(hub.find_job_owner_bst(job_one_id).is_some());
+        assert!(hub.find_job_owner_bst(job_other_id).is_some());
+
+        // Can't find unknown jobs
+        assert!(!hub.find_job_owner_bst(Uuid::new_v4()).is_some());
+    }
+
+    #[test]
+    fn refuses_jobs_whose_spoke_would_overflow() {
+        let config = HubConfig::new(TEST_SPOKE_DURATION_MS).with_max_future_ms(u64::MAX);
+        let mut hub = Hub::from_config(config);
+        assert_eq!(
+            hub.add_job(Job::new_auto_id(u64::MAX, "never")),
+            Err(YaadError::from(RejectReason::Overflow {
+                trigger_at_ms: u64::MAX
+            }))
+        );
+        assert!(hub.bst_spoke_map.is_empty(), "Refused jobs leave the hub as it was");
+        assert!(hub.jobs().is_empty());
+
+        let later = Job::new_auto_id(times::current_time_ms() + 1_000, "later");
+        assert_eq!(hub.add_job(later), Ok(()), "Hub still takes other jobs");
+    }
+
+    /// Asserts the hub's spokes are `spoke_duration_ms` wide, aligned and don't overlap
+    fn assert_aligned_spokes(hub: &Hub, spoke_duration_ms: u64) {
+        let bounds: Vec<&BoundingSpokeTime> = hub.bst_spoke_map.keys().collect();
+        for bst in &bounds {
+            assert_eq!(bst.get_start_time_ms() % spoke_duration_ms, 0, "{:?}", bst);
+            assert_eq!(
+                bst.get_end_time_ms() - bst.get_start_time_ms(),
+                spoke_duration_ms
+            );
+        }
+        for pair in bounds.windows(2) {
+            assert!(
+                pair[0].get_end_time_ms() <= pair[1].get_start_time_ms(),
+                "Spokes overlap: {:?}",
+                pair
+            );
+        }
+    }
+
+    #[test]
+    fn spoke_bounds_tile_the_timeline() {
+        let mut rng = thread_rng();
+        for _ in 0..10_000 {
+            let spoke_duration_ms = rng.gen_range(1, 100_000);
+            let trigger_at_ms = rng.gen_range(0, 1 << 50);
+            let job = Job::new_auto_id(trigger_at_ms, "job");
+            let bst = Hub::job_bounding_spoke_time(&job, spoke_duration_ms).unwrap();
+            assert!(
+                bst.get_start_time_ms() <= trigger_at_ms && trigger_at_ms < bst.get_end_time_ms(),
+                "{} isn't within {:?}",
+                trigger_at_ms,
+                bst
+            );
+            assert_eq!(bst.get_start_time_ms() % spoke_duration_ms, 0);
+            assert_eq!(bst.get_end_time_ms() - bst.get_start_time_ms(), spoke_duration_ms);
+
+            // The neighbours start right where this one ends and end right where it starts
+            let next = spoke_bounds_at(bst.get_end_time_ms(), spoke_duration_ms).unwrap();
+            assert_eq!(next.get_start_time_ms(), bst.get_end_time_ms());
+            if bst.get_start_time_ms() > 0 {
+                let previous = spoke_bounds_at(bst.get_start_time_ms() - 1, spoke_duration_ms);
+                assert_eq!(previous.unwrap().get_end_time_ms(), bst.get_start_time_ms());
+            }
+            // Every time within the bounds maps back onto them
+            let within = rng.gen_range(bst.get_start_time_ms(), bst.get_end_time_ms());
+            assert_eq!(spoke_bounds_at(within, spoke_duration_ms), Some(bst));
+        }
+    }
+
+    #[test]
+    fn spreads_jobs_over_one_spoke_per_window() {
+        const SPOKE_DURATION_MS: u64 = 10_000;
+        let mut hub = Hub::new(SPOKE_DURATION_MS);
+        let start_ms = times::current_time_ms() + 60_000;
+        let mut windows = HashSet::new();
+        for i in 0..10_000 {
+            // 10k jobs an hour apart from first to last
+            let trigger_at_ms = start_ms + i * 360;
+            windows.insert(trigger_at_ms / SPOKE_DURATION_MS);
+            hub.add_job(Job::new_auto_id(trigger_at_ms, "job")).unwrap();
+        }
+        assert!(windows.len() == 360 || windows.len() == 361);
+        assert_eq!(hub.bst_spoke_map.len(), windows.len());
+        assert_aligned_spokes(&hub, SPOKE_DURATION_MS);
+        let jobs: usize = hub.bst_spoke_map.values().map(|s| s.pending_job_len()).sum();
+        assert_eq!(jobs, 10_000);
+    }
+
+    #[test]
+    fn preallocates_contiguous_spokes() {
+        const SPOKE_DURATION_MS: u64 = 10_000;
+        let mut hub = Hub::new(SPOKE_DURATION_MS);
+        let now_ms = times::current_time_ms();
+        let created = hub.ensure_spokes_until(60_000);
+        assert!(created == 6 || created == 7, "Created {} spokes", created);
+        assert_eq!(hub.ensure_spokes_until(60_000), 0, "Existing spokes are kept");
+        assert_aligned_spokes(&hub, SPOKE_DURATION_MS);
+        let bounds: Vec<&BoundingSpokeTime> = hub.bst_spoke_map.keys().collect();
+        assert!(bounds[0].get_start_time_ms() <= now_ms);
+        assert!(bounds[bounds.len() - 1].get_end_time_ms() >= now_ms + 60_000);
+        for pair in bounds.windows(2) {
+            assert_eq!(pair[0].get_end_time_ms(), pair[1].get_start_time_ms());
+        }
+
+        hub.add_job(Job::new_auto_id(now_ms + 30_000, "job")).unwrap();
+        assert_eq!(hub.bst_spoke_map.len(), created, "Job lands in a preallocated spoke");
+
+        let mut ticked = Hub::new(TEST_SPOKE_DURATION_MS);
+        ticked.tick();
+        let preallocated = ticked.bst_spoke_map.len() as u64;
+        assert!(preallocated == PREALLOCATED_SPOKES || preallocated == PREALLOCATED_SPOKES + 1);
+    }
+
+    #[test]
+    fn jobs_on_a_spoke_boundary_belong_to_the_next_spoke() {
+        let start_ms = times::floor_ms_from_epoch(times::current_time_ms()) + 1_000;
+        let mut hub = Hub::new(TEST_SPOKE_DURATION_MS);
+        let last = Job::new_auto_id(start_ms + TEST_SPOKE_DURATION_MS - 1, "last");
+        let boundary = Job::new_auto_id(start_ms + TEST_SPOKE_DURATION_MS, "boundary");
+        let (last_id, boundary_id) = (last.get_metadata().get_id(), boundary.get_metadata().get_id());
+        hub.add_job(last).unwrap();
+        hub.add_job(boundary).unwrap();
+
+        assert_eq!(hub.bst_spoke_map.len(), 2);
+        assert_eq!(
+            hub.find_job_owner_bst(last_id),
+            Some(BoundingSpokeTime::new(
+                start_ms,
+                start_ms + TEST_SPOKE_DURATION_MS
+            ))
+        );
+        assert_eq!(
+            hub.find_job_owner_bst(boundary_id),
+            Some(BoundingSpokeTime::new(
+                start_ms + TEST_SPOKE_DURATION_MS,
+                start_ms + TEST_SPOKE_DURATION_MS * 2
+            ))
+        );
+    }
+
+    /// Ingestion used to print a line per job, which dominated the cost of adding jobs. Adding
+    /// 100k jobs should stay well within a second per 10k jobs even in debug builds.
+    #[test]
+    fn ingests_many_jobs_quietly() {
+        const JOBS: u64 = 100_000;
+        let (mut hub, clock) = mock_hub(1_000);
+        let now_ms = clock.now_ms();
+        let started = SystemTime::now();
+        for i in 0..JOBS {
+            // Half the jobs are due, the other half spread over the next minute
+            let trigger_at_ms = if i % 2 == 0 {
+                now_ms - 1_000
+            } else {
+                now_ms + 1_000 + i % 60_000
+            };
+            hub.add_job(Job::new_auto_id(trigger_at_ms, "job")).unwrap();
+        }
+        let elapsed = started.elapsed().unwrap();
+        assert!(
+            elapsed < Duration::from_secs(10),
+            "Adding {} jobs took {:?}",
+            JOBS,
+            elapsed
+        );
+        assert_eq!(hub.pending_job_count() as u64, JOBS);
+        assert_eq!(hub.walk_jobs().len() as u64, JOBS / 2);
+    }
+
+    #[test]
+    fn walks_past_and_spoke_jobs_in_trigger_order() {
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let start_ms = clock.now_ms();
+        for offset_ms in &[2, 5, 9, 14, 14, 21] {
+            hub.add_job(Job::new_auto_id(start_ms + offset_ms, "spoke"))
+                .unwrap();
+        }
+        clock.advance(50);
+        // These are due by now, so they land in the past spoke, interleaved with the spoke jobs
+        for offset_ms in &[1, 5, 10, 14, 22] {
+            hub.add_job(Job::new_auto_id(start_ms + offset_ms, "past"))
+                .unwrap();
+        }
+        assert_eq!(hub.past_spoke.pending_job_len(), 5);
+
+        let walked: Vec<(u64, Uuid)> = hub
+            .walk_jobs()
+            .iter()
+            .map(|j| (j.trigger_at_ms(), j.get_metadata().get_id()))
+            .collect();
+        assert_eq!(walked.len(), 11);
+        assert!(
+            walked.windows(2).all(|w| w[0] < w[1]),
+            "Jobs are walked by trigger time, then id: {:?}",
+            walked
+        );
+    }
+
+    #[test]
+    fn bounded_walks_hand_out_every_job_once_in_order() {
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let start_ms = clock.now_ms();
+        for offset_ms in &[3, 3, 8, 12, 25, 26] {
+            hub.add_job(Job::new_auto_id(start_ms + offset_ms, "spoke"))
+                .unwrap();
+        }
+        let spokes = hub.bst_spoke_map.len();
+        // Let every spoke expire, then add due jobs to the past spoke
+        clock.advance(60);
+        for offset_ms in &[1, 3, 20] {
+            hub.add_job(Job::new_auto_id(start_ms + offset_ms, "past"))
+                .unwrap();
+        }
+
+        let mut walked: Vec<(u64, Uuid)> = vec![];
+        loop {
+            let jobs = hub.walk_jobs_limit(1);
+            if jobs.is_empty() {
+                break;
+            }
+            assert_eq!(jobs.len(), 1);
+            if walked.is_empty() {
+                assert_eq!(
+                    hub.bst_spoke_map.len(),
+                    spokes,
+                    "Expired spokes with jobs left are kept"
+                );
+            }
+            walked.push((jobs[0].trigger_at_ms(), jobs[0].get_metadata().get_id()));
+        }
+        assert_eq!(walked.len(), 9, "Every job is walked once");
+        assert!(
+            walked.windows(2).all(|w| w[0] < w[1]),
+            "Jobs are walked in order: {:?}",
+            walked
+        );
+        assert_eq!(hub.bst_spoke_map.len(), 0, "Drained spokes are pruned");
+    }
+
+    #[test]
+    fn can_cancel_job() {
+        let start_time_ms = times::current_time_ms();
+        let mut hub = Hub::new(TEST_SPOKE_DURATION_MS);
+        let future_job = Job::new_auto_id(start_time_ms + 10_000, "future");
+        let other_job = Job::new_auto_id(start_time_ms + 10_001, "other");
+        let future_id = future_job.get_metadata().get_id();
+        hub.add_job(future_job).unwrap();
+        hub.add_job(other_job).unwrap();
+
+        assert!(hub.cancel_job(future_id));
+        assert!(hub.find_job_owner_bst(future_id).is_none());
+        // Job is gone, more cancels are idempotent
+        assert!(!hub.cancel_job(future_id));
+        assert!(!hub.cancel_job(Uuid::new_v4()), "Unknown jobs can't be cancelled");
+    }
+
+    #[test]
+    fn can_cancel_past_job() {
+        let start_time_ms = times::current_time_ms();
+        let mut hub = Hub::new(TEST_SPOKE_DURATION_MS);
+        let j = Job::new_auto_id(start_time_ms - 300, "past");
+        let id = j.get_metadata().get_id();
+        hub.add_job(j).unwrap();
+
+        assert!(hub.cancel_job(id));
+        assert!(!hub.cancel_job(id));
+        assert_eq!(hub.walk_jobs().len(), 0, "Cancelled past job is not walked");
+    }
+
+    #[test]
+    fn cancelling_walked_jobs_is_a_noop() {
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let start_time_ms = clock.now_ms();
+        let j = Job::new_auto_id(start_time_ms + 5, "soon");
+        let id = j.get_metadata().get_id();
+        hub.add_job(j).unwrap();
+
+        clock.advance(TEST_SPOKE_DURATION_MS * 2);
+        assert_eq!(hub.walk_jobs().len(), 1);
+        assert_eq!(hub.bst_spoke_map.len(), 0, "Walked spoke was pruned");
+        assert!(!hub.cancel_job(id));
+    }
+
+    #[test]
+    fn prunes_spokes_emptied_by_cancellation() {
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        // Start on a spoke boundary so all jobs land in one spoke
+        let spoke_start_ms = MOCK_START_MS + 20;
+        let mut ids = vec![];
+        for i in 0..3 {
+            let j = Job::new_auto_id(spoke_start_ms + i, "job");
+            ids.push(j.get_metadata().get_id());
+            hub.add_job(j).unwrap();
+        }
+        assert_eq!(hub.bst_spoke_map.len(), 1);
+        let bst = hub.find_job_owner_bst(ids[0]).unwrap();
+
+        hub.cancel_job(ids[0]);
+        hub.cancel_job(ids[1]);
+        assert_eq!(hub.bst_spoke_map[&bst].pending_job_len(), 1);
+
+        clock.advance(TEST_SPOKE_DURATION_MS * 4);
+        assert_eq!(hub.prune_spokes(), 0, "Spoke still has a live job");
+        hub.cancel_job(ids[2]);
+        assert_eq!(hub.bst_spoke_map[&bst].pending_job_len(), 0);
+        assert_eq!(hub.prune_spokes(), 1, "Expired spoke holding only tombstones is pruned");
+        assert_eq!(hub.walk_jobs().len(), 0);
+    }
+
+    #[test]
+    fn finds_next_trigger_time() {
+        let start_time_ms = times::current_time_ms();
+        let mut hub = Hub::new(TEST_SPOKE_DURATION_MS);
+        assert_eq!(hub.next_trigger_time_ms(), None, "Empty hub has nothing due");
+
+        let soon = Job::new_auto_id(start_time_ms + 5_000, "soon");
+        let soon_id = soon.get_metadata().get_id();
+        hub.add_job(Job::new_auto_id(start_time_ms + 9_000, "later"))
+            .unwrap();
+        hub.add_job(soon).unwrap();
+        assert_eq!(hub.next_trigger_time_ms(), Some(start_time_ms + 5_000));
+
+        hub.cancel_job(soon_id);
+        assert_eq!(
+            hub.next_trigger_time_ms(),
+            Some(start_time_ms + 9_000),
+            "Cancelled jobs are skipped"
+        );
+
+        hub.add_job(Job::new_auto_id(start_time_ms - 300, "past"))
+            .unwrap();
+        assert_eq!(hub.next_trigger_time_ms(), Some(start_time_ms - 300));
+    }
+
+    #[test]
+    fn ack_before_ttr() {
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let j = Job::new_auto_id(clock.now_ms() - 100, "job").with_ttr_ms(50);
+        let id = j.get_metadata().get_id();
+        hub.add_job(j).unwrap();
+
+        let reserved = hub.reserve_ready_jobs();
+        assert_eq!(reserved.len(), 1);
+        assert_eq!(hub.reserved_job_len(), 1);
+        assert!(hub.reservation_deadline_ms(id).unwrap() > clock.now_ms());
+        assert!(hub.ack(id));
+        assert_eq!(hub.reservation_deadline_ms(id), None);
+        assert!(!hub.ack(id), "Double ack is a noop");
+
+        clock.advance(60);
+        assert_eq!(hub.expire_reservations(), 0, "Acked jobs never come back");
+        assert_eq!(hub.reserve_ready_jobs().len(), 0);
+    }
+
+    #[test]
+    fn requeues_jobs_after_ttr() {
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let j = Job::new_auto_id(clock.now_ms() - 100, "job").with_ttr_ms(20);
+        let id = j.get_metadata().get_id();
+        hub.add_job(j).unwrap();
+        assert_eq!(hub.reserve_ready_jobs().len(), 1);
+        assert!(hub.next_reservation_deadline_ms().is_some());
+
+        assert_eq!(hub.expire_reservations(), 0, "TTR hasn't run out yet");
+        clock.advance(30);
+        assert_eq!(hub.expire_reservations(), 1);
+        assert!(!hub.ack(id), "Expired reservation can't be acked");
+
+        let again = hub.reserve_ready_jobs();
+        assert_eq!(again.len(), 1, "Expired job is handed out again");
+        assert_eq!(again[0].get_metadata().get_id(), id);
+        assert!(hub.ack(id));
+    }
+
+    #[test]
+    fn huge_ttrs_keep_jobs_reserved() {
+        let (mut hub, clock) = mock_hub(1_000);
+        let job = Job::new_auto_id(clock.now_ms() - 10, "forever").with_ttr_ms(u64::MAX);
+        let id = job.get_metadata().get_id();
+        hub.add_job(job).unwrap();
+        assert_eq!(hub.reserve_ready_jobs().len(), 1);
+        assert_eq!(hub.reservation_deadline_ms(id), Some(u64::MAX), "The deadline saturates");
+        clock.advance(60_000);
+        assert_eq!(hub.expire_reservations(), 0);
+        assert_eq!(hub.reserved_job_len(), 1);
+    }
+
+    #[test]
+    fn touching_jobs_with_huge_ttrs_saturates_their_deadline() {
+        let (mut hub, clock) = mock_hub(1_000);
+        let job = Job::new_auto_id(clock.now_ms() - 10, "forever").with_ttr_ms(u64::MAX - 5);
+        let id = job.get_metadata().get_id();
+        hub.add_job(job).unwrap();
+        assert_eq!(hub.reserve_ready_jobs().len(), 1);
+        clock.advance(10);
+        assert_eq!(hub.touch(id), Ok(u64::MAX));
+        assert_eq!(hub.expire_reservations(), 0, "Still reserved after the touch");
+        assert_eq!(hub.reserved_job_len(), 1);
+    }
+
+    #[test]
+    fn touching_keeps_jobs_reserved() {
+        let (mut hub, clock) = mock_hub(1_000);
+        let job = Job::new_auto_id(clock.now_ms() - 10, "slow").with_ttr_ms(100);
+        let id = job.get_metadata().get_id();
+        hub.add_job(job).unwrap();
+        assert_eq!(hub.touch(id), Err(TouchError::NotReserved(id)));
+        assert_eq!(hub.reserve_ready_jobs().len(), 1);
+        let first_deadline_ms = hub.reservation_deadline_ms(id).unwrap();
+
+        // Keep touching well past the original deadline
+        for _ in 0..6 {
+            clock.advance(50);
+            let deadline_ms = hub.touch(id).unwrap();
+            assert_eq!(hub.reservation_deadline_ms(id), Some(deadline_ms));
+            assert_eq!(hub.expire_reservations(), 0, "Touched job isn't re-queued");
+        }
+        assert!(clock.now_ms() > first_deadline_ms);
+        assert_eq!(hub.reserved_job_len(), 1);
+
+        clock.advance(150);
+        assert_eq!(hub.expire_reservations(), 1, "Untouched job is re-queued");
+        assert_eq!(hub.touch(id), Err(TouchError::NotReserved(id)));
+        let unknown = Uuid::new_v4();
+        assert_eq!(hub.touch(unknown), Err(TouchError::UnknownJob(unknown)));
+    }
+
+    #[test]
+    fn reschedules_jobs_earlier() {
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let start_ms = clock.now_ms();
+        let job = Job::new_auto_id(start_ms + 38, "retry").with_priority(3);
+        let id = job.get_metadata().get_id();
+        hub.add_job(job).unwrap();
+        hub.reschedule(id, start_ms + 32).unwrap();
+        assert_eq!(hub.spoke_count(), 1, "Moved within its spoke");
+        assert_eq!(hub.stats().total_body_bytes, 5);
+
+        clock.set(start_ms + 33);
+        let walked = hub.walk_jobs();
+        assert_eq!(walked.len(), 1);
+        assert_eq!(walked[0].get_metadata().get_id(), id, "The id is kept");
+        assert_eq!(walked[0].trigger_at_ms(), start_ms + 32);
+        assert_eq!(walked[0].priority(), 3);
+        clock.set(start_ms + 39);
+        assert!(hub.walk_jobs().is_empty(), "Doesn't fire again at the old time");
+        assert_eq!(hub.pending_job_count(), 0);
+    }
+
+    #[test]
+    fn reschedules_jobs_later_into_another_spoke() {
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let start_ms = clock.now_ms();
+        let job = Job::new_auto_id(start_ms + 5, "backoff");
+        let id = job.get_metadata().get_id();
+        hub.add_job(job).unwrap();
+        let old_bst = hub.find_job_owner_bst(id).unwrap();
+
+        hub.reschedule(id, start_ms + 25).unwrap();
+        assert_ne!(hub.find_job_owner_bst(id), Some(old_bst));
+        assert_eq!(hub.bst_spoke_map[&old_bst].pending_job_len(), 0);
+        assert_eq!(hub.stats().total_body_bytes, 7, "Bytes are only counted once");
+
+        clock.set(start_ms + 6);
+        assert!(hub.walk_jobs().is_empty(), "Doesn't fire at the old time");
+        clock.set(start_ms + 26);
+        let walked = hub.walk_jobs();
+        assert_eq!(walked.len(), 1);
+        assert_eq!(walked[0].get_metadata().get_id(), id);
+    }
+
+    #[test]
+    fn reschedules_jobs_into_the_past() {
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let now_ms = clock.now_ms();
+        let job = Job::new_auto_id(now_ms + 60_000, "now please");
+        let id = job.get_metadata().get_id();
+        hub.add_job(job).unwrap();
+
+        hub.reschedule(id, now_ms - 10).unwrap();
+        assert_eq!(hub.find_job_owner_bst(id), Some(hub.past_spoke.get_bounds()));
+        let walked = hub.walk_jobs();
+        assert_eq!(walked.len(), 1, "Fires on the next walk");
+        assert_eq!(walked[0].get_metadata().get_id(), id);
+        clock.advance(60_000);
+        assert!(hub.walk_jobs().is_empty());
+    }
+
+    #[test]
+    fn refuses_to_reschedule_jobs_it_does_not_schedule() {
+        let clock = Arc::new(MockClock::new(MOCK_START_MS));
+        let config = HubConfig::new(TEST_SPOKE_DURATION_MS).with_max_future_ms(u64::MAX);
+        let mut hub = Hub::from_config_with_clock(config, clock.clone());
+        let now_ms = clock.now_ms();
+        let unknown = Uuid::new_v4();
+        assert_eq!(
+            hub.reschedule(unknown, now_ms),
+            Err(RescheduleError::UnknownJob(unknown))
+        );
+
+        let job = Job::new_auto_id(now_ms - 10, "job");
+        let id = job.get_metadata().get_id();
+        hub.add_job(job).unwrap();
+        assert_eq!(hub.reserve_ready_jobs().len(), 1);
+        assert_eq!(hub.reschedule(id, now_ms + 100), Err(RescheduleError::Reserved(id)));
+        assert!(hub.bury(id, 0));
+        assert_eq!(hub.reschedule(id, now_ms + 100), Err(RescheduleError::Buried(id)));
+
+        hub.kick_job(id).unwrap();
+        match hub.reschedule(id, u64::MAX) {
+            Err(RescheduleError::Refused(YaadError::JobRejected {
+                reason: RejectReason::Overflow { .. },
+            })) => {}
+            other => panic!("Expected an overflow, got {:?}", other),
+        }
+        assert_eq!(hub.walk_jobs().len(), 1, "Refused job stays where it was");
+    }
+
+    #[test]
+    fn refuses_duplicate_job_ids() {
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let now_ms = clock.now_ms();
+        let job = Job::new_auto_id(now_ms + 5, "original");
+        let id = job.get_metadata().get_id();
+        hub.add_job(job).unwrap();
+        let bst = hub.find_job_owner_bst(id).unwrap();
+
+        let same_spoke = Job::new(id, now_ms + 6, "same spoke");
+        assert_eq!(hub.add_job(same_spoke), Err(RejectReason::Duplicate(id).into()));
+        let other_spoke = Job::new(id, now_ms + 60_000, "other spoke");
+        assert_eq!(hub.add_job(other_spoke), Err(RejectReason::Duplicate(id).into()));
+        let past = Job::new(id, now_ms - 10, "past");
+        assert_eq!(hub.add_job(past), Err(RejectReason::Duplicate(id).into()));
+        assert_eq!(hub.spoke_count(), 1, "No spoke was created for the duplicates");
+        assert_eq!(hub.find_job_owner_bst(id), Some(bst));
+        assert_eq!(hub.stats().total_body_bytes, 8);
+
+        clock.advance(5);
+        assert_eq!(hub.reserve_ready_jobs().len(), 1);
+        let reserved = Job::new(id, now_ms, "reserved");
+        assert_eq!(hub.add_job(reserved), Err(RejectReason::Duplicate(id).into()));
+        assert!(hub.bury(id, 0));
+        let buried = Job::new(id, now_ms, "buried");
+        assert_eq!(hub.add_job(buried), Err(RejectReason::Duplicate(id).into()));
+
+        assert!(hub.cancel_job(id));
+        hub.add_job(Job::new(id, now_ms, "again")).unwrap();
+        assert_eq!(hub.walk_jobs()[0].get_body().as_bytes(), b"again");
+    }
+
+    #[test]
+    fn refuses_jobs_past_its_horizon() {
+        let clock = Arc::new(MockClock::new(MOCK_START_MS));
+        let config = HubConfig::new(TEST_SPOKE_DURATION_MS).with_max_future_ms(60_000);
+        let mut hub = Hub::from_config_with_clock(config, clock.clone());
+        let horizon_ms = MOCK_START_MS + 60_000;
+
+        assert_eq!(hub.add_job(Job::new_auto_id(horizon_ms, "at the horizon")), Ok(()));
+        assert_eq!(
+            hub.add_job(Job::new_auto_id(horizon_ms + 1, "past the horizon")),
+            Err(YaadError::from(RejectReason::TooFarInFuture {
+                trigger_at_ms: horizon_ms + 1,
+                horizon_ms,
+            }))
+        );
+        assert_eq!(hub.spoke_count(), 1, "No spoke is created for the refused job");
+        assert_eq!(hub.pending_job_count(), 1);
+
+        let job = Job::new_auto_id(MOCK_START_MS - 10, "due");
+        let id = job.get_metadata().get_id();
+        hub.add_job(job).unwrap();
+        match hub.reschedule(id, horizon_ms + 1) {
+            Err(RescheduleError::Refused(YaadError::JobRejected {
+                reason: RejectReason::TooFarInFuture { .. },
+            })) => {}
+            other => panic!("Expected the horizon to refuse, got {:?}", other),
+        }
+        clock.advance(1);
+        assert_eq!(hub.add_job(Job::new_auto_id(horizon_ms + 1, "later")), Ok(()));
+        assert_eq!(Hub::new(1_000).config().max_future_ms, DEFAULT_MAX_FUTURE_MS);
+    }
+
+    /// Jobs for a batch: a minute of triggers in scrambled order, a few of them due already
+    fn batch_of_jobs(start_ms: u64, count: u64) -> Vec<Job> {
+        (0..count)
+            .map(|i| Job::new_auto_id(start_ms - 100 + (i * 7_919) % 60_000, "batched"))
+            .collect()
+    }
+
+    #[test]
+    fn batch_insert_walks_like_a_loop_of_inserts() {
+        let config = HubConfig::new(TEST_SPOKE_DURATION_MS).with_max_future_ms(30_000);
+        let clock = Arc::new(MockClock::new(MOCK_START_MS));
+        let mut looped = Hub::from_config_with_clock(config, clock.clone());
+        let mut batched = Hub::from_config_with_clock(config, clock.clone());
+
+        let mut jobs = batch_of_jobs(MOCK_START_MS, 2_000);
+        let held = Job::new_auto_id(MOCK_START_MS + 50, "held");
+        jobs.push(held.clone());
+        jobs.push(jobs[10].clone());
+        looped.add_job(held.clone()).unwrap();
+        batched.add_job(held).unwrap();
+
+        let mut loop_refused = vec![];
+        for job in jobs.clone() {
+            if let Err(e) = looped.add_job(job.clone()) {
+                loop_refused.push((job, e));
+            }
+        }
+        let batch_refused = batched.add_jobs(jobs);
+        let reasons = |refused: &[(Job, YaadError)]| {
+            let mut reasons: Vec<(Uuid, YaadError)> = refused
+                .iter()
+                .map(|r| (r.0.get_metadata().get_id(), r.1.clone()))
+                .collect();
+            reasons.sort_by_key(|r| r.0);
+            reasons
+        };
+        assert_eq!(reasons(&batch_refused), reasons(&loop_refused));
+        assert!(batch_refused.len() > 2, "Jobs past the horizon are refused");
+        assert_eq!(batched.counters().jobs_added(), looped.counters().jobs_added());
+        assert_eq!(batched.spoke_count(), looped.spoke_count());
+
+        for &ms in &[0, 5_000, 30_000] {
+            clock.set(MOCK_START_MS + ms);
+            let ids = |walked: Vec<Job>| -> Vec<Uuid> {
+                walked.iter().map(|j| j.get_metadata().get_id()).collect()
+            };
+            assert_eq!(ids(batched.walk_jobs()), ids(looped.walk_jobs()));
+        }
+        assert_eq!(batched.pending_job_count(), 0);
+    }
+
+    #[test]
+    fn batch_insert_beats_a_loop_of_inserts() {
+        const JOBS: u64 = 50_000;
+        let (mut looped, _) = mock_hub(1_000);
+        let (mut batched, _) = mock_hub(1_000);
+        let jobs = batch_of_jobs(MOCK_START_MS, JOBS);
+
+        let started = Instant::now();
+        for job in jobs.clone() {
+            looped.add_job(job).unwrap();
+        }
+        let loop_elapsed = started.elapsed();
+        let started = Instant::now();
+        assert!(batched.add_jobs(jobs).is_empty());
+        let batch_elapsed = started.elapsed();
+
+        println!(
+            "Added {} jobs in a loop in {:?}, in a batch in {:?}",
+            JOBS, loop_elapsed, batch_elapsed
+        );
+        assert_eq!(batched.pending_job_count(), looped.pending_job_count());
+        assert!(
+            batch_elapsed < loop_elapsed,
+            "Batch took {:?}, the loop {:?}",
+            batch_elapsed,
+            loop_elapsed
+        );
+    }
+
+    #[test]
+    fn iterates_scheduled_jobs_without_walking_them() {
+        fn scheduled(hub: &Hub, range: Option<(u64, u64)>) -> HashSet<Uuid> {
+            hub.iter_scheduled(range).map(|v| v.id).collect()
+        }
+        let (mut hub, clock) = mock_hub(1_000);
+        let past = Job::new_auto_id(MOCK_START_MS - 50, "past");
+        let past_id = past.get_metadata().get_id();
+        hub.add_job(past).unwrap();
+        let soon = add_at_offset(&mut hub, 500);
+        let later = add_at_offset(&mut hub, 2_500);
+        let far = add_at_offset(&mut hub, 60_000);
+        let cancelled = add_at_offset(&mut hub, 2_600);
+        assert!(hub.cancel_job(cancelled));
+
+        let all: HashSet<Uuid> = vec![past_id, soon, later, far].into_iter().collect();
+        assert_eq!(scheduled(&hub, None), all, "Cancelled jobs are left out");
+        let views: Vec<JobView> = hub.iter_scheduled(None).collect();
+        assert_eq!(views[0].id, past_id, "The past spoke comes first");
+        assert_eq!((views[0].state, views[0].body_len), (JobState::Ready, 4));
+        let view = views.iter().find(|v| v.id == soon).unwrap();
+        assert_eq!(view.state, JobState::Delayed);
+        assert_eq!(view.trigger_at_ms, MOCK_START_MS + 500);
+        assert_eq!(view.body.as_bytes(), b"at 500");
+
+        let at = |offset_ms| MOCK_START_MS + offset_ms;
+        let only = |ids: &[Uuid]| ids.iter().cloned().collect::<HashSet<Uuid>>();
+        assert_eq!(scheduled(&hub, Some((at(500), at(2_600)))), only(&[soon, later]));
+        assert_eq!(scheduled(&hub, Some((at(1_000), at(3_000)))), only(&[later]));
+        assert_eq!(scheduled(&hub, Some((0, at(1)))), only(&[past_id]));
+        assert_eq!(
+            scheduled(&hub, Some((at(2_501), at(60_000)))),
+            only(&[]),
+            "Ranges exclude their end"
+        );
+
+        clock.advance(2_500);
+        assert_eq!(scheduled(&hub, None), all, "Looking at due jobs doesn't walk them");
+        let walked: Vec<Uuid> = hub
+            .walk_jobs()
+            .iter()
+            .map(|j| j.get_metadata().get_id())
+            .collect();
+        assert_eq!(walked, vec![past_id, soon, later]);
+        assert_eq!(scheduled(&hub, None), only(&[far]));
+    }
+
+    #[test]
+    fn views_jobs_in_every_state() {
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let now_ms = clock.now_ms();
+        let delayed = Job::new_auto_id(now_ms + 500, "delayed");
+        let ready = Job::new_auto_id(now_ms - 10, "ready");
+        let delayed_id = delayed.get_metadata().get_id();
+        let ready_id = ready.get_metadata().get_id();
+        hub.add_job(delayed).unwrap();
+        hub.add_job(ready).unwrap();
+        assert!(hub.get_job(Uuid::new_v4()).is_none());
+
+        let view = hub.get_job(delayed_id).unwrap();
+        assert_eq!(view.id, delayed_id);
+        assert_eq!(view.trigger_at_ms, now_ms + 500);
+        assert_eq!(view.state, JobState::Delayed);
+        assert_eq!(view.body_len, 7);
+        assert_eq!(view.body.as_bytes(), b"delayed");
+        let view = hub.get_job(ready_id).unwrap();
+        assert_eq!((view.state, view.trigger_at_ms), (JobState::Ready, now_ms - 10));
+
+        assert_eq!(hub.reserve_ready_jobs().len(), 1);
+        let view = hub.get_job(ready_id).unwrap();
+        assert_eq!(view.state, JobState::Reserved);
+        assert_eq!(view.body.as_bytes(), b"ready");
+        assert!(hub.bury(ready_id, 0));
+        assert_eq!(hub.get_job(ready_id).unwrap().state, JobState::Buried);
+
+        clock.advance(500);
+        assert_eq!(hub.get_job(delayed_id).unwrap().state, JobState::Ready);
+        assert!(hub.cancel_job(delayed_id));
+        assert!(hub.get_job(delayed_id).is_none(), "Cancelled jobs are gone");
+    }
+
+    #[test]
+    fn draining_hub_refuses_new_jobs_but_walks_held_ones() {
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let now_ms = clock.now_ms();
+        let job = Job::new_auto_id(now_ms + 5, "before");
+        let id = job.get_metadata().get_id();
+        hub.add_job(job).unwrap();
+        hub.set_draining(true);
+        assert!(hub.is_draining());
+
+        let refused = Job::new_auto_id(now_ms + 5, "during");
+        let draining = YaadError::from(RejectReason::Draining);
+        assert_eq!(hub.add_job(refused.clone()), Err(draining.clone()));
+        assert_eq!(hub.add_jobs(vec![refused])[0].1, draining);
+        clock.advance(5);
+        let walked = hub.reserve_ready_jobs();
+        assert_eq!(walked.len(), 1, "Jobs added before draining still fire");
+        assert_eq!(hub.release(id, 0), Ok(()), "Held jobs can still be released");
+
+        hub.set_draining(false);
+        assert_eq!(hub.add_job(Job::new_auto_id(now_ms, "after")), Ok(()));
+        assert_eq!(hub.walk_jobs().len(), 2);
+    }
+
+    #[test]
+    fn full_hub_refuses_new_jobs_until_one_is_done() {
+        let clock = Arc::new(MockClock::new(MOCK_START_MS));
+        let config = HubConfig::new(TEST_SPOKE_DURATION_MS).with_max_pending_jobs(3);
+        let mut hub = Hub::from_config_with_clock(config, clock.clone());
+        let full = Err(YaadError::Capacity {
+            max_pending_jobs: 3,
+        });
+        let delayed = Job::new_auto_id(MOCK_START_MS + 50, "delayed");
+        let delayed_id = delayed.get_metadata().get_id();
+        hub.add_job(delayed).unwrap();
+        let refused = hub.add_jobs(vec![
+            Job::new_auto_id(MOCK_START_MS + 5, "ready"),
+            Job::new_auto_id(MOCK_START_MS + 6, "buried"),
+            Job::new_auto_id(MOCK_START_MS + 7, "one too many"),
+        ]);
+        assert_eq!(bodies(&[refused[0].0.clone()]), ["one too many"]);
+        assert_eq!(refused[0].1, full.clone().unwrap_err());
+        assert_eq!(hub.held_job_count(), 3);
+
+        clock.advance(10);
+        let reserved = hub.reserve_ready_jobs();
+        let buried_id = reserved[1].get_metadata().get_id();
+        assert!(hub.bury(buried_id, 0));
+        assert_eq!(hub.held_job_count(), 3, "Reserved and buried jobs count too");
+        assert_eq!(hub.add_job(Job::new_auto_id(MOCK_START_MS, "late")), full);
+
+        assert!(hub.ack(reserved[0].get_metadata().get_id()));
+        assert_eq!(hub.add_job(Job::new_auto_id(MOCK_START_MS, "late")), Ok(()));
+        assert_eq!(hub.add_job(Job::new_auto_id(MOCK_START_MS, "later")), full);
+        assert_eq!(hub.kick(1), 1, "Held jobs move about while the hub is full");
+        assert!(hub.cancel_job(delayed_id));
+        assert_eq!(hub.add_job(Job::new_auto_id(MOCK_START_MS, "later")), Ok(()));
+
+        assert_eq!(hub.walk_jobs().len(), 3);
+        assert_eq!(hub.held_job_count(), 0, "Walked jobs aren't held");
+    }
+
+    #[test]
+    fn counts_held_jobs_as_they_come_and_go() {
+        let (mut hub, clock) = mock_hub(100);
+        let recount = |hub: &Hub| {
+            hub.pending_job_count() + hub.reserved_job_len() + hub.buried_job_len()
+        };
+        let mut rng = ChaChaRng::from_seed(&[822]);
+        let mut reserved: Vec<Uuid> = vec![];
+        for _ in 0..2_000 {
+            let trigger_at_ms = clock.now_ms() - 100 + rng.gen_range(0, 800);
+            let job = Job::new_auto_id(trigger_at_ms, "job");
+            let id = job.get_metadata().get_id();
+            match rng.gen_range(0, 12) {
+                0 => drop(hub.add_jobs(vec![job.clone(), job])),
+                1 => {
+                    let jobs = hub.reserve_ready_jobs();
+                    reserved.extend(jobs.iter().map(|j| j.get_metadata().get_id()))
+                }
+                2 => drop(reserved.pop().map(|id| hub.ack(id))),
+                3 => drop(reserved.pop().map(|id| hub.release(id, rng.gen_range(0, 300)))),
+                4 => drop(reserved.pop().map(|id| hub.bury(id, 0))),
+                5 => drop(hub.kick(2)),
+                6 => drop(hub.walk_jobs_limit(3)),
+                7 => {
+                    hub.add_job(job).unwrap();
+                    let _ = hub.reschedule(id, clock.now_ms() + rng.gen_range(0, 500));
+                    hub.cancel_job(id);
+                }
+                8 => {
+                    clock.advance(rng.gen_range(0, 150));
+                    hub.expire_reservations();
+                    hub.tick();
+                }
+                9 => {
+                    if let Some(s) = hub.checkout_ready_spoke() {
+                        hub.checkin_spoke(s);
+                    }
+                }
+                _ => hub.add_job(job).unwrap(),
+            }
+            assert_eq!(hub.held_job_count(), recount(&hub));
+        }
+    }
+
+    #[test]
+    fn past_spoke_lets_go_of_walked_jobs() {
+        const JOBS: u64 = 100_000;
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        for _round in 0..2 {
+            let now_ms = clock.now_ms();
+            for i in 0..JOBS {
+                hub.add_job(Job::new_auto_id(now_ms - JOBS + i, "late")).unwrap();
+            }
+            assert_eq!(hub.stats().past.job_count as u64, JOBS);
+            assert_eq!(hub.walk_jobs().len() as u64, JOBS);
+
+            let stats = hub.stats();
+            assert_eq!((stats.past.job_count, stats.past.body_bytes), (0, 0));
+            assert_eq!(hub.past_spoke.heap_capacity(), 0, "Walked jobs' memory is released");
+            assert_eq!(hub.find_job_owner_bst(Uuid::new_v4()), None);
+            clock.advance(1);
+        }
+        let late = Job::new_auto_id(clock.now_ms() - 1, "late");
+        let id = late.get_metadata().get_id();
+        hub.add_job(late).unwrap();
+        assert_eq!(hub.find_job_owner_bst(id), Some(BoundingSpokeTime::new(0, u64::MAX)));
+    }
+
+    #[test]
+    fn retries_jobs_the_sink_refuses() {
+        const JOBS: u64 = 10;
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let now_ms = clock.now_ms();
+        for i in 0..JOBS {
+            hub.add_job(Job::new_auto_id(now_ms - JOBS + i, "job")).unwrap();
+        }
+        let attempts = AtomicUsize::new(0);
+        let delivered = Mutex::new(vec![]);
+        // Refuses every other job it is offered
+        let sink = FnSink::new(|job: Job| {
+            if attempts.fetch_add(1, AtomicOrdering::SeqCst) % 2 == 1 {
+                return Err(job);
+            }
+            delivered.lock().unwrap().push(job.get_metadata().get_id());
+            Ok(())
+        });
+
+        assert_eq!(hub.drain_into(&sink, 100), 5);
+        assert_eq!(hub.pending_job_count(), 5, "Refused jobs are scheduled again");
+        assert_eq!(hub.drain_into(&sink, 100), 0, "Retries wait a bit");
+        while hub.pending_job_count() > 0 {
+            clock.advance(SINK_RETRY_DELAY_MS);
+            hub.drain_into(&sink, 100);
+        }
+        let delivered: HashSet<Uuid> = delivered.into_inner().unwrap().into_iter().collect();
+        assert_eq!(delivered.len() as u64, JOBS, "Every job is delivered once");
+        assert!(attempts.into_inner() as u64 > JOBS);
+    }
+
+    #[test]
+    fn releases_every_reservation() {
+        let mut hub = Hub::new(TEST_SPOKE_DURATION_MS);
+        let now_ms = times::current_time_ms();
+        hub.add_job(Job::new_auto_id(now_ms - 100, "one")).unwrap();
+        hub.add_job(Job::new_auto_id(now_ms - 50, "two")).unwrap();
+        assert_eq!(hub.reserve_ready_jobs().len(), 2);
+
+        assert_eq!(hub.expire_reservations(), 0, "TTR hasn't run out yet");
+        assert_eq!(hub.release_reservations(), 2);
+        assert_eq!(hub.reserved_job_len(), 0);
+        assert_eq!(hub.next_trigger_time_ms(), Some(now_ms - 100));
+        assert_eq!(hub.walk_jobs().len(), 2, "Released jobs are handed out again");
+    }
+
+    #[test]
+    fn buries_and_kicks_reserved_jobs() {
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let now_ms = clock.now_ms();
+        for i in 0..3 {
+            hub.add_job(Job::new_auto_id(now_ms - 10 + i, "job")).unwrap();
+        }
+        let ids: Vec<Uuid> = hub
+            .reserve_ready_jobs()
+            .iter()
+            .map(|j| j.get_metadata().get_id())
+            .collect();
+        assert_eq!(ids.len(), 3);
+        for id in ids.iter().rev() {
+            assert!(hub.bury(*id, 7));
+        }
+        assert!(!hub.bury(ids[0], 0), "Only reserved jobs are buried");
+        assert_eq!(hub.reserved_job_len(), 0);
+        assert_eq!(hub.buried_job_len(), 3);
+        assert_eq!(hub.jobs().len(), 3, "Buried jobs are still held by the hub");
+
+        // Buried jobs aren't in any spoke, so walks and pruning leave them be
+        clock.advance(TEST_SPOKE_DURATION_MS * 2);
+        hub.prune_spokes();
+        assert!(hub.walk_jobs().is_empty());
+        assert_eq!(hub.buried_job_len(), 3);
+
+        assert_eq!(hub.kick(2), 2);
+        let kicked = hub.reserve_ready_jobs();
+        let mut kicked_ids: Vec<Uuid> = kicked.iter().map(|j| j.get_metadata().get_id()).collect();
+        kicked_ids.sort();
+        let mut first_buried = vec![ids[2], ids[1]];
+        first_buried.sort();
+        assert_eq!(kicked_ids, first_buried, "Jobs are kicked in the order they were buried");
+        assert!(kicked.iter().all(|j| j.priority() == 7 && j.trigger_at_ms() >= now_ms));
+
+        let unknown = Uuid::new_v4();
+        assert_eq!(hub.kick_job(unknown), Err(YaadError::NotFound(unknown)));
+        assert_eq!(hub.kick_job(ids[0]), Ok(()));
+        assert_eq!(hub.buried_job_len(), 0);
+        assert_eq!(hub.walk_jobs()[0].get_metadata().get_id(), ids[0]);
+    }
+
+    #[test]
+    fn counts_jobs_and_spokes() {
+        let mut hub = Hub::new(TEST_SPOKE_DURATION_MS);
+        let shared = Arc::new(Stats::new());
+        hub.set_counters(Arc::clone(&shared));
+        let now_ms = times::current_time_ms();
+        hub.add_job(Job::new_auto_id(now_ms - 10, "due")).unwrap();
+        let later = Job::new_auto_id(now_ms + 60_000, "later");
+        let later_id = later.get_metadata().get_id();
+        hub.add_job(later).unwrap();
+        assert_eq!(hub.pending_job_count(), 2);
+        assert_eq!(hub.ready_job_count(), 1);
+        assert_eq!(hub.spoke_count(), 1);
+        assert_eq!(hub.find_job(later_id).map(|j| j.1), Some(JobState::Delayed));
+
+        let reserved = hub.reserve_ready_jobs();
+        let due_id = reserved[0].get_metadata().get_id();
+        assert_eq!(hub.find_job(due_id).map(|j| j.1), Some(JobState::Reserved));
+        hub.release(due_id, 0).unwrap();
+        assert_eq!(hub.find_job(due_id).map(|j| j.1), Some(JobState::Ready));
+        assert!(hub.find_job(Uuid::new_v4()).is_none());
+
+        assert_eq!(shared.jobs_added(), 2, "Released jobs aren't added again");
+        assert_eq!(shared.jobs_walked(), 1);
+        assert_eq!(shared.spokes_live(), hub.spoke_count());
+        assert!(hub.cancel_job(later_id));
+        assert_eq!(hub.pending_job_count(), 1);
+
+        // Another hub counts into the same collector
+        let mut other = Hub::new(TEST_SPOKE_DURATION_MS);
+        other.add_job(Job::new_auto_id(now_ms + 60_000, "other")).unwrap();
+        other.set_counters(Arc::clone(&shared));
+        assert_eq!(shared.spokes_live(), hub.spoke_count() + 1);
+    }
+
+    #[test]
+    fn reports_body_bytes_per_spoke() {
+        let mut hub = Hub::new(1_000);
+        let now_ms = times::current_time_ms();
+        hub.add_job(Job::new_auto_id(now_ms - 10, "due")).unwrap();
+        let cancelled = Job::new_auto_id(now_ms + 60_000, "cancelled");
+        let cancelled_id = cancelled.get_metadata().get_id();
+        hub.add_job(cancelled).unwrap();
+        hub.add_job(Job::new_auto_id(now_ms + 60_000, "minute")).unwrap();
+        hub.add_job(Job::new_auto_id(now_ms + 120_000, "two minutes"))
+            .unwrap();
+
+        let stats = hub.stats();
+        assert_eq!((stats.past.job_count, stats.past.body_bytes), (1, 3));
+        assert_eq!(stats.spokes.len(), 2);
+        assert_eq!((stats.spokes[0].job_count, stats.spokes[0].body_bytes), (2, 15));
+        assert_eq!((stats.spokes[1].job_count, stats.spokes[1].body_bytes), (1, 11));
+        assert_eq!((stats.total_jobs, stats.total_body_bytes), (4, 29));
+
+        hub.cancel_job(cancelled_id);
+        assert_eq!(hub.stats().spokes[0].body_bytes, 6);
+        assert_eq!(hub.walk_jobs().len(), 1);
+        let stats = hub.stats();
+        assert_eq!((stats.past.job_count, stats.past.body_bytes), (0, 0));
+        assert_eq!((stats.total_jobs, stats.total_body_bytes), (2, 17));
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn compresses_large_bodies_while_scheduled() {
+        let clock = Arc::new(MockClock::new(MOCK_START_MS));
+        let config = HubConfig::new(1_000).with_compress_bodies_over_bytes(64);
+        let mut hub = Hub::from_config_with_clock(config, clock.clone());
+        assert_eq!(hub.config(), config);
+        let large = "{\"event\":\"signup\"}".repeat(100);
+        let job = Job::new_auto_id(MOCK_START_MS + 5_000, large.as_str());
+        let id = job.get_metadata().get_id();
+        hub.add_job(job).unwrap();
+        hub.add_job(Job::new_auto_id(MOCK_START_MS + 6_000, "small"))
+            .unwrap();
+
+        let stats = hub.stats();
+        assert_eq!(stats.total_body_bytes, large.len() + 5);
+        assert!(
+            stats.total_stored_body_bytes < large.len() / 4,
+            "Stored {} bytes",
+            stats.total_stored_body_bytes
+        );
+        let view = hub.get_job(id).unwrap();
+        assert!(view.body.is_compressed());
+        assert_eq!(view.body_len, large.len());
+        assert_eq!(&view.body.to_bytes()[..], large.as_bytes());
+        let (found, _) = hub.find_job(id).unwrap();
+        assert_eq!(found.get_body().as_bytes(), large.as_bytes());
+        assert_eq!(hub.peek_next_delayed().unwrap().1.as_bytes(), large.as_bytes());
+
+        clock.advance(6_000);
+        let mut walked = hub.walk_jobs();
+        walked.sort_by_key(|j| j.body().raw_len());
+        let bodies: Vec<_> = walked.iter().map(|j| j.body().clone()).collect();
+        assert!(bodies.iter().all(|b| !b.is_compressed()), "Walks hand out bodies as put");
+        assert_eq!(bodies[0].as_bytes(), b"small");
+        assert_eq!(bodies[1].as_bytes(), large.as_bytes());
+        let stats = hub.stats();
+        assert_eq!((stats.total_body_bytes, stats.total_stored_body_bytes), (0, 0));
+    }
+
+    #[test]
+    fn keeps_bodies_as_put_by_default() {
+        let mut hub = Hub::new(1_000);
+        let now_ms = times::current_time_ms();
+        let large = "a".repeat(100_000);
+        hub.add_job(Job::new_auto_id(now_ms + 60_000, large.as_str()))
+            .unwrap();
+        let stats = hub.stats();
+        assert_eq!(stats.total_body_bytes, large.len());
+        assert_eq!(stats.total_stored_body_bytes, large.len());
+    }
+
+    #[test]
+    fn stops_counting_pruned_spokes() {
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let start_ms = clock.now_ms() + 20;
+        hub.add_job(Job::new_auto_id(start_ms, "job")).unwrap();
+        assert_eq!(hub.counters().spokes_live(), 1);
+        clock.advance(TEST_SPOKE_DURATION_MS * 4);
+        assert_eq!(hub.walk_jobs().len(), 1);
+        assert_eq!(hub.counters().spokes_live(), 0);
+        assert_eq!(hub.counters().jobs_walked(), 1);
+    }
+
+    #[test]
+    fn peeking_does_not_walk() {
+        let mut hub = Hub::new(1_000);
+        let now_ms = times::current_time_ms();
+        assert!(hub.peek_next_ready().is_none());
+        assert!(hub.peek_next_delayed().is_none());
+        let later = Job::new_auto_id(now_ms + 60_000, "later");
+        let later_id = later.get_metadata().get_id();
+        hub.add_job(later).unwrap();
+        hub.add_job(Job::new_auto_id(now_ms + 30_000, "sooner")).unwrap();
+        hub.add_job(Job::new_auto_id(now_ms - 20, "due").with_priority(10))
+            .unwrap();
+        hub.add_job(Job::new_auto_id(now_ms - 20, "urgent").with_priority(0))
+            .unwrap();
+
+        let (jm, body) = hub.peek_next_ready().unwrap();
+        assert_eq!(body.as_bytes(), b"urgent");
+        let (_, body) = hub.peek_next_delayed().unwrap();
+        assert_eq!(body.as_bytes(), b"sooner");
+        let (_, body) = hub.peek_job(later_id).unwrap();
+        assert_eq!(body.as_bytes(), b"later");
+        assert!(hub.peek_job(Uuid::new_v4()).is_none());
+        assert_eq!(hub.pending_job_count(), 4, "Peeking doesn't walk");
+
+        let walked = hub.walk_jobs();
+        assert_eq!(walked.len(), 2);
+        assert_eq!(walked[0].get_metadata().get_id(), jm.get_id());
+        assert!(hub.peek_next_ready().is_none());
+        assert_eq!(hub.peek_next_delayed().unwrap().1.as_bytes(), b"sooner");
+        hub.cancel_job(later_id);
+        assert!(hub.peek_job(later_id).is_none());
+    }
+
+    #[test]
+    fn peeks_buried_jobs_in_kick_order() {
+        let mut hub = Hub::new(1_000);
+        let now_ms = times::current_time_ms();
+        hub.add_job(Job::new_auto_id(now_ms - 20, "first")).unwrap();
+        hub.add_job(Job::new_auto_id(now_ms - 10, "second")).unwrap();
+        assert!(hub.peek_next_buried().is_none());
+        let reserved = hub.reserve_ready_jobs();
+        // Bury the second job first, kicks go in burial order
+        for j in reserved.iter().rev() {
+            hub.bury(j.get_metadata().get_id(), 0);
+        }
+
+        let next = hub.peek_next_buried().unwrap();
+        assert_eq!(next.get_body().as_bytes(), b"second");
+        assert_eq!(hub.buried_job_len(), 2, "Peeking doesn't kick");
+        hub.kick(1);
+        assert_eq!(hub.peek_next_buried().unwrap().get_body().as_bytes(), b"first");
+    }
+
+    #[test]
+    fn cancels_buried_jobs() {
+        let mut hub = Hub::new(TEST_SPOKE_DURATION_MS);
+        let job = Job::new_auto_id(times::current_time_ms() - 10, "job");
+        let id = job.get_metadata().get_id();
+        hub.add_job(job).unwrap();
+        hub.reserve_ready_jobs();
+        assert!(hub.bury(id, 0));
+        assert!(hub.cancel_job(id));
+        assert_eq!(hub.kick(1), 0);
+    }
+
+    #[test]
+    fn release_reschedules_reserved_jobs() {
+        let mut hub = Hub::new(TEST_SPOKE_DURATION_MS);
+        let j = Job::new_auto_id(times::current_time_ms() - 100, "job");
+        let id = j.get_metadata().get_id();
+        hub.add_job(j).unwrap();
+        hub.reserve_ready_jobs();
+
+        let released_at_ms = times::current_time_ms();
+        hub.release(id, 5_000).unwrap();
+        assert_eq!(
+            hub.release(id, 5_000),
+            Err(YaadError::NotFound(id)),
+            "Job is no longer reserved"
+        );
+        assert!(hub.find_job_owner_bst(id).is_some());
+        assert!(hub.next_trigger_time_ms().unwrap() >= released_at_ms + 5_000);
+        assert_eq!(hub.reserve_ready_jobs().len(), 0);
+    }
+
+    #[test]
+    fn release_refuses_delays_past_the_end_of_the_clock() {
+        let (mut hub, _) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let j = Job::new_auto_id(MOCK_START_MS, "job");
+        let id = j.get_metadata().get_id();
+        hub.add_job(j).unwrap();
+        hub.reserve_ready_jobs();
+
+        assert_eq!(
+            hub.release(id, u64::MAX),
+            Err(YaadError::Clock {
+                now_ms: MOCK_START_MS,
+                delay_ms: u64::MAX
+            })
+        );
+        assert_eq!(hub.reserved_job_len(), 1, "The job stays reserved");
+        assert_eq!(hub.counters().internal_errors(), 0, "Refusals aren't bugs");
+    }
+
+    #[test]
+    fn release_can_set_a_priority() {
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let id = add_at_offset(&mut hub, 0);
+        assert_eq!(hub.reserve_ready_jobs()[0].releases(), 0);
+
+        hub.release_with_priority(id, 7, 1_000).unwrap();
+        let (jm, _) = hub.peek_job(id).unwrap();
+        assert_eq!(jm.trigger_at_ms(), MOCK_START_MS + 1_000);
+        assert_eq!(hub.reserve_ready_jobs().len(), 0, "Released job is delayed");
+
+        clock.advance(1_000);
+        let job = hub.reserve_ready_jobs().pop().unwrap();
+        assert_eq!(job.priority(), 7);
+        assert_eq!(job.releases(), 1);
+
+        hub.release_with_priority(id, 3, 0).unwrap();
+        assert_eq!(
+            hub.find_job_owner_bst(id),
+            Some(hub.past_spoke.get_bounds()),
+            "Released without a delay, the job is ready right away"
+        );
+        let job = hub.reserve_ready_jobs().pop().unwrap();
+        assert_eq!((job.priority(), job.releases()), (3, 2));
+    }
+
+    #[test]
+    fn buries_jobs_timing_out_too_often() {
+        let clock = Arc::new(MockClock::new(MOCK_START_MS));
+        let config = HubConfig::new(TEST_SPOKE_DURATION_MS).with_max_timeouts(2);
+        let mut hub = Hub::from_config_with_clock(config, clock.clone());
+        let job = Job::new_auto_id(MOCK_START_MS, "poison").with_ttr_ms(1_000);
+        let id = job.get_metadata().get_id();
+        hub.add_job(job).unwrap();
+
+        for timeouts in 0..2 {
+            let job = hub.reserve_ready_jobs().pop().unwrap();
+            assert_eq!((job.reserves(), job.timeouts()), (timeouts + 1, timeouts));
+            clock.advance(999);
+            assert_eq!(hub.expire_reservations(), 0, "TTR hasn't run out yet");
+            clock.advance(1);
+            assert_eq!(hub.expire_reservations(), 1);
+            let view = hub.get_job(id).unwrap();
+            assert_eq!(view.state, JobState::Ready);
+            assert_eq!((view.reserves, view.timeouts), (timeouts + 1, timeouts + 1));
+        }
+
+        hub.reserve_ready_jobs();
+        clock.advance(1_000);
+        assert_eq!(hub.expire_reservations(), 0, "Job is buried, not re-queued");
+        let view = hub.get_job(id).unwrap();
+        assert_eq!(view.state, JobState::Buried);
+        assert_eq!((view.reserves, view.releases, view.timeouts), (3, 0, 3));
+        assert_eq!(hub.counters().jobs_auto_buried(), 1);
+        assert!(hub.reserve_ready_jobs().is_empty());
+
+        assert_eq!(hub.kick(1), 1);
+        let job = hub.reserve_ready_jobs().pop().unwrap();
+        assert_eq!(job.reserves(), 4, "Kicking the job keeps its counts");
+        assert_eq!(hub.release_reservations(), 1);
+        assert_eq!(hub.get_job(id).unwrap().timeouts, 3, "Releasing isn't a timeout");
+    }
+
+    #[test]
+    fn snapshots_and_restores_jobs() {
+        let now_ms = times::current_time_ms();
+        let mut hub = Hub::new(TEST_SPOKE_DURATION_MS);
+        let past = Job::new_auto_id(now_ms - 300, "past");
+        let future = Job::new_auto_id(now_ms + 10_000, "future").with_ttr_ms(7_000);
+        let reserved = Job::new_auto_id(now_ms - 200, "reserved");
+        let cancelled = Job::new_auto_id(now_ms + 20_000, "cancelled");
+        let buried = Job::new_auto_id(now_ms - 100, "buried");
+        let (past_id, future_id, reserved_id, cancelled_id, buried_id) = (
+            past.get_metadata().get_id(),
+            future.get_metadata().get_id(),
+            reserved.get_metadata().get_id(),
+            cancelled.get_metadata().get_id(),
+            buried.get_metadata().get_id(),
+        );
+        hub.add_job(reserved).unwrap();
+        hub.add_job(buried).unwrap();
+        hub.reserve_ready_jobs();
+        assert!(hub.bury(buried_id, 3));
+        hub.add_job(past).unwrap();
+        hub.add_job(future).unwrap();
+        hub.add_job(cancelled).unwrap();
+        hub.cancel_job(cancelled_id);
+
+        let mut buf = vec![];
+        assert_eq!(hub.snapshot(&mut buf).unwrap(), 4);
+        let mut restored = Hub::restore(&buf[..], TEST_SPOKE_DURATION_MS).unwrap();
+
+        assert_eq!(restored.reserved_job_len(), 0, "Reservations aren't restored");
+        assert_eq!(restored.buried_job_len(), 1, "Buried jobs stay buried");
+        assert_eq!(restored.get_job(buried_id).unwrap().state, JobState::Buried);
+        assert!(restored.find_job_owner_bst(future_id).is_some());
+        assert!(restored.find_job_owner_bst(cancelled_id).is_none());
+        let restored_future = restored
+            .jobs()
+            .into_iter()
+            .find(|j| j.get_metadata().get_id() == future_id)
+            .unwrap();
+        assert_eq!(restored_future.trigger_at_ms(), now_ms + 10_000);
+        assert_eq!(restored_future.ttr_ms(), 7_000);
+
+        let mut walked: Vec<Uuid> = restored
+            .walk_jobs()
+            .iter()
+            .map(|j| j.get_metadata().get_id())
+            .collect();
+        walked.sort();
+        let mut expected = vec![past_id, reserved_id];
+        expected.sort();
+        assert_eq!(walked, expected, "Overdue jobs are ready on the first walk");
+
+        assert!(restored.kick_job(buried_id).is_ok());
+        let kicked = restored.walk_jobs();
+        assert_eq!(kicked.len(), 1);
+        assert_eq!(kicked[0].priority(), 3, "Buried with the priority of the bury");
+    }
+
+    #[test]
+    fn restores_with_the_config_given() {
+        let now_ms = times::current_time_ms();
+        let far_ms = now_ms + DEFAULT_MAX_FUTURE_MS + 60_000;
+        let config = HubConfig::new(TEST_SPOKE_DURATION_MS)
+            .with_max_future_ms(2 * DEFAULT_MAX_FUTURE_MS)
+            .with_max_pending_jobs(1);
+        let mut hub = Hub::from_config(config);
+        hub.add_job(Job::new_auto_id(far_ms, "far")).unwrap();
+        let mut buf = vec![];
+        assert_eq!(hub.snapshot(&mut buf).unwrap(), 1);
+
+        let e = Hub::restore(&buf[..], TEST_SPOKE_DURATION_MS).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData, "Past the default horizon");
+        let mut restored = Hub::restore_with_config(&buf[..], config).unwrap();
+        assert_eq!(restored.pending_job_count(), 1);
+        let refused = restored.add_job(Job::new_auto_id(now_ms + 1_000, "over the cap"));
+        assert_eq!(refused, Err(YaadError::Capacity { max_pending_jobs: 1 }));
+    }
+
+    #[test]
+    fn can_find_past_jobs() {
+        let start_time_ms = times::current_time_ms();
+        let mut hub = Hub::new(TEST_SPOKE_DURATION_MS);
+        let j = Job::new_auto_id(start_time_ms - 300, "one spoke");
+
+        let id = j.get_metadata().get_id();
+
+        hub.add_job(j).unwrap();
+        assert_eq!(hub.bst_spoke_map.len(), 0);
+        assert!(hub.find_job_owner_bst(id).is_some());
+        // Is Idempotent
+        assert!(hub.find_job_owner_bst(id).is_some());
+    }
+
+    /// Adds `total` jobs to a spoke starting at `start_ms` and cancels `cancelled` of them
+    fn add_spoke_with_stale(hub: &mut Hub, start_ms: u64, total: u64, cancelled: usize) {
+        let mut s = Spoke::new(start_ms, TEST_SPOKE_DURATION_MS);
+        let ids: Vec<Uuid> = (0..total)
+            .map(|i| {
+                let j = Job::new_auto_id(start_ms + i % TEST_SPOKE_DURATION_MS, "stale");
+                let id = j.get_metadata().get_id();
+                s.add_job(&j).unwrap();
+                id
+            }).collect();
+        ids.iter().take(cancelled).for_each(|id| {
+            s.cancel_job(*id);
+        });
+        hub.add_spoke(s);
+    }
+
+    #[test]
+    fn reports_stale_stats() {
+        let start_ms = times::current_time_ms() + 10_000;
+        let mut hub = Hub::new(TEST_SPOKE_DURATION_MS);
+        add_spoke_with_stale(&mut hub, start_ms, 4, 1);
+        add_spoke_with_stale(&mut hub, start_ms + 100, 4, 3);
+        add_spoke_with_stale(&mut hub, start_ms + 200, 4, 0);
+
+        let stats = hub.stale_stats();
+        assert_eq!(stats.total_stale_entries, 4);
+        assert_eq!(stats.worst_ratio, 0.75);
+        assert_eq!(
+            stats.worst_bounds.map(|b| b.get_start_time_ms()),
+            Some(start_ms + 100)
+        );
+        assert_eq!(
+            stats.worst_spoke_id,
+            Some(hub.bst_spoke_map.values().nth(1).unwrap().get_id())
+        );
+        assert_eq!(stats.spokes_above_threshold, 1);
+
+        hub.set_stale_compaction_ratio(0.2);
+        assert_eq!(hub.stale_stats().spokes_above_threshold, 2);
+    }
+
+    // Far enough in the future that spokes accept jobs, fixed so renders can be snapshotted
+    const LAYOUT_START_MS: u64 = 4_000_000_000_000;
+
+    fn layout_hub() -> Hub {
+        let mut hub = Hub::new(TEST_SPOKE_DURATION_MS);
+        add_spoke_with_stale(&mut hub, LAYOUT_START_MS, 2, 1);
+        add_spoke_with_stale(&mut hub, LAYOUT_START_MS + 10, 1, 0);
+        add_spoke_with_stale(&mut hub, LAYOUT_START_MS + 20, 3, 0);
+        hub
+    }
+
+    #[test]
+    fn renders_mermaid_layout() {
+        let hub = layout_hub();
+        assert_eq!(
+            hub.render_layout_at(LayoutFormat::Mermaid, LAYOUT_START_MS + 15),
+            "gantt
+    title yaad hub layout
+    dateFormat x
+    section past
+    past 9246c76d 0 live 0 stale :crit, past, 4000000000015, 4000000000016
+    section spokes
+    s0 e2455ffc 1 live 1 stale :done, s0, 4000000000000, 4000000000010
+    s1 3f3debd7 1 live 0 stale :active, s1, 4000000000010, 4000000000020
+    s2 dd95293b 3 live 0 stale :s2, 4000000000020, 4000000000030
+"
+        );
     }
 
-    /// Walk returns a Vector of Spokes that should be consumed next
-    /// Calls to this method can return empty vectors if no spokes are ready yet.
-    pub fn walk(&mut self) -> Vec<Job> {
-        let mut ready_jobs: Vec<Job> = vec![];
-        self.bst_spoke_map
-            .values_mut()
-            .take_while(|s| s.is_ready())
-            .for_each(|s| ready_jobs.append(s.walk().as_mut()));
-        self.prune_spokes();
-        ready_jobs
+    #[test]
+    fn renders_dot_layout() {
+        let hub = layout_hub();
+        assert_eq!(
+            hub.render_layout_at(LayoutFormat::Dot, LAYOUT_START_MS + 15),
+            r#"digraph hub {
+    rankdir=LR;
+    node [shape=box, style=filled];
+    past [label="past 9246c76d\n0 live / 0 stale", fillcolor=orange];
+    s0 [label="e2455ffc\n2096-10-02 07:06:40 UTC\n4000000000000..4000000000010\n1 live / 1 stale", fillcolor=gray];
+    s1 [label="3f3debd7\n2096-10-02 07:06:40 UTC\n4000000000010..4000000000020\n1 live / 0 stale", fillcolor=palegreen];
+    s2 [label="dd95293b\n2096-10-02 07:06:40 UTC\n4000000000020..4000000000030\n3 live / 0 stale", fillcolor=lightblue];
+    past -> s0 -> s1 -> s2;
+}
+"#
+        );
     }
 
-    pub fn prune_spokes(&mut self) -> u32 {
-        let to_remove: Vec<BoundingSpokeTime> = self
-            .bst_spoke_map
-            .values()
-            .take_while(|s| s.is_expired() && s.pending_job_len() == 0)
-            .map(|s| s.get_bounds())
-            .collect();
-        let mut prune_count = 0;
-        for k in to_remove {
-            if self.bst_spoke_map.remove(&k).is_some() {
-                prune_count += 1;
-            }
+    #[test]
+    fn collapses_empty_spokes_in_large_layouts() {
+        let mut hub = Hub::new(TEST_SPOKE_DURATION_MS);
+        for i in 0..10_000 {
+            hub.add_spoke(Spoke::new(
+                LAYOUT_START_MS + i * TEST_SPOKE_DURATION_MS,
+                TEST_SPOKE_DURATION_MS,
+            ));
         }
-        return prune_count;
-    }
+        add_spoke_with_stale(&mut hub, LAYOUT_START_MS + 200_000, 1, 0);
 
-    /// Add a new job to the Hub - the hub will find or create the right spoke for this job
-    pub fn add_job(&mut self, job: Job) -> &mut Hub {
-        // If None, past spoke accepted the job, else find the right spoke for it
-        println!("Adding job to hub. Job trigger: {}", job.trigger_at_ms());
-        match self.maybe_add_job_to_past(job) {
-            Some(j) => {
-                if self.add_job_to_spokes(j).is_some() {
-                    panic!("Hub should always accept a job")
-                }
-                return self;
-            }
-            None => return self,
+        for format in [LayoutFormat::Mermaid, LayoutFormat::Dot] {
+            let out = hub.render_layout_at(format, LAYOUT_START_MS);
+            assert!(out.len() < 2_000, "Collapsed layout is small: {}", out);
+            assert!(out.contains("10000 empty spokes"), "Empty run collapsed: {}", out);
         }
     }
 
-    /// Adds a job to the correct spoke based on the Job's trigger time
-    fn add_job_to_spokes(&mut self, job: Job) -> Option<Job> {
-        let job_bst = Hub::job_bounding_spoke_time(&job, self.spoke_duration_ms);
-        match {
-            // Try to skip as many bounds as possible : these bounds are before this job's bound
-            let next_spoke = self
-                .bst_spoke_map
-                .iter_mut()
-                .skip_while(|s| s.0 < &job_bst)
-                .next();
-            // This next spoke is a candidate that might accept this job
-            match next_spoke {
-                Some(s) => {
-                    // If spoke exists, try to give it the job
-                    match s.1.add_job(job) {
-                        // Spoke rejected the job and returned it to us, return to top level match
-                        Some(j) => Some(j),
-                        // Spoke accepted the job, yay!!
-                        None => None,
-                    }
-                }
-                // No spoke found, that we either didn't have a spoke or this job is
-                // too far in the future
-                None => Some(job),
-            }
-        } {
-            // If we weren't able to assign this job yet, create a spoke that might accept it
-            Some(j) => {
-                println!("Adding a new spoke to accomodate job: {:?}", job_bst);
-                self.add_spoke(Spoke::new_from_bounds(job_bst));
-                // Try adding job again, recursively
-                self.add_job_to_spokes(j)
-            }
-            None => None,
-        }
+    #[test]
+    fn rendering_does_not_perturb_walk() {
+        let (mut hub, clock) = mock_hub(TEST_SPOKE_DURATION_MS);
+        let start_ms = clock.now_ms();
+        hub.add_job(Job::new_auto_id(start_ms - 100, "past"))
+            .unwrap();
+        hub.add_job(Job::new_auto_id(start_ms + 2, "soon"))
+            .unwrap();
+        hub.render_layout(LayoutFormat::Mermaid);
+        hub.render_layout(LayoutFormat::Dot);
+
+        clock.advance(TEST_SPOKE_DURATION_MS + 5);
+        assert_eq!(hub.walk_jobs().len(), 2);
     }
 
-    /// Attempts to add a job to the past spoke if the job is in the past and returns None.
-    /// Otherwise, returns Some(job)
-    fn maybe_add_job_to_past(&mut self, job: Job) -> Option<Job> {
-        // If job is old, add to the past spoke
-        let current_time_ms = times::current_time_ms();
-        if job.trigger_at_ms() < current_time_ms {
-            // This job should be handed to the past spoke
-            println!(
-                "This job: {} is older then current time: {}",
-                job.trigger_at_ms(),
-                current_time_ms
-            );
-            match self.past_spoke.add_job(job) {
-                Some(_) => panic!("Past spoke should always accept a job"),
-                None => return None,
-            }
-        }
-        // else, hand it back
-        return Option::from(job);
+    #[test]
+    fn spoke_ids_are_stable_per_namespace() {
+        let trigger_ms = times::current_time_ms() + 10_000;
+        let spoke_id = |hub: &mut Hub| {
+            hub.add_job(Job::new_auto_id(trigger_ms, "job")).unwrap();
+            hub.bst_spoke_map.values().next().unwrap().get_id()
+        };
+
+        let namespace = Uuid::new_v4();
+        let first = spoke_id(&mut Hub::new_in_namespace(TEST_SPOKE_DURATION_MS, namespace));
+        let restarted = spoke_id(&mut Hub::new_in_namespace(TEST_SPOKE_DURATION_MS, namespace));
+        assert_eq!(first, restarted, "Same namespace and bounds give the same spoke id");
+
+        let other = spoke_id(&mut Hub::new_in_namespace(TEST_SPOKE_DURATION_MS, Uuid::new_v4()));
+        assert_ne!(first, other, "Hubs in different namespaces don't share spoke ids");
     }
 
-    /// Returns the span of a hypothetical Spoke that should own this job.
-    fn job_bounding_spoke_time(job: &Job, spoke_duration_ms: u64) -> BoundingSpokeTime {
-        let spoke_start = times::floor_ms_from_epoch(job.trigger_at_ms());
-        return BoundingSpokeTime::new(spoke_start, spoke_start + spoke_duration_ms);
+    #[test]
+    fn compacts_worst_spokes_first_within_budget() {
+        let start_ms = times::current_time_ms() + 10_000;
+        let mut hub = Hub::new(TEST_SPOKE_DURATION_MS);
+        hub.set_stale_compaction_ratio(0.2);
+        add_spoke_with_stale(&mut hub, start_ms, 4, 1);
+        add_spoke_with_stale(&mut hub, start_ms + 100, 4, 3);
+        add_spoke_with_stale(&mut hub, start_ms + 200, 10, 1);
+
+        assert_eq!(hub.compact_stale_spokes(1), 3, "Worst spoke is compacted first");
+        let stats = hub.stale_stats();
+        assert_eq!(stats.total_stale_entries, 2);
+        assert_eq!(stats.worst_ratio, 0.25);
+
+        assert_eq!(hub.compact_stale_spokes(5), 1, "Spokes below threshold are left alone");
+        assert_eq!(hub.stale_stats().total_stale_entries, 1);
+        assert_eq!(hub.stale_stats().spokes_above_threshold, 0);
     }
 
-    /// Returns a vec of all jobs that are ready to be consumed
-    pub fn walk_jobs(&mut self) -> Vec<Job> {
-        let mut jobs = self.past_spoke.walk();
-        jobs.append(self.walk().as_mut());
-        return jobs;
+    /// Adds a job triggering `offset_ms` after the mock clock's start, its body naming the offset
+    fn add_at_offset(hub: &mut Hub, offset_ms: u64) -> Uuid {
+        let job = Job::new_auto_id(MOCK_START_MS + offset_ms, format!("at {}", offset_ms));
+        let id = job.get_metadata().get_id();
+        hub.add_job(job).unwrap();
+        id
     }
-}
 
-#[cfg(test)]
-mod tests {
-    const TEST_SPOKE_DURATION_MS: u64 = 10;
+    fn bodies(jobs: &[Job]) -> Vec<String> {
+        jobs.iter()
+            .map(|j| j.get_body().to_string_lossy().into_owned())
+            .collect()
+    }
 
-    use super::*;
-    use std::thread;
-    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    /// Start times of the hub's spokes in map order, as offsets from the mock clock's start
+    fn spoke_starts(hub: &Hub) -> Vec<u64> {
+        hub.bst_spoke_map
+            .keys()
+            .map(|b| b.get_start_time_ms() - MOCK_START_MS)
+            .collect()
+    }
 
     #[test]
-    fn can_create_hub() {
-        let h: Hub = Hub::new(TEST_SPOKE_DURATION_MS);
-        assert_eq!(h.bst_spoke_map.len(), 0)
+    fn clock_stepping_back_prunes_nothing_early_and_fires_nothing_twice() {
+        let (mut hub, clock) = mock_hub(100);
+        for &offset in [120, 250, 460].iter() {
+            add_at_offset(&mut hub, offset);
+        }
+        clock.advance(260);
+        assert_eq!(bodies(&hub.walk_jobs()), ["at 120", "at 250"]);
+        assert_eq!(spoke_starts(&hub), vec![200, 400]);
+
+        clock.set(MOCK_START_MS + 150);
+        assert_eq!(hub.clock().now_ms(), MOCK_START_MS + 260, "The hub's time holds still");
+        assert!(hub.walk_jobs().is_empty(), "Walked jobs don't fire again");
+        assert_eq!(hub.prune_spokes(), 0);
+        assert_eq!(spoke_starts(&hub), vec![200, 400], "No spoke is pruned or revived");
+
+        add_at_offset(&mut hub, 180);
+        assert_eq!(spoke_starts(&hub), vec![200, 400]);
+        assert_eq!(bodies(&hub.walk_jobs()), ["at 180"], "Due by the hub's time, not the clock's");
+
+        clock.set(MOCK_START_MS + 301);
+        assert_eq!(hub.prune_spokes(), 1, "Expires once the clock passes its end");
+        assert_eq!(spoke_starts(&hub), vec![400]);
     }
 
     #[test]
-    fn can_add_spokes() {
-        let mut h = Hub::new(TEST_SPOKE_DURATION_MS);
-        h.add_spoke(Spoke::new(times::current_time_ms(), 10_000));
-        assert_eq!(h.bst_spoke_map.len(), 1)
+    fn walks_many_spokes_in_time_order() {
+        let (mut hub, clock) = mock_hub(100);
+        // Added out of order, so the map's order can't come from insertion order
+        for &offset in [750, 120, 460, 1_310, 230, 990].iter() {
+            add_at_offset(&mut hub, offset);
+        }
+        assert_eq!(spoke_starts(&hub), vec![100, 200, 400, 700, 900, 1_300]);
+
+        clock.advance(150);
+        assert_eq!(bodies(&hub.walk_jobs()), ["at 120"]);
+        clock.advance(400);
+        assert_eq!(bodies(&hub.walk_jobs()), ["at 230", "at 460"]);
+        clock.advance(450);
+        assert_eq!(bodies(&hub.walk_jobs()), ["at 750", "at 990"]);
+        assert_eq!(
+            spoke_starts(&hub),
+            vec![900, 1_300],
+            "Walked spokes are pruned once expired, spokes yet to come are left alone"
+        );
+        clock.advance(1_000);
+        assert_eq!(bodies(&hub.walk_jobs()), ["at 1310"]);
     }
 
     #[test]
-    fn walk_empty_hub() {
-        let mut h = Hub::new(TEST_SPOKE_DURATION_MS);
-        let res = h.walk();
-        assert_eq!(h.bst_spoke_map.len(), 0);
-        assert_eq!(res.len(), 0, "Empty hub walk should return no spokes")
+    fn drain_ready_leaves_jobs_it_does_not_get_to() {
+        let (mut hub, clock) = mock_hub(100);
+        assert!(hub.drain_ready().next().is_none(), "Empty hub drains nothing");
+        assert_eq!(hub.counters().jobs_walked(), 0);
+
+        for &offset in [120, 230, 460, 750].iter() {
+            add_at_offset(&mut hub, offset);
+        }
+        clock.advance(500);
+        // Due by now, so it lands in the past spoke and is drained among the spoke jobs
+        add_at_offset(&mut hub, 300);
+        let first = bodies(&hub.drain_ready().take(2).collect::<Vec<_>>());
+        assert_eq!(first, ["at 120", "at 230"]);
+        assert_eq!(hub.counters().jobs_walked(), 2, "Counted when the iterator is dropped");
+        assert_eq!(hub.pending_job_count(), 3, "The rest stay scheduled");
+
+        assert_eq!(bodies(&hub.drain_ready().collect::<Vec<_>>()), ["at 300", "at 460"]);
+        assert!(hub.drain_ready().next().is_none(), "Each job is drained once");
+        assert_eq!(hub.counters().late_deliveries(), 1);
+        assert_eq!(spoke_starts(&hub), vec![400, 700], "Expired spokes are pruned");
+
+        clock.advance(300);
+        assert_eq!(bodies(&hub.walk_jobs()), ["at 750"]);
     }
 
     #[test]
-    fn can_add_job_to_past() {
-        let mut h = Hub::new(TEST_SPOKE_DURATION_MS);
-        h.add_job(Job::new_auto_id(
-            times::current_time_ms() - 10_000,
-            "I am old",
-        ));
+    fn adds_jobs_to_the_spoke_covering_them() {
+        let (mut hub, clock) = mock_hub(100);
+        for &offset in [120, 460, 750, 990, 1_310].iter() {
+            add_at_offset(&mut hub, offset);
+        }
+        let id = add_at_offset(&mut hub, 470);
+        assert_eq!(spoke_starts(&hub), vec![100, 400, 700, 900, 1_300]);
         assert_eq!(
-            h.past_spoke.pending_job_len(),
-            1,
-            "Hub should put jobs triggering in the past into it's special, past spoke"
+            hub.find_job_owner_bst(id),
+            Some(BoundingSpokeTime::new(MOCK_START_MS + 400, MOCK_START_MS + 500))
         );
-    }
+        assert_eq!(hub.bst_spoke_map.values().nth(1).unwrap().pending_job_len(), 2);
 
-    #[test]
-    fn walk_hub_with_spokes() {
-        // |
-        // |     spoke1  walk1([s1,])            walk2([])         spoke2   walk3([s2,])
-        // | s1<---------20ms--------->s1+10 .......~10ms....... s2<--------25ms--------->s2+50
-        // |---------------------------------------------------------------------------------->time
-        let mut h = Hub::new(TEST_SPOKE_DURATION_MS);
-        let first_spoke_start = times::current_time_ms();
-        // Create a spoke that starts now and add it to the hub
-        let mut s1 = Spoke::new(first_spoke_start, 10);
-        s1.add_job(Job::new_auto_id(first_spoke_start + 2, "job"));
-        h.add_spoke(s1);
+        let id = add_at_offset(&mut hub, 1_150);
+        assert_eq!(
+            spoke_starts(&hub),
+            vec![100, 400, 700, 900, 1_100, 1_300],
+            "A missing spoke is created in its place"
+        );
+        assert_eq!(
+            hub.find_job_owner_bst(id),
+            Some(BoundingSpokeTime::new(MOCK_START_MS + 1_100, MOCK_START_MS + 1_200))
+        );
 
+        clock.advance(1_200);
         assert_eq!(
-            h.bst_spoke_map.len(),
-            1,
-            "Should list ownership of the newly added spoke"
+            bodies(&hub.walk_jobs()),
+            ["at 120", "at 460", "at 470", "at 750", "at 990", "at 1150"]
         );
+    }
 
-        // Wait for at least first spoke to be ready
-        thread::park_timeout(Duration::from_millis(15));
+    #[test]
+    fn prunes_only_expired_empty_spokes() {
+        let (mut hub, clock) = mock_hub(100);
+        for &offset in [120, 230, 250, 750, 1_310].iter() {
+            add_at_offset(&mut hub, offset);
+        }
+        let cancelled = add_at_offset(&mut hub, 460);
+        assert!(hub.cancel_job(cancelled));
+        assert_eq!(spoke_starts(&hub), vec![100, 200, 400, 700, 1_300]);
+
+        clock.advance(800);
+        assert_eq!(hub.prune_spokes(), 1, "Expired spokes holding jobs are kept");
+        assert_eq!(spoke_starts(&hub), vec![100, 200, 700, 1_300]);
 
+        assert_eq!(bodies(&hub.walk_jobs_limit(2)), ["at 120", "at 230"]);
         assert_eq!(
-            h.walk_jobs().len(),
-            1,
-            "Should have 1 job ready at: {}",
-            times::current_time_ms()
+            spoke_starts(&hub),
+            vec![200, 700, 1_300],
+            "A walked spoke is pruned while the expired one after it still holds a job"
         );
+
+        assert_eq!(bodies(&hub.walk_jobs()), ["at 250", "at 750"]);
         assert_eq!(
-            h.bst_spoke_map.len(),
-            0,
-            "Should have pruned spoke after consuming it"
+            spoke_starts(&hub),
+            vec![700, 1_300],
+            "The spoke ending at the current time hasn't expired yet"
         );
+        clock.advance(1);
+        assert_eq!(hub.prune_spokes(), 1);
+        assert_eq!(spoke_starts(&hub), vec![1_300]);
+    }
 
-        // Create another spoke that starts 10ms after the first spoke's starting time
-        let second_spoke_start = times::current_time_ms() + 10;
-        let mut s2 = Spoke::new(second_spoke_start, 25);
-        s2.add_job(Job::new_auto_id(second_spoke_start + 17, "job"));
-        h.add_spoke(s2);
-
-        assert_eq!(h.bst_spoke_map.len(), 1, "Should have 1 spoke");
+    #[test]
+    fn times_how_late_jobs_are_walked() {
+        let (mut hub, clock) = mock_hub(100);
+        let lags = |jobs: &[Job], now_ms: u64| -> Vec<u64> {
+            jobs.iter().map(|j| j.delivery_lag_ms(now_ms)).collect()
+        };
+        add_at_offset(&mut hub, 120);
+        add_at_offset(&mut hub, 150);
+        add_at_offset(&mut hub, 450);
+        let past = Job::new_auto_id(MOCK_START_MS - 30, "due already");
+        hub.add_job(past).unwrap();
 
-        // Wait for at least second spoke to be ready
-        thread::park_timeout(Duration::from_millis(30));
-        assert_eq!(h.walk_jobs().len(), 1, "Hub should return jobs");
+        clock.advance(160);
+        let now_ms = clock.now_ms();
+        assert_eq!(lags(&hub.walk_jobs(), now_ms), vec![190, 40, 10]);
+        let counters = Arc::clone(hub.counters());
+        assert_eq!(counters.late_deliveries(), 1, "Jobs due when added are late");
+        assert_eq!(counters.late_delivery_lag_ms(), 190);
+        assert_eq!(counters.on_time_deliveries(), 2);
+        assert_eq!(counters.on_time_delivery_lag_ms(), 50);
 
+        clock.advance(360);
+        assert_eq!(lags(&hub.walk_jobs(), clock.now_ms()), vec![70]);
         assert_eq!(
-            h.walk_jobs().len(),
-            0,
-            "Hub should not return a job after all were consumed"
+            counters.late_deliveries(),
+            2,
+            "Jobs reclaimed from an expired spoke are late"
         );
+        assert_eq!(counters.late_delivery_lag_ms(), 260);
 
-        thread::park_timeout(Duration::from_millis(10));
-        h.prune_spokes();
-        assert_eq!(h.bst_spoke_map.len(), 0);
+        add_at_offset(&mut hub, 530);
+        add_at_offset(&mut hub, 600);
+        clock.advance(90);
+        add_at_offset(&mut hub, 605);
+        let now_ms = clock.now_ms();
+        assert_eq!(lags(&hub.walk_jobs_limit(5), now_ms), vec![80, 10, 5]);
+        assert_eq!(counters.on_time_deliveries(), 4);
+        assert_eq!(counters.on_time_delivery_lag_ms(), 140);
+        assert_eq!(counters.late_deliveries(), 3);
+        assert_eq!(counters.late_delivery_lag_ms(), 265);
+        assert_eq!(counters.max_delivery_lag_ms(), 190);
+        assert_eq!(counters.jobs_walked(), 7);
     }
 
     #[test]
-    fn prune_expired_spokes() {
-        // |
-        // |     spoke1                           spoke2         walk1([s2,])
-        // | s1<---------5ms--------->s1+5 .2ms. s2(s1+7)<--------5ms--------->s2+50
-        // |---------------------------------------------------------------------------------->time
-        let mut h = Hub::new(TEST_SPOKE_DURATION_MS);
+    fn never_walks_jobs_early() {
+        const JOBS: usize = 1_000;
+        let (mut hub, clock) = mock_hub(100);
+        let mut rng = ChaChaRng::from_seed(&[802]);
+        let (mut added, mut walked) = (0, 0);
+        for _ in 0..100_000 {
+            if walked == JOBS {
+                break;
+            }
+            // Jobs keep coming while the hub is walked, some due already and the rest anywhere
+            // in the next few spokes' windows
+            for _ in 0..rng.gen_range(0, 20) {
+                if added < JOBS {
+                    let trigger_at_ms = clock.now_ms() - 200 + rng.gen_range(0, 1_200);
+                    hub.add_job(Job::new_auto_id(trigger_at_ms, "job")).unwrap();
+                    added += 1;
+                }
+            }
+            clock.advance(rng.gen_range(0, 50));
+            if rng.gen_weighted_bool(10) {
+                hub.tick();
+            }
+            let jobs = match rng.gen_range(0, 3) {
+                0 => hub.walk_strictly(Hub::walk_unchecked),
+                1 => hub.walk_strictly(Hub::walk_jobs_unchecked),
+                _ => {
+                    let max = rng.gen_range(1, 10);
+                    hub.walk_strictly(|h| h.walk_jobs_limit_unchecked(max))
+                }
+            };
+            walked += jobs.unwrap_or_else(|e| panic!("{}", e)).len();
+        }
+        assert_eq!(walked, JOBS, "Every job is walked eventually");
+    }
 
-        let first_spoke_start = times::current_time_ms();
-        h.add_spoke(Spoke::new(first_spoke_start, TEST_SPOKE_DURATION_MS));
-        assert_eq!(h.bst_spoke_map.len(), 1, "Can add a spoke to a hub");
+    #[test]
+    fn early_jobs_trip_the_walk_check() {
+        let (hub, _) = mock_hub(100);
+        let early = Job::new_auto_id(MOCK_START_MS + 5, "early");
+        let err = hub.check_walked(std::slice::from_ref(&early)).unwrap_err();
+        assert_eq!(err.id, early.get_metadata().get_id());
+        assert_eq!(err.walked_at_ms, MOCK_START_MS);
+        assert_eq!(
+            err.to_string(),
+            format!("Job {} triggering at {} was walked 5ms early", err.id, err.trigger_at_ms)
+        );
 
-        let second_spoke_start = first_spoke_start + TEST_SPOKE_DURATION_MS + 2;
-        h.add_spoke(Spoke::new(second_spoke_start, 10));
-        assert_eq!(h.bst_spoke_map.len(), 2, "Can add a spoke to a hub");
+        let clock = Arc::new(MockClock::new(MOCK_START_MS));
+        let config = HubConfig::new(100).with_early_walk_tolerance_ms(5);
+        let tolerant = Hub::from_config_with_clock(config, clock);
+        assert_eq!(tolerant.check_walked(&[early]), Ok(()));
+        let later = Job::new_auto_id(MOCK_START_MS + 6, "later");
+        assert!(tolerant.check_walked(&[later]).is_err());
+    }
 
-        thread::park_timeout(Duration::from_millis(TEST_SPOKE_DURATION_MS * 2 + 5));
-        assert_eq!(h.bst_spoke_map.len(), 2);
-        assert_eq!(h.prune_spokes(), 2, "Expired spokes are pruned");
+    /// Keeps every set of gauges a hub reports, and counts the jobs it reports
+    #[derive(Debug, Default)]
+    struct RecordingMetrics {
+        recorded: Mutex<Vec<HubGauges>>,
+        added: Mutex<Vec<usize>>,
+        lags_ms: Mutex<Vec<u64>>,
+        cancelled: Mutex<usize>,
+    }
+
+    impl HubMetrics for RecordingMetrics {
+        fn record_gauges(&self, gauges: &HubGauges) {
+            self.recorded.lock().unwrap().push(*gauges);
+        }
+
+        fn record_jobs_added(&self, jobs: usize) {
+            self.added.lock().unwrap().push(jobs);
+        }
+
+        fn record_delivery(&self, lag_ms: u64) {
+            self.lags_ms.lock().unwrap().push(lag_ms);
+        }
+
+        fn record_job_cancelled(&self) {
+            *self.cancelled.lock().unwrap() += 1;
+        }
     }
 
-    /// This test checks that we can calculate if a Spoke should own a job - a spoke should own a
-    /// job if that job's trigger time lies within the Spoke's duration.
     #[test]
-    fn test_job_bounding_spoke_times() {
-        // Find Duration since UNIX_EPOCH
-        let dur_from_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        // Find the ms since EPOCH, floored to the nearest decimal
-        let ms_from_epoch = times::floor_ms_from_epoch(times::duration_to_ms(dur_from_epoch));
+    fn reports_jobs_added_walked_and_cancelled() {
+        let (mut hub, clock) = mock_hub(100);
+        let metrics = Arc::new(RecordingMetrics::default());
+        hub.set_metrics(metrics.clone());
+        add_at_offset(&mut hub, 50);
+        let cancelled = add_at_offset(&mut hub, 70);
+        hub.add_job(Job::new_auto_id(MOCK_START_MS - 30, "late")).unwrap();
+        let batch = vec![
+            Job::new_auto_id(MOCK_START_MS + 150, "batch"),
+            Job::new_auto_id(MOCK_START_MS + 160, "batch"),
+        ];
+        assert!(hub.add_jobs(batch).is_empty());
+        assert!(hub.add_jobs(vec![]).is_empty());
+        assert_eq!(*metrics.added.lock().unwrap(), vec![1, 1, 1, 2], "Empty batches add nothing");
 
-        // ms from epoch down to closest 10 and then add 10ms
-        let ms_from_epoch = ms_from_epoch + 10;
-        let job_trigger_at_ms = ms_from_epoch + 7;
-        let j = Job::new_auto_id(job_trigger_at_ms, "foo");
+        assert!(hub.cancel_job(cancelled));
+        assert!(!hub.cancel_job(cancelled), "Jobs are only cancelled once");
+        assert_eq!(*metrics.cancelled.lock().unwrap(), 1);
 
-        // This job's bounds should be: ms_from_epoch -> ms_from_epoch + 10
-        let bst = self::Hub::job_bounding_spoke_time(&j, TEST_SPOKE_DURATION_MS);
-        assert!(bst.get_start_time_ms() <= job_trigger_at_ms);
-        assert!(job_trigger_at_ms <= bst.get_end_time_ms());
-        assert_eq!(
-            bst.get_end_time_ms() - bst.get_start_time_ms(),
-            TEST_SPOKE_DURATION_MS
-        );
+        clock.advance(60);
+        assert_eq!(hub.walk_jobs().len(), 2);
+        clock.advance(110);
+        assert_eq!(hub.walk_jobs().len(), 2);
+        assert_eq!(*metrics.lags_ms.lock().unwrap(), vec![90, 10, 20, 10]);
     }
 
     #[test]
-    fn add_job_to_hub() {
-        let start_time_ms = times::current_time_ms();
-        println!("-- Test Diagnostic: current_time_ms: {}\n", start_time_ms);
-        let mut hub = Hub::new(TEST_SPOKE_DURATION_MS);
-        // first spoke
-        hub.add_job(Job::new_auto_id(start_time_ms + 3, "one spoke"))
-            .add_job(Job::new_auto_id(start_time_ms + 4, "one spoke"));
+    fn ticks_report_spoke_map_gauges() {
+        let (mut hub, clock) = mock_hub(100);
+        let metrics = Arc::new(RecordingMetrics::default());
+        hub.set_metrics(metrics.clone());
+        add_at_offset(&mut hub, 50);
+        add_at_offset(&mut hub, 150);
+        add_at_offset(&mut hub, 10_050);
+        for body in &["late", "later", "reserved"] {
+            hub.add_job(Job::new_auto_id(MOCK_START_MS - 30, *body))
+                .unwrap();
+        }
+        clock.advance(120);
+        let walked = hub.walk_jobs_limit(1).pop().unwrap();
+        hub.reserve_job(walked);
 
-        // next spoke
-        hub.add_job(Job::new_auto_id(
-            start_time_ms + TEST_SPOKE_DURATION_MS * 2 + 4,
-            "foo",
-        )).add_job(Job::new_auto_id(
-            start_time_ms + TEST_SPOKE_DURATION_MS * 2 + 3,
-            "foo",
-        ));
+        hub.tick();
+        let expected = HubGauges {
+            spokes: 3,
+            oldest_expired_spoke_age_ms: 20,
+            past_jobs: 2,
+            reserved_jobs: 1,
+        };
+        assert_eq!(*metrics.recorded.lock().unwrap(), vec![expected]);
+        assert_eq!(hub.gauges().spokes, spoke_starts(&hub).len());
 
+        assert_eq!(hub.walk_jobs().len(), 3);
+        hub.tick();
+        let gauges = *metrics.recorded.lock().unwrap().last().unwrap();
         assert_eq!(
-            hub.bst_spoke_map.len(),
-            2,
-            "Failed at time: {}",
-            times::current_time_ms()
+            gauges.oldest_expired_spoke_age_ms, 0,
+            "Expired spoke was emptied and pruned"
         );
-        // wait for first spoke to become ready
-        thread::park_timeout(Duration::from_millis(TEST_SPOKE_DURATION_MS + 2));
+        assert_eq!(gauges.past_jobs, 0);
+        assert_eq!(gauges.reserved_jobs, 1);
+    }
 
-        println!(
-            "Test Diagnostic: current time ms: {}",
-            times::current_time_ms()
-        );
+    /// Returns a hub on a mock clock that checks a watchdog on every tick, and the reports the
+    /// watchdog handed its callback. Walks are due every second, and the spoke threshold leaves
+    /// room for the spokes ticks create ahead of time.
+    fn watched_hub() -> (Hub, Arc<MockClock>, Arc<Mutex<Vec<StallReport>>>) {
+        let clock = Arc::new(MockClock::new(MOCK_START_MS));
+        let thresholds = WatchdogThresholds {
+            max_ms_between_walks: Some(1_000),
+            max_spokes: Some(10),
+            max_past_jobs: Some(2),
+        };
+        let config = HubConfig::new(100).with_watchdog(thresholds);
+        let mut hub = Hub::from_config_with_clock(config, clock.clone());
+        let reports = Arc::new(Mutex::new(vec![]));
+        let recorded = Arc::clone(&reports);
+        hub.set_on_stall(Box::new(move |r| recorded.lock().unwrap().push(r)));
+        (hub, clock, reports)
+    }
 
-        let mut walk_one = hub.walk_jobs();
+    #[test]
+    fn watchdog_trips_when_walks_stop() {
+        let (mut hub, clock, reports) = watched_hub();
+        clock.advance(500);
+        hub.walk_jobs();
+        clock.advance(1_000);
+        hub.tick();
+        assert!(reports.lock().unwrap().is_empty(), "Walked 1000ms ago, not over yet");
+
+        clock.advance(100);
+        hub.tick();
+        hub.tick();
+        let expected = StallReport {
+            at_ms: MOCK_START_MS + 1_600,
+            stalls: vec![Stall::WalkOverdue {
+                ms_since_walk: 1_100,
+                max_ms: 1_000,
+            }],
+            gauges: HubGauges {
+                spokes: PREALLOCATED_SPOKES as usize,
+                ..HubGauges::default()
+            },
+            last_walk_ms: MOCK_START_MS + 500,
+            last_add_ms: None,
+            last_prune_ms: Some(MOCK_START_MS + 1_600),
+        };
         assert_eq!(
-            walk_one.len(),
-            2,
-            "Failed at time: {}",
-            times::current_time_ms()
+            *reports.lock().unwrap(),
+            vec![expected],
+            "A stall is reported once, not on every tick it lasts"
         );
+        assert_eq!(hub.counters().stalls(), 1);
+
+        hub.walk_jobs_limit(1);
+        hub.tick();
+        clock.advance(1_001);
+        hub.tick();
+        assert_eq!(reports.lock().unwrap().len(), 2, "Stalls again once walks stop again");
+        assert_eq!(hub.counters().stalls(), 2);
     }
 
     #[test]
-    fn can_find_jobs() {
-        let start_time_ms = times::current_time_ms();
-        let mut hub = Hub::new(TEST_SPOKE_DURATION_MS);
-        let job_one_spoke = Job::new_auto_id(start_time_ms + 3, "one spoke");
-        let job_other_spoke =
-            Job::new_auto_id(start_time_ms + TEST_SPOKE_DURATION_MS * 2 + 4, "foo");
-
-        let job_one_id = job_one_spoke.get_metadata().get_id();
-        let job_other_id = job_other_spoke.get_metadata().get_id();
-
-        hub.add_job(job_one_spoke)
-            .add_job(Job::new_auto_id(start_time_ms + 4, "one spoke"))
-            .add_job(job_other_spoke)
-            .add_job(Job::new_auto_id(
-                start_time_ms + TEST_SPOKE_DURATION_MS * 2 + 3,
-                "foo",
-            ));
-        assert_eq!(hub.bst_spoke_map.len(), 2);
+    fn watchdog_trips_on_too_many_spokes() {
+        let (mut hub, _, reports) = watched_hub();
+        for k in 1..=11 {
+            add_at_offset(&mut hub, k * 1_000);
+        }
+        hub.tick();
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 1);
+        let report = &reports[0];
+        assert_eq!(
+            report.stalls,
+            vec![Stall::TooManySpokes {
+                spokes: 11,
+                max: 10,
+            }]
+        );
+        assert_eq!(report.gauges.spokes, 11);
+        assert_eq!(report.last_walk_ms, MOCK_START_MS, "Never walked, so since creation");
+        assert_eq!(report.last_add_ms, Some(MOCK_START_MS));
+        assert!(report.to_string().contains("11 spokes, over 10"), "{}", report);
+    }
 
-        assert!(hub.find_job_owner_bst(job_one_id).is_some());
-        assert!(hub.find_job_owner_bst(job_other_id).is_some());
+    #[test]
+    fn watchdog_trips_on_past_backlog() {
+        let (mut hub, clock, reports) = watched_hub();
+        let due: Vec<Job> = (0..3)
+            .map(|i| Job::new_auto_id(MOCK_START_MS - 10, format!("due {}", i)))
+            .collect();
+        assert!(hub.add_jobs(due).is_empty());
+        clock.advance(10);
+        hub.walk_jobs_limit(0);
+        hub.tick();
+        {
+            let reports = reports.lock().unwrap();
+            assert_eq!(reports.len(), 1);
+            assert_eq!(
+                reports[0].stalls,
+                vec![Stall::PastBacklog {
+                    past_jobs: 3,
+                    max: 2,
+                }]
+            );
+            assert_eq!(reports[0].gauges.past_jobs, 3);
+            assert_eq!(reports[0].last_walk_ms, MOCK_START_MS + 10);
+            assert_eq!(reports[0].last_add_ms, Some(MOCK_START_MS));
+        }
 
-        // Can't find unknown jobs
-        assert!(!hub.find_job_owner_bst(Uuid::new_v4()).is_some());
+        assert_eq!(hub.walk_jobs().len(), 3);
+        hub.tick();
+        assert_eq!(reports.lock().unwrap().len(), 1);
+        assert_eq!(hub.counters().stalls(), 1);
     }
 
     #[test]
-    fn can_find_past_jobs() {
-        let start_time_ms = times::current_time_ms();
-        let mut hub = Hub::new(TEST_SPOKE_DURATION_MS);
-        let j = Job::new_auto_id(start_time_ms - 300, "one spoke");
+    fn watchdog_checks_nothing_by_default() {
+        let (mut hub, clock) = mock_hub(100);
+        for k in 0..20 {
+            add_at_offset(&mut hub, k * 1_000);
+        }
+        clock.advance(60_000);
+        hub.tick();
+        assert_eq!(hub.counters().stalls(), 0);
+        assert_eq!(hub.config().watchdog, WatchdogThresholds::default());
+    }
 
-        let id = j.get_metadata().get_id();
+    #[test]
+    fn adds_jobs_on_spoke_boundaries() {
+        for &duration_ms in &[1, 2, 10, 1_000, 60_000] {
+            let (mut hub, clock) = mock_hub(duration_ms);
+            let mut triggers = vec![];
+            for k in 1..4 {
+                let boundary_ms = MOCK_START_MS + k * duration_ms;
+                triggers.extend(&[boundary_ms - 1, boundary_ms, boundary_ms + 1]);
+            }
+            for &trigger_at_ms in &triggers {
+                let job = Job::new_auto_id(trigger_at_ms, "boundary");
+                let id = job.get_metadata().get_id();
+                hub.add_job(job).unwrap();
+                let owner = hub.find_job_owner_bst(id).unwrap();
+                let in_past = owner == hub.past_spoke.get_bounds();
+                assert!(
+                    in_past || owner.covers(trigger_at_ms),
+                    "Spoke {:?} owns a job triggering at {}",
+                    owner,
+                    trigger_at_ms
+                );
+                if !in_past {
+                    let start_ms = times::floor_to(trigger_at_ms, duration_ms);
+                    assert_eq!(owner.get_start_time_ms(), start_ms);
+                }
+            }
+            clock.advance(4 * duration_ms + 1);
+            assert_eq!(hub.walk_jobs().len(), triggers.len(), "{}ms spokes", duration_ms);
+        }
+    }
 
-        hub.add_job(j);
-        assert_eq!(hub.bst_spoke_map.len(), 0);
-        assert!(hub.find_job_owner_bst(id).is_some());
-        // Is Idempotent
-        assert!(hub.find_job_owner_bst(id).is_some());
+    #[test]
+    fn reports_spoke_bounds_that_miss_the_job() {
+        // Zero length spokes cover no time at all, so no spoke can take a future job
+        let (mut hub, _) = mock_hub(0);
+        let job = Job::new_auto_id(MOCK_START_MS + 10, "nowhere");
+        assert!(matches!(hub.add_job(job), Err(YaadError::Internal(_))));
+        assert_eq!(hub.spoke_count(), 0);
+        assert_eq!(hub.counters().internal_errors(), 1, "Inconsistencies are counted");
     }
 }
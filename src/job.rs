@@ -8,45 +8,98 @@
 //! execution time: a job whose trigger time is closer in the future is `greater` than a job that
 //! is due later.
 
+use bytes::Bytes;
+use errors::{RejectReason, YaadError};
+use std::borrow::Cow;
 use std::cmp::Ordering;
 use times;
 use uuid::{Uuid, UuidVersion};
 
+/// Time a consumer has to acknowledge a reserved job before it is handed out again, unless the
+/// job sets its own
+pub const DEFAULT_TTR_MS: u64 = 120_000;
+/// Priority of a job that doesn't set its own - 0 is the most urgent
+pub const DEFAULT_PRIORITY: u32 = u32::MAX / 2;
+
 ///The "Job" type has max possible values: u64::max_value() = 18446744073709551615.
 ///internal_id will overflow after max value - internal functioning should not be affected.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Job {
     job_metadata: JobMetadata,
     body: JobBody,
 }
 
+/// Serializes with the `serialization` feature. Stored and sent jobs should use
+/// [`SerializedJob`](::wire::SerializedJob) instead, whose layout doesn't follow this one's.
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serialization", derive(Serialize, Deserialize))]
 pub struct JobMetadata {
+    #[cfg_attr(feature = "serialization", serde(with = "::wire::uuid_format"))]
     id: Uuid,
     trigger_at_ms: u64,
+    ttr_ms: u64,
+    priority: u32,
+    /// When the job was created, in ms since the epoch
+    created_at_ms: u64,
+    /// How often the job was handed to a consumer
+    reserves: u32,
+    /// How often a consumer put the job back after reserving it
+    releases: u32,
+    /// How often the job was scheduled again because a consumer's TTR ran out
+    timeouts: u32,
+}
+
+/// Where a job's trigger time lies relative to a given time
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TemporalState {
+    /// The job fell due before then
+    Past,
+    /// The job falls due within that very millisecond
+    Current,
+    /// The job falls due later
+    Future,
 }
 
+/// A job's payload - arbitrary bytes, not necessarily utf-8. Cloning a body shares its bytes
+/// instead of copying them.
+///
+/// Hubs set up with [`HubConfig::with_compress_bodies_over_bytes`] keep large bodies compressed
+/// while their jobs wait. [`Job::get_body`] and walks hand bodies out as they were put.
+///
+/// With the `serialization` feature bodies serialize as their original bytes.
+///
+/// [`HubConfig::with_compress_bodies_over_bytes`]: ../hub/struct.HubConfig.html
 #[derive(Debug, Clone)]
-pub struct JobBody {
-    body: String,
+pub enum JobBody {
+    Plain(Bytes),
+    /// `data` decompresses to `raw_len` bytes
+    Compressed { raw_len: usize, data: Bytes },
 }
 
 impl Job {
     /// Creates a new job given an internal id, external id, trigger time in ms and the body.
+    /// Panics unless `id` is a uuid v4, use [`Job::try_new`] for ids that aren't known to be.
     /// TODO: This does not handle id collisions properly yet.
-    pub fn new(id: Uuid, trigger_at_ms: u64, body: &str) -> Job {
+    pub fn new<B: Into<JobBody>>(id: Uuid, trigger_at_ms: u64, body: B) -> Job {
+        match Job::try_new(id, trigger_at_ms, body) {
+            Ok(job) => job,
+            Err(_) => panic!("Only uuid v4 ids are accepted"),
+        }
+    }
+
+    /// Creates a new job like [`Job::new`], refusing it with [`RejectReason::InvalidId`] unless
+    /// `id` is a uuid v4 - e.g. for ids read from a client or a file
+    pub fn try_new<B: Into<JobBody>>(
+        id: Uuid,
+        trigger_at_ms: u64,
+        body: B,
+    ) -> Result<Job, YaadError> {
         match id.get_version() {
-            Some(ver) => match ver {
-                UuidVersion::Random => {
-                    let body = body.to_owned();
-                    Job {
-                        job_metadata: JobMetadata { id, trigger_at_ms },
-                        body: JobBody { body },
-                    }
-                }
-                _ => panic!("Only uuid v4 ids are accepted"),
-            },
-            _ => panic!("Only uuid v4 ids are accepted"),
+            Some(UuidVersion::Random) => Ok(Job {
+                job_metadata: JobMetadata::new(id, trigger_at_ms),
+                body: body.into(),
+            }),
+            _ => Err(RejectReason::InvalidId(id).into()),
         }
     }
 
@@ -56,25 +109,140 @@ impl Job {
 
     /// Creates new job that doesn't need an external id. An external id will not be generated in
     /// this case.
-    pub fn new_auto_id(trigger_at_ms: u64, body: &str) -> Job {
+    pub fn new_auto_id<B: Into<JobBody>>(trigger_at_ms: u64, body: B) -> Job {
         Job::new(Uuid::new_v4(), trigger_at_ms, body)
     }
 
+    /// Returns this job with its time-to-run set to `ttr_ms`
+    pub fn with_ttr_ms(mut self, ttr_ms: u64) -> Job {
+        self.job_metadata.ttr_ms = ttr_ms;
+        self
+    }
+
+    /// Returns this job with its priority set to `priority`
+    pub fn with_priority(mut self, priority: u32) -> Job {
+        self.job_metadata.priority = priority;
+        self
+    }
+
+    /// Returns this job marked as created at `created_at_ms`, e.g. when restoring it
+    pub fn with_created_at_ms(mut self, created_at_ms: u64) -> Job {
+        self.job_metadata.created_at_ms = created_at_ms;
+        self
+    }
+
+    /// Returns this job with its reserves, releases and timeouts counted as given, e.g. when
+    /// restoring it
+    pub(crate) fn with_counts(mut self, reserves: u32, releases: u32, timeouts: u32) -> Job {
+        self.job_metadata.reserves = reserves;
+        self.job_metadata.releases = releases;
+        self.job_metadata.timeouts = timeouts;
+        self
+    }
+
+    /// Returns this job with one more reserve counted
+    pub(crate) fn with_reserve_counted(mut self) -> Job {
+        self.job_metadata.reserves = self.job_metadata.reserves.saturating_add(1);
+        self
+    }
+
+    /// Returns this job with one more release counted
+    pub(crate) fn with_release_counted(mut self) -> Job {
+        self.job_metadata.releases = self.job_metadata.releases.saturating_add(1);
+        self
+    }
+
+    /// Returns this job with one more TTR timeout counted
+    pub(crate) fn with_timeout_counted(mut self) -> Job {
+        self.job_metadata.timeouts = self.job_metadata.timeouts.saturating_add(1);
+        self
+    }
+
+    /// Returns this job rescheduled to trigger at `trigger_at_ms`
+    pub fn with_trigger_at_ms(mut self, trigger_at_ms: u64) -> Job {
+        self.job_metadata.trigger_at_ms = trigger_at_ms;
+        self
+    }
+
     /// Returns the job's trigger time as milliseconds from UnixEpoch.
     #[inline]
     pub fn trigger_at_ms(&self) -> u64 {
         self.job_metadata.trigger_at_ms()
     }
 
+    /// Returns when the job was created, in ms since the epoch
+    #[inline]
+    pub fn created_at_ms(&self) -> u64 {
+        self.job_metadata.created_at_ms
+    }
+
+    /// Returns how long after its trigger time the job is handed out if that happens at
+    /// `now_ms`, or 0 if it isn't due yet
+    #[inline]
+    pub fn delivery_lag_ms(&self, now_ms: u64) -> u64 {
+        now_ms.saturating_sub(self.trigger_at_ms())
+    }
+
+    /// Returns how often the job was handed to a consumer
+    #[inline]
+    pub fn reserves(&self) -> u32 {
+        self.job_metadata.reserves
+    }
+
+    /// Returns how often a consumer put the job back after reserving it
+    #[inline]
+    pub fn releases(&self) -> u32 {
+        self.job_metadata.releases
+    }
+
+    /// Returns how often a consumer's TTR ran out before it acknowledged the job
+    #[inline]
+    pub fn timeouts(&self) -> u32 {
+        self.job_metadata.timeouts
+    }
+
+    /// Returns how long a consumer has to acknowledge this job once reserved
+    #[inline]
+    pub fn ttr_ms(&self) -> u64 {
+        self.job_metadata.ttr_ms
+    }
+
+    /// Returns the job's priority - of two jobs due at the same time, the one with the lower
+    /// priority is walked first
+    #[inline]
+    pub fn priority(&self) -> u32 {
+        self.job_metadata.priority
+    }
+
     /// Returns true if the job should trigger right now.
     #[inline]
     pub fn is_ready(&self) -> bool {
         self.job_metadata.is_ready()
     }
 
+    /// Returns true if the job should trigger by `now_ms`
+    #[inline]
+    pub fn is_ready_at(&self, now_ms: u64) -> bool {
+        self.job_metadata.is_ready_at(now_ms)
+    }
+
+    /// Returns where the job's trigger time lies relative to `now_ms`
+    #[inline]
+    pub fn temporal_state_at(&self, now_ms: u64) -> TemporalState {
+        self.job_metadata.temporal_state_at(now_ms)
+    }
+
+    /// Returns the job's body as it was put. Its bytes are shared with the job rather than copied,
+    /// unless the body has to be decompressed.
     #[inline]
     pub fn get_body(&self) -> JobBody {
-        self.body.clone()
+        self.body.decompressed()
+    }
+
+    /// Returns the job's body as stored, compressed or not, without copying it
+    #[inline]
+    pub fn body(&self) -> &JobBody {
+        &self.body
     }
 
     #[inline]
@@ -85,7 +253,23 @@ impl Job {
 
 impl JobMetadata {
     pub fn new(id: Uuid, trigger_at_ms: u64) -> JobMetadata {
-        JobMetadata { id, trigger_at_ms }
+        JobMetadata {
+            id,
+            trigger_at_ms,
+            ttr_ms: DEFAULT_TTR_MS,
+            priority: DEFAULT_PRIORITY,
+            created_at_ms: times::current_time_ms(),
+            reserves: 0,
+            releases: 0,
+            timeouts: 0,
+        }
+    }
+
+    /// Returns when the job was created, in ms since the epoch. Rescheduling a job doesn't
+    /// change it.
+    #[inline]
+    pub fn created_at_ms(&self) -> u64 {
+        self.created_at_ms
     }
 
     /// Returns the job's trigger time as milliseconds from UnixEpoch.
@@ -94,10 +278,44 @@ impl JobMetadata {
         self.trigger_at_ms
     }
 
+    /// Returns how often the job was handed to a consumer
+    #[inline]
+    pub fn reserves(&self) -> u32 {
+        self.reserves
+    }
+
+    /// Returns how often a consumer put the job back after reserving it
+    #[inline]
+    pub fn releases(&self) -> u32 {
+        self.releases
+    }
+
+    /// Returns how often a consumer's TTR ran out before it acknowledged the job
+    #[inline]
+    pub fn timeouts(&self) -> u32 {
+        self.timeouts
+    }
+
     /// Returns true if the job should trigger right now.
     #[inline]
     pub fn is_ready(&self) -> bool {
-        self.trigger_at_ms <= times::current_time_ms()
+        self.is_ready_at(times::current_time_ms())
+    }
+
+    /// Returns true if the job should trigger by `now_ms`
+    #[inline]
+    pub fn is_ready_at(&self, now_ms: u64) -> bool {
+        self.temporal_state_at(now_ms) != TemporalState::Future
+    }
+
+    /// Returns where the job's trigger time lies relative to `now_ms`
+    #[inline]
+    pub fn temporal_state_at(&self, now_ms: u64) -> TemporalState {
+        match self.trigger_at_ms.cmp(&now_ms) {
+            Ordering::Less => TemporalState::Past,
+            Ordering::Equal => TemporalState::Current,
+            Ordering::Greater => TemporalState::Future,
+        }
     }
 
     #[inline]
@@ -106,6 +324,137 @@ impl JobMetadata {
     }
 }
 
+impl JobBody {
+    /// Returns the bytes the body is stored as - the compressed ones if it is
+    /// [compressed](JobBody::is_compressed). Bodies from [`Job::get_body`] never are.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        match *self {
+            JobBody::Plain(ref body) => body,
+            JobBody::Compressed { ref data, .. } => data,
+        }
+    }
+
+    /// Returns a handle on the body's original bytes, sharing them instead of copying unless the
+    /// body has to be decompressed
+    pub fn to_bytes(&self) -> Bytes {
+        match *self {
+            JobBody::Plain(ref body) => body.clone(),
+            JobBody::Compressed { raw_len, ref data } => decompress(data, raw_len).into(),
+        }
+    }
+
+    /// Returns the body as text for logging, with invalid utf-8 replaced
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        match *self {
+            JobBody::Plain(ref body) => String::from_utf8_lossy(body),
+            JobBody::Compressed { .. } => {
+                Cow::Owned(String::from_utf8_lossy(&self.to_bytes()).into_owned())
+            }
+        }
+    }
+
+    #[inline]
+    pub fn is_compressed(&self) -> bool {
+        match *self {
+            JobBody::Plain(_) => false,
+            JobBody::Compressed { .. } => true,
+        }
+    }
+
+    /// Returns the length of the body as it was put
+    #[inline]
+    pub fn raw_len(&self) -> usize {
+        match *self {
+            JobBody::Plain(ref body) => body.len(),
+            JobBody::Compressed { raw_len, .. } => raw_len,
+        }
+    }
+
+    /// Returns the number of bytes the body takes up as stored
+    #[inline]
+    pub fn stored_len(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    /// Returns the body uncompressed
+    pub fn decompressed(&self) -> JobBody {
+        match *self {
+            JobBody::Plain(_) => self.clone(),
+            JobBody::Compressed { .. } => JobBody::Plain(self.to_bytes()),
+        }
+    }
+
+    /// Returns the body compressed if it is longer than `threshold_bytes` and compressing makes
+    /// it shorter, or as it is otherwise. Without the `compression` feature bodies are never
+    /// compressed.
+    pub fn compressed_over(self, threshold_bytes: usize) -> JobBody {
+        match self {
+            JobBody::Plain(ref body) if body.len() > threshold_bytes => match compress(body) {
+                Some(data) if data.len() < body.len() => JobBody::Compressed {
+                    raw_len: body.len(),
+                    data: data.into(),
+                },
+                _ => self.clone(),
+            },
+            _ => self,
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+fn compress(raw: &[u8]) -> Option<Vec<u8>> {
+    Some(::lz4_flex::compress(raw))
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress(_raw: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+/// Compressed bodies are only ever made by [`JobBody::compressed_over`], so they decompress
+#[cfg(feature = "compression")]
+fn decompress(data: &[u8], raw_len: usize) -> Vec<u8> {
+    ::lz4_flex::decompress(data, raw_len).expect("Compressed job body is corrupt")
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(_data: &[u8], _raw_len: usize) -> Vec<u8> {
+    panic!("Compressed job bodies need the compression feature")
+}
+
+impl From<Bytes> for JobBody {
+    fn from(body: Bytes) -> JobBody {
+        JobBody::Plain(body)
+    }
+}
+
+impl From<Vec<u8>> for JobBody {
+    fn from(body: Vec<u8>) -> JobBody {
+        JobBody::Plain(body.into())
+    }
+}
+
+impl From<String> for JobBody {
+    fn from(body: String) -> JobBody {
+        JobBody::Plain(body.into())
+    }
+}
+
+/// Copies the bytes
+impl<'a> From<&'a [u8]> for JobBody {
+    fn from(body: &'a [u8]) -> JobBody {
+        JobBody::Plain(Bytes::copy_from_slice(body))
+    }
+}
+
+/// Copies the text
+impl<'a> From<&'a str> for JobBody {
+    fn from(body: &'a str) -> JobBody {
+        body.as_bytes().into()
+    }
+}
+
 impl Ord for Job {
     /// A Job is greater than another job if the job's trigger time will happen before the other's
     fn cmp(&self, other: &Job) -> Ordering {
@@ -132,11 +481,17 @@ impl PartialEq for Job {
 }
 
 impl Ord for JobMetadata {
-    /// A Job is greater than another job if the job's trigger time will happen before the other's
+    /// A Job is greater than another job if the job's trigger time will happen before the other's.
+    /// Jobs triggering at the same time are ordered by priority, then by id, the lower value being
+    /// greater, so that walks hand them out in a deterministic order.
     fn cmp(&self, other: &JobMetadata) -> Ordering {
         // Flip ordering - we want min heap
         // Close trigger time means job > further trigger_at time.
-        self.trigger_at_ms.cmp(&other.trigger_at_ms).reverse()
+        self.trigger_at_ms
+            .cmp(&other.trigger_at_ms)
+            .then_with(|| self.priority.cmp(&other.priority))
+            .then_with(|| self.id.cmp(&other.id))
+            .reverse()
     }
 }
 
@@ -167,6 +522,115 @@ mod tests {
         assert_eq!(j.job_metadata.id, id, "Should be able to create a job");
     }
 
+    #[test]
+    fn refuses_ids_other_than_uuid_v4() {
+        let id = Uuid::new_v5(&::uuid::NAMESPACE_DNS, "yaad");
+        assert_eq!(
+            Job::try_new(id, 5, "Test Body").unwrap_err(),
+            RejectReason::InvalidId(id).into()
+        );
+        assert!(Job::try_new(Uuid::new_v4(), 5, "Test Body").is_ok());
+    }
+
+    #[test]
+    fn jobs_have_a_ttr() {
+        let j = Job::new_auto_id(5, "Test Body");
+        assert_eq!(j.ttr_ms(), DEFAULT_TTR_MS);
+        let j = j.with_ttr_ms(1_000).with_trigger_at_ms(10);
+        assert_eq!(j.ttr_ms(), 1_000);
+        assert_eq!(j.trigger_at_ms(), 10);
+    }
+
+    #[test]
+    fn measures_delivery_lag() {
+        let before = times::current_time_ms();
+        let j = Job::new_auto_id(1_000, "Test Body");
+        assert!(j.created_at_ms() >= before);
+        assert_eq!(j.delivery_lag_ms(1_250), 250);
+        assert_eq!(j.delivery_lag_ms(1_000), 0, "Delivered right on time");
+        assert_eq!(j.delivery_lag_ms(500), 0, "Not due yet");
+
+        let created_at_ms = j.created_at_ms();
+        let j = j.with_trigger_at_ms(2_000);
+        assert_eq!(j.created_at_ms(), created_at_ms, "Rescheduling keeps the creation time");
+        assert_eq!(j.delivery_lag_ms(2_020), 20);
+    }
+
+    #[test]
+    fn bodies_are_binary_safe() {
+        let bytes = vec![b'a', b'\r', b'\n', 0xFF, 0];
+        let j = Job::new_auto_id(5, bytes.clone());
+        assert_eq!(j.get_body().as_bytes(), &bytes[..]);
+        assert_eq!(j.get_body().to_string_lossy(), "a\r\n\u{FFFD}\u{0}");
+
+        let j = Job::new_auto_id(5, "text");
+        assert_eq!(j.get_body().as_bytes(), b"text");
+    }
+
+    /// A JSON blob repeating itself, like the large bodies worth compressing
+    fn compressible_body(len: usize) -> Vec<u8> {
+        br#"{"user":42,"event":"signup","tags":["a","b"]},"#
+            .iter()
+            .cycle()
+            .take(len)
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn compressed_bodies_round_trip() {
+        let raw = compressible_body(200_000);
+        let body = JobBody::from(raw.clone()).compressed_over(1_024);
+        assert!(body.is_compressed());
+        assert_eq!(body.raw_len(), raw.len());
+        assert!(body.stored_len() < raw.len() / 10, "Stored {} bytes", body.stored_len());
+        assert_eq!(&body.to_bytes()[..], &raw[..]);
+        assert_eq!(body.decompressed().as_bytes(), &raw[..]);
+        assert_eq!(body.to_string_lossy().len(), raw.len());
+
+        let j = Job::new_auto_id(5, body);
+        assert!(j.body().is_compressed(), "The job keeps its body as stored");
+        assert!(!j.get_body().is_compressed());
+        assert_eq!(j.get_body().as_bytes(), &raw[..]);
+    }
+
+    #[test]
+    fn incompressible_bodies_stay_plain() {
+        let raw: Vec<u8> = (0..4_096).map(|_| ::rand::random::<u8>()).collect();
+        let body = JobBody::from(raw.clone()).compressed_over(1_024);
+        assert!(!body.is_compressed(), "Compressing doesn't make random bytes shorter");
+        assert_eq!((body.raw_len(), body.stored_len()), (raw.len(), raw.len()));
+        assert_eq!(body.as_bytes(), &raw[..]);
+    }
+
+    #[test]
+    fn bodies_up_to_the_threshold_stay_plain() {
+        let body = JobBody::from(compressible_body(1_024)).compressed_over(1_024);
+        assert!(!body.is_compressed());
+        assert_eq!(body.stored_len(), 1_024);
+
+        let body = JobBody::from(compressible_body(1_025)).compressed_over(1_024);
+        assert_eq!(body.is_compressed(), cfg!(feature = "compression"));
+        assert_eq!(body.raw_len(), 1_025);
+        assert_eq!(body.to_bytes().len(), 1_025);
+    }
+
+    #[test]
+    fn classifies_trigger_times() {
+        let now_ms = times::current_time_ms();
+        let at = |trigger_at_ms| Job::new_auto_id(trigger_at_ms, "job").temporal_state_at(now_ms);
+        assert_eq!(at(now_ms - 1), TemporalState::Past);
+        assert_eq!(at(0), TemporalState::Past);
+        assert_eq!(at(now_ms), TemporalState::Current);
+        assert_eq!(at(now_ms + 1), TemporalState::Future);
+        assert_eq!(at(u64::MAX), TemporalState::Future, "Far future doesn't overflow");
+
+        assert!(Job::new_auto_id(now_ms, "now").is_ready(), "Due this millisecond");
+        assert!(!Job::new_auto_id(now_ms + 60_000, "later").is_ready());
+        assert!(Job::new_auto_id(now_ms + 60_000, "later").is_ready_at(now_ms + 60_000));
+    }
+
     #[test]
     fn id_equality() {
         let id = Uuid::new_v4();
@@ -199,12 +663,24 @@ mod tests {
         let two = Job::new_auto_id(2, "two");
         assert_eq!(
             one.cmp(&two),
-            Ordering::Equal,
-            "When two jobs have same trigger_at time, ordering comparison is Equal"
+            one.get_metadata().get_id().cmp(&two.get_metadata().get_id()).reverse(),
+            "When two jobs have same trigger_at time, the job with the smaller id is greater"
         );
+        assert_eq!(one.cmp(&one.clone()), Ordering::Equal);
         assert!(
             one.ne(&two),
             "When two jobs have same trigger_at time, equality comparison is not affected"
         );
+
+        let urgent = Job::new_auto_id(2, "urgent").with_priority(0);
+        let relaxed = Job::new_auto_id(2, "relaxed").with_priority(DEFAULT_PRIORITY);
+        assert!(
+            urgent > relaxed,
+            "Of two jobs with the same trigger_at time, the lower priority is greater"
+        );
+        assert!(
+            Job::new_auto_id(1, "early").with_priority(u32::MAX) > urgent,
+            "Trigger time takes precedence over priority"
+        );
     }
 }
@@ -0,0 +1,197 @@
+//! Renders the hub's spoke layout as a Mermaid gantt chart or a DOT graph for visual debugging.
+//!
+//! Rendering is pure: it works off a list of rows describing each spoke and the time it was
+//! rendered at, so the same hub state and clock always produce the same output.
+
+use spoke::BoundingSpokeTime;
+use times;
+
+/// Hubs with more spokes than this get runs of empty spokes collapsed into a single entry
+pub const COLLAPSE_SPOKES_OVER: usize = 100;
+/// Rendering stops listing spokes individually after this many entries
+pub const MAX_RENDERED_SPOKES: usize = 500;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LayoutFormat {
+    Mermaid,
+    Dot,
+}
+
+/// Where a spoke sits relative to the render time
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SpokeState {
+    Future,
+    Ready,
+    Expired,
+}
+
+/// A single spoke as seen by the renderer
+#[derive(Debug, Clone)]
+pub struct SpokeRow {
+    pub short_id: String,
+    pub bounds: BoundingSpokeTime,
+    pub live: usize,
+    pub stale: usize,
+}
+
+impl SpokeRow {
+    fn state(&self, now_ms: u64) -> SpokeState {
+        if self.bounds.is_expired_at(now_ms) {
+            SpokeState::Expired
+        } else if self.bounds.is_ready_at(now_ms) {
+            SpokeState::Ready
+        } else {
+            SpokeState::Future
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.live == 0 && self.stale == 0
+    }
+}
+
+/// A rendered entry: either one spoke or a run of empty spokes collapsed together
+enum Entry<'a> {
+    Spoke(&'a SpokeRow),
+    Collapsed {
+        count: usize,
+        start_ms: u64,
+        end_ms: u64,
+    },
+    Truncated(usize),
+}
+
+/// Renders the past spoke and the ordered spoke rows in the requested format.
+pub fn render(format: LayoutFormat, past: &SpokeRow, spokes: &[SpokeRow], now_ms: u64) -> String {
+    let entries = entries(spokes);
+    match format {
+        LayoutFormat::Mermaid => render_mermaid(past, &entries, now_ms),
+        LayoutFormat::Dot => render_dot(past, &entries, now_ms),
+    }
+}
+
+fn entries<'a>(spokes: &'a [SpokeRow]) -> Vec<Entry<'a>> {
+    let collapse = spokes.len() > COLLAPSE_SPOKES_OVER;
+    let mut entries: Vec<Entry> = vec![];
+    let mut listed = 0;
+    for (i, s) in spokes.iter().enumerate() {
+        if listed == MAX_RENDERED_SPOKES {
+            entries.push(Entry::Truncated(spokes.len() - i));
+            break;
+        }
+        if collapse && s.is_empty() {
+            if let Some(&mut Entry::Collapsed {
+                ref mut count,
+                ref mut end_ms,
+                ..
+            }) = entries.last_mut()
+            {
+                *count += 1;
+                *end_ms = s.bounds.get_end_time_ms();
+                continue;
+            }
+            entries.push(Entry::Collapsed {
+                count: 1,
+                start_ms: s.bounds.get_start_time_ms(),
+                end_ms: s.bounds.get_end_time_ms(),
+            });
+        } else {
+            entries.push(Entry::Spoke(s));
+        }
+        listed += 1;
+    }
+    entries
+}
+
+fn render_mermaid(past: &SpokeRow, entries: &[Entry], now_ms: u64) -> String {
+    let mut out = String::from("gantt\n    title yaad hub layout\n    dateFormat x\n");
+    out.push_str("    section past\n");
+    out.push_str(&format!(
+        "    past {} {} live {} stale :crit, past, {}, {}\n",
+        past.short_id,
+        past.live,
+        past.stale,
+        now_ms,
+        now_ms + 1
+    ));
+    out.push_str("    section spokes\n");
+    for (i, e) in entries.iter().enumerate() {
+        match *e {
+            Entry::Spoke(s) => {
+                let tag = match s.state(now_ms) {
+                    SpokeState::Future => "",
+                    SpokeState::Ready => "active, ",
+                    SpokeState::Expired => "done, ",
+                };
+                out.push_str(&format!(
+                    "    s{} {} {} live {} stale :{}s{}, {}, {}\n",
+                    i,
+                    s.short_id,
+                    s.live,
+                    s.stale,
+                    tag,
+                    i,
+                    s.bounds.get_start_time_ms(),
+                    s.bounds.get_end_time_ms()
+                ));
+            }
+            Entry::Collapsed {
+                count,
+                start_ms,
+                end_ms,
+            } => out.push_str(&format!(
+                "    {} empty spokes :s{}, {}, {}\n",
+                count, i, start_ms, end_ms
+            )),
+            Entry::Truncated(count) => out.push_str(&format!("    %% {} more spokes\n", count)),
+        }
+    }
+    out
+}
+
+fn render_dot(past: &SpokeRow, entries: &[Entry], now_ms: u64) -> String {
+    let mut out = String::from("digraph hub {\n    rankdir=LR;\n    node [shape=box, style=filled];\n");
+    out.push_str(&format!(
+        "    past [label=\"past {}\\n{} live / {} stale\", fillcolor=orange];\n",
+        past.short_id, past.live, past.stale
+    ));
+    let mut names = vec![String::from("past")];
+    for (i, e) in entries.iter().enumerate() {
+        let name = format!("s{}", i);
+        match *e {
+            Entry::Spoke(s) => {
+                let color = match s.state(now_ms) {
+                    SpokeState::Future => "lightblue",
+                    SpokeState::Ready => "palegreen",
+                    SpokeState::Expired => "gray",
+                };
+                out.push_str(&format!(
+                    "    {} [label=\"{}\\n{}\\n{}..{}\\n{} live / {} stale\", fillcolor={}];\n",
+                    name,
+                    s.short_id,
+                    times::to_string(s.bounds.get_start_time_ms()),
+                    s.bounds.get_start_time_ms(),
+                    s.bounds.get_end_time_ms(),
+                    s.live,
+                    s.stale,
+                    color
+                ));
+            }
+            Entry::Collapsed {
+                count,
+                start_ms,
+                end_ms,
+            } => out.push_str(&format!(
+                "    {} [label=\"{} empty spokes\\n{}..{}\", fillcolor=white];\n",
+                name, count, start_ms, end_ms
+            )),
+            Entry::Truncated(count) => out.push_str(&format!(
+                "    {} [label=\"{} more spokes\", shape=plaintext];\n",
+                name, count
+            )),
+        }
+        names.push(name);
+    }
+    out.push_str(&format!("    {};\n}}\n", names.join(" -> ")));
+    out
+}
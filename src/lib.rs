@@ -0,0 +1,78 @@
+//! yaad schedules jobs on a hierarchical timing wheel: a [`Hub`] holds a chain of time bounded
+//! [`Spoke`]s, each holding the jobs that fall due within its bounds.
+//!
+//! The scheduling core only depends on `uuid`, `rand`, `chrono` and `log`, so it can be embedded
+//! in another service without the beanstalkd server. Depend on yaad with
+//! `default-features = false` to leave out the server and its dependencies. The hub logs through
+//! the `log` facade, so its messages go wherever the embedding service's logger sends them. The
+//! `compression` feature, on by default, adds `lz4_flex` for compressing large job bodies, and
+//! the `serialization` feature, which the server turns on, adds `serde` and the versioned job
+//! schema in [`wire`].
+//!
+//! ```
+//! extern crate yaad;
+//!
+//! use yaad::hub::Hub;
+//! use yaad::job::Job;
+//! use yaad::times;
+//!
+//! let mut hub = Hub::new(1_000);
+//! let now_ms = times::current_time_ms();
+//! hub.add_job(Job::new_auto_id(now_ms - 10, "due")).unwrap();
+//! hub.add_job(Job::new_auto_id(now_ms + 60_000, "later")).unwrap();
+//!
+//! let ready = hub.walk_jobs();
+//! assert_eq!(ready.len(), 1);
+//! assert_eq!(ready[0].get_body().as_bytes(), b"due");
+//! ```
+//!
+//! A [`Scheduler`] does the same with delays and job handles instead of epoch milliseconds and ids.
+
+extern crate bytes;
+extern crate chrono;
+#[macro_use]
+extern crate log;
+#[cfg(feature = "compression")]
+extern crate lz4_flex;
+extern crate rand;
+#[cfg(feature = "serialization")]
+extern crate serde;
+#[cfg(feature = "serialization")]
+#[macro_use]
+extern crate serde_derive;
+extern crate uuid;
+
+/// Logs per-job diagnostics at trace level. These run on every job added, so they are optimized
+/// out of release builds unless the `job-tracing` feature is enabled.
+macro_rules! trace_job {
+    ($($arg:tt)*) => {
+        if cfg!(any(debug_assertions, feature = "job-tracing")) {
+            trace!($($arg)*);
+        }
+    };
+}
+
+pub mod clock;
+pub mod errors;
+pub mod gauges;
+pub mod hub;
+pub mod ids;
+pub mod job;
+pub mod layout;
+pub mod persistence;
+pub mod scheduler;
+pub mod shared;
+pub mod sink;
+pub mod spoke;
+pub mod stats;
+pub mod times;
+#[cfg(feature = "serialization")]
+pub mod wire;
+pub mod watchdog;
+
+pub use errors::YaadError;
+pub use hub::Hub;
+pub use job::Job;
+pub use scheduler::Scheduler;
+pub use shared::SharedHub;
+pub use spoke::{BoundingSpokeTime, Spoke};
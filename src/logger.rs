@@ -0,0 +1,62 @@
+//! Prints the `log` records of the hub and the server to stdout, at the level set in the config.
+
+use log::{self, LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+/// Level logged at unless the config sets `log_level`
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+struct StdoutLogger;
+
+impl Log for StdoutLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            println!("{} {}: {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StdoutLogger = StdoutLogger;
+
+/// Installs the logger, logging records at `level` and above. Fails if a logger is installed
+/// already.
+pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_logger(&LOGGER)?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Parses a `log_level` setting like `debug`, falling back to [`DEFAULT_LEVEL`] if it is missing
+/// or unknown
+pub fn level_from_setting(setting: Option<&str>) -> LevelFilter {
+    match setting.map(|s| s.parse()) {
+        Some(Ok(level)) => level,
+        Some(Err(_)) => {
+            println!(
+                "Unknown log_level {:?}, logging at {}",
+                setting.unwrap_or_default(),
+                DEFAULT_LEVEL
+            );
+            DEFAULT_LEVEL
+        }
+        None => DEFAULT_LEVEL,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_levels() {
+        assert_eq!(level_from_setting(Some("debug")), LevelFilter::Debug);
+        assert_eq!(level_from_setting(Some("TRACE")), LevelFilter::Trace);
+        assert_eq!(level_from_setting(Some("loud")), DEFAULT_LEVEL);
+        assert_eq!(level_from_setting(None), DEFAULT_LEVEL);
+    }
+}
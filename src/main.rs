@@ -1,35 +1,171 @@
+extern crate bytes;
+extern crate colored;
 extern crate config;
+extern crate libc;
+#[macro_use]
+extern crate log;
 extern crate rand;
 extern crate serde;
+extern crate serde_json;
 extern crate statsd;
 extern crate uuid;
-extern crate chrono;
-extern crate colored;
+extern crate yaad;
 
 #[macro_use]
 extern crate serde_derive;
 
-// our modules
+use std::process;
+
+// our modules - the scheduling core lives in the yaad library
 pub mod demo;
-pub mod hub;
-pub mod job;
+pub mod logger;
+pub mod metrics;
+pub mod prometheus;
+pub mod protocols;
 pub mod settings;
-pub mod spoke;
-pub mod times;
+pub mod shutdown;
+
+use metrics::Metrics;
+use protocols::beanstalkd::{self, Beanstalkd};
+use protocols::core::ConnectionLimits;
+use protocols::jsonline::{self, JsonLine};
+use std::sync::Arc;
+use std::time::Duration;
+use yaad::hub;
+use yaad::watchdog::WatchdogThresholds;
 
 fn main() {
     let settings = settings::Settings::new();
     match settings {
         Result::Ok(r) => {
             println!("Config parsed OK: {:?}", r);
+            let level = logger::level_from_setting(r.log_level.as_deref());
+            if let Err(e) = logger::init(level) {
+                println!("Failed to set up logging: {}", e);
+            }
             match r.mode.as_ref() {
-                "demo" => demo::demo(r),
-                // not implemented yet
-                // "consumer" => demo::consumer(),
-                // "producer" => demo::producer(),
+                "demo" => {
+                    let outcome = demo::demo(r);
+                    process::exit(outcome.exit_code());
+                }
+                "beanstalkd" => {
+                    // Without an address, TCP is only listened on if there is no unix socket
+                    let addr = match (&r.addr, &r.unix_socket_path) {
+                        (None, None) => Some("127.0.0.1:11300".into()),
+                        (addr, _) => addr.clone(),
+                    };
+                    let grace_ms = r
+                        .shutdown_grace_ms
+                        .unwrap_or(beanstalkd::DEFAULT_SHUTDOWN_GRACE_MS);
+                    let spoke_duration_ms = r
+                        .spoke_duration_ms
+                        .unwrap_or(beanstalkd::DEFAULT_SPOKE_DURATION_MS);
+                    let max_job_size = r
+                        .max_job_body_bytes
+                        .unwrap_or(beanstalkd::MAX_JOB_SIZE);
+                    let max_future_ms = r.max_future_ms.unwrap_or(hub::DEFAULT_MAX_FUTURE_MS);
+                    let tick_interval_ms =
+                        r.tick_interval_ms.unwrap_or(hub::DEFAULT_TICK_INTERVAL_MS);
+                    let mut server = Beanstalkd::new(addr, r.snapshot_path.clone(), grace_ms)
+                        .with_spoke_duration_ms(spoke_duration_ms)
+                        .with_max_future_ms(max_future_ms)
+                        .with_max_job_size(max_job_size)
+                        .with_tick_interval_ms(tick_interval_ms)
+                        .with_connection_limits(connection_limits(&r))
+                        .with_snapshot_command(r.snapshot_command.unwrap_or(false));
+                    if let Some(threshold_bytes) = r.compress_bodies_over_bytes {
+                        server = server.with_compress_bodies_over_bytes(threshold_bytes);
+                    }
+                    if let Some(max_timeouts) = r.max_timeouts {
+                        server = server.with_max_timeouts(max_timeouts);
+                    }
+                    if let Some(max_pending_jobs) = r.max_pending_jobs {
+                        server = server.with_max_pending_jobs(max_pending_jobs);
+                    }
+                    server = server.with_watchdog(watchdog_thresholds(&r));
+                    // Gauges are only sent if a statsd_addr is configured
+                    let metrics = Metrics::from_setting(r.statsd_addr.as_deref());
+                    if metrics.is_enabled() {
+                        server = server.with_metrics(Arc::new(metrics));
+                    }
+                    if let Some(ref addr) = r.metrics_http_addr {
+                        server = server.with_metrics_http_addr(addr.clone());
+                    }
+                    if let Some(ref path) = r.unix_socket_path {
+                        server = server.with_unix_socket_path(path.clone());
+                    }
+                    if let Err(e) = server.listen_and_serve() {
+                        println!("Beanstalkd server failed: {}", e);
+                        process::exit(1);
+                    }
+                }
+                "jsonline" => {
+                    let addr = r.addr.clone().unwrap_or(jsonline::DEFAULT_ADDR.into());
+                    let grace_ms = r
+                        .shutdown_grace_ms
+                        .unwrap_or(beanstalkd::DEFAULT_SHUTDOWN_GRACE_MS);
+                    let spoke_duration_ms = r
+                        .spoke_duration_ms
+                        .unwrap_or(beanstalkd::DEFAULT_SPOKE_DURATION_MS);
+                    let max_job_size = r.max_job_body_bytes.unwrap_or(jsonline::MAX_JOB_SIZE);
+                    let max_future_ms = r.max_future_ms.unwrap_or(hub::DEFAULT_MAX_FUTURE_MS);
+                    let tick_interval_ms =
+                        r.tick_interval_ms.unwrap_or(hub::DEFAULT_TICK_INTERVAL_MS);
+                    let mut server = JsonLine::new(addr, r.snapshot_path.clone(), grace_ms)
+                        .with_spoke_duration_ms(spoke_duration_ms)
+                        .with_max_future_ms(max_future_ms)
+                        .with_max_job_size(max_job_size)
+                        .with_tick_interval_ms(tick_interval_ms)
+                        .with_connection_limits(connection_limits(&r));
+                    if let Some(threshold_bytes) = r.compress_bodies_over_bytes {
+                        server = server.with_compress_bodies_over_bytes(threshold_bytes);
+                    }
+                    if let Some(max_timeouts) = r.max_timeouts {
+                        server = server.with_max_timeouts(max_timeouts);
+                    }
+                    if let Some(max_pending_jobs) = r.max_pending_jobs {
+                        server = server.with_max_pending_jobs(max_pending_jobs);
+                    }
+                    server = server.with_watchdog(watchdog_thresholds(&r));
+                    let metrics = Metrics::from_setting(r.statsd_addr.as_deref());
+                    if metrics.is_enabled() {
+                        server = server.with_metrics(Arc::new(metrics));
+                    }
+                    if let Some(ref addr) = r.metrics_http_addr {
+                        server = server.with_metrics_http_addr(addr.clone());
+                    }
+                    if let Err(e) = server.listen_and_serve() {
+                        println!("JSON line server failed: {}", e);
+                        process::exit(1);
+                    }
+                }
                 _ => println!("Unknown mode. Exiting..."),
             }
         }
         Result::Err(r) => println!("Error parsing config: {:?}", r),
     }
 }
+
+/// Returns the limits clients of the servers are held to, the defaults unless configured otherwise
+fn watchdog_thresholds(settings: &settings::Settings) -> WatchdogThresholds {
+    WatchdogThresholds {
+        max_ms_between_walks: settings.watchdog_max_ms_between_walks,
+        max_spokes: settings.watchdog_max_spokes,
+        max_past_jobs: settings.watchdog_max_past_jobs,
+    }
+}
+
+fn connection_limits(settings: &settings::Settings) -> ConnectionLimits {
+    let defaults = ConnectionLimits::default();
+    ConnectionLimits {
+        max_connections: settings
+            .max_connections
+            .unwrap_or(defaults.max_connections),
+        client_idle_timeout: settings
+            .client_idle_timeout_ms
+            .map_or(defaults.client_idle_timeout, Duration::from_millis),
+        max_reserved_jobs: settings
+            .max_reserved_jobs
+            .unwrap_or(defaults.max_reserved_jobs),
+    }
+}
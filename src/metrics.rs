@@ -0,0 +1,187 @@
+//! Sends the demo's metrics and the hubs' gauges to statsd, if a `statsd_addr` is configured.
+//!
+//! [`Metrics`] is shared between threads behind an `Arc`. Without an address, or with one the
+//! client can't be set up for, every call is a no-op, so the demo runs without a statsd daemon.
+//!
+//! The servers hand every tube's hub the [`HubMetrics`] a [`ServerMetrics`] makes for it - statsd
+//! [`TubeMetrics`], the Prometheus endpoint's, see [`prometheus`](::prometheus), or both.
+
+use statsd::Client;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+use yaad::gauges::{HubGauges, HubMetrics};
+
+/// Prefix of every metric sent
+pub const METRIC_PREFIX: &str = "yaad.";
+
+pub struct Metrics {
+    client: Option<Client>,
+}
+
+impl Metrics {
+    /// Sends metrics to the statsd server at `addr`. A bad address is reported and leaves the
+    /// metrics disabled.
+    pub fn new(addr: &str) -> Metrics {
+        match Client::new(addr, METRIC_PREFIX) {
+            Ok(client) => Metrics {
+                client: Some(client),
+            },
+            Err(e) => {
+                warn!("Not sending metrics to statsd at {}: {}", addr, e);
+                Metrics::disabled()
+            }
+        }
+    }
+
+    /// Sends metrics to `statsd_addr` if set, and nowhere otherwise
+    pub fn from_setting(statsd_addr: Option<&str>) -> Metrics {
+        statsd_addr.map_or_else(Metrics::disabled, Metrics::new)
+    }
+
+    /// Drops every metric
+    pub fn disabled() -> Metrics {
+        Metrics { client: None }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.client.is_some()
+    }
+
+    pub fn incr(&self, metric: &str) {
+        if let Some(ref client) = self.client {
+            client.incr(metric);
+        }
+    }
+
+    /// Sets the gauge `metric` to `value`
+    pub fn gauge(&self, metric: &str, value: u64) {
+        if let Some(ref client) = self.client {
+            client.gauge(metric, value as f64);
+        }
+    }
+
+    /// Sends `ms` as the timing `metric`
+    pub fn timing(&self, metric: &str, ms: u64) {
+        if let Some(ref client) = self.client {
+            client.timer(metric, ms as f64);
+        }
+    }
+
+    /// Runs `f`, sending how long it took as `metric`
+    pub fn time<F: FnOnce() -> R, R>(&self, metric: &str, f: F) -> R {
+        match self.client {
+            Some(ref client) => client.time(metric, f),
+            None => f(),
+        }
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Metrics")
+            .field("enabled", &self.is_enabled())
+            .finish()
+    }
+}
+
+/// Sends a hub's gauges as `hub.<gauge>`
+impl HubMetrics for Metrics {
+    fn record_gauges(&self, gauges: &HubGauges) {
+        for &(name, value) in &gauges.named() {
+            self.gauge(&format!("hub.{}", name), value);
+        }
+    }
+}
+
+/// Sends the gauges of a tube's hub as `tube.<tube>.hub.<gauge>`
+#[derive(Debug)]
+pub struct TubeMetrics {
+    prefix: String,
+    metrics: Arc<Metrics>,
+}
+
+impl TubeMetrics {
+    pub fn new(tube: &str, metrics: Arc<Metrics>) -> TubeMetrics {
+        TubeMetrics {
+            prefix: format!("tube.{}.hub.", tube),
+            metrics,
+        }
+    }
+}
+
+impl HubMetrics for TubeMetrics {
+    fn record_gauges(&self, gauges: &HubGauges) {
+        for &(name, value) in &gauges.named() {
+            self.metrics.gauge(&format!("{}{}", self.prefix, name), value);
+        }
+    }
+}
+
+/// Where a server's tubes report their hubs' metrics, each tube under its own name
+pub trait ServerMetrics: Debug + Send + Sync {
+    /// Returns what the hub of `tube` reports to
+    fn for_tube(self: Arc<Self>, tube: &str) -> Arc<dyn HubMetrics>;
+}
+
+impl ServerMetrics for Metrics {
+    fn for_tube(self: Arc<Self>, tube: &str) -> Arc<dyn HubMetrics> {
+        Arc::new(TubeMetrics::new(tube, self))
+    }
+}
+
+/// Reports to every one of several metrics backends
+#[derive(Debug)]
+pub struct Fanout<M: ?Sized>(pub Vec<Arc<M>>);
+
+impl ServerMetrics for Fanout<dyn ServerMetrics> {
+    fn for_tube(self: Arc<Self>, tube: &str) -> Arc<dyn HubMetrics> {
+        let tube_metrics = self.0.iter().map(|m| Arc::clone(m).for_tube(tube));
+        Arc::new(Fanout(tube_metrics.collect()))
+    }
+}
+
+impl HubMetrics for Fanout<dyn HubMetrics> {
+    fn record_gauges(&self, gauges: &HubGauges) {
+        for m in &self.0 {
+            m.record_gauges(gauges);
+        }
+    }
+
+    fn record_jobs_added(&self, jobs: usize) {
+        for m in &self.0 {
+            m.record_jobs_added(jobs);
+        }
+    }
+
+    fn record_delivery(&self, lag_ms: u64) {
+        for m in &self.0 {
+            m.record_delivery(lag_ms);
+        }
+    }
+
+    fn record_job_cancelled(&self) {
+        for m in &self.0 {
+            m.record_job_cancelled();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_without_statsd() {
+        let metrics = Metrics::from_setting(None);
+        assert!(!metrics.is_enabled());
+        metrics.incr("demojob.produced.count");
+        metrics.timing("demojob.delivery_lag", 5);
+        metrics.gauge("hub.spokes", 3);
+        metrics.record_gauges(&HubGauges::default());
+        assert_eq!(metrics.time("demojob.addjob.duration", || 42), 42);
+
+        let metrics = Metrics::from_setting(Some("not an address"));
+        assert!(!metrics.is_enabled(), "A bad address disables the metrics");
+        assert!(Metrics::from_setting(Some("127.0.0.1:8125")).is_enabled());
+    }
+}
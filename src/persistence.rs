@@ -0,0 +1,464 @@
+//! Snapshots of scheduled jobs, so a restarted process picks up where the last one left off.
+//!
+//! A snapshot is the magic bytes `YAAD`, a format version byte and then one length-prefixed record
+//! per job. Each record is labelled with the name of the queue the job belongs to, so one snapshot
+//! can hold several hubs - a lone hub uses the empty label - and says whether the job was
+//! scheduled, reserved or buried, see [`SavedState`], and how often it was reserved, released and
+//! timed out. All integers are big endian:
+//!
+//! ```text
+//! | label_len: u8 | label | id: 16 bytes | trigger_at_ms: u64 | ttr_ms: u64 | priority: u32 |
+//! | created_at_ms: u64 | state: u8 | reserves: u32 | releases: u32 | timeouts: u32 |
+//! | body_len: u32 | body |
+//! ```
+//!
+//! Snapshots written by older releases are read too, so an upgrade picks up the jobs the last
+//! release left behind. Their records lack the fields added since, which take their defaults:
+//!
+//! - version 1 records have no label, priority or creation time, and restore to the empty label
+//! - version 2 records have no priority or creation time
+//! - version 3 records have no creation time
+//! - version 4 records have no state or counts
+//!
+//! A missing priority is [`DEFAULT_PRIORITY`], a missing creation time is the time of the
+//! restore, a missing state is [`SavedState::Ready`] and missing counts are 0.
+
+use job::{Job, DEFAULT_PRIORITY};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use uuid::Uuid;
+
+const MAGIC: &[u8; 4] = b"YAAD";
+const VERSION: u8 = 5;
+/// The oldest version still read, every version from it up to [`VERSION`] is
+const OLDEST_VERSION: u8 = 1;
+
+/// Where a job was when its snapshot was taken
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SavedState {
+    /// Scheduled in a spoke, due or not
+    Ready,
+    /// Handed to a consumer that hadn't acknowledged it yet
+    Reserved,
+    /// Shelved until kicked
+    Buried,
+}
+
+impl SavedState {
+    fn to_byte(self) -> u8 {
+        match self {
+            SavedState::Ready => 0,
+            SavedState::Reserved => 1,
+            SavedState::Buried => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<SavedState> {
+        match byte {
+            0 => Ok(SavedState::Ready),
+            1 => Ok(SavedState::Reserved),
+            2 => Ok(SavedState::Buried),
+            _ => Err(invalid_data("Unknown job state")),
+        }
+    }
+}
+
+/// Writes a snapshot holding the labelled `jobs`, each in the state given with it, and returns
+/// the number of jobs written
+pub fn write_jobs<'a, W, I>(writer: &mut W, jobs: I) -> io::Result<usize>
+where
+    W: Write,
+    I: IntoIterator<Item = (&'a str, &'a Job, SavedState)>,
+{
+    let mut snapshot = SnapshotWriter::new(writer)?;
+    for (label, job, state) in jobs {
+        snapshot.write_job(label, job, state)?;
+    }
+    snapshot.finish()
+}
+
+/// Writes a snapshot one record at a time, so the jobs don't all have to be at hand at once
+pub struct SnapshotWriter<W: Write> {
+    writer: W,
+    count: usize,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    /// Starts a snapshot by writing its header
+    pub fn new(mut writer: W) -> io::Result<SnapshotWriter<W>> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        Ok(SnapshotWriter { writer, count: 0 })
+    }
+
+    /// Writes the record of `job` in `state`, labelled with `label`
+    pub fn write_job(&mut self, label: &str, job: &Job, state: SavedState) -> io::Result<()> {
+        let body = job.get_body();
+        let body = body.as_bytes();
+        if label.len() > u8::MAX as usize {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Label too long"));
+        }
+        if body.len() > u32::MAX as usize {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Job body too large"));
+        }
+        let writer = &mut self.writer;
+        writer.write_all(&[label.len() as u8])?;
+        writer.write_all(label.as_bytes())?;
+        writer.write_all(job.get_metadata().get_id().as_bytes())?;
+        writer.write_all(&job.trigger_at_ms().to_be_bytes())?;
+        writer.write_all(&job.ttr_ms().to_be_bytes())?;
+        writer.write_all(&job.priority().to_be_bytes())?;
+        writer.write_all(&job.created_at_ms().to_be_bytes())?;
+        writer.write_all(&[state.to_byte()])?;
+        writer.write_all(&job.reserves().to_be_bytes())?;
+        writer.write_all(&job.releases().to_be_bytes())?;
+        writer.write_all(&job.timeouts().to_be_bytes())?;
+        writer.write_all(&(body.len() as u32).to_be_bytes())?;
+        writer.write_all(body)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Returns the number of jobs written so far
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Flushes the snapshot and returns the number of jobs written
+    pub fn finish(mut self) -> io::Result<usize> {
+        self.writer.flush()?;
+        Ok(self.count)
+    }
+}
+
+/// Reads back every labelled job in a snapshot written by [`write_jobs`], along with its state
+pub fn read_jobs<R: Read>(reader: &mut R) -> io::Result<Vec<(String, Job, SavedState)>> {
+    let mut header = [0u8; 5];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Err(invalid_data("Not a yaad snapshot"));
+    }
+    let version = header[4];
+    if !(OLDEST_VERSION..=VERSION).contains(&version) {
+        return Err(invalid_data("Unknown yaad snapshot version"));
+    }
+    let mut jobs = vec![];
+    loop {
+        // Records of version 1 start with the id, all later ones with the label
+        let mut first = [0u8; 1];
+        if fill(reader, &mut first)? == 0 {
+            return Ok(jobs);
+        }
+        let mut id = [0u8; 16];
+        let label = if version >= 2 {
+            let mut label = vec![0u8; first[0] as usize];
+            reader.read_exact(&mut label)?;
+            reader.read_exact(&mut id)?;
+            String::from_utf8(label).map_err(|_| invalid_data("Label is not utf-8"))?
+        } else {
+            id[0] = first[0];
+            reader.read_exact(&mut id[1..])?;
+            String::new()
+        };
+        let id = Uuid::from_bytes(&id).map_err(|_| invalid_data("Bad job id"))?;
+        let trigger_at_ms = read_u64(reader)?;
+        let ttr_ms = read_u64(reader)?;
+        let priority = if version >= 3 { read_u32(reader)? } else { DEFAULT_PRIORITY };
+        let created_at_ms = if version >= 4 { Some(read_u64(reader)?) } else { None };
+        let (state, counts) = if version >= 5 {
+            let mut state = [0u8; 1];
+            reader.read_exact(&mut state)?;
+            let state = SavedState::from_byte(state[0])?;
+            (state, (read_u32(reader)?, read_u32(reader)?, read_u32(reader)?))
+        } else {
+            (SavedState::Ready, (0, 0, 0))
+        };
+        let mut body = vec![0u8; read_u32(reader)? as usize];
+        reader.read_exact(&mut body)?;
+        let job = Job::try_new(id, trigger_at_ms, body)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
+            .with_ttr_ms(ttr_ms)
+            .with_priority(priority)
+            .with_counts(counts.0, counts.1, counts.2);
+        let job = match created_at_ms {
+            Some(created_at_ms) => job.with_created_at_ms(created_at_ms),
+            None => job,
+        };
+        jobs.push((label, job, state));
+    }
+}
+
+/// Writes a snapshot to `path`. The file is replaced atomically, so a crash midway leaves the
+/// previous snapshot intact.
+pub fn save<'a, I>(path: &Path, jobs: I) -> io::Result<usize>
+where
+    I: IntoIterator<Item = (&'a str, &'a Job, SavedState)>,
+{
+    save_with(path, |snapshot| {
+        for (label, job, state) in jobs {
+            snapshot.write_job(label, job, state)?;
+        }
+        Ok(())
+    })
+}
+
+/// Writes a snapshot to `path` with the records `write` hands to the writer, replacing the file
+/// atomically like [`save`]. Returns once the snapshot is on disk, the rename included. Returns
+/// the number of jobs written.
+pub fn save_with<F>(path: &Path, write: F) -> io::Result<usize>
+where
+    F: FnOnce(&mut SnapshotWriter<BufWriter<File>>) -> io::Result<()>,
+{
+    let tmp = path.with_extension("tmp");
+    let count = {
+        let mut snapshot = SnapshotWriter::new(BufWriter::new(File::create(&tmp)?))?;
+        write(&mut snapshot)?;
+        let count = snapshot.count();
+        let file = snapshot.writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        count
+    };
+    fs::rename(&tmp, path)?;
+    // The rename is only durable once the directory holding the file is synced too
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(count)
+}
+
+/// Reads until `buf` is full or the reader runs out, returning the number of bytes read
+fn fill<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn round_trips_jobs() {
+        let jobs = [
+            ("", Job::new_auto_id(1, "one"), SavedState::Ready),
+            (
+                "emails",
+                Job::new_auto_id(2, &b"line\r\nbreak\xff"[..])
+                    .with_ttr_ms(5_000)
+                    .with_priority(7)
+                    .with_created_at_ms(1_234)
+                    .with_counts(3, 2, 1),
+                SavedState::Reserved,
+            ),
+            ("", Job::new_auto_id(3, ""), SavedState::Buried),
+        ];
+        let mut buf = vec![];
+        assert_eq!(write_jobs(&mut buf, jobs.iter().map(|j| (j.0, &j.1, j.2))).unwrap(), 3);
+
+        let read = read_jobs(&mut &buf[..]).unwrap();
+        assert_eq!(read.len(), 3);
+        for ((a_label, a, a_state), (b_label, b, b_state)) in jobs.iter().zip(read.iter()) {
+            assert_eq!(a_label, b_label);
+            assert_eq!(a_state, b_state);
+            assert_eq!(a, b);
+            assert_eq!(a.trigger_at_ms(), b.trigger_at_ms());
+            assert_eq!(a.ttr_ms(), b.ttr_ms());
+            assert_eq!(a.priority(), b.priority());
+            assert_eq!(a.created_at_ms(), b.created_at_ms());
+            assert_eq!(
+                (a.reserves(), a.releases(), a.timeouts()),
+                (b.reserves(), b.releases(), b.timeouts())
+            );
+            assert_eq!(a.get_body().as_bytes(), b.get_body().as_bytes());
+        }
+    }
+
+    /// Returns a snapshot of `version` holding one job record, laid out by hand as that version
+    /// wrote it: `fields` are the big endian integers between the id and the body
+    fn old_snapshot(version: u8, label: Option<&str>, id: Uuid, fields: &[&[u8]]) -> Vec<u8> {
+        let mut buf = b"YAAD".to_vec();
+        buf.push(version);
+        if let Some(label) = label {
+            buf.push(label.len() as u8);
+            buf.extend_from_slice(label.as_bytes());
+        }
+        buf.extend_from_slice(id.as_bytes());
+        for field in fields {
+            buf.extend_from_slice(field);
+        }
+        buf.extend_from_slice(&2u32.to_be_bytes());
+        buf.extend_from_slice(b"hi");
+        buf
+    }
+
+    #[test]
+    fn reads_version_1_snapshots() {
+        let id = Uuid::new_v4();
+        let before_ms = ::times::current_time_ms();
+        let buf = old_snapshot(1, None, id, &[&1_500u64.to_be_bytes(), &9_000u64.to_be_bytes()]);
+        let jobs = read_jobs(&mut &buf[..]).unwrap();
+        assert_eq!(jobs.len(), 1);
+        let (ref label, ref job, state) = jobs[0];
+        assert_eq!(state, SavedState::Ready);
+        assert_eq!(label, "", "Jobs without a label belong to the lone hub");
+        assert_eq!(job.get_metadata().get_id(), id);
+        assert_eq!(job.trigger_at_ms(), 1_500);
+        assert_eq!(job.ttr_ms(), 9_000);
+        assert_eq!(job.priority(), DEFAULT_PRIORITY);
+        assert!(job.created_at_ms() >= before_ms, "Created when restored");
+        assert_eq!(job.get_body().as_bytes(), b"hi");
+    }
+
+    #[test]
+    fn reads_version_2_snapshots() {
+        let id = Uuid::new_v4();
+        let fields: &[&[u8]] = &[&1_500u64.to_be_bytes(), &9_000u64.to_be_bytes()];
+        let buf = old_snapshot(2, Some("emails"), id, fields);
+        let jobs = read_jobs(&mut &buf[..]).unwrap();
+        assert_eq!(jobs.len(), 1);
+        let (ref label, ref job, state) = jobs[0];
+        assert_eq!(state, SavedState::Ready);
+        assert_eq!(label, "emails");
+        assert_eq!(job.get_metadata().get_id(), id);
+        assert_eq!(job.trigger_at_ms(), 1_500);
+        assert_eq!(job.ttr_ms(), 9_000);
+        assert_eq!(job.priority(), DEFAULT_PRIORITY);
+        assert_eq!(job.get_body().as_bytes(), b"hi");
+    }
+
+    #[test]
+    fn reads_version_3_snapshots() {
+        let id = Uuid::new_v4();
+        let before_ms = ::times::current_time_ms();
+        let fields: &[&[u8]] =
+            &[&1_500u64.to_be_bytes(), &9_000u64.to_be_bytes(), &7u32.to_be_bytes()];
+        let buf = old_snapshot(3, Some("emails"), id, fields);
+        let jobs = read_jobs(&mut &buf[..]).unwrap();
+        assert_eq!(jobs.len(), 1);
+        let (ref label, ref job, state) = jobs[0];
+        assert_eq!(state, SavedState::Ready);
+        assert_eq!(label, "emails");
+        assert_eq!(job.get_metadata().get_id(), id);
+        assert_eq!(job.ttr_ms(), 9_000);
+        assert_eq!(job.priority(), 7);
+        assert!(job.created_at_ms() >= before_ms, "Created when restored");
+        assert_eq!(job.get_body().as_bytes(), b"hi");
+    }
+
+    #[test]
+    fn reads_version_4_snapshots() {
+        let id = Uuid::new_v4();
+        let fields: &[&[u8]] = &[
+            &1_500u64.to_be_bytes(),
+            &9_000u64.to_be_bytes(),
+            &7u32.to_be_bytes(),
+            &1_234u64.to_be_bytes(),
+        ];
+        let buf = old_snapshot(4, Some("emails"), id, fields);
+        let jobs = read_jobs(&mut &buf[..]).unwrap();
+        assert_eq!(jobs.len(), 1);
+        let (ref label, ref job, state) = jobs[0];
+        assert_eq!(state, SavedState::Ready, "Version 4 didn't record states");
+        assert_eq!(label, "emails");
+        assert_eq!(job.get_metadata().get_id(), id);
+        assert_eq!(job.priority(), 7);
+        assert_eq!(job.created_at_ms(), 1_234);
+        assert_eq!((job.reserves(), job.releases(), job.timeouts()), (0, 0, 0));
+        assert_eq!(job.get_body().as_bytes(), b"hi");
+    }
+
+    #[test]
+    fn rejects_bad_snapshots() {
+        assert!(read_jobs(&mut &b"NOPE\x02"[..]).is_err());
+        assert!(read_jobs(&mut &b"YAAD\x00"[..]).is_err(), "Unknown versions are rejected");
+        assert!(read_jobs(&mut &[&b"YAAD"[..], &[VERSION + 1]].concat()[..]).is_err());
+
+        let mut buf = vec![];
+        let job = Job::new_auto_id(1, "truncated");
+        write_jobs(&mut buf, vec![("tube", &job, SavedState::Ready)]).unwrap();
+        let full_len = buf.len();
+        for cut in &[2, full_len - 7, full_len - 10] {
+            assert_eq!(
+                read_jobs(&mut &buf[..full_len - cut]).unwrap_err().kind(),
+                ErrorKind::UnexpectedEof,
+                "A record cut short is an error, not the end of the snapshot"
+            );
+        }
+        // The state byte comes right before the counts, the body length and the body
+        buf[full_len - 26] = 3;
+        assert_eq!(
+            read_jobs(&mut &buf[..]).unwrap_err().kind(),
+            ErrorKind::InvalidData,
+            "Unknown states are rejected"
+        );
+        let id = Uuid::new_v5(&::uuid::NAMESPACE_DNS, "yaad");
+        let buf = old_snapshot(1, None, id, &[&1u64.to_be_bytes(), &1u64.to_be_bytes()]);
+        assert_eq!(
+            read_jobs(&mut &buf[..]).unwrap_err().kind(),
+            ErrorKind::InvalidData,
+            "Only uuid v4 ids are accepted"
+        );
+    }
+
+    #[test]
+    fn saves_snapshot_files() {
+        let path = env::temp_dir().join(format!("yaad-snapshot-test-{}", process::id()));
+        let (a, b) = (Job::new_auto_id(1, "a"), Job::new_auto_id(2, "b"));
+        save(&path, vec![("", &a, SavedState::Ready)]).unwrap();
+        save(&path, vec![("", &a, SavedState::Ready), ("", &b, SavedState::Buried)]).unwrap();
+        assert!(!path.with_extension("tmp").exists(), "Temporary file is renamed into place");
+
+        let jobs = read_jobs(&mut File::open(&path).unwrap()).unwrap();
+        assert_eq!(jobs.len(), 2, "Later snapshots replace earlier ones");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn failed_streamed_snapshots_keep_the_previous_one() {
+        let path = env::temp_dir().join(format!("yaad-streamed-test-{}", process::id()));
+        let written = save_with(&path, |snapshot| {
+            snapshot.write_job("a", &Job::new_auto_id(1, "a"), SavedState::Ready)?;
+            snapshot.write_job("b", &Job::new_auto_id(2, "b"), SavedState::Ready)
+        });
+        assert_eq!(written.unwrap(), 2);
+
+        let failed = save_with(&path, |snapshot| {
+            snapshot.write_job("c", &Job::new_auto_id(3, "c"), SavedState::Ready)?;
+            Err(io::Error::other("Interrupted"))
+        });
+        assert!(failed.is_err());
+        let jobs = read_jobs(&mut File::open(&path).unwrap()).unwrap();
+        let labels: Vec<&str> = jobs.iter().map(|j| j.0.as_str()).collect();
+        assert_eq!(labels, vec!["a", "b"]);
+        fs::remove_file(&path).unwrap();
+        let _ = fs::remove_file(path.with_extension("tmp"));
+    }
+}
@@ -0,0 +1,403 @@
+//! Serves the hubs' metrics over HTTP in the Prometheus text format, if a `metrics_http_addr` is
+//! configured.
+//!
+//! [`PrometheusMetrics`] keeps what every tube's hub reports to it: counters of the jobs added,
+//! delivered and cancelled, the hub's gauges as of its last tick, and a histogram of how long
+//! after their trigger time jobs were delivered. A [`MetricsEndpoint`] answers `GET /metrics` with
+//! all of it, on a thread of its own, until the server it runs alongside shuts down.
+//!
+//! Every series is labelled with its tube, e.g. `yaad_jobs_added_total{tube="default"} 3`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use metrics::{Fanout, ServerMetrics};
+use yaad::gauges::{HubGauges, HubMetrics};
+
+/// Prefix of every metric served
+pub const METRIC_PREFIX: &str = "yaad_";
+/// Upper bounds of the delivery lag histogram's buckets, in ms
+pub const DELIVERY_LAG_BUCKETS_MS: [u64; 10] =
+    [1, 5, 10, 50, 100, 500, 1_000, 5_000, 10_000, 60_000];
+/// How often the endpoint checks whether it should stop while no scrape comes in
+const POLL_MS: u64 = 50;
+/// How long a scraper gets to send its request
+const READ_TIMEOUT_MS: u64 = 5_000;
+/// Longest request head read, anything past it is ignored
+const MAX_REQUEST_LEN: usize = 8_192;
+
+/// Every tube's metrics, as the hubs reported them
+#[derive(Debug, Default)]
+pub struct PrometheusMetrics {
+    tubes: Mutex<BTreeMap<String, TubeSeries>>,
+}
+
+/// What the hub of one tube reported
+#[derive(Debug, Default, Clone, PartialEq)]
+struct TubeSeries {
+    jobs_added: u64,
+    jobs_delivered: u64,
+    jobs_cancelled: u64,
+    gauges: HubGauges,
+    /// Deliveries per bucket of [`DELIVERY_LAG_BUCKETS_MS`], each only counting the lags above
+    /// the bucket before it. Lags past the last bucket are only counted in `jobs_delivered`.
+    lag_buckets: [u64; DELIVERY_LAG_BUCKETS_MS.len()],
+    lag_sum_ms: u64,
+}
+
+impl PrometheusMetrics {
+    pub fn new() -> PrometheusMetrics {
+        PrometheusMetrics::default()
+    }
+
+    fn update<F: FnOnce(&mut TubeSeries)>(&self, tube: &str, f: F) {
+        let mut tubes = self.tubes.lock().unwrap();
+        match tubes.get_mut(tube) {
+            Some(series) => f(series),
+            None => f(tubes.entry(tube.to_owned()).or_default()),
+        }
+    }
+
+    /// Returns every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let tubes = self.tubes.lock().unwrap().clone();
+        let mut out = String::new();
+        let counter_names = TubeSeries::default().counters();
+        for (i, &(name, help, _)) in counter_names.iter().enumerate() {
+            header(&mut out, name, help, "counter");
+            for (tube, series) in &tubes {
+                sample(&mut out, name, &[("tube", tube)], series.counters()[i].2);
+            }
+        }
+
+        let gauge_names = HubGauges::default().named();
+        for (i, &(gauge, _)) in gauge_names.iter().enumerate() {
+            let name = format!("hub_{}", gauge);
+            header(&mut out, &name, gauge_help(gauge), "gauge");
+            for (tube, series) in &tubes {
+                sample(&mut out, &name, &[("tube", tube)], series.gauges.named()[i].1);
+            }
+        }
+
+        let name = "delivery_lag_ms";
+        header(&mut out, name, "How long after their trigger time jobs were walked", "histogram");
+        for (tube, series) in &tubes {
+            let mut delivered = 0;
+            for (bound, count) in DELIVERY_LAG_BUCKETS_MS.iter().zip(&series.lag_buckets) {
+                delivered += count;
+                let le = bound.to_string();
+                let labels = [("tube", tube.as_str()), ("le", le.as_str())];
+                sample(&mut out, "delivery_lag_ms_bucket", &labels, delivered);
+            }
+            let labels = [("tube", tube.as_str()), ("le", "+Inf")];
+            sample(&mut out, "delivery_lag_ms_bucket", &labels, series.jobs_delivered);
+            sample(&mut out, "delivery_lag_ms_sum", &[("tube", tube)], series.lag_sum_ms);
+            sample(&mut out, "delivery_lag_ms_count", &[("tube", tube)], series.jobs_delivered);
+        }
+        out
+    }
+}
+
+impl TubeSeries {
+    /// Returns every counter with its name and help line
+    fn counters(&self) -> [(&'static str, &'static str, u64); 3] {
+        [
+            ("jobs_added_total", "Jobs added to the tube", self.jobs_added),
+            ("jobs_delivered_total", "Jobs walked off the tube's hub", self.jobs_delivered),
+            (
+                "jobs_cancelled_total",
+                "Jobs cancelled before they were walked",
+                self.jobs_cancelled,
+            ),
+        ]
+    }
+}
+
+impl ServerMetrics for PrometheusMetrics {
+    fn for_tube(self: Arc<Self>, tube: &str) -> Arc<dyn HubMetrics> {
+        self.update(tube, |_| {});
+        Arc::new(PrometheusTube {
+            tube: tube.to_owned(),
+            metrics: self,
+        })
+    }
+}
+
+/// Where the hub of one tube reports to
+#[derive(Debug)]
+struct PrometheusTube {
+    tube: String,
+    metrics: Arc<PrometheusMetrics>,
+}
+
+impl HubMetrics for PrometheusTube {
+    fn record_gauges(&self, gauges: &HubGauges) {
+        self.metrics.update(&self.tube, |s| s.gauges = *gauges);
+    }
+
+    fn record_jobs_added(&self, jobs: usize) {
+        self.metrics.update(&self.tube, |s| s.jobs_added += jobs as u64);
+    }
+
+    fn record_delivery(&self, lag_ms: u64) {
+        self.metrics.update(&self.tube, |s| {
+            s.jobs_delivered += 1;
+            s.lag_sum_ms = s.lag_sum_ms.saturating_add(lag_ms);
+            if let Some(i) = DELIVERY_LAG_BUCKETS_MS.iter().position(|&b| lag_ms <= b) {
+                s.lag_buckets[i] += 1;
+            }
+        });
+    }
+
+    fn record_job_cancelled(&self) {
+        self.metrics.update(&self.tube, |s| s.jobs_cancelled += 1);
+    }
+}
+
+/// Returns the help line of one of the [`HubGauges`]
+fn gauge_help(gauge: &str) -> &'static str {
+    match gauge {
+        "spokes" => "Spokes the tube's hub keeps, not counting its past spoke",
+        "oldest_expired_spoke_age_ms" => "How long ago the oldest expired spoke still kept ended",
+        "past_jobs" => "Jobs due and waiting in the past spoke to be walked",
+        "reserved_jobs" => "Jobs reserved and not yet deleted, released or buried",
+        _ => "A gauge of the tube's hub",
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {}{} {}", METRIC_PREFIX, name, help);
+    let _ = writeln!(out, "# TYPE {}{} {}", METRIC_PREFIX, name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: u64) {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|&(label, value)| format!("{}=\"{}\"", label, escape_label(value)))
+        .collect();
+    let _ = writeln!(out, "{}{}{{{}}} {}", METRIC_PREFIX, name, labels.join(","), value);
+}
+
+/// Escapes a label value as the text format wants it
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Answers `GET /metrics` with the metrics of a [`PrometheusMetrics`] until stopped, one scrape
+/// at a time. Dropping the endpoint stops it too.
+#[derive(Debug)]
+pub struct MetricsEndpoint {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsEndpoint {
+    /// Binds to `addr` and serves `metrics` on a thread of its own
+    pub fn bind(addr: &str, metrics: Arc<PrometheusMetrics>) -> io::Result<MetricsEndpoint> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = thread::Builder::new()
+            .name("metrics-http".into())
+            .spawn(move || {
+                while !stopped.load(Ordering::SeqCst) {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            if let Err(e) = serve_scrape(stream, &metrics) {
+                                error!("Failed to serve metrics scrape: {}", e);
+                            }
+                        }
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                            thread::sleep(Duration::from_millis(POLL_MS));
+                        }
+                        Err(e) => error!("Failed to accept metrics scrape: {}", e),
+                    }
+                }
+            })?;
+        Ok(MetricsEndpoint {
+            addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the address the endpoint listens on, e.g. the port picked for `127.0.0.1:0`
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops serving and waits for the scrape being served, if any, to finish
+    pub fn stop(mut self) {
+        self.shut_down();
+    }
+
+    fn shut_down(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Metrics endpoint panicked");
+            }
+        }
+    }
+}
+
+impl Drop for MetricsEndpoint {
+    fn drop(&mut self) {
+        self.shut_down();
+    }
+}
+
+/// Binds a [`MetricsEndpoint`] to `addr`, if set, to run alongside a server, and makes `metrics`
+/// report to it as well as wherever they reported to before
+pub fn serve_alongside(
+    addr: Option<&str>,
+    metrics: &mut Option<Arc<dyn ServerMetrics>>,
+) -> io::Result<Option<MetricsEndpoint>> {
+    let addr = match addr {
+        Some(addr) => addr,
+        None => return Ok(None),
+    };
+    let prometheus = Arc::new(PrometheusMetrics::new());
+    let endpoint = MetricsEndpoint::bind(addr, Arc::clone(&prometheus))?;
+    info!("Metrics served on: http://{}/metrics", endpoint.local_addr());
+    *metrics = Some(match metrics.take() {
+        Some(other) => Arc::new(Fanout(vec![other, prometheus])),
+        None => prometheus,
+    });
+    Ok(Some(endpoint))
+}
+
+/// Reads one request off `stream` and answers it, closing the connection after
+fn serve_scrape(mut stream: TcpStream, metrics: &PrometheusMetrics) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_millis(READ_TIMEOUT_MS)))?;
+    let mut request = vec![];
+    let mut chunk = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_LEN {
+        match stream.read(&mut chunk)? {
+            0 => break,
+            n => request.extend_from_slice(&chunk[..n]),
+        }
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        (Some("GET"), _) => ("404 Not Found", "Metrics are served at /metrics\n".into()),
+        _ => ("405 Method Not Allowed", "Only GET is served\n".into()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics::Metrics;
+    use yaad::clock::MockClock;
+    use yaad::hub::Hub;
+    use yaad::job::Job;
+
+    const START_MS: u64 = 1_500_000_000_000;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_hub_metrics_over_http() {
+        let metrics = Arc::new(PrometheusMetrics::new());
+        let clock = Arc::new(MockClock::new(START_MS));
+        let mut hub = Hub::new_with_clock(100, clock.clone());
+        hub.set_metrics(Arc::clone(&metrics).for_tube("default"));
+        for &offset in &[120, 150, 180] {
+            hub.add_job(Job::new_auto_id(START_MS + offset, "job")).unwrap();
+        }
+        let cancelled = Job::new_auto_id(START_MS + 190, "cancelled");
+        let cancelled_id = cancelled.get_metadata().get_id();
+        hub.add_job(cancelled).unwrap();
+        assert!(hub.cancel_job(cancelled_id));
+        clock.advance(200);
+        assert_eq!(hub.walk_jobs().len(), 3);
+        hub.tick();
+
+        let endpoint = MetricsEndpoint::bind("127.0.0.1:0", Arc::clone(&metrics)).unwrap();
+        let response = get(endpoint.local_addr(), "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        let lines: Vec<&str> = body.lines().collect();
+        for expected in &[
+            "# TYPE yaad_jobs_added_total counter",
+            "yaad_jobs_added_total{tube=\"default\"} 4",
+            "yaad_jobs_delivered_total{tube=\"default\"} 3",
+            "yaad_jobs_cancelled_total{tube=\"default\"} 1",
+            "# TYPE yaad_hub_spokes gauge",
+            "yaad_hub_spokes{tube=\"default\"} 1",
+            "yaad_hub_past_jobs{tube=\"default\"} 0",
+            "# TYPE yaad_delivery_lag_ms histogram",
+            "yaad_delivery_lag_ms_bucket{tube=\"default\",le=\"10\"} 0",
+            "yaad_delivery_lag_ms_bucket{tube=\"default\",le=\"50\"} 2",
+            "yaad_delivery_lag_ms_bucket{tube=\"default\",le=\"100\"} 3",
+            "yaad_delivery_lag_ms_bucket{tube=\"default\",le=\"+Inf\"} 3",
+            "yaad_delivery_lag_ms_sum{tube=\"default\"} 150",
+            "yaad_delivery_lag_ms_count{tube=\"default\"} 3",
+        ] {
+            assert!(lines.contains(expected), "Missing {} in:\n{}", expected, body);
+        }
+        for line in lines.iter().filter(|l| !l.starts_with('#')) {
+            let value = line.rsplit(' ').next().unwrap();
+            assert!(value.parse::<u64>().is_ok(), "Not a sample: {}", line);
+        }
+
+        assert!(get(endpoint.local_addr(), "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        endpoint.stop();
+    }
+
+    #[test]
+    fn serves_alongside_the_metrics_configured_already() {
+        let mut metrics: Option<Arc<dyn ServerMetrics>> = None;
+        assert!(serve_alongside(None, &mut metrics).unwrap().is_none());
+        assert!(metrics.is_none(), "Nothing to report to without an address");
+
+        let mut metrics: Option<Arc<dyn ServerMetrics>> = Some(Arc::new(Metrics::disabled()));
+        let endpoint = serve_alongside(Some("127.0.0.1:0"), &mut metrics)
+            .unwrap()
+            .unwrap();
+        metrics.unwrap().for_tube("emails").record_jobs_added(2);
+        let response = get(endpoint.local_addr(), "/metrics");
+        assert!(response.contains("\nyaad_jobs_added_total{tube=\"emails\"} 2\n"), "{}", response);
+    }
+
+    #[test]
+    fn stops_serving_when_stopped() {
+        let endpoint = MetricsEndpoint::bind("127.0.0.1:0", Arc::new(PrometheusMetrics::new()))
+            .unwrap();
+        let addr = endpoint.local_addr();
+        let response = get(addr, "/metrics");
+        assert!(response.ends_with("# TYPE yaad_delivery_lag_ms histogram\n"), "No tubes yet");
+        endpoint.stop();
+        assert!(TcpStream::connect(addr).is_err(), "The listener is closed");
+    }
+}
@@ -0,0 +1,244 @@
+//! Splits a client's byte stream into beanstalkd frames.
+//!
+//! Most commands are a single line, but a `put` line is followed by a data block of exactly the
+//! declared number of bytes plus `\r\n`, and the data may itself contain `\r\n`. The decoder reads
+//! command lines until it sees a put, then switches to reading that many raw bytes before going
+//! back to lines. Lines and data blocks are both bounded so a client can't make the server buffer
+//! an unbounded amount of input.
+//!
+//! A data block is split off the read buffer rather than copied out of it, so the job created from
+//! it shares the bytes the client sent.
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use super::{parse_command, Command, ProtocolError};
+
+/// Something decoded from the client's stream
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    /// A command line. A put is always followed by its `Data` or an error.
+    Command(Command),
+    /// The data block of the preceding put, without its trailing `\r\n`
+    Data(Bytes),
+    /// Input that has to be answered with an error. The decoder has already skipped past it.
+    Error(ProtocolError),
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    /// Reading a command line
+    Command,
+    /// Reading a put's data block of this many bytes, plus `\r\n`
+    Data(usize),
+    /// Discarding the rest of an oversized data block
+    SkipBytes(usize),
+    /// Discarding the rest of an oversized command line
+    SkipLine,
+}
+
+pub struct Decoder {
+    state: State,
+    buf: BytesMut,
+    max_line_len: usize,
+    max_body_len: usize,
+}
+
+impl Decoder {
+    /// Creates a decoder accepting command lines of up to `max_line_len` bytes, counting the
+    /// `\r\n`, and put bodies of up to `max_body_len` bytes.
+    pub fn new(max_line_len: usize, max_body_len: usize) -> Decoder {
+        Decoder {
+            state: State::Command,
+            buf: BytesMut::new(),
+            max_line_len,
+            max_body_len,
+        }
+    }
+
+    /// Appends bytes read from the client
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Returns true if no part of a command or data block is buffered, i.e. the client is between
+    /// commands
+    pub fn is_idle(&self) -> bool {
+        self.state == State::Command && self.buf.is_empty()
+    }
+
+    /// Returns the next complete frame, or None if more input is needed. Blank lines are skipped.
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        loop {
+            match self.state {
+                State::Command => match self.buf.iter().position(|&b| b == b'\n') {
+                    Some(end) => {
+                        let line = self.buf.split_to(end + 1);
+                        if line.len() > self.max_line_len {
+                            return Some(Frame::Error(ProtocolError::BadFormat));
+                        }
+                        if line.iter().all(|b| b.is_ascii_whitespace()) {
+                            continue;
+                        }
+                        return Some(self.command(&line));
+                    }
+                    None if self.buf.len() > self.max_line_len => {
+                        self.buf.clear();
+                        self.state = State::SkipLine;
+                        return Some(Frame::Error(ProtocolError::BadFormat));
+                    }
+                    None => return None,
+                },
+                State::Data(len) => {
+                    if self.buf.len() < len + 2 {
+                        return None;
+                    }
+                    let mut data = self.buf.split_to(len + 2);
+                    self.state = State::Command;
+                    if !data.ends_with(b"\r\n") {
+                        return Some(Frame::Error(ProtocolError::ExpectedCrlf));
+                    }
+                    data.truncate(len);
+                    return Some(Frame::Data(data.freeze()));
+                }
+                State::SkipBytes(remaining) => {
+                    let skipped = remaining.min(self.buf.len());
+                    self.buf.advance(skipped);
+                    if skipped < remaining {
+                        self.state = State::SkipBytes(remaining - skipped);
+                        return None;
+                    }
+                    self.state = State::Command;
+                }
+                State::SkipLine => match self.buf.iter().position(|&b| b == b'\n') {
+                    Some(end) => {
+                        self.buf.advance(end + 1);
+                        self.state = State::Command;
+                    }
+                    None => {
+                        self.buf.clear();
+                        return None;
+                    }
+                },
+            }
+        }
+    }
+
+    /// Parses a command line, switching to data mode after a put
+    fn command(&mut self, line: &[u8]) -> Frame {
+        match parse_command(line) {
+            Ok(Command::Put { bytes, .. }) if bytes > self.max_body_len => {
+                // Discard the data block so the next command is read from the right place
+                self.state = State::SkipBytes(bytes.saturating_add(2));
+                Frame::Error(ProtocolError::JobTooBig)
+            }
+            Ok(command) => {
+                if let Command::Put { bytes, .. } = command {
+                    self.state = State::Data(bytes);
+                    // Room for the whole block, so it ends up in one allocation of its own
+                    self.buf.reserve(bytes + 2);
+                }
+                Frame::Command(command)
+            }
+            Err(e) => Frame::Error(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT: &[u8] = b"put 0 0 60 4\r\na\r\nb\r\n\r\nreserve\r\nput 0 0 60 2\r\nabcd\r\n\
+                           put 0 0 60 9\r\n012345678\r\nput 0 0 60 0\r\n\r\n";
+
+    fn decoder() -> Decoder {
+        Decoder::new(32, 8)
+    }
+
+    fn frames(decoder: &mut Decoder) -> Vec<Frame> {
+        let mut frames = vec![];
+        while let Some(frame) = decoder.next_frame() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    fn put(bytes: usize) -> Frame {
+        Frame::Command(Command::Put {
+            priority: 0,
+            delay_ms: 0,
+            ttr_ms: 60_000,
+            bytes,
+        })
+    }
+
+    #[test]
+    fn decodes_chunks_and_bytes_alike() {
+        let mut whole = decoder();
+        whole.feed(INPUT);
+        let whole = frames(&mut whole);
+
+        let mut split = decoder();
+        let mut bytewise = vec![];
+        for b in INPUT {
+            split.feed(&[*b]);
+            bytewise.extend(frames(&mut split));
+        }
+
+        assert_eq!(whole, bytewise);
+        assert_eq!(
+            whole,
+            vec![
+                put(4),
+                Frame::Data(Bytes::from_static(b"a\r\nb")),
+                Frame::Command(Command::Reserve { timeout_ms: None }),
+                put(2),
+                Frame::Error(ProtocolError::ExpectedCrlf),
+                Frame::Error(ProtocolError::JobTooBig),
+                put(0),
+                Frame::Data(Bytes::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_start_of_the_next_frame_buffered() {
+        let mut decoder = decoder();
+        decoder.feed(b"put 0 0 60 2\r\nab\r\nput 0 0 60 2\r\nc");
+        assert_eq!(
+            frames(&mut decoder),
+            vec![put(2), Frame::Data(Bytes::from_static(b"ab")), put(2)]
+        );
+        assert!(!decoder.is_idle(), "The second put's data is incomplete");
+
+        decoder.feed(b"d\r\nres");
+        assert_eq!(frames(&mut decoder), vec![Frame::Data(Bytes::from_static(b"cd"))]);
+        decoder.feed(b"erve\r\n");
+        assert_eq!(
+            frames(&mut decoder),
+            vec![Frame::Command(Command::Reserve { timeout_ms: None })]
+        );
+        assert!(decoder.is_idle());
+    }
+
+    #[test]
+    fn rejects_long_lines_without_buffering_them() {
+        let mut decoder = decoder();
+        decoder.feed(&[b'x'; 40]);
+        assert_eq!(
+            decoder.next_frame(),
+            Some(Frame::Error(ProtocolError::BadFormat))
+        );
+        decoder.feed(&[b'x'; 4_000]);
+        assert_eq!(decoder.next_frame(), None);
+        assert!(decoder.buf.is_empty(), "Rest of the line is discarded");
+        assert!(!decoder.is_idle(), "The long line hasn't ended yet");
+
+        decoder.feed(b"x\r\nreserve\r\n");
+        assert_eq!(
+            decoder.next_frame(),
+            Some(Frame::Command(Command::Reserve { timeout_ms: None }))
+        );
+        assert!(decoder.is_idle());
+    }
+}
@@ -0,0 +1,2348 @@
+//! A [beanstalkd](https://github.com/beanstalkd/beanstalkd/blob/master/doc/protocol.txt)
+//! protocol front end for the Hub.
+//!
+//! Every client connection is served on its own thread against a single shared
+//! [`TubeRegistry`]. A connection stays open across commands - protocol errors are reported back
+//! to the client and the next command is read from the same stream.
+//!
+//! On shutdown the server stops accepting connections and hangs up on each client once it is
+//! between commands, waiting up to a grace period for all of them. Jobs still reserved are then
+//! put back into their tubes so they are handed out again after a restart.
+//!
+//! Besides the standard commands, two extensions take their times in milliseconds instead of
+//! seconds, for clients that know about yaad:
+//!
+//! - `putms <pri> <delay_ms> <ttr_ms> <bytes>\r\n<data>\r\n` works like `put`
+//! - `reserve-with-timeout-ms <timeout_ms>\r\n` works like `reserve-with-timeout`
+//!
+//! Their replies are the same as those of the standard commands.
+//!
+//! A server set up with [`Beanstalkd::with_snapshot_command`] also takes `snapshot <path>\r\n`,
+//! which writes every job to a snapshot file at `path` on the server, a tube at a time so other
+//! clients carry on meanwhile. It is answered with `OK\r\n` once the file is synced to disk, or
+//! `INTERNAL_ERROR\r\n` if it couldn't be written. Servers without it answer
+//! `UNKNOWN_COMMAND\r\n`, as anyone who can connect could overwrite files with it.
+//!
+//! Puts of jobs triggering further ahead than the tubes' `max_future_ms` are refused with
+//! `TOO_FAR_IN_FUTURE\r\n` instead of being inserted.
+//!
+//! Like beanstalkd, the server goes into drain mode on SIGUSR1: puts are answered with
+//! `DRAINING\r\n` while the jobs already put are still handed out. `pause-tube` is the other way
+//! round - it stops handing out a tube's jobs for a while but keeps taking puts. A server set up
+//! with [`Beanstalkd::with_max_pending_jobs`] answers puts on a full tube with `DRAINING\r\n`
+//! too, so standard clients back off until some of its jobs are deleted.
+//!
+//! Malformed input is answered with an error and the connection carries on with the next command,
+//! but a client sending nothing else, like a port scanner or a TLS client, is hung up on after
+//! [`MAX_CONSECUTIVE_ERRORS`] errors in a row.
+//!
+//! Clients are held to the server's [`ConnectionLimits`]: connections over the limit are hung up
+//! on right away, as are clients that stay silent for too long, whose reserved jobs are released
+//! back to their tubes. A reserve by a client holding as many jobs reserved as it may is answered
+//! with `TOO_MANY_RESERVED\r\n`.
+//!
+//! Like beanstalkd, a reserve by a client holding a job whose TTR runs out within a second is
+//! answered with `DEADLINE_SOON\r\n` unless a job is ready, so the client can finish or touch it.
+//! A reserve already waiting is answered so once that second starts.
+
+mod codec;
+
+use bytes::Bytes;
+use metrics::ServerMetrics;
+use prometheus;
+use protocols::core::{self, ConnectionLimits, Reservation, Session};
+use shutdown;
+use std::fs;
+use std::io::{self, ErrorKind, IoSlice, Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use yaad::errors::{RejectReason, YaadError};
+use yaad::hub::{HubConfig, DEFAULT_TICK_INTERVAL_MS};
+use yaad::job::{Job, JobBody};
+use yaad::watchdog::WatchdogThresholds;
+
+use self::codec::{Decoder, Frame};
+pub use protocols::core::{Listener, StatsDict, TubeRegistry, DEFAULT_TUBE};
+
+/// Largest job body accepted by put unless configured otherwise, matching beanstalkd's default
+/// max-job-size
+pub const MAX_JOB_SIZE: usize = 65_535;
+/// Spoke duration of every tube's hub unless configured otherwise
+pub const DEFAULT_SPOKE_DURATION_MS: u64 = 10_000;
+/// Longest command line accepted, counting the trailing `\r\n`, matching beanstalkd's line buffer
+pub const MAX_LINE_LEN: usize = 224;
+/// Longest tube name accepted by use, watch and ignore
+pub const MAX_TUBE_NAME_LEN: usize = 200;
+/// Protocol errors in a row a client is answered with before the connection is closed
+pub const MAX_CONSECUTIVE_ERRORS: usize = 10;
+/// How long clients get to finish their current command on shutdown, unless configured otherwise
+pub const DEFAULT_SHUTDOWN_GRACE_MS: u64 = 5_000;
+/// Most bytes read off a client's stream at once
+const READ_CHUNK_LEN: usize = 4096;
+/// Milliseconds per unit of the times taken by the standard commands
+const SECOND_MS: u64 = 1_000;
+
+pub struct Beanstalkd {
+    addr: Option<String>,
+    unix_socket_path: Option<PathBuf>,
+    snapshot_path: Option<PathBuf>,
+    shutdown_grace: Duration,
+    hub_config: HubConfig,
+    max_job_size: usize,
+    tick_interval: Duration,
+    metrics: Option<Arc<dyn ServerMetrics>>,
+    metrics_http_addr: Option<String>,
+    limits: ConnectionLimits,
+    snapshot_command: bool,
+}
+
+/// What clients may send besides the standard commands
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CommandOptions {
+    /// Puts with bodies over this many bytes are refused
+    pub max_job_size: usize,
+    /// Whether `snapshot <path>` is served
+    pub snapshot_command: bool,
+}
+
+impl CommandOptions {
+    /// Returns the options accepting bodies of up to `max_job_size` bytes, without the snapshot
+    /// command
+    pub fn new(max_job_size: usize) -> CommandOptions {
+        CommandOptions {
+            max_job_size,
+            snapshot_command: false,
+        }
+    }
+}
+
+impl Beanstalkd {
+    /// Creates a server listening on the TCP address `addr`, if set. With a `snapshot_path`, jobs
+    /// are restored from it on startup and written back to it when the server stops. On
+    /// shutdown, clients get `shutdown_grace_ms` to finish their current command.
+    pub fn new(
+        addr: Option<String>,
+        snapshot_path: Option<String>,
+        shutdown_grace_ms: u64,
+    ) -> Beanstalkd {
+        Beanstalkd {
+            addr,
+            unix_socket_path: None,
+            snapshot_path: snapshot_path.map(PathBuf::from),
+            shutdown_grace: Duration::from_millis(shutdown_grace_ms),
+            hub_config: HubConfig::new(DEFAULT_SPOKE_DURATION_MS),
+            max_job_size: MAX_JOB_SIZE,
+            tick_interval: Duration::from_millis(DEFAULT_TICK_INTERVAL_MS),
+            metrics: None,
+            metrics_http_addr: None,
+            limits: ConnectionLimits::default(),
+            snapshot_command: false,
+        }
+    }
+
+    /// Returns this server also listening on a unix socket at `path`, which is removed again when
+    /// the server stops
+    pub fn with_unix_socket_path(mut self, path: String) -> Beanstalkd {
+        self.unix_socket_path = Some(PathBuf::from(path));
+        self
+    }
+
+    /// Returns this server with every tube's hub using spokes of `spoke_duration_ms`
+    pub fn with_spoke_duration_ms(mut self, spoke_duration_ms: u64) -> Beanstalkd {
+        self.hub_config.spoke_duration_ms = spoke_duration_ms;
+        self
+    }
+
+    /// Returns this server refusing puts of jobs that trigger more than `max_future_ms` from now
+    pub fn with_max_future_ms(mut self, max_future_ms: u64) -> Beanstalkd {
+        self.hub_config.max_future_ms = max_future_ms;
+        self
+    }
+
+    /// Returns this server keeping job bodies longer than `threshold_bytes` compressed while
+    /// they wait
+    pub fn with_compress_bodies_over_bytes(mut self, threshold_bytes: usize) -> Beanstalkd {
+        self.hub_config = self.hub_config.with_compress_bodies_over_bytes(threshold_bytes);
+        self
+    }
+
+    /// Returns this server burying reserved jobs whose TTR ran out more than `max_timeouts`
+    /// times instead of handing them out again
+    pub fn with_max_timeouts(mut self, max_timeouts: u32) -> Beanstalkd {
+        self.hub_config = self.hub_config.with_max_timeouts(max_timeouts);
+        self
+    }
+
+    /// Returns this server refusing puts on a tube that holds `max_pending_jobs` jobs already
+    pub fn with_max_pending_jobs(mut self, max_pending_jobs: usize) -> Beanstalkd {
+        self.hub_config = self.hub_config.with_max_pending_jobs(max_pending_jobs);
+        self
+    }
+
+    /// Returns this server logging an error whenever a tube's hub goes over `thresholds`
+    pub fn with_watchdog(mut self, thresholds: WatchdogThresholds) -> Beanstalkd {
+        self.hub_config = self.hub_config.with_watchdog(thresholds);
+        self
+    }
+
+    /// Returns this server accepting job bodies of up to `max_job_size` bytes
+    pub fn with_max_job_size(mut self, max_job_size: usize) -> Beanstalkd {
+        self.max_job_size = max_job_size;
+        self
+    }
+
+    /// Returns this server running the tubes' housekeeping every `tick_interval_ms`
+    pub fn with_tick_interval_ms(mut self, tick_interval_ms: u64) -> Beanstalkd {
+        self.tick_interval = Duration::from_millis(tick_interval_ms);
+        self
+    }
+
+    /// Returns this server sending every tube's hub gauges to `metrics` on each tick
+    pub fn with_metrics(mut self, metrics: Arc<dyn ServerMetrics>) -> Beanstalkd {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Returns this server also serving every tube's metrics at `http://<addr>/metrics` in the
+    /// Prometheus text format, see [`prometheus`](::prometheus)
+    pub fn with_metrics_http_addr(mut self, addr: String) -> Beanstalkd {
+        self.metrics_http_addr = Some(addr);
+        self
+    }
+
+    /// Returns this server holding its clients to `limits`
+    pub fn with_connection_limits(mut self, limits: ConnectionLimits) -> Beanstalkd {
+        self.limits = limits;
+        self
+    }
+
+    /// Returns this server letting clients write snapshots with `snapshot <path>`
+    pub fn with_snapshot_command(mut self, enabled: bool) -> Beanstalkd {
+        self.snapshot_command = enabled;
+        self
+    }
+
+    /// Binds to the configured address and unix socket and serves clients on both until SIGTERM
+    /// or SIGINT arrives, along with the metrics endpoint if one is configured. Puts are refused
+    /// once SIGUSR1 arrives.
+    pub fn listen_and_serve(&self) -> io::Result<()> {
+        let mut listeners: Vec<Listener> = vec![];
+        if let Some(ref addr) = self.addr {
+            listeners.push(TcpListener::bind(addr)?.into());
+        }
+        if let Some(ref path) = self.unix_socket_path {
+            listeners.push(core::bind_unix(path)?.into());
+        }
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Neither an address nor a unix socket path to listen on",
+            ));
+        }
+        for listener in &listeners {
+            let description = listener.describe()?;
+            info!("Beanstalkd listening on: {}", description);
+        }
+
+        let mut metrics = self.metrics.clone();
+        let http_addr = self.metrics_http_addr.as_deref();
+        let endpoint = prometheus::serve_alongside(http_addr, &mut metrics)?;
+
+        let tubes = match self.snapshot_path {
+            Some(ref path) => core::restore(path)?,
+            None => vec![],
+        };
+        let registry = Arc::new(TubeRegistry::from_snapshot(tubes, self.hub_config));
+        if let Some(metrics) = metrics {
+            registry.set_metrics(metrics);
+        }
+        let (trigger, shutdown) = mpsc::channel();
+        shutdown::notify_on_terminate(trigger)?;
+        shutdown::watch_for_drain()?;
+
+        let served = serve_until(
+            listeners,
+            Arc::clone(&registry),
+            &shutdown,
+            self.shutdown_grace,
+            CommandOptions {
+                max_job_size: self.max_job_size,
+                snapshot_command: self.snapshot_command,
+            },
+            self.tick_interval,
+            self.limits,
+        );
+        if let Some(ref path) = self.unix_socket_path {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Failed to remove unix socket {}: {}", path.display(), e);
+            }
+        }
+        if let Some(endpoint) = endpoint {
+            endpoint.stop();
+        }
+        if let Some(ref path) = self.snapshot_path {
+            registry.snapshot_or_log(path);
+        }
+        served
+    }
+}
+
+/// Serves beanstalkd clients on every listener until a message arrives on `shutdown`, see
+/// [`core::serve_until`]. Clients may send the commands `options` allow.
+pub fn serve_until(
+    listeners: Vec<Listener>,
+    registry: Arc<TubeRegistry>,
+    shutdown: &Receiver<()>,
+    grace: Duration,
+    options: CommandOptions,
+    tick_interval: Duration,
+    limits: ConnectionLimits,
+) -> io::Result<()> {
+    core::serve_until(
+        listeners,
+        registry,
+        shutdown,
+        grace,
+        tick_interval,
+        limits,
+        move |client, peer, registry, limits| {
+            handle_client(client, peer, registry, options, limits)
+        },
+    )
+}
+
+/// Errors reported back to the client. The connection stays usable after any of them, unless
+/// the client sends nothing but malformed input, see [`MAX_CONSECUTIVE_ERRORS`].
+#[derive(Debug, PartialEq)]
+enum ProtocolError {
+    BadFormat,
+    UnknownCommand,
+    JobTooBig,
+    ExpectedCrlf,
+    InternalError,
+    TooFarInFuture,
+    Draining,
+    TooManyReserved,
+}
+
+impl ProtocolError {
+    fn reply(&self) -> &'static str {
+        match *self {
+            ProtocolError::BadFormat => "BAD_FORMAT\r\n",
+            ProtocolError::UnknownCommand => "UNKNOWN_COMMAND\r\n",
+            ProtocolError::JobTooBig => "JOB_TOO_BIG\r\n",
+            ProtocolError::ExpectedCrlf => "EXPECTED_CRLF\r\n",
+            ProtocolError::InternalError => "INTERNAL_ERROR\r\n",
+            ProtocolError::TooFarInFuture => "TOO_FAR_IN_FUTURE\r\n",
+            ProtocolError::Draining => "DRAINING\r\n",
+            ProtocolError::TooManyReserved => "TOO_MANY_RESERVED\r\n",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    /// put <pri> <delay> <ttr> <bytes>, or putms <pri> <delay_ms> <ttr_ms> <bytes>
+    Put {
+        priority: u32,
+        delay_ms: u64,
+        ttr_ms: u64,
+        bytes: usize,
+    },
+    /// reserve, or reserve-with-timeout <seconds> / reserve-with-timeout-ms <ms> when
+    /// `timeout_ms` is set
+    Reserve { timeout_ms: Option<u64> },
+    /// delete <id>
+    Delete { id: u64 },
+    /// touch <id>
+    Touch { id: u64 },
+    /// release <id> <pri> <delay>
+    Release {
+        id: u64,
+        priority: u32,
+        delay_ms: u64,
+    },
+    /// bury <id> <pri>
+    Bury { id: u64, priority: u32 },
+    /// kick <bound>
+    Kick { bound: u32 },
+    /// kick-job <id>
+    KickJob { id: u64 },
+    /// peek <id>
+    Peek { id: u64 },
+    /// peek-ready
+    PeekReady,
+    /// peek-delayed
+    PeekDelayed,
+    /// peek-buried
+    PeekBuried,
+    /// stats
+    Stats,
+    /// stats-tube <tube>
+    StatsTube { tube: String },
+    /// stats-job <id>
+    StatsJob { id: u64 },
+    /// use <tube>
+    Use { tube: String },
+    /// watch <tube>
+    Watch { tube: String },
+    /// ignore <tube>
+    Ignore { tube: String },
+    /// list-tubes
+    ListTubes,
+    /// list-tube-used
+    ListTubeUsed,
+    /// list-tubes-watched
+    ListTubesWatched,
+    /// pause-tube <tube> <delay>
+    PauseTube { tube: String, delay_secs: u32 },
+    /// snapshot <path>
+    Snapshot { path: PathBuf },
+    /// quit
+    Quit,
+}
+
+/// Checks a tube name against beanstalkd's rules: up to 200 letters, digits and `-+/;.$_()`, not
+/// starting with a hyphen
+fn parse_tube(name: &str) -> Result<String, ProtocolError> {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || "-+/;.$_()".contains(c);
+    if name.len() > MAX_TUBE_NAME_LEN || name.starts_with('-') || !name.chars().all(valid_char) {
+        return Err(ProtocolError::BadFormat);
+    }
+    Ok(name.to_owned())
+}
+
+/// Parses a time given in units of `unit_ms`, e.g. seconds for the standard commands, into ms
+fn parse_ms(arg: &str, unit_ms: u64) -> Result<u64, ProtocolError> {
+    arg.parse::<u64>()
+        .ok()
+        .and_then(|t| t.checked_mul(unit_ms))
+        .ok_or(ProtocolError::BadFormat)
+}
+
+/// Parses the arguments of put and putms, whose delay and ttr are in units of `unit_ms`
+fn parse_put(args: &[&str], unit_ms: u64) -> Result<Command, ProtocolError> {
+    if args.len() != 4 {
+        return Err(ProtocolError::BadFormat);
+    }
+    let priority = args[0].parse().map_err(|_| ProtocolError::BadFormat)?;
+    let delay_ms = parse_ms(args[1], unit_ms)?;
+    let ttr_ms = parse_ms(args[2], unit_ms)?;
+    let bytes = args[3].parse().map_err(|_| ProtocolError::BadFormat)?;
+    Ok(Command::Put {
+        priority,
+        delay_ms,
+        ttr_ms,
+        bytes,
+    })
+}
+
+fn parse_command(line: &[u8]) -> Result<Command, ProtocolError> {
+    let line = str::from_utf8(line).map_err(|_| ProtocolError::BadFormat)?;
+    let mut parts = line.split_whitespace();
+    let name = parts.next();
+    let args: Vec<&str> = parts.collect();
+    let arity = |n: usize| {
+        if args.len() == n {
+            Ok(())
+        } else {
+            Err(ProtocolError::BadFormat)
+        }
+    };
+    match name {
+        Some("put") => parse_put(&args, SECOND_MS),
+        Some("putms") => parse_put(&args, 1),
+        Some("reserve") => {
+            arity(0)?;
+            Ok(Command::Reserve { timeout_ms: None })
+        }
+        Some("reserve-with-timeout") => {
+            arity(1)?;
+            Ok(Command::Reserve {
+                timeout_ms: Some(parse_ms(args[0], SECOND_MS)?),
+            })
+        }
+        Some("reserve-with-timeout-ms") => {
+            arity(1)?;
+            Ok(Command::Reserve {
+                timeout_ms: Some(parse_ms(args[0], 1)?),
+            })
+        }
+        Some("delete") => {
+            arity(1)?;
+            let id = args[0].parse().map_err(|_| ProtocolError::BadFormat)?;
+            Ok(Command::Delete { id })
+        }
+        Some("touch") => {
+            arity(1)?;
+            let id = args[0].parse().map_err(|_| ProtocolError::BadFormat)?;
+            Ok(Command::Touch { id })
+        }
+        Some("release") => {
+            arity(3)?;
+            let id = args[0].parse().map_err(|_| ProtocolError::BadFormat)?;
+            let priority = args[1].parse().map_err(|_| ProtocolError::BadFormat)?;
+            let delay_ms = parse_ms(args[2], SECOND_MS)?;
+            Ok(Command::Release {
+                id,
+                priority,
+                delay_ms,
+            })
+        }
+        Some("bury") => {
+            arity(2)?;
+            let id = args[0].parse().map_err(|_| ProtocolError::BadFormat)?;
+            let priority = args[1].parse().map_err(|_| ProtocolError::BadFormat)?;
+            Ok(Command::Bury { id, priority })
+        }
+        Some("kick") => {
+            arity(1)?;
+            let bound = args[0].parse().map_err(|_| ProtocolError::BadFormat)?;
+            Ok(Command::Kick { bound })
+        }
+        Some("kick-job") => {
+            arity(1)?;
+            let id = args[0].parse().map_err(|_| ProtocolError::BadFormat)?;
+            Ok(Command::KickJob { id })
+        }
+        Some("peek") => {
+            arity(1)?;
+            let id = args[0].parse().map_err(|_| ProtocolError::BadFormat)?;
+            Ok(Command::Peek { id })
+        }
+        Some("peek-ready") => {
+            arity(0)?;
+            Ok(Command::PeekReady)
+        }
+        Some("peek-delayed") => {
+            arity(0)?;
+            Ok(Command::PeekDelayed)
+        }
+        Some("peek-buried") => {
+            arity(0)?;
+            Ok(Command::PeekBuried)
+        }
+        Some("stats") => {
+            arity(0)?;
+            Ok(Command::Stats)
+        }
+        Some("stats-tube") => {
+            arity(1)?;
+            Ok(Command::StatsTube {
+                tube: parse_tube(args[0])?,
+            })
+        }
+        Some("stats-job") => {
+            arity(1)?;
+            let id = args[0].parse().map_err(|_| ProtocolError::BadFormat)?;
+            Ok(Command::StatsJob { id })
+        }
+        Some("use") => {
+            arity(1)?;
+            Ok(Command::Use {
+                tube: parse_tube(args[0])?,
+            })
+        }
+        Some("watch") => {
+            arity(1)?;
+            Ok(Command::Watch {
+                tube: parse_tube(args[0])?,
+            })
+        }
+        Some("ignore") => {
+            arity(1)?;
+            Ok(Command::Ignore {
+                tube: parse_tube(args[0])?,
+            })
+        }
+        Some("list-tubes") => {
+            arity(0)?;
+            Ok(Command::ListTubes)
+        }
+        Some("list-tube-used") => {
+            arity(0)?;
+            Ok(Command::ListTubeUsed)
+        }
+        Some("list-tubes-watched") => {
+            arity(0)?;
+            Ok(Command::ListTubesWatched)
+        }
+        Some("pause-tube") => {
+            arity(2)?;
+            let delay_secs = args[1].parse().map_err(|_| ProtocolError::BadFormat)?;
+            Ok(Command::PauseTube {
+                tube: parse_tube(args[0])?,
+                delay_secs,
+            })
+        }
+        Some("snapshot") => {
+            arity(1)?;
+            Ok(Command::Snapshot {
+                path: PathBuf::from(args[0]),
+            })
+        }
+        Some("quit") => {
+            arity(0)?;
+            Ok(Command::Quit)
+        }
+        _ => Err(ProtocolError::UnknownCommand),
+    }
+}
+
+/// Serves one client, whichever way it connected, until it quits, hangs up or goes idle for
+/// longer than `limits` allow, or the server shuts down.
+///
+/// Clients may pipeline, sending several commands without waiting for the replies. Every frame
+/// complete in what was read so far is answered, in the order it was sent, before more is read.
+/// The start of a frame that didn't arrive whole stays buffered in the decoder.
+fn handle_client<S: Read + Write>(
+    mut stream: S,
+    peer: &str,
+    registry: &TubeRegistry,
+    options: CommandOptions,
+    limits: &ConnectionLimits,
+) -> io::Result<()> {
+    debug!("Accepted client connection from: {}", peer);
+    let mut decoder = Decoder::new(MAX_LINE_LEN, options.max_job_size);
+    let mut chunk = [0u8; READ_CHUNK_LEN];
+    let mut session = Session::new(registry).with_max_reserved_jobs(limits.max_reserved_jobs);
+    // When the client last sent something, or was last answered
+    let mut last_active = Instant::now();
+    // Priority, delay and ttr of a put whose data block hasn't been decoded yet
+    let mut pending_put: Option<(u32, u64, u64)> = None;
+    // Frames in a row the decoder couldn't make sense of
+    let mut errors = 0;
+    loop {
+        if registry.is_closed() && pending_put.is_none() && decoder.is_idle() {
+            info!("Closing client connection for shutdown: {}", peer);
+            return Ok(());
+        }
+        let n = match stream.read(&mut chunk) {
+            Ok(n) => n,
+            // The read timed out - check for a shutdown and keep waiting
+            Err(ref e) if core::is_read_timeout(e) => {
+                if last_active.elapsed() >= limits.client_idle_timeout {
+                    let released = session.release_all();
+                    info!(
+                        "Closing client connection idle for {:?}, released {} reserved jobs: {}",
+                        limits.client_idle_timeout, released, peer
+                    );
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            debug!("Client disconnected: {}", peer);
+            return Ok(());
+        }
+        decoder.feed(&chunk[..n]);
+        while let Some(frame) = decoder.next_frame() {
+            errors = match frame {
+                Frame::Error(_) => errors + 1,
+                _ => 0,
+            };
+            let reply = match frame {
+                Frame::Command(Command::Put {
+                    priority,
+                    delay_ms,
+                    ttr_ms,
+                    ..
+                }) => {
+                    pending_put = Some((priority, delay_ms, ttr_ms));
+                    continue;
+                }
+                Frame::Data(data) => {
+                    let (priority, delay_ms, ttr_ms) = pending_put
+                        .take()
+                        .expect("Decoder only emits data after a put");
+                    put(&session, priority, delay_ms, ttr_ms, data)
+                }
+                Frame::Command(Command::Reserve { timeout_ms }) => {
+                    let timeout = timeout_ms.map(Duration::from_millis);
+                    match reserve(&mut session, timeout) {
+                        Some(reply) => {
+                            reply.write_to(&mut stream)?;
+                            continue;
+                        }
+                        None => {
+                            info!("Closing client connection for shutdown: {}", peer);
+                            return Ok(());
+                        }
+                    }
+                }
+                Frame::Command(Command::Delete { id }) => {
+                    found_or_not(session.delete(id), b"DELETED\r\n")
+                }
+                Frame::Command(Command::Touch { id }) => {
+                    found_or_not(session.touch(id), b"TOUCHED\r\n")
+                }
+                Frame::Command(Command::Release {
+                    id,
+                    priority,
+                    delay_ms,
+                }) => release(&mut session, id, priority, delay_ms),
+                Frame::Command(Command::Bury { id, priority }) => {
+                    found_or_not(session.bury(id, priority), b"BURIED\r\n")
+                }
+                Frame::Command(Command::Kick { bound }) => {
+                    let kicked = registry.kick(session.using(), bound as usize);
+                    format!("KICKED {}\r\n", kicked).into_bytes()
+                }
+                Frame::Command(Command::KickJob { id }) => {
+                    found_or_not(registry.kick_job(id), b"KICKED\r\n")
+                }
+                Frame::Command(Command::Peek { id }) => {
+                    found(registry.peek(id).map(|job| (job, id))).write_to(&mut stream)?;
+                    continue;
+                }
+                Frame::Command(Command::PeekReady) => {
+                    found(registry.peek_ready(session.using())).write_to(&mut stream)?;
+                    continue;
+                }
+                Frame::Command(Command::PeekDelayed) => {
+                    found(registry.peek_delayed(session.using())).write_to(&mut stream)?;
+                    continue;
+                }
+                Frame::Command(Command::PeekBuried) => {
+                    found(registry.peek_buried(session.using())).write_to(&mut stream)?;
+                    continue;
+                }
+                Frame::Command(Command::Stats) => stats(Some(registry.server_stats())),
+                Frame::Command(Command::StatsTube { tube }) => stats(registry.tube_stats(&tube)),
+                Frame::Command(Command::StatsJob { id }) => stats(registry.job_stats(id)),
+                Frame::Command(Command::Use { tube }) => {
+                    let reply = format!("USING {}\r\n", tube).into_bytes();
+                    session.use_tube(tube);
+                    reply
+                }
+                Frame::Command(Command::Watch { tube }) => {
+                    format!("WATCHING {}\r\n", session.watch(tube)).into_bytes()
+                }
+                // A client has to watch at least one tube, so the last one can't be ignored
+                Frame::Command(Command::Ignore { tube }) => match session.ignore(&tube) {
+                    Some(watched) => format!("WATCHING {}\r\n", watched).into_bytes(),
+                    None => b"NOT_IGNORED\r\n".to_vec(),
+                },
+                Frame::Command(Command::ListTubes) => list(&registry.tube_names()),
+                Frame::Command(Command::ListTubeUsed) => {
+                    format!("USING {}\r\n", session.using()).into_bytes()
+                }
+                Frame::Command(Command::ListTubesWatched) => list(session.watching()),
+                Frame::Command(Command::PauseTube { tube, delay_secs }) => {
+                    let delay = Duration::from_secs(u64::from(delay_secs));
+                    found_or_not(registry.pause(&tube, delay), b"PAUSED\r\n")
+                }
+                Frame::Command(Command::Snapshot { ref path }) if options.snapshot_command => {
+                    snapshot(registry, path)
+                }
+                Frame::Command(Command::Snapshot { .. }) => {
+                    ProtocolError::UnknownCommand.reply().as_bytes().to_vec()
+                }
+                // Commands pipelined after the quit are dropped along with the connection
+                Frame::Command(Command::Quit) => {
+                    info!("Client quit: {}", peer);
+                    return Ok(());
+                }
+                Frame::Error(e) => {
+                    pending_put = None;
+                    e.reply().as_bytes().to_vec()
+                }
+            };
+            stream.write_all(&reply)?;
+            if errors >= MAX_CONSECUTIVE_ERRORS {
+                warn!("Closing client connection after {} errors: {}", errors, peer);
+                return Ok(());
+            }
+        }
+        // Counted from after the replies, a reserve may have kept the client waiting meanwhile
+        last_active = Instant::now();
+    }
+}
+
+/// Schedules a put's data block on the session's tube `delay_ms` from now
+fn put(session: &Session, priority: u32, delay_ms: u64, ttr_ms: u64, data: Bytes) -> Vec<u8> {
+    match session.put(session.new_job(priority, delay_ms, ttr_ms, data)) {
+        Ok(id) => format!("INSERTED {}\r\n", id).into_bytes(),
+        Err(YaadError::JobRejected {
+            reason: RejectReason::TooFarInFuture { .. },
+        }) => ProtocolError::TooFarInFuture.reply().as_bytes().to_vec(),
+        // A full tube answers like a draining server does, so standard clients back off and retry
+        Err(YaadError::JobRejected {
+            reason: RejectReason::Draining,
+        })
+        | Err(YaadError::Capacity { .. }) => {
+            ProtocolError::Draining.reply().as_bytes().to_vec()
+        }
+        Err(e) => {
+            error!("Failed to put job on tube {}: {}", session.using(), e);
+            ProtocolError::InternalError.reply().as_bytes().to_vec()
+        }
+    }
+}
+
+/// Waits for the next ready job on any watched tube and hands it to this client until it is
+/// deleted, unless it holds too many reserved jobs already. Returns None if the server started
+/// shutting down instead.
+fn reserve(session: &mut Session, timeout: Option<Duration>) -> Option<Reply> {
+    match session.reserve(timeout) {
+        Reservation::Reserved(job, id) => Some(Reply::with_body("RESERVED", id, job.get_body())),
+        Reservation::TimedOut => Some(Reply::line(b"TIMED_OUT\r\n")),
+        Reservation::DeadlineSoon => Some(Reply::line(b"DEADLINE_SOON\r\n")),
+        Reservation::LimitReached => Some(Reply::line(
+            ProtocolError::TooManyReserved.reply().as_bytes(),
+        )),
+        Reservation::Closed => None,
+    }
+}
+
+/// Writes a snapshot of every tube to `path`, answering once it is on disk
+fn snapshot(registry: &TubeRegistry, path: &Path) -> Vec<u8> {
+    match registry.snapshot_online(path) {
+        Ok(n) => {
+            info!("Snapshotted {} jobs to {}", n, path.display());
+            b"OK\r\n".to_vec()
+        }
+        Err(e) => {
+            error!("Failed to snapshot jobs to {}: {}", path.display(), e);
+            ProtocolError::InternalError.reply().as_bytes().to_vec()
+        }
+    }
+}
+
+/// Replies with `reply` if the job a command was about was found, or NOT_FOUND if it wasn't
+fn found_or_not(found: bool, reply: &[u8]) -> Vec<u8> {
+    if found {
+        reply.to_vec()
+    } else {
+        b"NOT_FOUND\r\n".to_vec()
+    }
+}
+
+/// Puts a job this client reserved back with a new priority, to be ready `delay_ms` from now
+fn release(session: &mut Session, id: u64, priority: u32, delay_ms: u64) -> Vec<u8> {
+    match session.release(id, priority, delay_ms) {
+        Ok(released) => found_or_not(released, b"RELEASED\r\n"),
+        // A delay past the end of the clock is too far ahead for any hub
+        Err(YaadError::JobRejected {
+            reason: RejectReason::TooFarInFuture { .. },
+        })
+        | Err(YaadError::Clock { .. }) => ProtocolError::TooFarInFuture.reply().as_bytes().to_vec(),
+        Err(e) => {
+            error!("Failed to release job {}: {}", id, e);
+            ProtocolError::InternalError.reply().as_bytes().to_vec()
+        }
+    }
+}
+
+/// Replies with a peeked job and its id, or NOT_FOUND if there was nothing to peek at
+fn found(peeked: Option<(Job, u64)>) -> Reply {
+    match peeked {
+        Some((job, id)) => Reply::with_body("FOUND", id, job.get_body()),
+        None => Reply::line(b"NOT_FOUND\r\n"),
+    }
+}
+
+/// A reply line, followed by the body of the job it is about if there is one. The body is
+/// written straight from the job rather than copied next to the line first.
+struct Reply {
+    line: Vec<u8>,
+    body: Option<JobBody>,
+}
+
+impl Reply {
+    fn line(line: &[u8]) -> Reply {
+        Reply {
+            line: line.to_vec(),
+            body: None,
+        }
+    }
+
+    /// A `<word> <id> <bytes>\r\n<data>\r\n` reply
+    fn with_body(word: &str, id: u64, body: JobBody) -> Reply {
+        Reply {
+            line: format!("{} {} {}\r\n", word, id, body.as_bytes().len()).into_bytes(),
+            body: Some(body),
+        }
+    }
+
+    fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let (body, crlf): (&[u8], &[u8]) = match self.body {
+            Some(ref body) => (body.as_bytes(), b"\r\n"),
+            None => (b"", b""),
+        };
+        let mut parts = [IoSlice::new(&self.line), IoSlice::new(body), IoSlice::new(crlf)];
+        let mut parts = &mut parts[..];
+        while !parts.is_empty() {
+            match out.write_vectored(parts) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "failed to write reply")),
+                Ok(n) => IoSlice::advance_slices(&mut parts, n),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replies with `dict` as a YAML dictionary, or NOT_FOUND if there is nothing to report on
+fn stats(dict: Option<StatsDict>) -> Vec<u8> {
+    let dict = match dict {
+        Some(d) => d,
+        None => return b"NOT_FOUND\r\n".to_vec(),
+    };
+    let mut yaml = String::from("---\n");
+    for (name, value) in dict {
+        yaml.push_str(&format!("{}: {}\n", name, value));
+    }
+    yaml_reply(&yaml)
+}
+
+/// Replies with `names` as a YAML list, like `list-tubes`
+fn list(names: &[String]) -> Vec<u8> {
+    let mut yaml = String::from("---\n");
+    for name in names {
+        yaml.push_str(&format!("- {}\n", name));
+    }
+    yaml_reply(&yaml)
+}
+
+/// Wraps a YAML document the way beanstalkd sends it: `OK <bytes>`, followed by the document
+fn yaml_reply(yaml: &str) -> Vec<u8> {
+    let mut reply = format!("OK {}\r\n", yaml.len()).into_bytes();
+    reply.extend_from_slice(yaml.as_bytes());
+    reply.extend_from_slice(b"\r\n");
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::env;
+    use std::io::{BufRead, BufReader};
+    use std::net::{SocketAddr, TcpStream};
+    use std::os::unix::net::UnixStream;
+    use std::process;
+    use std::thread;
+    use yaad::hub::DEFAULT_MAX_FUTURE_MS;
+
+    /// Counts the bytes allocated by each thread, to check that job bodies aren't copied
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            // Never fails, the count is just not kept while the thread is being torn down
+            let _ = ALLOCATED.try_with(|a| a.set(a.get() + layout.size()));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocated() -> usize {
+        ALLOCATED.with(Cell::get)
+    }
+
+    fn start_server() -> (SocketAddr, Arc<TubeRegistry>) {
+        // The trigger is dropped right away, so the server never shuts down
+        let (addr, registry, _, _) = start_stoppable_server(Duration::from_millis(0), MAX_JOB_SIZE);
+        (addr, registry)
+    }
+
+    #[test]
+    fn serves_tcp_and_unix_socket_clients_alike() {
+        let path = env::temp_dir().join(format!("yaad-beanstalkd-test-{}.sock", process::id()));
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let listeners = vec![tcp.into(), core::bind_unix(&path).unwrap().into()];
+        let registry = Arc::new(TubeRegistry::from_snapshot(
+            vec![],
+            HubConfig::new(DEFAULT_SPOKE_DURATION_MS),
+        ));
+        let (trigger, shutdown) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let grace = Duration::from_millis(0);
+            let tick_interval = Duration::from_millis(DEFAULT_TICK_INTERVAL_MS);
+            let limits = ConnectionLimits::default();
+            let options = CommandOptions::new(MAX_JOB_SIZE);
+            serve_until(listeners, registry, &shutdown, grace, options, tick_interval, limits)
+        });
+
+        let mut producer = connect(addr);
+        let id = inserted_id(&send(&mut producer, b"put 0 0 60 5\r\nhello\r\n"));
+        let mut consumer = BufReader::new(UnixStream::connect(&path).unwrap());
+        assert_eq!(
+            send(&mut consumer, b"reserve\r\n"),
+            format!("RESERVED {} 5\r\n", id)
+        );
+        assert_eq!(read_line(&mut consumer), "hello\r\n");
+        assert_eq!(
+            send(&mut consumer, format!("delete {}\r\n", id).as_bytes()),
+            "DELETED\r\n"
+        );
+        let id = inserted_id(&send(&mut consumer, b"put 0 0 60 2\r\nhi\r\n"));
+        assert_eq!(
+            send(&mut producer, b"reserve-with-timeout 0\r\n"),
+            format!("RESERVED {} 2\r\n", id)
+        );
+
+        trigger.send(()).unwrap();
+        server.join().unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn start_stoppable_server(
+        grace: Duration,
+        max_job_size: usize,
+    ) -> (
+        SocketAddr,
+        Arc<TubeRegistry>,
+        mpsc::Sender<()>,
+        thread::JoinHandle<io::Result<()>>,
+    ) {
+        let options = CommandOptions::new(max_job_size);
+        let hub_config = HubConfig::new(DEFAULT_SPOKE_DURATION_MS);
+        start_limited_server(hub_config, grace, options, ConnectionLimits::default())
+    }
+
+    fn start_limited_server(
+        hub_config: HubConfig,
+        grace: Duration,
+        options: CommandOptions,
+        limits: ConnectionLimits,
+    ) -> (
+        SocketAddr,
+        Arc<TubeRegistry>,
+        mpsc::Sender<()>,
+        thread::JoinHandle<io::Result<()>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let registry = Arc::new(TubeRegistry::from_snapshot(vec![], hub_config));
+        let server_registry = Arc::clone(&registry);
+        let (trigger, shutdown) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let listeners = vec![listener.into()];
+            let tick_interval = Duration::from_millis(DEFAULT_TICK_INTERVAL_MS);
+            let registry = server_registry;
+            serve_until(listeners, registry, &shutdown, grace, options, tick_interval, limits)
+        });
+        (addr, registry, trigger, server)
+    }
+
+    /// Writes `request` and returns the next reply line
+    fn send<S: Read + Write>(client: &mut BufReader<S>, request: &[u8]) -> String {
+        client.get_mut().write_all(request).unwrap();
+        read_line(client)
+    }
+
+    fn read_line<S: Read>(client: &mut BufReader<S>) -> String {
+        let mut reply = String::new();
+        client.read_line(&mut reply).unwrap();
+        reply
+    }
+
+    fn connect(addr: SocketAddr) -> BufReader<TcpStream> {
+        BufReader::new(TcpStream::connect(addr).unwrap())
+    }
+
+    fn inserted_id(reply: &str) -> String {
+        assert!(reply.starts_with("INSERTED "), "Got: {}", reply);
+        reply.trim_start_matches("INSERTED ").trim().to_owned()
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            parse_command(b"put 1 2 3 4\r\n"),
+            Ok(Command::Put {
+                priority: 1,
+                delay_ms: 2_000,
+                ttr_ms: 3_000,
+                bytes: 4
+            })
+        );
+        assert_eq!(
+            parse_command(b"putms 1 250 1500 4\r\n"),
+            Ok(Command::Put {
+                priority: 1,
+                delay_ms: 250,
+                ttr_ms: 1_500,
+                bytes: 4
+            })
+        );
+        assert_eq!(
+            parse_command(b"putms 1 250 1500\r\n"),
+            Err(ProtocolError::BadFormat)
+        );
+        assert_eq!(
+            parse_command(b"put 1 2 3\r\n"),
+            Err(ProtocolError::BadFormat)
+        );
+        assert_eq!(
+            parse_command(b"put 1 -2 3 4\r\n"),
+            Err(ProtocolError::BadFormat)
+        );
+        assert_eq!(
+            parse_command(b"put a b c d\r\n"),
+            Err(ProtocolError::BadFormat)
+        );
+        assert_eq!(
+            parse_command(b"reserve\r\n"),
+            Ok(Command::Reserve { timeout_ms: None })
+        );
+        assert_eq!(
+            parse_command(b"reserve-with-timeout 5\r\n"),
+            Ok(Command::Reserve {
+                timeout_ms: Some(5_000)
+            })
+        );
+        assert_eq!(
+            parse_command(b"reserve-with-timeout-ms 5\r\n"),
+            Ok(Command::Reserve {
+                timeout_ms: Some(5)
+            })
+        );
+        assert_eq!(
+            parse_command(b"reserve-with-timeout\r\n"),
+            Err(ProtocolError::BadFormat)
+        );
+        assert_eq!(
+            parse_command(b"delete 12\r\n"),
+            Ok(Command::Delete { id: 12 })
+        );
+        assert_eq!(
+            parse_command(b"delete -1\r\n"),
+            Err(ProtocolError::BadFormat)
+        );
+        assert_eq!(
+            parse_command(b"bury 12 0\r\n"),
+            Ok(Command::Bury {
+                id: 12,
+                priority: 0
+            })
+        );
+        assert_eq!(parse_command(b"bury 12\r\n"), Err(ProtocolError::BadFormat));
+        assert_eq!(
+            parse_command(b"release 12 5 1\r\n"),
+            Ok(Command::Release {
+                id: 12,
+                priority: 5,
+                delay_ms: 1_000
+            })
+        );
+        assert_eq!(
+            parse_command(b"release 12 5\r\n"),
+            Err(ProtocolError::BadFormat)
+        );
+        assert_eq!(
+            parse_command(b"touch 12\r\n"),
+            Ok(Command::Touch { id: 12 })
+        );
+        assert_eq!(parse_command(b"kick 5\r\n"), Ok(Command::Kick { bound: 5 }));
+        assert_eq!(
+            parse_command(b"kick-job 12\r\n"),
+            Ok(Command::KickJob { id: 12 })
+        );
+        assert_eq!(parse_command(b"peek 12\r\n"), Ok(Command::Peek { id: 12 }));
+        assert_eq!(parse_command(b"peek\r\n"), Err(ProtocolError::BadFormat));
+        assert_eq!(parse_command(b"peek-ready\r\n"), Ok(Command::PeekReady));
+        assert_eq!(
+            parse_command(b"peek-delayed 1\r\n"),
+            Err(ProtocolError::BadFormat)
+        );
+        assert_eq!(parse_command(b"peek-buried\r\n"), Ok(Command::PeekBuried));
+        assert_eq!(parse_command(b"stats\r\n"), Ok(Command::Stats));
+        assert_eq!(
+            parse_command(b"stats-tube emails\r\n"),
+            Ok(Command::StatsTube {
+                tube: "emails".to_owned()
+            })
+        );
+        assert_eq!(
+            parse_command(b"stats-job 12\r\n"),
+            Ok(Command::StatsJob { id: 12 })
+        );
+        assert_eq!(
+            parse_command(b"fly\r\n"),
+            Err(ProtocolError::UnknownCommand)
+        );
+        assert_eq!(parse_command(b"list-tubes\r\n"), Ok(Command::ListTubes));
+        assert_eq!(
+            parse_command(b"list-tube-used\r\n"),
+            Ok(Command::ListTubeUsed)
+        );
+        assert_eq!(
+            parse_command(b"list-tubes-watched x\r\n"),
+            Err(ProtocolError::BadFormat)
+        );
+        assert_eq!(
+            parse_command(b"pause-tube emails 10\r\n"),
+            Ok(Command::PauseTube {
+                tube: "emails".to_owned(),
+                delay_secs: 10
+            })
+        );
+        assert_eq!(
+            parse_command(b"pause-tube emails\r\n"),
+            Err(ProtocolError::BadFormat)
+        );
+        assert_eq!(
+            parse_command(b"snapshot /tmp/jobs.snap\r\n"),
+            Ok(Command::Snapshot {
+                path: PathBuf::from("/tmp/jobs.snap")
+            })
+        );
+        assert_eq!(
+            parse_command(b"snapshot\r\n"),
+            Err(ProtocolError::BadFormat)
+        );
+        assert_eq!(parse_command(b"quit\r\n"), Ok(Command::Quit));
+        assert_eq!(
+            parse_command(b"quit now\r\n"),
+            Err(ProtocolError::BadFormat)
+        );
+        assert_eq!(
+            parse_command(b"use emails.v2\r\n"),
+            Ok(Command::Use {
+                tube: "emails.v2".to_owned()
+            })
+        );
+        assert_eq!(
+            parse_command(b"watch -a\r\n"),
+            Err(ProtocolError::BadFormat)
+        );
+        assert_eq!(
+            parse_command(b"ignore a*\r\n"),
+            Err(ProtocolError::BadFormat)
+        );
+        let long = format!("use {}\r\n", "a".repeat(MAX_TUBE_NAME_LEN + 1));
+        assert_eq!(
+            parse_command(long.as_bytes()),
+            Err(ProtocolError::BadFormat)
+        );
+    }
+
+    #[test]
+    fn serves_commands_until_quit() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+        assert_eq!(send(&mut client, b"use emails\r\n"), "USING emails\r\n");
+        assert_eq!(send(&mut client, b"fly\r\n"), "UNKNOWN_COMMAND\r\n");
+        assert_eq!(send(&mut client, b"watch emails\r\n"), "WATCHING 2\r\n");
+
+        client.get_mut().write_all(b"quit\r\n").unwrap();
+        assert_eq!(read_line(&mut client), "", "Server hangs up on quit");
+    }
+
+    #[test]
+    fn put_delayed_job() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+
+        let id = inserted_id(&send(&mut client, b"put 0 1 60 5\r\nhello\r\n"));
+        assert_eq!(
+            send(&mut client, b"reserve-with-timeout 0\r\n"),
+            "TIMED_OUT\r\n",
+            "Job is delayed"
+        );
+        assert_eq!(
+            send(&mut client, b"reserve-with-timeout 2\r\n"),
+            format!("RESERVED {} 5\r\n", id),
+            "Job is ready after its delay"
+        );
+        assert_eq!(read_line(&mut client), "hello\r\n");
+    }
+
+    #[test]
+    fn refuses_puts_past_the_horizon() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+
+        let horizon_secs = DEFAULT_MAX_FUTURE_MS / 1_000;
+        let put = format!("put 0 {} 60 1\r\na\r\n", horizon_secs + 1);
+        assert_eq!(send(&mut client, put.as_bytes()), "TOO_FAR_IN_FUTURE\r\n");
+        let put = format!("put 0 {} 60 1\r\na\r\n", horizon_secs - 60);
+        inserted_id(&send(&mut client, put.as_bytes()));
+    }
+
+    #[test]
+    fn put_job_delayed_by_ms() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+
+        let put_at = Instant::now();
+        let id = inserted_id(&send(&mut client, b"putms 0 250 1500 5\r\nhello\r\n"));
+        assert_eq!(
+            send(&mut client, b"reserve-with-timeout-ms 100\r\n"),
+            "TIMED_OUT\r\n",
+            "Job is delayed"
+        );
+        assert_eq!(
+            send(&mut client, b"reserve-with-timeout-ms 2000\r\n"),
+            format!("RESERVED {} 5\r\n", id),
+            "Job is ready after its delay"
+        );
+        let waited = put_at.elapsed();
+        assert!(waited >= Duration::from_millis(250), "Ready after {:?}", waited);
+        assert!(waited < Duration::from_millis(1_500), "Ready after {:?}", waited);
+        assert_eq!(read_line(&mut client), "hello\r\n");
+    }
+
+    #[test]
+    fn reserve_blocks_until_a_job_is_put() {
+        let (addr, _) = start_server();
+        let mut consumer = connect(addr);
+        consumer.get_mut().write_all(b"reserve\r\n").unwrap();
+
+        let mut producer = connect(addr);
+        let id = inserted_id(&send(&mut producer, b"put 0 0 60 3\r\nfoo\r\n"));
+
+        assert_eq!(read_line(&mut consumer), format!("RESERVED {} 3\r\n", id));
+        assert_eq!(read_line(&mut consumer), "foo\r\n");
+    }
+
+    #[test]
+    fn waiting_reserve_is_told_a_reserved_job_runs_out_soon() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+        let id = inserted_id(&send(&mut client, b"put 0 0 2 1\r\na\r\n"));
+        assert_eq!(send(&mut client, b"reserve\r\n"), format!("RESERVED {} 1\r\n", id));
+        read_line(&mut client);
+
+        let reserved_at = Instant::now();
+        assert_eq!(send(&mut client, b"reserve\r\n"), "DEADLINE_SOON\r\n");
+        let waited = reserved_at.elapsed();
+        assert!(waited >= Duration::from_millis(900), "Told after {:?}", waited);
+        assert!(waited < Duration::from_millis(1_800), "Told after {:?}", waited);
+        assert_eq!(
+            send(&mut client, b"reserve-with-timeout 5\r\n"),
+            "DEADLINE_SOON\r\n",
+            "Within the last second, reserves are told right away"
+        );
+
+        assert_eq!(send(&mut client, format!("touch {}\r\n", id).as_bytes()), "TOUCHED\r\n");
+        assert_eq!(
+            send(&mut client, b"reserve-with-timeout 0\r\n"),
+            "TIMED_OUT\r\n"
+        );
+    }
+
+    #[test]
+    fn blocked_reservers_take_turns() {
+        let (addr, registry) = start_server();
+        let consumers: Vec<_> = (0..3)
+            .map(|_| {
+                let mut consumer = connect(addr);
+                thread::spawn(move || {
+                    let mut reserved = 0;
+                    loop {
+                        let reply = send(&mut consumer, b"reserve-with-timeout 1\r\n");
+                        if reply == "TIMED_OUT\r\n" {
+                            return reserved;
+                        }
+                        let id = reply.split_whitespace().nth(1).unwrap().to_owned();
+                        read_line(&mut consumer);
+                        let delete = format!("delete {}\r\n", id);
+                        assert_eq!(send(&mut consumer, delete.as_bytes()), "DELETED\r\n");
+                        reserved += 1;
+                    }
+                })
+            })
+            .collect();
+
+        let mut producer = connect(addr);
+        for _ in 0..6 {
+            // Every consumer is back in the queue before the next job is put
+            while registry.waiting_reservers() < 3 {
+                thread::sleep(Duration::from_millis(1));
+            }
+            inserted_id(&send(&mut producer, b"put 0 0 60 3\r\njob\r\n"));
+        }
+        let reserved: Vec<usize> = consumers.into_iter().map(|c| c.join().unwrap()).collect();
+        assert_eq!(reserved, vec![2, 2, 2]);
+    }
+
+    #[test]
+    fn each_job_is_reserved_once() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+        let first = inserted_id(&send(&mut client, b"put 0 0 60 3\r\none\r\n"));
+        let second = inserted_id(&send(&mut client, b"put 0 0 60 3\r\ntwo\r\n"));
+
+        let mut other = connect(addr);
+        let mut reserved = vec![];
+        for c in [&mut client, &mut other].iter_mut() {
+            let reply = send(c, b"reserve-with-timeout 1\r\n");
+            assert!(reply.starts_with("RESERVED "), "Got: {}", reply);
+            reserved.push(reply.split_whitespace().nth(1).unwrap().to_owned());
+            read_line(c);
+        }
+        reserved.sort();
+        let mut expected = vec![first, second];
+        expected.sort();
+        assert_eq!(reserved, expected);
+        assert_eq!(
+            send(&mut client, b"reserve-with-timeout 0\r\n"),
+            "TIMED_OUT\r\n"
+        );
+    }
+
+    #[test]
+    fn delete_acknowledges_reserved_jobs() {
+        let (addr, registry) = start_server();
+        let mut client = connect(addr);
+        let id = inserted_id(&send(&mut client, b"put 0 0 60 2\r\nhi\r\n"));
+        let delete = format!("delete {}\r\n", id);
+
+        send(&mut client, b"reserve\r\n");
+        read_line(&mut client);
+
+        let mut other = connect(addr);
+        assert_eq!(
+            send(&mut other, delete.as_bytes()),
+            "NOT_FOUND\r\n",
+            "Job is reserved by another client"
+        );
+        assert_eq!(send(&mut client, delete.as_bytes()), "DELETED\r\n");
+        assert_eq!(send(&mut client, delete.as_bytes()), "NOT_FOUND\r\n");
+        assert_eq!(
+            registry.tracked_id_len(),
+            0,
+            "Deleted jobs' ids are forgotten"
+        );
+    }
+
+    #[test]
+    fn ids_are_sequential() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+        assert_eq!(
+            send(&mut client, b"put 0 0 60 1\r\na\r\n"),
+            "INSERTED 1\r\n"
+        );
+        assert_eq!(
+            send(&mut client, b"put 0 0 60 1\r\nb\r\n"),
+            "INSERTED 2\r\n"
+        );
+        assert_eq!(send(&mut client, b"reserve\r\n"), "RESERVED 1 1\r\n");
+    }
+
+    #[test]
+    fn deletes_delayed_and_ready_jobs() {
+        let (addr, registry) = start_server();
+        let mut client = connect(addr);
+        let delayed = inserted_id(&send(&mut client, b"put 0 60 60 1\r\na\r\n"));
+        let ready = inserted_id(&send(&mut client, b"put 0 0 60 1\r\nb\r\n"));
+
+        let mut other = connect(addr);
+        for id in &[delayed, ready] {
+            let delete = format!("delete {}\r\n", id);
+            assert_eq!(send(&mut other, delete.as_bytes()), "DELETED\r\n");
+            assert_eq!(send(&mut other, delete.as_bytes()), "NOT_FOUND\r\n");
+        }
+        assert_eq!(
+            send(&mut client, b"reserve-with-timeout 0\r\n"),
+            "TIMED_OUT\r\n"
+        );
+        assert_eq!(registry.tracked_id_len(), 0);
+    }
+
+    #[test]
+    fn delete_unknown_job() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+        assert_eq!(send(&mut client, b"delete 42\r\n"), "NOT_FOUND\r\n");
+    }
+
+    #[test]
+    fn bury_kick_and_reserve_again() {
+        let (addr, registry) = start_server();
+        let mut client = connect(addr);
+        let first = inserted_id(&send(&mut client, b"put 0 0 60 1\r\na\r\n"));
+        let second = inserted_id(&send(&mut client, b"put 0 0 60 1\r\nb\r\n"));
+        let bury_first = format!("bury {} 0\r\n", first);
+
+        let mut other = connect(addr);
+        assert_eq!(
+            send(&mut other, bury_first.as_bytes()),
+            "NOT_FOUND\r\n",
+            "Only reserved jobs can be buried"
+        );
+        for id in &[&first, &second] {
+            assert_eq!(
+                send(&mut client, b"reserve\r\n"),
+                format!("RESERVED {} 1\r\n", id)
+            );
+            read_line(&mut client);
+        }
+        assert_eq!(
+            send(&mut other, bury_first.as_bytes()),
+            "NOT_FOUND\r\n",
+            "Job is reserved by another client"
+        );
+        assert_eq!(send(&mut client, bury_first.as_bytes()), "BURIED\r\n");
+        let bury_second = format!("bury {} 0\r\n", second);
+        assert_eq!(send(&mut client, bury_second.as_bytes()), "BURIED\r\n");
+        assert_eq!(registry.buried_job_len(), 2);
+        assert_eq!(
+            send(&mut client, b"reserve-with-timeout 0\r\n"),
+            "TIMED_OUT\r\n",
+            "Buried jobs aren't handed out"
+        );
+
+        assert_eq!(send(&mut other, b"kick 1\r\n"), "KICKED 1\r\n");
+        assert_eq!(
+            send(&mut other, b"reserve\r\n"),
+            format!("RESERVED {} 1\r\n", first)
+        );
+        read_line(&mut other);
+        let kick_second = format!("kick-job {}\r\n", second);
+        assert_eq!(send(&mut other, kick_second.as_bytes()), "KICKED\r\n");
+        assert_eq!(send(&mut other, kick_second.as_bytes()), "NOT_FOUND\r\n");
+        assert_eq!(
+            send(&mut other, b"reserve\r\n"),
+            format!("RESERVED {} 1\r\n", second)
+        );
+        read_line(&mut other);
+        assert_eq!(send(&mut other, b"kick 10\r\n"), "KICKED 0\r\n");
+        assert_eq!(registry.buried_job_len(), 0);
+    }
+
+    #[test]
+    fn releases_reserved_jobs_after_a_delay() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+        let id = inserted_id(&send(&mut client, b"put 0 0 60 1\r\na\r\n"));
+        let release = format!("release {} 5 1\r\n", id);
+
+        let mut other = connect(addr);
+        assert_eq!(
+            send(&mut other, release.as_bytes()),
+            "NOT_FOUND\r\n",
+            "Only reserved jobs can be released"
+        );
+        assert_eq!(
+            send(&mut client, b"reserve\r\n"),
+            format!("RESERVED {} 1\r\n", id)
+        );
+        read_line(&mut client);
+        assert_eq!(
+            send(&mut other, release.as_bytes()),
+            "NOT_FOUND\r\n",
+            "Job is reserved by another client"
+        );
+        assert_eq!(send(&mut client, release.as_bytes()), "RELEASED\r\n");
+        assert_eq!(send(&mut client, release.as_bytes()), "NOT_FOUND\r\n");
+        assert_eq!(
+            send(&mut client, b"reserve-with-timeout 0\r\n"),
+            "TIMED_OUT\r\n",
+            "Released job is delayed"
+        );
+        assert_eq!(
+            send(&mut other, b"reserve-with-timeout 3\r\n"),
+            format!("RESERVED {} 1\r\n", id)
+        );
+        read_line(&mut other);
+
+        let stats = send_stats(&mut other, format!("stats-job {}\r\n", id).as_bytes());
+        assert_eq!(stats["pri"], "5");
+        assert_eq!(stats["reserves"], "2");
+        assert_eq!(stats["releases"], "1");
+    }
+
+    #[test]
+    fn deletes_buried_jobs() {
+        let (addr, registry) = start_server();
+        let mut client = connect(addr);
+        let id = inserted_id(&send(&mut client, b"put 0 0 60 1\r\na\r\n"));
+        send(&mut client, b"reserve\r\n");
+        read_line(&mut client);
+        assert_eq!(
+            send(&mut client, format!("bury {} 0\r\n", id).as_bytes()),
+            "BURIED\r\n"
+        );
+
+        let mut other = connect(addr);
+        let delete = format!("delete {}\r\n", id);
+        assert_eq!(send(&mut other, delete.as_bytes()), "DELETED\r\n");
+        assert_eq!(registry.buried_job_len(), 0);
+        assert_eq!(registry.tracked_id_len(), 0);
+    }
+
+    #[test]
+    fn peeks_without_reserving() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+        for peek in &["peek-ready", "peek-delayed", "peek-buried", "peek 42"] {
+            let request = format!("{}\r\n", peek);
+            assert_eq!(send(&mut client, request.as_bytes()), "NOT_FOUND\r\n");
+        }
+        let ready = inserted_id(&send(&mut client, b"put 0 0 60 5\r\nready\r\n"));
+        let delayed = inserted_id(&send(&mut client, b"put 0 60 60 7\r\ndelayed\r\n"));
+
+        for _ in 0..2 {
+            assert_eq!(
+                send(&mut client, b"peek-ready\r\n"),
+                format!("FOUND {} 5\r\n", ready)
+            );
+            assert_eq!(read_line(&mut client), "ready\r\n");
+        }
+        assert_eq!(
+            send(&mut client, b"peek-delayed\r\n"),
+            format!("FOUND {} 7\r\n", delayed)
+        );
+        assert_eq!(read_line(&mut client), "delayed\r\n");
+
+        assert_eq!(
+            send(&mut client, b"reserve\r\n"),
+            format!("RESERVED {} 5\r\n", ready),
+            "Peeked job is still ready"
+        );
+        read_line(&mut client);
+        assert_eq!(
+            send(&mut client, format!("bury {} 0\r\n", ready).as_bytes()),
+            "BURIED\r\n"
+        );
+        assert_eq!(
+            send(&mut client, b"peek-buried\r\n"),
+            format!("FOUND {} 5\r\n", ready)
+        );
+        read_line(&mut client);
+        assert_eq!(
+            send(&mut client, format!("peek {}\r\n", delayed).as_bytes()),
+            format!("FOUND {} 7\r\n", delayed)
+        );
+        read_line(&mut client);
+        assert_eq!(send(&mut client, b"peek-ready\r\n"), "NOT_FOUND\r\n");
+
+        let mut other = connect(addr);
+        send(&mut other, b"use emails\r\n");
+        assert_eq!(
+            send(&mut other, b"peek-buried\r\n"),
+            "NOT_FOUND\r\n",
+            "Peeks look at the used tube"
+        );
+    }
+
+    /// Sends `request` and parses the YAML dictionary in the OK reply
+    fn send_stats(client: &mut BufReader<TcpStream>, request: &[u8]) -> HashMap<String, String> {
+        let reply = send(client, request);
+        assert!(reply.starts_with("OK "), "Got: {}", reply);
+        let len: usize = reply.trim_start_matches("OK ").trim().parse().unwrap();
+        let mut yaml = vec![0u8; len + 2];
+        client.read_exact(&mut yaml).unwrap();
+        assert!(yaml.ends_with(b"\r\n"));
+        let yaml = String::from_utf8(yaml).unwrap();
+        let mut lines = yaml.trim_end().lines();
+        assert_eq!(lines.next(), Some("---"));
+        lines
+            .map(|l| {
+                let (name, value) = l.split_at(l.find(": ").expect("Not a YAML key value pair"));
+                (name.to_owned(), value[2..].to_owned())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reports_stats() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+        send(&mut client, b"use emails\r\n");
+        let put = b"put 0 0 60 1\r\na\r\n";
+        let first = inserted_id(&send(&mut client, put));
+        send(&mut client, put);
+        send(&mut client, b"put 0 60 60 1\r\nb\r\n");
+        send(&mut client, b"watch emails\r\n");
+        send(&mut client, b"reserve\r\n");
+        read_line(&mut client);
+        let mut other = connect(addr);
+        send(&mut other, b"put 0 0 60 1\r\nc\r\n");
+
+        let stats = send_stats(&mut other, b"stats\r\n");
+        assert_eq!(stats["current-jobs-ready"], "2");
+        assert_eq!(stats["current-jobs-reserved"], "1");
+        assert_eq!(stats["current-jobs-delayed"], "1");
+        assert_eq!(stats["current-jobs-buried"], "0");
+        assert_eq!(stats["current-job-bytes"], "3", "Reserved job isn't scheduled");
+        assert_eq!(stats["total-jobs"], "4");
+        assert_eq!(stats["current-tubes"], "2");
+        assert_eq!(stats["current-connections"], "2");
+        assert_eq!(stats["total-connections"], "2");
+
+        let emails = send_stats(&mut other, b"stats-tube emails\r\n");
+        assert_eq!(emails["name"], "emails");
+        assert_eq!(emails["current-jobs-ready"], "1");
+        assert_eq!(emails["current-job-bytes"], "2");
+        assert_eq!(emails["total-jobs"], "3");
+        assert_eq!(send(&mut other, b"stats-tube sms\r\n"), "NOT_FOUND\r\n");
+
+        let job = send_stats(&mut other, format!("stats-job {}\r\n", first).as_bytes());
+        assert_eq!(job["id"], first);
+        assert_eq!(job["tube"], "emails");
+        assert_eq!(job["state"], "reserved");
+        assert_eq!(job["ttr"], "60");
+        assert_eq!(send(&mut other, b"stats-job 42\r\n"), "NOT_FOUND\r\n");
+    }
+
+    #[test]
+    fn requeues_jobs_after_ttr() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+        let id = inserted_id(&send(&mut client, b"put 0 0 1 2\r\nhi\r\n"));
+        let reserved = format!("RESERVED {} 2\r\n", id);
+        assert_eq!(send(&mut client, b"reserve\r\n"), reserved);
+        read_line(&mut client);
+
+        // The job is handed out again once its one second ttr runs out
+        let mut other = connect(addr);
+        assert_eq!(
+            send(&mut other, b"reserve-with-timeout 0\r\n"),
+            "TIMED_OUT\r\n"
+        );
+        assert_eq!(send(&mut other, b"reserve-with-timeout 3\r\n"), reserved);
+        read_line(&mut other);
+
+        let delete = format!("delete {}\r\n", id);
+        assert_eq!(send(&mut client, delete.as_bytes()), "NOT_FOUND\r\n");
+        assert_eq!(send(&mut other, delete.as_bytes()), "DELETED\r\n");
+    }
+
+    #[test]
+    fn touch_extends_reservations() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+        let id = inserted_id(&send(&mut client, b"put 0 0 1 2\r\nhi\r\n"));
+        let touch = format!("touch {}\r\n", id);
+        assert_eq!(
+            send(&mut client, touch.as_bytes()),
+            "NOT_FOUND\r\n",
+            "Only reserved jobs can be touched"
+        );
+        send(&mut client, b"reserve\r\n");
+        read_line(&mut client);
+
+        // Keep touching the job for twice its one second ttr
+        let mut other = connect(addr);
+        assert_eq!(
+            send(&mut other, touch.as_bytes()),
+            "NOT_FOUND\r\n",
+            "Job is reserved by another client"
+        );
+        for _ in 0..5 {
+            thread::sleep(Duration::from_millis(400));
+            assert_eq!(send(&mut client, touch.as_bytes()), "TOUCHED\r\n");
+            assert_eq!(
+                send(&mut other, b"reserve-with-timeout 0\r\n"),
+                "TIMED_OUT\r\n",
+                "Touched job isn't handed out again"
+            );
+        }
+        assert_eq!(
+            send(&mut client, format!("delete {}\r\n", id).as_bytes()),
+            "DELETED\r\n",
+            "The touched reservation is still this client's"
+        );
+    }
+
+    #[test]
+    fn shutdown_releases_reserved_jobs() {
+        let grace = Duration::from_secs(5);
+        let (addr, registry, trigger, server) = start_stoppable_server(grace, MAX_JOB_SIZE);
+        let mut client = connect(addr);
+        let id = inserted_id(&send(&mut client, b"put 0 0 60 2\r\nhi\r\n"));
+        assert_eq!(
+            send(&mut client, b"reserve\r\n"),
+            format!("RESERVED {} 2\r\n", id)
+        );
+        read_line(&mut client);
+        let mut waiting = connect(addr);
+        waiting.get_mut().write_all(b"reserve\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(registry.reserved_job_len(), 1);
+
+        let started = Instant::now();
+        trigger.send(()).unwrap();
+        server.join().unwrap().unwrap();
+        assert!(
+            started.elapsed() < grace,
+            "Idle clients don't hold up the shutdown"
+        );
+        assert_eq!(read_line(&mut client), "", "Idle client is hung up on");
+        assert_eq!(read_line(&mut waiting), "", "Waiting reserve is hung up on");
+        assert_eq!(registry.reserved_job_len(), 0);
+        assert_eq!(registry.tracked_id_len(), 1, "Released job is kept");
+        assert!(TcpStream::connect(addr).is_err(), "No new connections");
+    }
+
+    #[test]
+    fn shutdown_lets_clients_finish_their_command() {
+        let grace = Duration::from_millis(500);
+        let (addr, _, trigger, server) = start_stoppable_server(grace, MAX_JOB_SIZE);
+        let mut finishing = connect(addr);
+        finishing
+            .get_mut()
+            .write_all(b"put 0 0 60 5\r\nab")
+            .unwrap();
+        let mut stuck = connect(addr);
+        stuck.get_mut().write_all(b"put 0 0 60 5\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        trigger.send(()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(send(&mut finishing, b"cde\r\n"), "INSERTED 1\r\n");
+        assert_eq!(read_line(&mut finishing), "");
+
+        server.join().unwrap().unwrap();
+        assert!(
+            started.elapsed() >= grace,
+            "Unfinished commands get the grace period"
+        );
+        assert_eq!(read_line(&mut stuck), "", "Unfinished put is cut off");
+    }
+
+    #[test]
+    fn round_trips_binary_bodies() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+        let body = b"a\r\nb\xffc";
+        let mut put = format!("put 0 0 60 {}\r\n", body.len()).into_bytes();
+        put.extend_from_slice(body);
+        put.extend_from_slice(b"\r\n");
+        let id = inserted_id(&send(&mut client, &put));
+
+        assert_eq!(
+            send(&mut client, b"reserve\r\n"),
+            format!("RESERVED {} {}\r\n", id, body.len())
+        );
+        let mut data = vec![0u8; body.len() + 2];
+        client.read_exact(&mut data).unwrap();
+        assert_eq!(&data[..body.len()], &body[..]);
+        assert_eq!(&data[body.len()..], b"\r\n");
+    }
+
+    #[test]
+    fn bad_format_keeps_connection_alive() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+
+        assert_eq!(send(&mut client, b"put 0 0 10\r\n"), "BAD_FORMAT\r\n");
+        // Data block is longer than declared
+        assert_eq!(
+            send(&mut client, b"put 0 0 10 3\r\nhello\r\n"),
+            "EXPECTED_CRLF\r\n"
+        );
+        let long = format!("reserve-with-timeout {}\r\n", "0".repeat(MAX_LINE_LEN));
+        assert_eq!(send(&mut client, long.as_bytes()), "BAD_FORMAT\r\n");
+        // The oversized data block is discarded before the reply is sent
+        let mut too_big = b"put 0 0 10 70000\r\n".to_vec();
+        too_big.extend(vec![b'x'; 70_000]);
+        too_big.extend(b"\r\n");
+        assert_eq!(send(&mut client, &too_big), "JOB_TOO_BIG\r\n");
+
+        let id = inserted_id(&send(&mut client, b"put 0 0 10 2\r\nhi\r\n"));
+        assert_eq!(
+            send(&mut client, b"reserve-with-timeout 1\r\n"),
+            format!("RESERVED {} 2\r\n", id)
+        );
+    }
+
+    #[test]
+    fn answers_garbage_and_recovers() {
+        let mut too_big = b"put 0 0 10 70000\r\n".to_vec();
+        too_big.extend(vec![b'x'; 70_000]);
+        too_big.extend(b"\r\n");
+        let long_line = format!("use {}\r\n", "x".repeat(MAX_LINE_LEN));
+        let mut long_junk = vec![0xab; 4 * MAX_LINE_LEN];
+        long_junk.extend(b"\r\n");
+        let cases: Vec<(&[u8], &str)> = vec![
+            (b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03\r\n", "BAD_FORMAT\r\n"),
+            (b"\xff\xfe\xfd\r\n", "BAD_FORMAT\r\n"),
+            (b"\x00\x01\x02\r\n", "UNKNOWN_COMMAND\r\n"),
+            (b"GET / HTTP/1.0\r\n", "UNKNOWN_COMMAND\r\n"),
+            (b"PUT 0 0 10 2\r\n", "UNKNOWN_COMMAND\r\n"),
+            (long_line.as_bytes(), "BAD_FORMAT\r\n"),
+            (&long_junk, "BAD_FORMAT\r\n"),
+            (b"reserve now\r\n", "BAD_FORMAT\r\n"),
+            (b"put -1 0 10 2\r\n", "BAD_FORMAT\r\n"),
+            (b"put 0 -5 10 2\r\n", "BAD_FORMAT\r\n"),
+            (b"put 0 0 -10 2\r\n", "BAD_FORMAT\r\n"),
+            (b"put 0 0 10 -2\r\n", "BAD_FORMAT\r\n"),
+            (b"put 4294967296 0 10 2\r\n", "BAD_FORMAT\r\n"),
+            (b"put 0 0 10 2 9\r\n", "BAD_FORMAT\r\n"),
+            (b"put 0 0 10 x\r\n", "BAD_FORMAT\r\n"),
+            (b"put 0 0 10 2\r\nhi!!\r\n", "EXPECTED_CRLF\r\n"),
+            (b"put 0 0 10 2\r\nhi\n\n", "EXPECTED_CRLF\r\n"),
+            (&too_big, "JOB_TOO_BIG\r\n"),
+            (b"reserve-with-timeout -1\r\n", "BAD_FORMAT\r\n"),
+            (b"delete -1\r\n", "BAD_FORMAT\r\n"),
+            (b"delete 18446744073709551616\r\n", "BAD_FORMAT\r\n"),
+            (b"touch\r\n", "BAD_FORMAT\r\n"),
+            (b"release 1 2\r\n", "BAD_FORMAT\r\n"),
+            (b"bury x 0\r\n", "BAD_FORMAT\r\n"),
+            (b"kick -3\r\n", "BAD_FORMAT\r\n"),
+            (b"stats-job 1 2\r\n", "BAD_FORMAT\r\n"),
+            (b"use caf\xc3\xa9\r\n", "BAD_FORMAT\r\n"),
+            (b"watch \xe2\x98\x83\r\n", "BAD_FORMAT\r\n"),
+            (b"use -leading\r\n", "BAD_FORMAT\r\n"),
+            (b"use two tubes\r\n", "BAD_FORMAT\r\n"),
+            (b"ignore\r\n", "BAD_FORMAT\r\n"),
+            (b"pause-tube default -1\r\n", "BAD_FORMAT\r\n"),
+        ];
+
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+        for (input, reply) in cases {
+            let shown = String::from_utf8_lossy(input);
+            assert_eq!(send(&mut client, input), reply, "Input: {:?}", shown);
+            assert_eq!(
+                send(&mut client, b"list-tube-used\r\n"),
+                "USING default\r\n",
+                "Connection recovers after {:?}",
+                shown
+            );
+        }
+        let id = inserted_id(&send(&mut client, b"put 0 0 10 2\r\nhi\r\n"));
+        assert_eq!(
+            send(&mut client, b"reserve-with-timeout 1\r\n"),
+            format!("RESERVED {} 2\r\n", id)
+        );
+    }
+
+    #[test]
+    fn hangs_up_on_clients_sending_only_garbage() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+        let junk = b"\x16\x03\x01\x00\xa5\r\n".repeat(MAX_CONSECUTIVE_ERRORS - 1);
+        client.get_mut().write_all(&junk).unwrap();
+        for _ in 1..MAX_CONSECUTIVE_ERRORS {
+            assert_eq!(read_line(&mut client), "BAD_FORMAT\r\n");
+        }
+        assert_eq!(
+            send(&mut client, b"list-tube-used\r\n"),
+            "USING default\r\n",
+            "A valid command resets the count"
+        );
+
+        let junk = b"GET / HTTP/1.0\r\n".repeat(MAX_CONSECUTIVE_ERRORS);
+        client.get_mut().write_all(&junk).unwrap();
+        for _ in 0..MAX_CONSECUTIVE_ERRORS {
+            assert_eq!(read_line(&mut client), "UNKNOWN_COMMAND\r\n");
+        }
+        assert_eq!(read_line(&mut client), "", "Connection is closed");
+    }
+
+    fn start_server_with_limits(limits: ConnectionLimits) -> (SocketAddr, Arc<TubeRegistry>) {
+        let (grace, options) = (Duration::from_millis(0), CommandOptions::new(MAX_JOB_SIZE));
+        let hub_config = HubConfig::new(DEFAULT_SPOKE_DURATION_MS);
+        let (addr, registry, _, _) = start_limited_server(hub_config, grace, options, limits);
+        (addr, registry)
+    }
+
+    #[test]
+    fn drops_idle_clients_and_releases_their_jobs() {
+        let (addr, registry) = start_server_with_limits(ConnectionLimits {
+            client_idle_timeout: Duration::from_millis(300),
+            ..ConnectionLimits::default()
+        });
+        let mut silent = connect(addr);
+        let mut stuck = connect(addr);
+        let id = inserted_id(&send(&mut stuck, b"put 0 0 60 5\r\nhello\r\n"));
+        assert_eq!(
+            send(&mut stuck, b"reserve\r\n"),
+            format!("RESERVED {} 5\r\n", id)
+        );
+        assert_eq!(read_line(&mut stuck), "hello\r\n");
+
+        let mut busy = connect(addr);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            send(&mut busy, b"reserve-with-timeout 0\r\n"),
+            "TIMED_OUT\r\n",
+            "The job is still reserved"
+        );
+        // Keeps talking, so it outlives the idle timeout
+        for _ in 0..4 {
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(send(&mut busy, b"list-tube-used\r\n"), "USING default\r\n");
+        }
+        assert_eq!(read_line(&mut silent), "", "Connection is closed");
+        assert_eq!(read_line(&mut stuck), "", "Connection is closed");
+        assert_eq!(registry.reserved_job_len(), 0);
+        assert_eq!(
+            send(&mut busy, b"reserve-with-timeout 0\r\n"),
+            format!("RESERVED {} 5\r\n", id),
+            "The idle client's job was released"
+        );
+    }
+
+    #[test]
+    fn hangs_up_on_connections_over_the_limit() {
+        let (addr, _) = start_server_with_limits(ConnectionLimits {
+            max_connections: 2,
+            ..ConnectionLimits::default()
+        });
+        let mut first = connect(addr);
+        let mut second = connect(addr);
+        for client in &mut [&mut first, &mut second] {
+            assert_eq!(send(client, b"list-tube-used\r\n"), "USING default\r\n");
+        }
+        let mut refused = connect(addr);
+        // The write may race the hang up, only the closed connection matters
+        let _ = refused.get_mut().write_all(b"list-tube-used\r\n");
+        let mut reply = String::new();
+        assert!(
+            refused.read_line(&mut reply).map_or(true, |n| n == 0),
+            "Got: {}",
+            reply
+        );
+
+        assert_eq!(send(&mut first, b"quit\r\n"), "");
+        // The server notices the hang up on its own time
+        let mut replies = vec![];
+        for _ in 0..20 {
+            let mut client = connect(addr);
+            let _ = client.get_mut().write_all(b"list-tube-used\r\n");
+            let mut reply = String::new();
+            let _ = client.read_line(&mut reply);
+            replies.push(reply);
+            if replies.last().unwrap() == "USING default\r\n" {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(replies.last().unwrap(), "USING default\r\n", "Got: {:?}", replies);
+    }
+
+    #[test]
+    fn caps_jobs_reserved_per_client() {
+        let (addr, _) = start_server_with_limits(ConnectionLimits {
+            max_reserved_jobs: 2,
+            ..ConnectionLimits::default()
+        });
+        let mut producer = connect(addr);
+        let mut consumer = connect(addr);
+        let ids: Vec<String> = (0..3)
+            .map(|_| inserted_id(&send(&mut producer, b"put 0 0 60 1\r\nx\r\n")))
+            .collect();
+        for id in &ids[..2] {
+            assert_eq!(
+                send(&mut consumer, b"reserve-with-timeout 0\r\n"),
+                format!("RESERVED {} 1\r\n", id)
+            );
+            assert_eq!(read_line(&mut consumer), "x\r\n");
+        }
+        assert_eq!(
+            send(&mut consumer, b"reserve-with-timeout 0\r\n"),
+            "TOO_MANY_RESERVED\r\n"
+        );
+        assert_eq!(
+            send(&mut producer, b"reserve-with-timeout 0\r\n"),
+            format!("RESERVED {} 1\r\n", ids[2]),
+            "The cap is per client"
+        );
+        assert_eq!(read_line(&mut producer), "x\r\n");
+
+        let put = format!("put 0 0 60 1\r\nx\r\ndelete {}\r\n", ids[0]);
+        let id = inserted_id(&send(&mut consumer, put.as_bytes()));
+        assert_eq!(read_line(&mut consumer), "DELETED\r\n");
+        assert_eq!(
+            send(&mut consumer, b"reserve-with-timeout 0\r\n"),
+            format!("RESERVED {} 1\r\n", id)
+        );
+    }
+
+    #[test]
+    fn refuses_jobs_over_the_configured_size() {
+        let (addr, _, _, _) = start_stoppable_server(Duration::from_millis(0), 4);
+        let mut client = connect(addr);
+        assert_eq!(
+            send(&mut client, b"put 0 0 10 5\r\nhello\r\n"),
+            "JOB_TOO_BIG\r\n"
+        );
+        inserted_id(&send(&mut client, b"put 0 0 10 4\r\nhell\r\n"));
+    }
+
+    /// Writes `request` and reads exactly as many bytes as `expected` back
+    fn assert_reply(client: &mut BufReader<TcpStream>, request: &[u8], expected: &[u8]) {
+        client.get_mut().write_all(request).unwrap();
+        let mut reply = vec![0; expected.len()];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&reply),
+            String::from_utf8_lossy(expected)
+        );
+    }
+
+    #[test]
+    fn draining_refuses_puts() {
+        let (addr, registry) = start_server();
+        let mut client = connect(addr);
+
+        let id = inserted_id(&send(&mut client, b"put 0 0 60 6\r\nbefore\r\n"));
+        registry.set_draining(true);
+        assert_eq!(send(&mut client, b"put 0 0 60 6\r\nduring\r\n"), "DRAINING\r\n");
+        assert_eq!(
+            send(&mut client, b"reserve-with-timeout 0\r\n"),
+            format!("RESERVED {} 6\r\n", id),
+            "Jobs put before draining are still handed out"
+        );
+        assert_eq!(read_line(&mut client), "before\r\n");
+    }
+
+    #[test]
+    fn full_tube_answers_puts_with_draining() {
+        let hub_config = HubConfig::new(DEFAULT_SPOKE_DURATION_MS).with_max_pending_jobs(2);
+        let options = CommandOptions::new(MAX_JOB_SIZE);
+        let grace = Duration::from_millis(0);
+        let (addr, _, _, _) =
+            start_limited_server(hub_config, grace, options, ConnectionLimits::default());
+        let mut client = connect(addr);
+
+        let id = inserted_id(&send(&mut client, b"put 0 0 60 5\r\nfirst\r\n"));
+        inserted_id(&send(&mut client, b"put 0 60 60 6\r\nsecond\r\n"));
+        assert_eq!(send(&mut client, b"put 0 0 60 5\r\nthird\r\n"), "DRAINING\r\n");
+        let stats = send_stats(&mut client, b"stats\r\n");
+        assert_eq!(stats["current-jobs-total"], "2");
+        assert_eq!(stats["max-jobs-per-tube"], "2");
+
+        assert_eq!(
+            send(&mut client, b"reserve-with-timeout 0\r\n"),
+            format!("RESERVED {} 5\r\n", id)
+        );
+        assert_eq!(read_line(&mut client), "first\r\n");
+        assert_eq!(send(&mut client, b"put 0 0 60 5\r\nthird\r\n"), "DRAINING\r\n");
+        let delete = format!("delete {}\r\n", id);
+        assert_eq!(send(&mut client, delete.as_bytes()), "DELETED\r\n");
+        inserted_id(&send(&mut client, b"put 0 0 60 5\r\nthird\r\n"));
+        let stats = send_stats(&mut client, b"stats-tube default\r\n");
+        assert_eq!(stats["current-jobs-total"], "2");
+        assert_eq!(stats["max-jobs"], "2");
+    }
+
+    #[test]
+    fn paused_tube_resumes_after_its_delay() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+
+        assert_eq!(send(&mut client, b"pause-tube emails 1\r\n"), "NOT_FOUND\r\n");
+        let id = inserted_id(&send(&mut client, b"put 0 0 60 5\r\nhello\r\n"));
+        let paused_at = Instant::now();
+        assert_eq!(send(&mut client, b"pause-tube default 1\r\n"), "PAUSED\r\n");
+        assert_eq!(
+            send(&mut client, b"reserve-with-timeout 0\r\n"),
+            "TIMED_OUT\r\n",
+            "Paused tube hands out nothing"
+        );
+        assert_eq!(
+            send(&mut client, b"reserve-with-timeout 3\r\n"),
+            format!("RESERVED {} 5\r\n", id),
+            "Tube resumes by itself"
+        );
+        // The pause starts at the tube's clock, whose milliseconds are truncated, so it may end up
+        // to 1ms short of a second after `paused_at`
+        assert!(paused_at.elapsed() >= Duration::from_millis(999));
+        assert_eq!(read_line(&mut client), "hello\r\n");
+    }
+
+    #[test]
+    fn lists_tubes() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+        assert_reply(
+            &mut client,
+            b"list-tubes\r\n",
+            b"OK 14\r\n---\n- default\n\r\n",
+        );
+        assert_reply(&mut client, b"list-tube-used\r\n", b"USING default\r\n");
+        assert_reply(
+            &mut client,
+            b"list-tubes-watched\r\n",
+            b"OK 14\r\n---\n- default\n\r\n",
+        );
+
+        assert_eq!(send(&mut client, b"use emails\r\n"), "USING emails\r\n");
+        assert_eq!(send(&mut client, b"watch alerts\r\n"), "WATCHING 2\r\n");
+        assert_eq!(send(&mut client, b"ignore default\r\n"), "WATCHING 1\r\n");
+        assert_reply(
+            &mut client,
+            b"list-tubes\r\n",
+            b"OK 32\r\n---\n- alerts\n- default\n- emails\n\r\n",
+        );
+        assert_reply(&mut client, b"list-tube-used\r\n", b"USING emails\r\n");
+        assert_reply(
+            &mut client,
+            b"list-tubes-watched\r\n",
+            b"OK 13\r\n---\n- alerts\n\r\n",
+        );
+
+        let mut other = connect(addr);
+        assert_reply(&mut other, b"list-tube-used\r\n", b"USING default\r\n");
+    }
+
+    #[test]
+    fn use_watch_and_ignore_tubes() {
+        let (addr, _) = start_server();
+        let mut producer = connect(addr);
+        assert_eq!(send(&mut producer, b"use emails\r\n"), "USING emails\r\n");
+        let id = inserted_id(&send(&mut producer, b"put 0 0 60 2\r\nhi\r\n"));
+
+        let mut consumer = connect(addr);
+        assert_eq!(
+            send(&mut consumer, b"reserve-with-timeout 0\r\n"),
+            "TIMED_OUT\r\n",
+            "Consumer only watches the default tube"
+        );
+        assert_eq!(send(&mut consumer, b"watch emails\r\n"), "WATCHING 2\r\n");
+        assert_eq!(send(&mut consumer, b"watch emails\r\n"), "WATCHING 2\r\n");
+        assert_eq!(send(&mut consumer, b"ignore default\r\n"), "WATCHING 1\r\n");
+        assert_eq!(send(&mut consumer, b"ignore emails\r\n"), "NOT_IGNORED\r\n");
+        assert_eq!(
+            send(&mut consumer, b"reserve-with-timeout 0\r\n"),
+            format!("RESERVED {} 2\r\n", id)
+        );
+        read_line(&mut consumer);
+        assert_eq!(
+            send(&mut consumer, format!("delete {}\r\n", id).as_bytes()),
+            "DELETED\r\n"
+        );
+    }
+
+    #[test]
+    fn answers_pipelined_puts_in_order() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+        let mut puts = vec![];
+        for i in 0..5 {
+            puts.extend_from_slice(format!("put 0 0 60 6\r\nbody-{}\r\n", i).as_bytes());
+        }
+        client.get_mut().write_all(&puts).unwrap();
+
+        let ids: Vec<u64> = (0..5)
+            .map(|_| inserted_id(&read_line(&mut client)).parse().unwrap())
+            .collect();
+        assert!(ids.windows(2).all(|w| w[0] < w[1]), "Inserted out of order: {:?}", ids);
+        for (i, id) in ids.iter().enumerate() {
+            assert_eq!(
+                send(&mut client, b"reserve-with-timeout 0\r\n"),
+                format!("RESERVED {} 6\r\n", id)
+            );
+            assert_eq!(read_line(&mut client), format!("body-{}\r\n", i));
+        }
+    }
+
+    #[test]
+    fn answers_pipelined_puts_and_reserves_in_order() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+        client
+            .get_mut()
+            .write_all(
+                b"put 0 0 60 1\r\na\r\nreserve-with-timeout 0\r\nput 0 0 60 1\r\nb\r\n\
+                  reserve-with-timeout 0\r\nreserve-with-timeout 0\r\nlist-tube-used\r\n",
+            )
+            .unwrap();
+
+        let a = inserted_id(&read_line(&mut client));
+        assert_eq!(read_line(&mut client), format!("RESERVED {} 1\r\n", a));
+        assert_eq!(read_line(&mut client), "a\r\n");
+        let b = inserted_id(&read_line(&mut client));
+        assert_eq!(read_line(&mut client), format!("RESERVED {} 1\r\n", b));
+        assert_eq!(read_line(&mut client), "b\r\n");
+        assert_eq!(read_line(&mut client), "TIMED_OUT\r\n");
+        assert_eq!(read_line(&mut client), "USING default\r\n");
+    }
+
+    #[test]
+    fn snapshots_every_tube_on_request() {
+        let options = CommandOptions {
+            max_job_size: MAX_JOB_SIZE,
+            snapshot_command: true,
+        };
+        let hub_config = HubConfig::new(DEFAULT_SPOKE_DURATION_MS);
+        let grace = Duration::from_millis(0);
+        let (addr, _, _, _) =
+            start_limited_server(hub_config, grace, options, ConnectionLimits::default());
+        let mut client = connect(addr);
+        inserted_id(&send(&mut client, b"put 0 0 60 3\r\none\r\n"));
+        assert_eq!(send(&mut client, b"use emails\r\n"), "USING emails\r\n");
+        inserted_id(&send(&mut client, b"put 0 30 60 3\r\ntwo\r\n"));
+
+        let path = env::temp_dir().join(format!("yaad-beanstalkd-snapshot-{}", process::id()));
+        let request = format!("snapshot {}\r\n", path.display());
+        assert_eq!(send(&mut client, request.as_bytes()), "OK\r\n");
+        let mut jobs = yaad::persistence::read_jobs(&mut fs::File::open(&path).unwrap()).unwrap();
+        jobs.sort_by(|a, b| a.0.cmp(&b.0));
+        let labels: Vec<&str> = jobs.iter().map(|j| j.0.as_str()).collect();
+        assert_eq!(labels, vec!["default", "emails"]);
+        assert_eq!(jobs[1].1.get_body().as_bytes(), b"two");
+        fs::remove_file(&path).unwrap();
+
+        let unwritable = path.join("not-a-dir").join("jobs");
+        let request = format!("snapshot {}\r\n", unwritable.display());
+        assert_eq!(send(&mut client, request.as_bytes()), "INTERNAL_ERROR\r\n");
+    }
+
+    #[test]
+    fn snapshot_command_is_unknown_unless_enabled() {
+        let (addr, _) = start_server();
+        let mut client = connect(addr);
+        assert_eq!(
+            send(&mut client, b"snapshot /tmp/jobs.snap\r\n"),
+            "UNKNOWN_COMMAND\r\n"
+        );
+    }
+
+    #[test]
+    fn put_bodies_are_reserved_without_copies() {
+        const JOBS: usize = 10_000;
+        let mut put = format!("put 0 0 60 {}\r\n", MAX_JOB_SIZE).into_bytes();
+        put.extend_from_slice(&vec![b'x'; MAX_JOB_SIZE]);
+        put.extend_from_slice(b"\r\n");
+        let mut decoder = Decoder::new(MAX_LINE_LEN, MAX_JOB_SIZE);
+
+        let before = allocated();
+        for _ in 0..JOBS {
+            // Fed the way the server reads it off the stream
+            for chunk in put.chunks(READ_CHUNK_LEN) {
+                decoder.feed(chunk);
+                while let Some(frame) = decoder.next_frame() {
+                    if let Frame::Data(data) = frame {
+                        let job = Job::new_auto_id(0, data.clone());
+                        assert_eq!(job.get_body().as_bytes().as_ptr(), data.as_ptr());
+                        let reply = Reply::with_body("RESERVED", 1, job.get_body());
+                        reply.write_to(&mut io::sink()).unwrap();
+                    }
+                }
+            }
+        }
+        let allocated = allocated() - before;
+        let body_bytes = JOBS * MAX_JOB_SIZE;
+        // Copying each body out of the read buffer, out of the job and into the reply took three
+        // times the body bytes
+        assert!(
+            allocated < body_bytes * 3 / 2,
+            "Allocated {} bytes for {} body bytes",
+            allocated,
+            body_bytes
+        );
+    }
+}
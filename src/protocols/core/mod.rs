@@ -0,0 +1,258 @@
+//! The protocol-agnostic half of the servers: the tubes behind them, the sockets they listen on,
+//! the connections they serve and what a client can do with the hub.
+//!
+//! A wire protocol only decodes its requests into calls on a [`Session`] and encodes the
+//! outcomes as replies. Accepting connections, running the tubes' housekeeping and shutting down
+//! gracefully are handled by [`serve_until`] for every protocol alike, and all of them share the
+//! same [`TubeRegistry`].
+
+mod session;
+mod sockets;
+mod tubes;
+
+use shutdown;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind};
+use std::net::Shutdown;
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use yaad::job::Job;
+use yaad::persistence::{self, SavedState};
+
+pub use self::session::{Reservation, Session, MIN_TTR_MS};
+pub use self::sockets::{bind_unix, Client, Listener};
+pub use self::tubes::{StatsDict, TubeRegistry, DEFAULT_TUBE};
+
+/// Connections served at once unless configured otherwise
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+/// How long a client may stay silent before it is hung up on, unless configured otherwise
+pub const DEFAULT_CLIENT_IDLE_TIMEOUT_MS: u64 = 300_000;
+/// Jobs a client may hold reserved at once unless configured otherwise
+pub const DEFAULT_MAX_RESERVED_JOBS: usize = 1024;
+/// How often the listener and idle connections check whether the server is shutting down
+const SHUTDOWN_POLL_MS: u64 = 50;
+
+/// Limits on what the clients of a server can hold on to, so that stuck or greedy clients can't
+/// take the server down
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionLimits {
+    /// Connections served at once. Clients connecting beyond it are hung up on right away.
+    pub max_connections: usize,
+    /// How long a client may go without sending anything before it is hung up on and the jobs it
+    /// reserved are released
+    pub client_idle_timeout: Duration,
+    /// Jobs a client may hold reserved at once
+    pub max_reserved_jobs: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> ConnectionLimits {
+        ConnectionLimits {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            client_idle_timeout: Duration::from_millis(DEFAULT_CLIENT_IDLE_TIMEOUT_MS),
+            max_reserved_jobs: DEFAULT_MAX_RESERVED_JOBS,
+        }
+    }
+}
+
+/// Reads the jobs, labelled with their tube and in their saved state, from the snapshot at `path`.
+/// Returns no jobs if there is no snapshot yet.
+pub fn restore(path: &Path) -> io::Result<Vec<(String, Job, SavedState)>> {
+    match File::open(path) {
+        Ok(f) => {
+            let jobs = persistence::read_jobs(&mut BufReader::new(f))?;
+            info!("Restored {} jobs from {}", jobs.len(), path.display());
+            Ok(jobs)
+        }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+/// Accepts connections on every listener, serving each one with `handler` on a thread of its
+/// own, until a message arrives on `shutdown`, and runs the tubes' housekeeping every
+/// `tick_interval` meanwhile. Then closes the registry, gives the open connections up to `grace`
+/// to finish their current request and puts every job still reserved back into its tube.
+/// Clients connecting while `limits.max_connections` are served already are hung up on.
+///
+/// The handler gets the client, a description of its peer, the registry and the limits to hold
+/// the client to. Reads off the client time out every so often, so the handler can stop once
+/// [`TubeRegistry::is_closed`] or the client has been idle for too long.
+pub fn serve_until<H>(
+    listeners: Vec<Listener>,
+    registry: Arc<TubeRegistry>,
+    shutdown: &Receiver<()>,
+    grace: Duration,
+    tick_interval: Duration,
+    limits: ConnectionLimits,
+    handler: H,
+) -> io::Result<()>
+where
+    H: Fn(Client, &str, &TubeRegistry, &ConnectionLimits) -> io::Result<()> + Send + Sync + 'static,
+{
+    let poll = Duration::from_millis(SHUTDOWN_POLL_MS);
+    for listener in &listeners {
+        listener.set_nonblocking(true)?;
+    }
+    let handler = Arc::new(move |client, peer: &str, registry: &TubeRegistry| {
+        handler(client, peer, registry, &limits)
+    });
+    let connections = Arc::new(Connections::new());
+    let mut last_tick: Option<Instant> = None;
+    // A disconnected trigger can't ask for a shutdown anymore, so keep serving
+    while shutdown.try_recv().is_err() {
+        if last_tick.is_none_or(|t| t.elapsed() >= tick_interval) {
+            if shutdown::drain_requested() && !registry.is_draining() {
+                info!("Draining, no longer accepting puts");
+                registry.set_draining(true);
+            }
+            registry.tick();
+            last_tick = Some(Instant::now());
+        }
+        let mut accepted = false;
+        for listener in &listeners {
+            match listener.accept() {
+                Ok(client) => {
+                    accepted = true;
+                    if connections.len() >= limits.max_connections {
+                        refuse(client, limits.max_connections);
+                        continue;
+                    }
+                    serve_client(client, &registry, &connections, &handler, poll)?;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => error!("Failed to accept client connection: {}", e),
+            }
+        }
+        if !accepted {
+            thread::sleep(poll);
+        }
+    }
+
+    info!("Shutting down, no longer accepting connections");
+    drop(listeners);
+    registry.close();
+    let cut_off = connections.drain(grace);
+    if cut_off > 0 {
+        info!(
+            "Hung up on {} clients still busy after {:?}",
+            cut_off, grace
+        );
+    }
+    let released = registry.release_reservations();
+    info!("Released {} reserved jobs", released);
+    Ok(())
+}
+
+/// Serves `client` on a thread of its own. Fails only if the thread can't be started.
+fn serve_client<H>(
+    client: Client,
+    registry: &Arc<TubeRegistry>,
+    connections: &Arc<Connections>,
+    handler: &Arc<H>,
+    poll: Duration,
+) -> io::Result<()>
+where
+    H: Fn(Client, &str, &TubeRegistry) -> io::Result<()> + Send + Sync + 'static,
+{
+    // Reads time out so an idle connection notices the shutdown
+    let set_up = client
+        .prepare(poll)
+        .and_then(|_| client.peer())
+        .and_then(|peer| connections.add(&client).map(|id| (id, peer)));
+    let (id, peer) = match set_up {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to set up client connection: {}", e);
+            return Ok(());
+        }
+    };
+    let (registry, connections) = (Arc::clone(registry), Arc::clone(connections));
+    let handler = Arc::clone(handler);
+    thread::Builder::new()
+        .name(format!("client-{}", id))
+        .spawn(move || {
+            registry.counters().record_connection_opened();
+            if let Err(e) = handler(client, &peer, &registry) {
+                error!("Client connection closed with error: {}", e);
+            }
+            registry.counters().record_connection_closed();
+            connections.remove(id);
+        })?;
+    Ok(())
+}
+
+/// Hangs up on a client connecting over the connection limit
+fn refuse(client: Client, max_connections: usize) {
+    let peer = client.peer().unwrap_or_else(|e| e.to_string());
+    warn!(
+        "Hanging up on client {}: serving {} connections already",
+        peer, max_connections
+    );
+    // The client may have hung up already
+    let _ = client.shutdown(Shutdown::Both);
+}
+
+/// Returns true if a read failed only because it timed out, in which case the handler should
+/// check for a shutdown and keep waiting
+pub fn is_read_timeout(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
+
+/// Client connections still being served, so a shutdown can wait for them to close
+struct Connections {
+    open: Mutex<(u64, HashMap<u64, Client>)>,
+    closed: Condvar,
+}
+
+impl Connections {
+    fn new() -> Connections {
+        Connections {
+            open: Mutex::new((0, HashMap::new())),
+            closed: Condvar::new(),
+        }
+    }
+
+    /// Tracks a connection until it is removed and returns its id
+    fn add(&self, stream: &Client) -> io::Result<u64> {
+        let stream = stream.try_clone()?;
+        let mut open = self.open.lock().unwrap();
+        open.0 += 1;
+        let id = open.0;
+        open.1.insert(id, stream);
+        Ok(id)
+    }
+
+    /// Returns the number of connections still being served
+    fn len(&self) -> usize {
+        self.open.lock().unwrap().1.len()
+    }
+
+    fn remove(&self, id: u64) {
+        self.open.lock().unwrap().1.remove(&id);
+        self.closed.notify_all();
+    }
+
+    /// Waits up to `grace` for every connection to close, then hangs up on the ones still open.
+    /// Returns how many had to be hung up on.
+    fn drain(&self, grace: Duration) -> usize {
+        let deadline = Instant::now() + grace;
+        let mut open = self.open.lock().unwrap();
+        while !open.1.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            open = self.closed.wait_timeout(open, deadline - now).unwrap().0;
+        }
+        for stream in open.1.values() {
+            // The client may have hung up already
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        open.1.len()
+    }
+}
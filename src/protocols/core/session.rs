@@ -0,0 +1,216 @@
+//! What one client connection can do with the tubes, whichever protocol it speaks.
+//!
+//! A [`Session`] remembers the tube the client puts jobs on, the tubes it reserves jobs from and
+//! the reservations it holds, so that only the client that reserved a job can delete, touch,
+//! release or bury it while the reservation lasts.
+
+use std::collections::HashMap;
+use std::time::Duration;
+use yaad::errors::YaadError;
+use yaad::job::{Job, JobBody};
+
+use super::tubes::{TubeRegistry, DEFAULT_TUBE};
+use super::DEFAULT_MAX_RESERVED_JOBS;
+
+/// Shortest TTR a job can have. Like beanstalkd, a TTR of 0 is bumped to it.
+pub const MIN_TTR_MS: u64 = 1_000;
+/// How long before one of its reservations runs out a client waiting in reserve is told so
+/// instead of handed another job, beanstalkd's safety margin
+pub const DEADLINE_SOON_MS: u64 = 1_000;
+
+/// The outcome of [`Session::reserve`]
+#[derive(Debug)]
+pub enum Reservation {
+    /// A job and its id, handed to this client until it deletes the job or the TTR runs out
+    Reserved(Job, u64),
+    TimedOut,
+    /// A job this client reserved runs out within [`DEADLINE_SOON_MS`] and no job was ready, so
+    /// the client should finish or touch it before waiting for another one
+    DeadlineSoon,
+    /// The client holds as many reserved jobs as it may already, see
+    /// [`Session::with_max_reserved_jobs`]
+    LimitReached,
+    /// The server started shutting down, so no more jobs are handed out
+    Closed,
+}
+
+pub struct Session<'a> {
+    registry: &'a TubeRegistry,
+    /// Tube this client puts jobs on, and the tubes it reserves jobs from
+    using: String,
+    watching: Vec<String>,
+    /// Ids of jobs handed to this client that it hasn't deleted yet, with their reservation
+    /// deadlines
+    reserved: HashMap<u64, u64>,
+    max_reserved_jobs: usize,
+}
+
+impl<'a> Session<'a> {
+    /// Starts a session using and watching only the default tube
+    pub fn new(registry: &'a TubeRegistry) -> Session<'a> {
+        Session {
+            registry,
+            using: DEFAULT_TUBE.to_owned(),
+            watching: vec![DEFAULT_TUBE.to_owned()],
+            reserved: HashMap::new(),
+            max_reserved_jobs: DEFAULT_MAX_RESERVED_JOBS,
+        }
+    }
+
+    /// Returns this session refusing to reserve jobs while it holds `max_reserved_jobs` already
+    pub fn with_max_reserved_jobs(mut self, max_reserved_jobs: usize) -> Session<'a> {
+        self.max_reserved_jobs = max_reserved_jobs;
+        self
+    }
+
+    pub fn registry(&self) -> &'a TubeRegistry {
+        self.registry
+    }
+
+    pub fn using(&self) -> &str {
+        &self.using
+    }
+
+    pub fn watching(&self) -> &[String] {
+        &self.watching
+    }
+
+    /// Puts jobs on `tube` from now on
+    pub fn use_tube(&mut self, tube: String) {
+        self.registry.touch(&tube);
+        self.using = tube;
+    }
+
+    /// Reserves jobs from `tube` as well. Returns the number of tubes watched.
+    pub fn watch(&mut self, tube: String) -> usize {
+        if !self.watching.contains(&tube) {
+            self.registry.touch(&tube);
+            self.watching.push(tube);
+        }
+        self.watching.len()
+    }
+
+    /// Stops reserving jobs from `tube` and returns the number of tubes still watched. A client
+    /// has to watch at least one tube, so the last one can't be ignored.
+    pub fn ignore(&mut self, tube: &str) -> Option<usize> {
+        if self.watching.len() == 1 && self.watching[0] == tube {
+            return None;
+        }
+        self.watching.retain(|t| t != tube);
+        Some(self.watching.len())
+    }
+
+    /// Returns a job triggering `delay_ms` from now, reserved for `ttr_ms` at a time
+    pub fn new_job<B: Into<JobBody>>(
+        &self,
+        priority: u32,
+        delay_ms: u64,
+        ttr_ms: u64,
+        body: B,
+    ) -> Job {
+        let trigger_at_ms = self.registry.now_ms().saturating_add(delay_ms);
+        let ttr_ms = if ttr_ms == 0 { MIN_TTR_MS } else { ttr_ms };
+        Job::new_auto_id(trigger_at_ms, body)
+            .with_ttr_ms(ttr_ms)
+            .with_priority(priority)
+    }
+
+    /// Schedules `job` on the used tube. Returns its id, or why the tube's hub refused it.
+    pub fn put(&self, job: Job) -> Result<u64, YaadError> {
+        self.registry.put(&self.using, job)
+    }
+
+    /// Waits up to `timeout`, or forever if it is None, for the next ready job on any watched
+    /// tube and hands it to this client. Stops waiting with [`Reservation::DeadlineSoon`] once a
+    /// job the client holds is within [`DEADLINE_SOON_MS`] of running out, right away if it is
+    /// already.
+    pub fn reserve(&mut self, timeout: Option<Duration>) -> Reservation {
+        // Reservations that ran out don't count, their jobs went back to their tubes
+        let now_ms = self.registry.now_ms();
+        self.reserved.retain(|_, deadline_ms| *deadline_ms > now_ms);
+        if self.reserved.len() >= self.max_reserved_jobs {
+            return Reservation::LimitReached;
+        }
+        let soon_ms = self
+            .reserved
+            .values()
+            .min()
+            .map(|deadline_ms| deadline_ms.saturating_sub(DEADLINE_SOON_MS));
+        match self.registry.reserve_until(&self.watching, timeout, soon_ms) {
+            Some((job, id, deadline_ms)) => {
+                self.reserved.insert(id, deadline_ms);
+                Reservation::Reserved(job, id)
+            }
+            None if self.registry.is_closed() => Reservation::Closed,
+            None if soon_ms.is_some_and(|soon_ms| self.registry.now_ms() >= soon_ms) => {
+                Reservation::DeadlineSoon
+            }
+            None => Reservation::TimedOut,
+        }
+    }
+
+    /// Deletes a delayed or ready job, or one this client reserved as long as its reservation
+    /// hasn't run out. Returns false if there is no such job.
+    pub fn delete(&mut self, id: u64) -> bool {
+        self.registry.delete(id, self.reserved.remove(&id))
+    }
+
+    /// Gives a job this client reserved its full TTR again, as long as its reservation hasn't
+    /// run out. Returns false if the job isn't reserved by this client.
+    pub fn touch(&mut self, id: u64) -> bool {
+        match self.registry.touch_job(id, self.reserved.get(&id).cloned()) {
+            Some(deadline_ms) => {
+                self.reserved.insert(id, deadline_ms);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Puts a job this client reserved back with a new priority, to be ready `delay_ms` from
+    /// now, as long as its reservation hasn't run out. Returns false if the job isn't reserved by
+    /// this client.
+    pub fn release(&mut self, id: u64, priority: u32, delay_ms: u64) -> Result<bool, YaadError> {
+        let deadline_ms = self.reserved.get(&id).cloned();
+        let released = self.registry.release(id, deadline_ms, priority, delay_ms)?;
+        if released {
+            self.reserved.remove(&id);
+        }
+        Ok(released)
+    }
+
+    /// Shelves a job this client reserved until it is kicked, as long as its reservation hasn't
+    /// run out. Returns false if the job isn't reserved by this client.
+    pub fn bury(&mut self, id: u64, priority: u32) -> bool {
+        let buried = self
+            .registry
+            .bury(id, self.reserved.get(&id).cloned(), priority);
+        if buried {
+            self.reserved.remove(&id);
+        }
+        buried
+    }
+
+    /// Puts every job this client still holds reserved back on its tube, ready right away and
+    /// with the priority it had, e.g. when the client is hung up on. Returns the number of jobs
+    /// released.
+    pub fn release_all(&mut self) -> usize {
+        let registry = self.registry;
+        self.reserved
+            .drain()
+            .filter(|&(id, deadline_ms)| {
+                let priority = match registry.peek(id) {
+                    Some(job) => job.priority(),
+                    None => return false,
+                };
+                match registry.release(id, Some(deadline_ms), priority, 0) {
+                    Ok(released) => released,
+                    Err(e) => {
+                        error!("Failed to release job {}: {}", id, e);
+                        false
+                    }
+                }
+            })
+            .count()
+    }
+}
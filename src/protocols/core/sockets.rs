@@ -0,0 +1,184 @@
+//! The sockets clients reach the server on: a TCP address, a unix socket, or both.
+//!
+//! Every kind of connection is served by the same handler. [`Client`] reads and writes whichever
+//! stream it wraps, so the rest of the server doesn't have to know how the client connected.
+
+use std::fs;
+use std::io::{self, ErrorKind, IoSlice, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{SocketAddr as UnixSocketAddr, UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+/// A socket the server accepts clients on
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref l) => l.set_nonblocking(nonblocking),
+            Listener::Unix(ref l) => l.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<Client> {
+        match *self {
+            Listener::Tcp(ref l) => l.accept().map(|(s, _)| Client::Tcp(s)),
+            Listener::Unix(ref l) => l.accept().map(|(s, _)| Client::Unix(s)),
+        }
+    }
+
+    /// Describes where clients connect, for logging
+    pub fn describe(&self) -> io::Result<String> {
+        match *self {
+            Listener::Tcp(ref l) => Ok(l.local_addr()?.to_string()),
+            Listener::Unix(ref l) => Ok(describe_unix(&l.local_addr()?)),
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Listener {
+        Listener::Tcp(listener)
+    }
+}
+
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Listener {
+        Listener::Unix(listener)
+    }
+}
+
+/// Binds a unix socket at `path`, replacing the socket file of a server that is gone. Fails if a
+/// server still listens there, and leaves anything at `path` that isn't a socket alone.
+pub fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    let is_socket = fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket());
+    if is_socket {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                ErrorKind::AddrInUse,
+                format!("A server is listening on {} already", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+fn describe_unix(addr: &UnixSocketAddr) -> String {
+    match addr.as_pathname() {
+        Some(path) => format!("unix:{}", path.display()),
+        None => "unix socket".to_owned(),
+    }
+}
+
+/// A client connected over TCP or a unix socket
+#[derive(Debug)]
+pub enum Client {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Client {
+    /// Describes the client for logging. Unix socket clients rarely bind a path of their own, so
+    /// they are described by the socket they connected to.
+    pub fn peer(&self) -> io::Result<String> {
+        match *self {
+            Client::Tcp(ref s) => Ok(s.peer_addr()?.to_string()),
+            Client::Unix(ref s) => Ok(describe_unix(&s.local_addr()?)),
+        }
+    }
+
+    /// Makes reads block for at most `read_timeout`, so the connection's thread can check for a
+    /// shutdown in between
+    pub fn prepare(&self, read_timeout: Duration) -> io::Result<()> {
+        match *self {
+            Client::Tcp(ref s) => {
+                s.set_nonblocking(false)?;
+                s.set_read_timeout(Some(read_timeout))
+            }
+            Client::Unix(ref s) => {
+                s.set_nonblocking(false)?;
+                s.set_read_timeout(Some(read_timeout))
+            }
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Client> {
+        match *self {
+            Client::Tcp(ref s) => s.try_clone().map(Client::Tcp),
+            Client::Unix(ref s) => s.try_clone().map(Client::Unix),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match *self {
+            Client::Tcp(ref s) => s.shutdown(how),
+            Client::Unix(ref s) => s.shutdown(how),
+        }
+    }
+}
+
+impl Read for Client {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Client::Tcp(ref mut s) => s.read(buf),
+            Client::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Client {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Client::Tcp(ref mut s) => s.write(buf),
+            Client::Unix(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        match *self {
+            Client::Tcp(ref mut s) => s.write_vectored(bufs),
+            Client::Unix(ref mut s) => s.write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Client::Tcp(ref mut s) => s.flush(),
+            Client::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn replaces_stale_sockets_only() {
+        let path = env::temp_dir().join(format!("yaad-sockets-test-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = bind_unix(&path).unwrap();
+        let err = bind_unix(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse, "A server listens there");
+
+        drop(listener);
+        assert!(path.exists(), "Dropping a listener leaves its socket file behind");
+        let listener = bind_unix(&path).expect("Stale socket is replaced");
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+
+        fs::write(&path, b"not a socket").unwrap();
+        assert!(bind_unix(&path).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"not a socket", "Other files are left alone");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1224 @@
+//! Named tubes, each scheduling its jobs on its own Hub.
+//!
+//! All tubes live behind one lock so that a client watching several tubes can wait for the next
+//! job on any of them in one place. Clients waiting in reserve queue up, and jobs that become
+//! ready are handed to the one that waited longest among those watching the job's tube, so no
+//! worker starves while another keeps getting jobs. Job ids are handed out across tubes, like
+//! beanstalkd does, and map to the owning tube and the hub's Uuid until the job is deleted.
+
+use metrics::ServerMetrics;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::Path;
+use std::process;
+use std::mem;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use yaad::clock::Clock;
+use yaad::errors::YaadError;
+use yaad::hub::{Hub, HubConfig, JobState};
+use yaad::job::Job;
+use yaad::persistence::{self, SavedState};
+use yaad::stats::Stats;
+
+/// Tube every connection uses and watches until told otherwise
+pub const DEFAULT_TUBE: &str = "default";
+
+pub struct TubeRegistry {
+    state: Mutex<State>,
+    /// Shared by every tube's hub and the connections served
+    stats: Arc<Stats>,
+    started: Instant,
+}
+
+/// A `stats` reply: counters by name, in the order they are reported
+pub type StatsDict = Vec<(&'static str, String)>;
+
+/// A reserved job with its id and reservation deadline
+type Reserved = (Job, u64, u64);
+
+struct State {
+    tubes: HashMap<String, Tube>,
+    next_id: u64,
+    uuids: HashMap<u64, (String, Uuid)>,
+    ids: HashMap<Uuid, u64>,
+    /// Set once the server shuts down - no jobs are handed out after that
+    closed: bool,
+    stats: Arc<Stats>,
+    /// Config of the hubs of tubes created on the fly, and the clock they read the time off
+    hub_config: HubConfig,
+    clock: Arc<dyn Clock>,
+    /// Set while every tube refuses puts, see [`TubeRegistry::set_draining`]
+    draining: bool,
+    /// Where every tube's hub reports its gauges, see [`TubeRegistry::set_metrics`]
+    metrics: Option<Arc<dyn ServerMetrics>>,
+    /// Clients waiting in reserve, longest waiting first
+    waiters: VecDeque<Waiter>,
+    next_ticket: u64,
+}
+
+/// A client waiting in reserve for a job on any of the tubes it watches. The job is sent to it
+/// once it is the longest waiting client a ready job can go to.
+struct Waiter {
+    ticket: u64,
+    watched: Vec<String>,
+    sender: mpsc::Sender<Reserved>,
+}
+
+/// A tube's hub, plus the jobs that were walked off it but not yet reserved. Reserved jobs are
+/// tracked by the hub until they are deleted, buried or their TTR runs out, and buried ones until
+/// they are kicked or deleted.
+struct Tube {
+    hub: Hub,
+    ready: VecDeque<Job>,
+    /// Jobs ever put on this tube
+    total_jobs: usize,
+    /// How long the tube was last paused for, and until when no jobs are handed out
+    pause: Duration,
+    paused_until_ms: u64,
+}
+
+/// Number of jobs in each state, on one tube or all of them
+#[derive(Default)]
+struct JobCounts {
+    ready: usize,
+    reserved: usize,
+    delayed: usize,
+    buried: usize,
+}
+
+impl JobCounts {
+    fn add(&mut self, other: JobCounts) {
+        self.ready += other.ready;
+        self.reserved += other.reserved;
+        self.delayed += other.delayed;
+        self.buried += other.buried;
+    }
+
+    fn report(&self, dict: &mut StatsDict) {
+        dict.push(("current-jobs-ready", self.ready.to_string()));
+        dict.push(("current-jobs-reserved", self.reserved.to_string()));
+        dict.push(("current-jobs-delayed", self.delayed.to_string()));
+        dict.push(("current-jobs-buried", self.buried.to_string()));
+        let total = self.ready + self.reserved + self.delayed + self.buried;
+        dict.push(("current-jobs-total", total.to_string()));
+    }
+}
+
+impl Tube {
+    fn new(
+        name: &str,
+        mut hub: Hub,
+        stats: &Arc<Stats>,
+        draining: bool,
+        metrics: &Option<Arc<dyn ServerMetrics>>,
+    ) -> Tube {
+        hub.set_counters(Arc::clone(stats)).set_draining(draining);
+        if let Some(ref metrics) = *metrics {
+            hub.set_metrics(Arc::clone(metrics).for_tube(name));
+        }
+        Tube {
+            hub,
+            ready: VecDeque::new(),
+            total_jobs: 0,
+            pause: Duration::from_millis(0),
+            paused_until_ms: 0,
+        }
+    }
+
+    fn is_paused_at(&self, now_ms: u64) -> bool {
+        now_ms < self.paused_until_ms
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.hub.clock().now_ms()
+    }
+
+    /// Returns a copy of a job on this tube and where it is, walked jobs waiting to be reserved
+    /// included
+    fn find_job(&self, uuid: Uuid) -> Option<(Job, JobState)> {
+        let ready = self
+            .ready
+            .iter()
+            .find(|j| j.get_metadata().get_id() == uuid);
+        match ready {
+            Some(j) => Some((j.clone(), JobState::Ready)),
+            None => self.hub.find_job(uuid),
+        }
+    }
+
+    /// Returns a copy of the job reserve would hand out next, walked or not. The hub breaks ties
+    /// between jobs due at the same time with the same priority by their Uuid rather than put
+    /// order, so among those this may pick a different one than reserve.
+    fn peek_ready(&self) -> Option<Job> {
+        match self.ready.front() {
+            Some(j) => Some(j.clone()),
+            None => self
+                .hub
+                .peek_next_ready()
+                .map(|(jm, body)| Job::new_from_metadata(jm, body)),
+        }
+    }
+
+    fn peek_delayed(&self) -> Option<Job> {
+        self.hub
+            .peek_next_delayed()
+            .map(|(jm, body)| Job::new_from_metadata(jm, body))
+    }
+
+    fn peek_buried(&self) -> Option<Job> {
+        self.hub.peek_next_buried().cloned()
+    }
+
+    fn counts(&self) -> JobCounts {
+        let due = self.hub.ready_job_count();
+        JobCounts {
+            ready: due + self.ready.len(),
+            reserved: self.hub.reserved_job_len(),
+            delayed: self.hub.pending_job_count() - due,
+            buried: self.hub.buried_job_len(),
+        }
+    }
+
+    /// Returns the number of jobs in the tube not yet deleted, what the hub's
+    /// [`HubConfig::max_pending_jobs`] limits. Jobs walked and waiting to be reserved count too.
+    fn job_count(&self) -> usize {
+        self.hub.held_job_count() + self.ready.len()
+    }
+
+    /// Returns why a put is refused if the tube holds its limit of jobs already
+    fn capacity_error(&self) -> Option<YaadError> {
+        let max_pending_jobs = self.hub.config().max_pending_jobs?;
+        if self.job_count() < max_pending_jobs {
+            return None;
+        }
+        Some(YaadError::Capacity { max_pending_jobs })
+    }
+
+    /// Returns copies of every job in the tube not yet deleted, each with the state a snapshot
+    /// records
+    fn saved_jobs(&self) -> Vec<(Job, SavedState)> {
+        let mut jobs = self.hub.saved_jobs();
+        jobs.extend(self.ready.iter().map(|j| (j.clone(), SavedState::Ready)));
+        jobs
+    }
+
+    /// Returns the total length of the bodies of the jobs scheduled on this tube or walked and
+    /// waiting to be reserved
+    fn body_bytes(&self) -> usize {
+        let ready: usize = self.ready.iter().map(|j| j.get_body().as_bytes().len()).sum();
+        self.hub.stats().total_body_bytes + ready
+    }
+
+    /// Re-queues expired reservations and walks the hub if no walked job is left over. Jobs due
+    /// at the same time with the same priority are queued in the order they were put, going by
+    /// their ids, like beanstalkd does.
+    fn refill(&mut self, ids: &HashMap<Uuid, u64>) {
+        self.hub.expire_reservations();
+        if self.ready.is_empty() {
+            let mut jobs = self.hub.walk_jobs();
+            jobs.sort_by_key(|j| {
+                let id = ids.get(&j.get_metadata().get_id());
+                (
+                    j.trigger_at_ms(),
+                    j.priority(),
+                    id.map_or(u64::MAX, |id| *id),
+                )
+            });
+            self.ready.extend(jobs);
+        }
+    }
+
+    /// Returns when this tube next has something to hand out - a job falling due, a reservation
+    /// running out or its pause ending
+    fn next_event_ms(&mut self) -> Option<u64> {
+        if self.is_paused_at(self.now_ms()) {
+            return Some(self.paused_until_ms);
+        }
+        min_option(
+            self.hub.next_trigger_time_ms(),
+            self.hub.next_reservation_deadline_ms(),
+        )
+    }
+
+    fn delete(&mut self, uuid: Uuid, reservation_deadline_ms: Option<u64>) -> bool {
+        match self.hub.reservation_deadline_ms(uuid) {
+            Some(d) => reservation_deadline_ms == Some(d) && self.hub.ack(uuid),
+            None => {
+                self.hub.cancel_job(uuid) || {
+                    let before = self.ready.len();
+                    self.ready.retain(|j| j.get_metadata().get_id() != uuid);
+                    self.ready.len() < before
+                }
+            }
+        }
+    }
+}
+
+impl State {
+    fn tube(&mut self, name: &str) -> &mut Tube {
+        let (stats, metrics, clock) = (&self.stats, &self.metrics, &self.clock);
+        let (hub_config, draining) = (self.hub_config, self.draining);
+        self.tubes.entry(name.to_owned()).or_insert_with(|| {
+            let hub = Hub::from_config_with_clock(hub_config, Arc::clone(clock));
+            Tube::new(name, hub, stats, draining, metrics)
+        })
+    }
+
+    /// Refills `name` and returns the trigger time of its next ready job. A paused tube has no
+    /// ready job until its pause ends.
+    fn refill(&mut self, name: &str) -> Option<u64> {
+        let (stats, metrics, clock) = (&self.stats, &self.metrics, &self.clock);
+        let (hub_config, draining) = (self.hub_config, self.draining);
+        let tube = self.tubes.entry(name.to_owned()).or_insert_with(|| {
+            let hub = Hub::from_config_with_clock(hub_config, Arc::clone(clock));
+            Tube::new(name, hub, stats, draining, metrics)
+        });
+        if tube.is_paused_at(tube.now_ms()) {
+            return None;
+        }
+        tube.refill(&self.ids);
+        tube.ready.front().map(|j| j.trigger_at_ms())
+    }
+
+    /// Returns the client facing id of a job, allocating one if the job doesn't have one yet -
+    /// jobs restored from a snapshot are only given ids once they are reserved.
+    fn external_id(&mut self, tube: &str, uuid: Uuid) -> u64 {
+        if let Some(id) = self.ids.get(&uuid) {
+            return *id;
+        }
+        self.next_id += 1;
+        self.uuids.insert(self.next_id, (tube.to_owned(), uuid));
+        self.ids.insert(uuid, self.next_id);
+        self.next_id
+    }
+
+    /// Reserves the ready job due first across the `watched` tubes, if any
+    fn reserve_next(&mut self, watched: &[String]) -> Option<Reserved> {
+        let mut next: Option<(&str, u64)> = None;
+        for name in watched {
+            if let Some(t) = self.refill(name) {
+                if next.is_none_or(|n| t < n.1) {
+                    next = Some((name, t));
+                }
+            }
+        }
+        let (name, _) = next?;
+        let (job, deadline_ms) = {
+            let tube = self.tube(name);
+            let job = tube.ready.pop_front().expect("Tube has a ready job");
+            let job = tube.hub.reserve_job(job);
+            let deadline_ms = tube
+                .hub
+                .reservation_deadline_ms(job.get_metadata().get_id())
+                .expect("Job was just reserved");
+            (job, deadline_ms)
+        };
+        let id = self.external_id(name, job.get_metadata().get_id());
+        Some((job, id, deadline_ms))
+    }
+
+    /// Queues a client waiting for a job on the `watched` tubes behind every client waiting
+    /// already. Returns its ticket, to leave the queue with.
+    fn wait(&mut self, watched: &[String], sender: mpsc::Sender<Reserved>) -> u64 {
+        self.next_ticket += 1;
+        self.waiters.push_back(Waiter {
+            ticket: self.next_ticket,
+            watched: watched.to_vec(),
+            sender,
+        });
+        self.next_ticket
+    }
+
+    fn stop_waiting(&mut self, ticket: u64) {
+        self.waiters.retain(|w| w.ticket != ticket);
+    }
+
+    /// Hands ready jobs to the waiting clients, longest waiting first, until no waiting client
+    /// watches a tube with a ready job. A client that went away without leaving the queue is
+    /// skipped, and the job it was handed goes back to the front of its tube.
+    fn hand_out(&mut self) {
+        if self.closed {
+            return;
+        }
+        let mut i = 0;
+        while i < self.waiters.len() {
+            let watched = mem::take(&mut self.waiters[i].watched);
+            let reserved = self.reserve_next(&watched);
+            self.waiters[i].watched = watched;
+            let reserved = match reserved {
+                Some(reserved) => reserved,
+                None => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let waiter = self.waiters.remove(i).expect("Waiter was just looked at");
+            if let Err(mpsc::SendError((job, id, _))) = waiter.sender.send(reserved) {
+                if let Some((tube, uuid)) = self.uuids.get(&id).cloned() {
+                    let tube = self.tube(&tube);
+                    tube.hub.ack(uuid);
+                    tube.ready.push_front(job);
+                }
+            }
+        }
+    }
+
+    fn forget(&mut self, id: u64) {
+        if let Some((_, uuid)) = self.uuids.remove(&id) {
+            self.ids.remove(&uuid);
+        }
+    }
+}
+
+impl TubeRegistry {
+    /// Creates a registry holding only the default tube, scheduled on `hub`. Tubes created later
+    /// get hubs with the same config and clock.
+    pub fn new(hub: Hub) -> TubeRegistry {
+        let hub_config = hub.config();
+        let clock = Arc::clone(hub.clock());
+        let stats = Arc::new(Stats::new());
+        let mut tubes = HashMap::new();
+        let draining = hub.is_draining();
+        let tube = Tube::new(DEFAULT_TUBE, hub, &stats, draining, &None);
+        tubes.insert(DEFAULT_TUBE.to_owned(), tube);
+        TubeRegistry {
+            state: Mutex::new(State {
+                tubes,
+                next_id: 0,
+                uuids: HashMap::new(),
+                ids: HashMap::new(),
+                closed: false,
+                stats: Arc::clone(&stats),
+                hub_config,
+                clock,
+                draining,
+                metrics: None,
+                waiters: VecDeque::new(),
+                next_ticket: 0,
+            }),
+            stats,
+            started: Instant::now(),
+        }
+    }
+
+    /// Creates a registry from jobs read back from a snapshot, labelled with their tube, whose
+    /// hubs are set up by `hub_config`. Jobs without a label go to the default tube. Buried jobs
+    /// are buried again and reserved ones are ready, as their client is gone. Jobs the hub
+    /// refuses are logged and dropped so one bad record doesn't keep the server from starting.
+    pub fn from_snapshot(
+        jobs: Vec<(String, Job, SavedState)>,
+        hub_config: HubConfig,
+    ) -> TubeRegistry {
+        let registry = TubeRegistry::new(Hub::from_config(hub_config));
+        {
+            let mut state = registry.state.lock().unwrap();
+            for (tube, job, saved) in jobs {
+                let tube = if tube.is_empty() { DEFAULT_TUBE } else { &tube };
+                let hub = &mut state.tube(tube).hub;
+                let added = match saved {
+                    SavedState::Buried => hub.add_buried_job(job),
+                    SavedState::Ready | SavedState::Reserved => hub.add_job(job),
+                };
+                if let Err(e) = added {
+                    error!("Dropping job restored into tube {}: {}", tube, e);
+                }
+            }
+        }
+        registry
+    }
+
+    /// Creates `tube` if it doesn't exist yet
+    pub fn touch(&self, tube: &str) {
+        self.state.lock().unwrap().tube(tube);
+    }
+
+    /// Schedules a job on `tube` and hands it to the client waiting longest for one, if it is
+    /// ready and some client waits for it. Returns the job's id, or why the tube's hub refused
+    /// the job.
+    pub fn put(&self, tube: &str, job: Job) -> Result<u64, YaadError> {
+        let mut state = self.state.lock().unwrap();
+        let uuid = job.get_metadata().get_id();
+        let tube_state = state.tube(tube);
+        if let Some(e) = tube_state.capacity_error() {
+            return Err(e);
+        }
+        tube_state.hub.add_job(job)?;
+        tube_state.total_jobs += 1;
+        let id = state.external_id(tube, uuid);
+        state.hand_out();
+        Ok(id)
+    }
+
+    /// Returns the current time as the tubes' hubs see it
+    pub fn now_ms(&self) -> u64 {
+        self.state.lock().unwrap().clock.now_ms()
+    }
+
+    /// Hands the jobs that are ready now to the clients waiting in reserve, e.g. after a test
+    /// moved the hubs' clock forward
+    #[cfg(test)]
+    pub fn wake_reservers(&self) {
+        self.state.lock().unwrap().hand_out();
+    }
+
+    /// Returns the number of clients waiting in reserve
+    pub fn waiting_reservers(&self) -> usize {
+        self.state.lock().unwrap().waiters.len()
+    }
+
+    /// Returns the id of the job the hub knows as `uuid`, for protocols that hand out Uuids
+    /// instead of ids. Jobs restored from a snapshot get their id the first time they are looked
+    /// up. Returns None if no tube has the job.
+    pub fn id_of(&self, uuid: Uuid) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(id) = state.ids.get(&uuid) {
+            return Some(*id);
+        }
+        let owner = state.tubes.iter().find(|&(_, tube)| {
+            tube.hub.get_job(uuid).is_some()
+                || tube.ready.iter().any(|j| j.get_metadata().get_id() == uuid)
+        });
+        let tube = owner.map(|(name, _)| name.clone())?;
+        Some(state.external_id(&tube, uuid))
+    }
+
+    /// Reserves the ready job due first across the `watched` tubes, waiting up to `timeout` for
+    /// one to become ready. Waits forever if `timeout` is None. Returns the job with its id and
+    /// reservation deadline, or None on timeout or once the registry is closed.
+    pub fn reserve(
+        &self,
+        watched: &[String],
+        timeout: Option<Duration>,
+    ) -> Option<(Job, u64, u64)> {
+        self.reserve_until(watched, timeout, None)
+    }
+
+    /// Reserves like [`TubeRegistry::reserve`], but stops waiting once the tubes' clock reaches
+    /// `until_ms` as well, returning None. A job that is ready by then is still reserved.
+    ///
+    /// Clients waiting already are served first: this one only gets a ready job none of them
+    /// watches, or else queues up behind them until a job is handed to it.
+    pub fn reserve_until(
+        &self,
+        watched: &[String],
+        timeout: Option<Duration>,
+        until_ms: Option<u64>,
+    ) -> Option<Reserved> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut state = self.state.lock().unwrap();
+        state.hand_out();
+        if state.closed {
+            return None;
+        }
+        if let Some(reserved) = state.reserve_next(watched) {
+            return Some(reserved);
+        }
+        let (sender, receiver) = mpsc::channel();
+        let ticket = state.wait(watched, sender);
+        loop {
+            let now_ms = state.clock.now_ms();
+            let now = Instant::now();
+            if state.closed
+                || until_ms.is_some_and(|until_ms| now_ms >= until_ms)
+                || deadline.is_some_and(|deadline| now >= deadline)
+            {
+                state.stop_waiting(ticket);
+                // A job handed over before leaving the queue is reserved all the same
+                return receiver.try_recv().ok();
+            }
+
+            // Sleep until the next job is due, a reservation expires, `until_ms` or the timeout
+            // runs out, whichever is first, unless a job is handed over before. Jobs put
+            // meanwhile are handed over right away.
+            let mut next_ms = until_ms;
+            for name in watched {
+                next_ms = min_option(next_ms, state.tube(name).next_event_ms());
+            }
+            let mut wait =
+                next_ms.map(|t| Duration::from_millis(t.saturating_sub(now_ms).max(1)));
+            if let Some(deadline) = deadline {
+                wait = Some(wait.map_or(deadline - now, |w| w.min(deadline - now)));
+            }
+            drop(state);
+            let received = match wait {
+                Some(w) => receiver.recv_timeout(w),
+                // Closing the registry drops the sender, ending the wait
+                None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            if let Ok(reserved) = received {
+                return Some(reserved);
+            }
+            state = self.state.lock().unwrap();
+            // Whatever fell due meanwhile goes to the clients waiting longest
+            state.hand_out();
+            if let Ok(reserved) = receiver.try_recv() {
+                return Some(reserved);
+            }
+        }
+    }
+
+    /// Deletes a job for good. A reserved job can only be deleted by passing the deadline of its
+    /// current reservation - a client whose reservation ran out can't delete the job after
+    /// someone else reserved it. Delayed and ready jobs can be deleted by anyone.
+    pub fn delete(&self, id: u64, reservation_deadline_ms: Option<u64>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let (tube, uuid) = match state.uuids.get(&id) {
+            Some(&(ref tube, uuid)) => (tube.clone(), uuid),
+            None => return false,
+        };
+        let deleted = state.tube(&tube).delete(uuid, reservation_deadline_ms);
+        if deleted {
+            state.forget(id);
+        }
+        deleted
+    }
+
+    /// Shelves a job this client reserved until it is kicked, with its priority set to
+    /// `priority`. Like [`TubeRegistry::delete`], it takes the deadline of the current
+    /// reservation. Returns false if the job isn't reserved under that deadline.
+    pub fn bury(&self, id: u64, reservation_deadline_ms: Option<u64>, priority: u32) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let (tube, uuid) = match state.uuids.get(&id) {
+            Some(&(ref tube, uuid)) => (tube.clone(), uuid),
+            None => return false,
+        };
+        let hub = &mut state.tube(&tube).hub;
+        match hub.reservation_deadline_ms(uuid) {
+            Some(d) => reservation_deadline_ms == Some(d) && hub.bury(uuid, priority),
+            None => false,
+        }
+    }
+
+    /// Gives a job this client reserved its full TTR again, counting from now. Like
+    /// [`TubeRegistry::delete`], it takes the deadline of the current reservation. Returns the new
+    /// deadline, or None if the job isn't reserved under that deadline.
+    pub fn touch_job(&self, id: u64, reservation_deadline_ms: Option<u64>) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        let (tube, uuid) = match state.uuids.get(&id) {
+            Some(&(ref tube, uuid)) => (tube.clone(), uuid),
+            None => return None,
+        };
+        let hub = &mut state.tube(&tube).hub;
+        match hub.reservation_deadline_ms(uuid) {
+            Some(d) if reservation_deadline_ms == Some(d) => hub.touch(uuid).ok(),
+            _ => None,
+        }
+    }
+
+    /// Puts a job this client reserved back on its tube with its priority set to `priority`, to
+    /// be ready `delay_ms` from now, handing it to a waiting client if it is ready. Like
+    /// [`TubeRegistry::delete`], it takes the deadline of the current reservation. Returns false
+    /// if the job isn't reserved under that deadline, and an error if its tube's hub refused it,
+    /// leaving it reserved.
+    pub fn release(
+        &self,
+        id: u64,
+        reservation_deadline_ms: Option<u64>,
+        priority: u32,
+        delay_ms: u64,
+    ) -> Result<bool, YaadError> {
+        let mut state = self.state.lock().unwrap();
+        let (tube, uuid) = match state.uuids.get(&id) {
+            Some(&(ref tube, uuid)) => (tube.clone(), uuid),
+            None => return Ok(false),
+        };
+        let hub = &mut state.tube(&tube).hub;
+        let released = match hub.reservation_deadline_ms(uuid) {
+            Some(d) if reservation_deadline_ms == Some(d) => {
+                hub.release_with_priority(uuid, priority, delay_ms)?;
+                true
+            }
+            _ => false,
+        };
+        if released {
+            state.hand_out();
+        }
+        Ok(released)
+    }
+
+    /// Makes up to `max` buried jobs on `tube` ready again and hands them to the clients
+    /// waiting in reserve. Returns the number of jobs kicked.
+    pub fn kick(&self, tube: &str, max: usize) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let kicked = state.tube(tube).hub.kick(max);
+        if kicked > 0 {
+            state.hand_out();
+        }
+        kicked
+    }
+
+    /// Makes a buried job ready again, wherever it is buried. Returns false if the job isn't
+    /// buried.
+    pub fn kick_job(&self, id: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let (tube, uuid) = match state.uuids.get(&id) {
+            Some(&(ref tube, uuid)) => (tube.clone(), uuid),
+            None => return false,
+        };
+        let kicked = match state.tube(&tube).hub.kick_job(uuid) {
+            Ok(()) => true,
+            Err(YaadError::NotFound(_)) => false,
+            Err(e) => {
+                error!("Failed to kick job {}: {}", id, e);
+                false
+            }
+        };
+        if kicked {
+            state.hand_out();
+        }
+        kicked
+    }
+
+    /// Returns a copy of a job without reserving it, whatever state it is in, or None if there is
+    /// no such job
+    pub fn peek(&self, id: u64) -> Option<Job> {
+        let state = self.state.lock().unwrap();
+        let (name, uuid) = state.uuids.get(&id)?;
+        state.tubes.get(name)?.find_job(*uuid).map(|j| j.0)
+    }
+
+    /// Returns the next ready job on `tube` and its id without reserving it
+    pub fn peek_ready(&self, tube: &str) -> Option<(Job, u64)> {
+        self.peek_tube(tube, Tube::peek_ready)
+    }
+
+    /// Returns the delayed job on `tube` that falls due first and its id
+    pub fn peek_delayed(&self, tube: &str) -> Option<(Job, u64)> {
+        self.peek_tube(tube, Tube::peek_delayed)
+    }
+
+    /// Returns the buried job on `tube` that would be kicked first and its id
+    pub fn peek_buried(&self, tube: &str) -> Option<(Job, u64)> {
+        self.peek_tube(tube, Tube::peek_buried)
+    }
+
+    fn peek_tube<F: Fn(&Tube) -> Option<Job>>(&self, tube: &str, peek: F) -> Option<(Job, u64)> {
+        let mut state = self.state.lock().unwrap();
+        let job = peek(state.tubes.get(tube)?)?;
+        let id = state.external_id(tube, job.get_metadata().get_id());
+        Some((job, id))
+    }
+
+    /// Returns the collector counting jobs across all tubes, for the server to count its
+    /// connections in
+    pub fn counters(&self) -> &Arc<Stats> {
+        &self.stats
+    }
+
+    /// Returns the names of every tube, sorted. Tubes are kept once created, whether they hold
+    /// jobs or not.
+    pub fn tube_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.state.lock().unwrap().tubes.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Returns the server wide counters reported by `stats`
+    pub fn server_stats(&self) -> StatsDict {
+        let state = self.state.lock().unwrap();
+        let mut counts = JobCounts::default();
+        for tube in state.tubes.values() {
+            counts.add(tube.counts());
+        }
+        let mut dict = vec![];
+        counts.report(&mut dict);
+        let body_bytes: usize = state.tubes.values().map(|t| t.body_bytes()).sum();
+        dict.push(("current-job-bytes", body_bytes.to_string()));
+        if let Some(max) = state.hub_config.max_pending_jobs {
+            dict.push(("max-jobs-per-tube", max.to_string()));
+        }
+        let stats = &self.stats;
+        dict.push(("total-jobs", stats.jobs_added().to_string()));
+        dict.push(("total-jobs-walked", stats.jobs_walked().to_string()));
+        dict.push(("total-jobs-auto-buried", stats.jobs_auto_buried().to_string()));
+        dict.push(("total-stalls", stats.stalls().to_string()));
+        dict.push(("total-internal-errors", stats.internal_errors().to_string()));
+        dict.push(("current-tubes", state.tubes.len().to_string()));
+        dict.push(("current-spokes", stats.spokes_live().to_string()));
+        dict.push(("current-connections", stats.connections_open().to_string()));
+        dict.push(("current-waiting", state.waiters.len().to_string()));
+        dict.push(("total-connections", stats.connections_total().to_string()));
+        dict.push(("draining", state.draining.to_string()));
+        dict.push(("pid", process::id().to_string()));
+        dict.push(("uptime", self.started.elapsed().as_secs().to_string()));
+        dict
+    }
+
+    /// Returns the counters reported by `stats-tube`, or None if there is no such tube
+    pub fn tube_stats(&self, name: &str) -> Option<StatsDict> {
+        let state = self.state.lock().unwrap();
+        let tube = state.tubes.get(name)?;
+        let mut dict = vec![("name", name.to_owned())];
+        tube.counts().report(&mut dict);
+        dict.push(("current-job-bytes", tube.body_bytes().to_string()));
+        if let Some(max) = tube.hub.config().max_pending_jobs {
+            dict.push(("max-jobs", max.to_string()));
+        }
+        dict.push(("total-jobs", tube.total_jobs.to_string()));
+        dict.push(("current-spokes", tube.hub.spoke_count().to_string()));
+        let left_ms = tube.paused_until_ms.saturating_sub(tube.now_ms());
+        dict.push(("pause", tube.pause.as_secs().to_string()));
+        dict.push(("pause-time-left", (left_ms / 1000).to_string()));
+        Some(dict)
+    }
+
+    /// Returns what `stats-job` reports about a job, or None if there is no such job
+    pub fn job_stats(&self, id: u64) -> Option<StatsDict> {
+        let state = self.state.lock().unwrap();
+        let (name, uuid) = state.uuids.get(&id)?;
+        let tube = state.tubes.get(name)?;
+        let (job, job_state) = tube.find_job(*uuid)?;
+        let now_ms = tube.now_ms();
+        let (state_name, until_ms) = match job_state {
+            JobState::Ready => ("ready", None),
+            JobState::Delayed => ("delayed", Some(job.trigger_at_ms())),
+            JobState::Reserved => ("reserved", tube.hub.reservation_deadline_ms(*uuid)),
+            JobState::Buried => ("buried", None),
+        };
+        let time_left_secs = until_ms.map_or(0, |t| t.saturating_sub(now_ms) / 1000);
+        Some(vec![
+            ("id", id.to_string()),
+            ("tube", name.clone()),
+            ("state", state_name.to_owned()),
+            ("pri", job.priority().to_string()),
+            ("ttr", (job.ttr_ms() / 1000).to_string()),
+            ("time-left", time_left_secs.to_string()),
+            ("reserves", job.reserves().to_string()),
+            ("timeouts", job.timeouts().to_string()),
+            ("releases", job.releases().to_string()),
+        ])
+    }
+
+    /// Stops handing out jobs from `tube` for `delay`. Jobs can still be put, and reserve picks the
+    /// tube up again by itself once the delay ran out. Returns false if there is no such tube.
+    pub fn pause(&self, tube: &str, delay: Duration) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.tubes.get_mut(tube) {
+            Some(t) => {
+                t.pause = delay;
+                t.paused_until_ms = t.now_ms() + delay.as_millis() as u64;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Makes every tube refuse puts as draining while `draining` is set, tubes created later
+    /// included. Jobs already put are still handed out.
+    pub fn set_draining(&self, draining: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.draining = draining;
+        for tube in state.tubes.values_mut() {
+            tube.hub.set_draining(draining);
+        }
+    }
+
+    /// Returns true while puts are refused, see [`TubeRegistry::set_draining`]
+    pub fn is_draining(&self) -> bool {
+        self.state.lock().unwrap().draining
+    }
+
+    /// Makes every tube's hub report its gauges to `metrics` on each tick, and its jobs as they
+    /// come and go, tubes created later included
+    pub fn set_metrics(&self, metrics: Arc<dyn ServerMetrics>) {
+        let mut state = self.state.lock().unwrap();
+        for (name, tube) in &mut state.tubes {
+            tube.hub.set_metrics(Arc::clone(&metrics).for_tube(name));
+        }
+        state.metrics = Some(metrics);
+    }
+
+    /// Runs every tube's hub housekeeping, see [`Hub::tick`], and hands the jobs that fell due
+    /// meanwhile to the clients waiting in reserve
+    pub fn tick(&self) {
+        let mut state = self.state.lock().unwrap();
+        for tube in state.tubes.values_mut() {
+            tube.hub.tick();
+        }
+        state.hand_out();
+    }
+
+    /// Stops handing out jobs and wakes every client waiting in reserve. Jobs can still be put
+    /// and deleted, so clients can finish what they are doing.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        // Dropping their senders ends the clients' waits
+        state.waiters.clear();
+    }
+
+    /// Returns true once [`TubeRegistry::close`] was called
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// Puts every reserved job back into its tube, whether or not its TTR ran out. Returns the
+    /// number of jobs released.
+    pub fn release_reservations(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state
+            .tubes
+            .values_mut()
+            .map(|t| t.hub.release_reservations())
+            .sum()
+    }
+
+    /// Writes every job not yet deleted, labelled with its tube, to a snapshot at `path` and
+    /// logs the outcome
+    pub fn snapshot_or_log(&self, path: &Path) {
+        let state = self.state.lock().unwrap();
+        let mut jobs: Vec<(&str, Job, SavedState)> = vec![];
+        for (name, tube) in &state.tubes {
+            let name = name.as_str();
+            jobs.extend(tube.saved_jobs().into_iter().map(|j| (name, j.0, j.1)));
+        }
+        match persistence::save(path, jobs.iter().map(|j| (j.0, &j.1, j.2))) {
+            Ok(n) => info!("Snapshotted {} jobs to {}", n, path.display()),
+            Err(e) => error!("Failed to snapshot jobs to {}: {}", path.display(), e),
+        }
+    }
+
+    /// Writes every job not yet deleted to a snapshot at `path` like
+    /// [`TubeRegistry::snapshot_or_log`], but copies one tube at a time and writes it out with no
+    /// lock held, so clients are only held up while the jobs of a single tube are copied. Each
+    /// tube is written as it was when its turn came. Returns the number of jobs written once the
+    /// file is synced to disk.
+    pub fn snapshot_online(&self, path: &Path) -> io::Result<usize> {
+        let names = self.tube_names();
+        persistence::save_with(path, |snapshot| {
+            for name in &names {
+                let jobs = match self.state.lock().unwrap().tubes.get(name) {
+                    Some(tube) => tube.saved_jobs(),
+                    None => continue,
+                };
+                for (job, state) in &jobs {
+                    snapshot.write_job(name, job, *state)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns the number of jobs with a client facing id
+    #[cfg(test)]
+    pub fn tracked_id_len(&self) -> usize {
+        self.state.lock().unwrap().uuids.len()
+    }
+
+    /// Returns the number of reserved jobs across all tubes
+    #[cfg(test)]
+    pub fn reserved_job_len(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.tubes.values().map(|t| t.hub.reserved_job_len()).sum()
+    }
+
+    /// Returns the number of buried jobs across all tubes
+    #[cfg(test)]
+    pub fn buried_job_len(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.tubes.values().map(|t| t.hub.buried_job_len()).sum()
+    }
+}
+
+fn min_option(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{self, File};
+    use std::process;
+    use yaad::errors::RejectReason;
+    use yaad::times;
+
+    const SPOKE_DURATION_MS: u64 = 10_000;
+
+    fn watching(tubes: &[&str]) -> Vec<String> {
+        tubes.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn reserves_only_from_watched_tubes() {
+        let registry = TubeRegistry::new(Hub::new(SPOKE_DURATION_MS));
+        let now_ms = times::current_time_ms();
+        registry
+            .put("emails", Job::new_auto_id(now_ms - 10, "email"))
+            .unwrap();
+        let sms = registry
+            .put("sms", Job::new_auto_id(now_ms - 20, "sms"))
+            .unwrap();
+
+        let none = Some(Duration::from_millis(0));
+        assert!(registry.reserve(&watching(&[DEFAULT_TUBE]), none).is_none());
+        let (job, id, _) = registry
+            .reserve(&watching(&["emails", "sms"]), none)
+            .unwrap();
+        assert_eq!(id, sms, "The job due first is reserved first");
+        assert_eq!(job.get_body().as_bytes(), b"sms");
+    }
+
+    #[test]
+    fn reserves_urgent_jobs_first() {
+        let registry = TubeRegistry::new(Hub::new(SPOKE_DURATION_MS));
+        let due_ms = times::current_time_ms() - 10;
+        let relaxed = Job::new_auto_id(due_ms, "relaxed").with_priority(1_024);
+        let first = registry.put(DEFAULT_TUBE, relaxed).unwrap();
+        let urgent = Job::new_auto_id(due_ms, "urgent").with_priority(0);
+        registry.put(DEFAULT_TUBE, urgent).unwrap();
+        let fifo = Job::new_auto_id(due_ms, "also relaxed").with_priority(1_024);
+        registry.put(DEFAULT_TUBE, fifo).unwrap();
+
+        let none = Some(Duration::from_millis(0));
+        let default = watching(&[DEFAULT_TUBE]);
+        let (job, _, _) = registry.reserve(&default, none).unwrap();
+        assert_eq!(job.get_body().as_bytes(), b"urgent");
+        let (_, id, _) = registry.reserve(&default, none).unwrap();
+        assert_eq!(
+            id, first,
+            "Jobs with the same priority are reserved in put order"
+        );
+    }
+
+    #[test]
+    fn waiting_clients_are_served_in_turn() {
+        let registry = TubeRegistry::new(Hub::new(SPOKE_DURATION_MS));
+        let (gone, _) = mpsc::channel();
+        let (first, first_receiver) = mpsc::channel();
+        let (emails, emails_receiver) = mpsc::channel();
+        {
+            let mut state = registry.state.lock().unwrap();
+            state.wait(&watching(&["emails"]), emails);
+            // Its receiver was dropped with the client, so it never gets a job
+            state.wait(&watching(&[DEFAULT_TUBE]), gone);
+            state.wait(&watching(&[DEFAULT_TUBE]), first);
+        }
+        assert_eq!(registry.waiting_reservers(), 3);
+
+        let now_ms = times::current_time_ms();
+        let id = registry
+            .put(DEFAULT_TUBE, Job::new_auto_id(now_ms - 10, "one"))
+            .unwrap();
+        let (job, handed_id, _) = first_receiver.try_recv().unwrap();
+        assert_eq!((job.get_body().as_bytes(), handed_id), (&b"one"[..], id));
+        assert!(emails_receiver.try_recv().is_err(), "Jobs only go to clients watching their tube");
+        assert_eq!(registry.waiting_reservers(), 1);
+        assert_eq!(registry.reserved_job_len(), 1);
+
+        // Only the emails client waits now, and a fresh reserve takes what it doesn't watch
+        let later = registry
+            .put(DEFAULT_TUBE, Job::new_auto_id(now_ms - 5, "two"))
+            .unwrap();
+        let default = watching(&[DEFAULT_TUBE]);
+        let none = Some(Duration::from_millis(0));
+        assert_eq!(registry.reserve(&default, none).unwrap().1, later);
+        registry.close();
+        assert_eq!(registry.waiting_reservers(), 0);
+        assert!(emails_receiver.recv().is_err(), "Closing ends every wait");
+    }
+
+    #[test]
+    fn paused_tube_resumes_by_itself() {
+        let registry = TubeRegistry::new(Hub::new(SPOKE_DURATION_MS));
+        let now_ms = times::current_time_ms();
+        assert!(!registry.pause("emails", Duration::from_millis(200)), "No such tube");
+        let id = registry
+            .put(DEFAULT_TUBE, Job::new_auto_id(now_ms - 10, "ready"))
+            .unwrap();
+        let paused_at = Instant::now();
+        assert!(registry.pause(DEFAULT_TUBE, Duration::from_millis(200)));
+
+        let default = watching(&[DEFAULT_TUBE]);
+        assert!(registry.reserve(&default, Some(Duration::from_millis(0))).is_none());
+        let stats = registry.tube_stats(DEFAULT_TUBE).unwrap();
+        assert!(stats.contains(&("pause", "0".to_owned())), "Paused for less than a second");
+        let (_, reserved, _) = registry.reserve(&default, Some(Duration::from_secs(2))).unwrap();
+        assert_eq!(reserved, id);
+        // The tube's clock truncates to milliseconds, so the pause may end up to 1ms early
+        assert!(paused_at.elapsed() >= Duration::from_millis(199));
+    }
+
+    #[test]
+    fn draining_refuses_puts_on_every_tube() {
+        let registry = TubeRegistry::new(Hub::new(SPOKE_DURATION_MS));
+        let now_ms = times::current_time_ms();
+        let before = registry
+            .put(DEFAULT_TUBE, Job::new_auto_id(now_ms - 10, "before"))
+            .unwrap();
+        registry.set_draining(true);
+        assert!(registry.is_draining());
+
+        let refused = registry.put(DEFAULT_TUBE, Job::new_auto_id(now_ms, "during"));
+        assert_eq!(refused, Err(RejectReason::Draining.into()));
+        let refused = registry.put("new-tube", Job::new_auto_id(now_ms, "during"));
+        assert_eq!(refused, Err(RejectReason::Draining.into()), "New tubes are draining too");
+        assert!(registry.server_stats().contains(&("draining", "true".to_owned())));
+
+        let none = Some(Duration::from_millis(0));
+        let (_, id, _) = registry.reserve(&watching(&[DEFAULT_TUBE]), none).unwrap();
+        assert_eq!(id, before, "Jobs put before draining are still handed out");
+        registry.set_draining(false);
+        assert!(registry.put("new-tube", Job::new_auto_id(now_ms, "after")).is_ok());
+    }
+
+    #[test]
+    fn full_tube_refuses_puts_until_a_job_is_deleted() {
+        let hub_config = HubConfig::new(SPOKE_DURATION_MS).with_max_pending_jobs(2);
+        let registry = TubeRegistry::new(Hub::from_config(hub_config));
+        let now_ms = times::current_time_ms();
+        let ready = registry
+            .put(DEFAULT_TUBE, Job::new_auto_id(now_ms - 10, "ready"))
+            .unwrap();
+        registry
+            .put(DEFAULT_TUBE, Job::new_auto_id(now_ms + 60_000, "delayed"))
+            .unwrap();
+
+        let refused = registry.put(DEFAULT_TUBE, Job::new_auto_id(now_ms, "one too many"));
+        assert_eq!(refused, Err(YaadError::Capacity { max_pending_jobs: 2 }));
+        assert!(
+            registry.put("other", Job::new_auto_id(now_ms, "elsewhere")).is_ok(),
+            "Each tube has its own limit"
+        );
+
+        assert!(registry.delete(ready, None));
+        assert!(registry.put(DEFAULT_TUBE, Job::new_auto_id(now_ms, "fits")).is_ok());
+    }
+
+    #[test]
+    fn kicks_buried_jobs_on_their_tube() {
+        let registry = TubeRegistry::new(Hub::new(SPOKE_DURATION_MS));
+        let now_ms = times::current_time_ms();
+        registry
+            .put("emails", Job::new_auto_id(now_ms - 10, "email"))
+            .unwrap();
+        let none = Some(Duration::from_millis(0));
+        let emails = watching(&["emails"]);
+        let (_, id, deadline_ms) = registry.reserve(&emails, none).unwrap();
+        assert!(
+            !registry.bury(id, Some(deadline_ms + 1), 0),
+            "Stale reservation"
+        );
+        assert!(registry.bury(id, Some(deadline_ms), 0));
+        assert_eq!(registry.buried_job_len(), 1);
+        assert!(registry.reserve(&emails, none).is_none());
+
+        assert_eq!(
+            registry.kick(DEFAULT_TUBE, 10),
+            0,
+            "Kicks only reach their tube"
+        );
+        assert_eq!(registry.kick("emails", 10), 1);
+        let (job, kicked_id, deadline_ms) = registry.reserve(&emails, none).unwrap();
+        assert_eq!((job.get_body().as_bytes(), kicked_id), (&b"email"[..], id));
+
+        assert!(registry.bury(id, Some(deadline_ms), 0));
+        assert!(registry.kick_job(id));
+        assert!(!registry.kick_job(id), "Job isn't buried anymore");
+        assert!(registry.reserve(&emails, none).is_some());
+    }
+
+    #[test]
+    fn peeks_jobs_on_their_tube() {
+        let registry = TubeRegistry::new(Hub::new(SPOKE_DURATION_MS));
+        let now_ms = times::current_time_ms();
+        let ready = registry
+            .put("emails", Job::new_auto_id(now_ms - 10, "ready"))
+            .unwrap();
+        let delayed = registry
+            .put("emails", Job::new_auto_id(now_ms + 60_000, "delayed"))
+            .unwrap();
+
+        assert!(registry.peek_ready(DEFAULT_TUBE).is_none());
+        assert!(
+            registry.peek_ready("sms").is_none(),
+            "Peeking doesn't create tubes"
+        );
+        let (job, id) = registry.peek_ready("emails").unwrap();
+        assert_eq!((job.get_body().as_bytes(), id), (&b"ready"[..], ready));
+        assert_eq!(registry.peek_delayed("emails").unwrap().1, delayed);
+        assert_eq!(
+            registry.peek(delayed).unwrap().get_body().as_bytes(),
+            b"delayed"
+        );
+        assert!(registry.peek_buried("emails").is_none());
+
+        let none = Some(Duration::from_millis(0));
+        let (_, id, deadline_ms) = registry.reserve(&watching(&["emails"]), none).unwrap();
+        assert_eq!(id, ready, "Peeking doesn't reserve");
+        assert!(registry.peek_ready("emails").is_none());
+        assert!(registry.bury(id, Some(deadline_ms), 0));
+        assert_eq!(registry.peek_buried("emails").unwrap().1, ready);
+    }
+
+    #[test]
+    fn snapshots_keep_jobs_in_their_tubes() {
+        let path = env::temp_dir().join(format!("yaad-tubes-test-{}", process::id()));
+        let registry = TubeRegistry::new(Hub::new(SPOKE_DURATION_MS));
+        let now_ms = times::current_time_ms();
+        registry
+            .put(DEFAULT_TUBE, Job::new_auto_id(now_ms - 10, "default"))
+            .unwrap();
+        registry
+            .put("emails", Job::new_auto_id(now_ms - 10, "email"))
+            .unwrap();
+        registry
+            .put("emails", Job::new_auto_id(now_ms + 60_000, "later"))
+            .unwrap();
+        registry.snapshot_or_log(&path);
+
+        let jobs = persistence::read_jobs(&mut File::open(&path).unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        let restored = TubeRegistry::from_snapshot(jobs, HubConfig::new(SPOKE_DURATION_MS));
+        let none = Some(Duration::from_millis(0));
+        let emails = watching(&["emails"]);
+        let (job, _, _) = restored.reserve(&emails, none).unwrap();
+        assert_eq!(job.get_body().as_bytes(), b"email");
+        assert!(
+            restored.reserve(&emails, none).is_none(),
+            "Later job is still delayed"
+        );
+        let (job, _, _) = restored.reserve(&watching(&[DEFAULT_TUBE]), none).unwrap();
+        assert_eq!(job.get_body().as_bytes(), b"default");
+    }
+
+    #[test]
+    fn snapshots_keep_buried_jobs_buried() {
+        let path = env::temp_dir().join(format!("yaad-tubes-buried-test-{}", process::id()));
+        let registry = TubeRegistry::new(Hub::new(SPOKE_DURATION_MS));
+        let now_ms = times::current_time_ms();
+        for body in &["buried", "reserved"] {
+            registry
+                .put("emails", Job::new_auto_id(now_ms - 10, *body))
+                .unwrap();
+        }
+        let none = Some(Duration::from_millis(0));
+        let emails = watching(&["emails"]);
+        let (_, id, deadline_ms) = registry.reserve(&emails, none).unwrap();
+        assert!(registry.bury(id, Some(deadline_ms), 5));
+        registry.reserve(&emails, none).unwrap();
+        registry.snapshot_or_log(&path);
+
+        let jobs = persistence::read_jobs(&mut File::open(&path).unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        let restored = TubeRegistry::from_snapshot(jobs, HubConfig::new(SPOKE_DURATION_MS));
+        let stats = restored.server_stats();
+        let stat = |name: &str| stats.iter().find(|s| s.0 == name).unwrap().1.clone();
+        assert_eq!(stat("current-jobs-buried"), "1");
+        assert_eq!(stat("current-jobs-reserved"), "0");
+        assert_eq!(stat("current-jobs-ready"), "1", "Reserved jobs are ready again");
+
+        let (job, _, _) = restored.reserve(&emails, none).unwrap();
+        assert_eq!(job.get_body().as_bytes(), b"reserved");
+        assert_eq!(restored.kick("emails", 10), 1);
+        let (job, _, _) = restored.reserve(&emails, none).unwrap();
+        assert_eq!((job.get_body().as_bytes(), job.priority()), (&b"buried"[..], 5));
+        assert_eq!(job.reserves(), 2, "Reserves before the snapshot still count");
+    }
+}
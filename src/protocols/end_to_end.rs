@@ -0,0 +1,92 @@
+//! Runs the beanstalkd server in process and talks to it over TCP, with the hub's clock moved
+//! forward by the tests rather than by waiting.
+
+use std::thread;
+
+use super::testutil::{TestServer, MOCK_START_MS};
+
+#[test]
+fn delayed_job_is_reserved_once_its_delay_passed_and_deleted() {
+    let server = TestServer::with_mock_clock();
+    let mut client = server.connect();
+
+    let id = client.put(0, 5, 60, b"hello").unwrap();
+    assert_eq!(server.registry().now_ms(), MOCK_START_MS);
+    assert_eq!(client.reserve(Some(0)), Err("TIMED_OUT".to_owned()));
+
+    server.advance(4_999);
+    assert_eq!(client.reserve(Some(0)), Err("TIMED_OUT".to_owned()));
+
+    server.advance(1);
+    assert_eq!(client.reserve(Some(0)), Ok((id, b"hello".to_vec())));
+    assert_eq!(client.delete(id), Ok(()));
+
+    let stats = client.stats().unwrap();
+    assert_eq!(stats["total-jobs"], "1");
+    assert_eq!(stats["current-jobs-ready"], "0");
+    assert_eq!(stats["current-jobs-reserved"], "0");
+    assert_eq!(stats["current-jobs-delayed"], "0");
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn waiting_reserve_gets_job_when_clock_passes_its_delay() {
+    let server = TestServer::with_mock_clock();
+    let id = server.connect().put(0, 30, 60, b"later").unwrap();
+
+    let mut waiting = server.connect();
+    let reserver = thread::spawn(move || waiting.reserve(None));
+    // Whether the reserve is waiting yet or not, it only finds the job after the delay passed
+    server.advance(30_000);
+    assert_eq!(reserver.join().unwrap(), Ok((id, b"later".to_vec())));
+}
+
+#[test]
+fn reserve_is_told_a_deadline_is_soon_unless_a_job_is_ready() {
+    let server = TestServer::with_mock_clock();
+    let mut client = server.connect();
+    let first = client.put(0, 0, 3, b"first").unwrap();
+    assert_eq!(client.reserve(Some(0)), Ok((first, b"first".to_vec())));
+    assert_eq!(client.reserve(Some(0)), Err("TIMED_OUT".to_owned()));
+
+    server.advance(2_000);
+    let second = client.put(0, 0, 60, b"second").unwrap();
+    assert_eq!(
+        client.reserve(Some(0)),
+        Ok((second, b"second".to_vec())),
+        "A ready job is handed out however soon a reservation runs out"
+    );
+    assert_eq!(client.reserve(Some(0)), Err("DEADLINE_SOON".to_owned()));
+
+    assert_eq!(client.send(format!("touch {}\r\n", first).as_bytes()), "TOUCHED");
+    let reserver = thread::spawn(move || client.reserve(None));
+    // Whether the reserve is waiting yet or not, it is told once the last second starts
+    server.advance(2_000);
+    assert_eq!(reserver.join().unwrap(), Err("DEADLINE_SOON".to_owned()));
+}
+
+#[test]
+fn deleting_unknown_job_is_not_found() {
+    let server = TestServer::with_mock_clock();
+    let mut client = server.connect();
+    assert_eq!(client.delete(42), Err("NOT_FOUND".to_owned()));
+
+    let id = client.put(0, 0, 60, b"once").unwrap();
+    assert_eq!(client.delete(id), Ok(()));
+    assert_eq!(client.delete(id), Err("NOT_FOUND".to_owned()));
+}
+
+#[test]
+fn malformed_puts_are_refused_and_connection_stays_usable() {
+    let server = TestServer::with_mock_clock();
+    let mut client = server.connect();
+
+    assert_eq!(client.send(b"put 0 0 60 five\r\n"), "BAD_FORMAT");
+    assert_eq!(client.send(b"put 0 0\r\n"), "BAD_FORMAT");
+    // The body is longer than announced
+    assert_eq!(client.send(b"put 0 0 60 3\r\nhello\r\n"), "EXPECTED_CRLF");
+
+    let id = client.put(0, 0, 60, b"fine").unwrap();
+    assert_eq!(client.reserve(Some(0)), Ok((id, b"fine".to_vec())));
+    assert_eq!(client.stats().unwrap()["total-jobs"], "1");
+}
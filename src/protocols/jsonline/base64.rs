@@ -0,0 +1,107 @@
+//! Standard base64 with padding (RFC 4648), which job bodies are sent as since JSON strings can't
+//! carry arbitrary bytes.
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const PAD: u8 = b'=';
+
+/// Returns the base64 encoding of `bytes`
+pub fn encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(encoded_len(bytes.len()));
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).cloned().unwrap_or(0),
+            chunk.get(2).cloned().unwrap_or(0),
+        ];
+        let sextets = [
+            b[0] >> 2,
+            (b[0] & 0x03) << 4 | b[1] >> 4,
+            (b[1] & 0x0f) << 2 | b[2] >> 6,
+            b[2] & 0x3f,
+        ];
+        // A chunk of n bytes fills n + 1 sextets, the rest is padding
+        for (i, &s) in sextets.iter().enumerate() {
+            let c = if i <= chunk.len() { ALPHABET[s as usize] } else { PAD };
+            encoded.push(c as char);
+        }
+    }
+    encoded
+}
+
+/// Returns the length of the base64 encoding of `len` bytes
+pub fn encoded_len(len: usize) -> usize {
+    len.div_ceil(3) * 4
+}
+
+/// Decodes `encoded`, or returns None if it isn't padded base64
+pub fn decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+    let quads = encoded.len() / 4;
+    for (n, quad) in encoded.chunks(4).enumerate() {
+        // Only the last quad may be padded, by one or two characters
+        let padding = quad.iter().rev().take_while(|&&c| c == PAD).count();
+        if padding > 2 || (padding > 0 && n + 1 < quads) {
+            return None;
+        }
+        let mut sextets = [0u8; 4];
+        for (s, &c) in sextets.iter_mut().zip(&quad[..4 - padding]) {
+            *s = sextet(c)?;
+        }
+        let bytes = [
+            sextets[0] << 2 | sextets[1] >> 4,
+            sextets[1] << 4 | sextets[2] >> 2,
+            sextets[2] << 6 | sextets[3],
+        ];
+        decoded.extend_from_slice(&bytes[..3 - padding]);
+    }
+    Some(decoded)
+}
+
+fn sextet(c: u8) -> Option<u8> {
+    match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test vectors from RFC 4648, section 10
+    const VECTORS: &[(&str, &str)] = &[
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ];
+
+    #[test]
+    fn encodes_and_decodes_rfc_vectors() {
+        for &(plain, encoded) in VECTORS {
+            assert_eq!(encode(plain.as_bytes()), encoded);
+            assert_eq!(encoded_len(plain.len()), encoded.len());
+            assert_eq!(decode(encoded), Some(plain.as_bytes().to_vec()));
+        }
+        let bytes: Vec<u8> = (0..=255).collect();
+        assert_eq!(decode(&encode(&bytes)), Some(bytes));
+    }
+
+    #[test]
+    fn refuses_malformed_input() {
+        for encoded in &["Zg", "Zg=", "Z===", "Zg==Zm8=", "Zm9v!A==", "Zm 9v", "Zm9v\n"] {
+            assert_eq!(decode(encoded), None, "Decoded {:?}", encoded);
+        }
+    }
+}
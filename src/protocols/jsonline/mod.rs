@@ -0,0 +1,618 @@
+//! A newline-delimited JSON protocol front end for the Hub, for clients that would rather not
+//! speak beanstalkd.
+//!
+//! Every request is a JSON object on a line of its own naming its command in `cmd`, and is
+//! answered with a JSON object on a line of its own naming the outcome in `status`. Jobs are
+//! identified by their hub Uuid and their bodies are sent as base64:
+//!
+//! ```text
+//! {"cmd":"put","delay_ms":1500,"body":"aGVsbG8="}
+//! {"status":"inserted","id":"67e55044-10b1-426f-9247-bb680e5fe0c8"}
+//! {"cmd":"reserve","timeout_ms":0}
+//! {"status":"reserved","id":"67e55044-10b1-426f-9247-bb680e5fe0c8","body":"aGVsbG8="}
+//! {"cmd":"cancel","id":"67e55044-10b1-426f-9247-bb680e5fe0c8"}
+//! {"status":"cancelled"}
+//! ```
+//!
+//! A put may also set the job's `priority` and `ttr_ms`. A reserve without a `timeout_ms` waits
+//! for a job for as long as it takes, and is answered with `{"status":"timed_out"}` otherwise.
+//! Either way, a client holding a reserved job that runs out within a second is answered with
+//! `{"status":"deadline_soon"}` instead of waiting, unless a job is ready.
+//! Cancelling a delayed or ready job deletes it, and so does cancelling a job this client
+//! reserved, which is how a consumer acknowledges it. Unknown ids are answered with
+//! `{"status":"not_found"}`.
+//!
+//! Malformed requests are answered with `{"status":"error","error":"<reason>"}` and the
+//! connection carries on with the next line, while blank lines are skipped. Jobs are scheduled on
+//! the default tube of a [`TubeRegistry`], just like beanstalkd's, see [`core`], and clients are
+//! held to the same [`ConnectionLimits`].
+
+mod base64;
+
+use metrics::ServerMetrics;
+use prometheus;
+use protocols::beanstalkd;
+use protocols::core::{self, ConnectionLimits, Listener, Reservation, Session, TubeRegistry};
+use serde_json;
+use shutdown;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use yaad::errors::{RejectReason, YaadError};
+use yaad::hub::{HubConfig, DEFAULT_TICK_INTERVAL_MS};
+use yaad::watchdog::WatchdogThresholds;
+
+/// Address listened on unless configured otherwise, next to beanstalkd's
+pub const DEFAULT_ADDR: &str = "127.0.0.1:11301";
+/// Largest job body accepted by put unless configured otherwise, before it is base64 encoded
+pub const MAX_JOB_SIZE: usize = beanstalkd::MAX_JOB_SIZE;
+/// TTR of jobs put without one
+pub const DEFAULT_TTR_MS: u64 = 60_000;
+/// Room a request line gets besides its base64 encoded body
+const MAX_REQUEST_OVERHEAD: usize = 1024;
+/// Most bytes read off a client's stream at once
+const READ_CHUNK_LEN: usize = 4096;
+
+pub struct JsonLine {
+    addr: String,
+    snapshot_path: Option<PathBuf>,
+    shutdown_grace: Duration,
+    hub_config: HubConfig,
+    max_job_size: usize,
+    tick_interval: Duration,
+    metrics: Option<Arc<dyn ServerMetrics>>,
+    metrics_http_addr: Option<String>,
+    limits: ConnectionLimits,
+}
+
+impl JsonLine {
+    /// Creates a server listening on the TCP address `addr`, see [`Beanstalkd::new`] for the
+    /// snapshot and shutdown grace period
+    ///
+    /// [`Beanstalkd::new`]: ::protocols::beanstalkd::Beanstalkd::new
+    pub fn new(addr: String, snapshot_path: Option<String>, shutdown_grace_ms: u64) -> JsonLine {
+        JsonLine {
+            addr,
+            snapshot_path: snapshot_path.map(PathBuf::from),
+            shutdown_grace: Duration::from_millis(shutdown_grace_ms),
+            hub_config: HubConfig::new(beanstalkd::DEFAULT_SPOKE_DURATION_MS),
+            max_job_size: MAX_JOB_SIZE,
+            tick_interval: Duration::from_millis(DEFAULT_TICK_INTERVAL_MS),
+            metrics: None,
+            metrics_http_addr: None,
+            limits: ConnectionLimits::default(),
+        }
+    }
+
+    /// Returns this server with every tube's hub using spokes of `spoke_duration_ms`
+    pub fn with_spoke_duration_ms(mut self, spoke_duration_ms: u64) -> JsonLine {
+        self.hub_config.spoke_duration_ms = spoke_duration_ms;
+        self
+    }
+
+    /// Returns this server refusing puts of jobs that trigger more than `max_future_ms` from now
+    pub fn with_max_future_ms(mut self, max_future_ms: u64) -> JsonLine {
+        self.hub_config.max_future_ms = max_future_ms;
+        self
+    }
+
+    /// Returns this server keeping job bodies longer than `threshold_bytes` compressed while
+    /// they wait
+    pub fn with_compress_bodies_over_bytes(mut self, threshold_bytes: usize) -> JsonLine {
+        self.hub_config = self.hub_config.with_compress_bodies_over_bytes(threshold_bytes);
+        self
+    }
+
+    /// Returns this server burying reserved jobs whose TTR ran out more than `max_timeouts`
+    /// times instead of handing them out again
+    pub fn with_max_timeouts(mut self, max_timeouts: u32) -> JsonLine {
+        self.hub_config = self.hub_config.with_max_timeouts(max_timeouts);
+        self
+    }
+
+    /// Returns this server refusing puts on a tube that holds `max_pending_jobs` jobs already
+    pub fn with_max_pending_jobs(mut self, max_pending_jobs: usize) -> JsonLine {
+        self.hub_config = self.hub_config.with_max_pending_jobs(max_pending_jobs);
+        self
+    }
+
+    /// Returns this server logging an error whenever a tube's hub goes over `thresholds`
+    pub fn with_watchdog(mut self, thresholds: WatchdogThresholds) -> JsonLine {
+        self.hub_config = self.hub_config.with_watchdog(thresholds);
+        self
+    }
+
+    /// Returns this server accepting job bodies of up to `max_job_size` bytes
+    pub fn with_max_job_size(mut self, max_job_size: usize) -> JsonLine {
+        self.max_job_size = max_job_size;
+        self
+    }
+
+    /// Returns this server running the tubes' housekeeping every `tick_interval_ms`
+    pub fn with_tick_interval_ms(mut self, tick_interval_ms: u64) -> JsonLine {
+        self.tick_interval = Duration::from_millis(tick_interval_ms);
+        self
+    }
+
+    /// Returns this server sending every tube's hub gauges to `metrics` on each tick
+    pub fn with_metrics(mut self, metrics: Arc<dyn ServerMetrics>) -> JsonLine {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Returns this server also serving every tube's metrics at `http://<addr>/metrics` in the
+    /// Prometheus text format, see [`prometheus`](::prometheus)
+    pub fn with_metrics_http_addr(mut self, addr: String) -> JsonLine {
+        self.metrics_http_addr = Some(addr);
+        self
+    }
+
+    /// Returns this server holding its clients to `limits`
+    pub fn with_connection_limits(mut self, limits: ConnectionLimits) -> JsonLine {
+        self.limits = limits;
+        self
+    }
+
+    /// Binds to the configured address and serves clients until SIGTERM or SIGINT arrives, along
+    /// with the metrics endpoint if one is configured. Puts are refused once SIGUSR1 arrives.
+    pub fn listen_and_serve(&self) -> io::Result<()> {
+        let listener: Listener = TcpListener::bind(&self.addr)?.into();
+        let description = listener.describe()?;
+        info!("JSON line protocol listening on: {}", description);
+
+        let mut metrics = self.metrics.clone();
+        let http_addr = self.metrics_http_addr.as_deref();
+        let endpoint = prometheus::serve_alongside(http_addr, &mut metrics)?;
+
+        let tubes = match self.snapshot_path {
+            Some(ref path) => core::restore(path)?,
+            None => vec![],
+        };
+        let registry = Arc::new(TubeRegistry::from_snapshot(tubes, self.hub_config));
+        if let Some(metrics) = metrics {
+            registry.set_metrics(metrics);
+        }
+        let (trigger, shutdown) = mpsc::channel();
+        shutdown::notify_on_terminate(trigger)?;
+        shutdown::watch_for_drain()?;
+
+        let served = serve_until(
+            vec![listener],
+            Arc::clone(&registry),
+            &shutdown,
+            self.shutdown_grace,
+            self.max_job_size,
+            self.tick_interval,
+            self.limits,
+        );
+        if let Some(endpoint) = endpoint {
+            endpoint.stop();
+        }
+        if let Some(ref path) = self.snapshot_path {
+            registry.snapshot_or_log(path);
+        }
+        served
+    }
+}
+
+/// Serves JSON line clients on every listener until a message arrives on `shutdown`, see
+/// [`core::serve_until`]. Puts with bodies over `max_job_size` bytes are refused.
+pub fn serve_until(
+    listeners: Vec<Listener>,
+    registry: Arc<TubeRegistry>,
+    shutdown: &Receiver<()>,
+    grace: Duration,
+    max_job_size: usize,
+    tick_interval: Duration,
+    limits: ConnectionLimits,
+) -> io::Result<()> {
+    core::serve_until(
+        listeners,
+        registry,
+        shutdown,
+        grace,
+        tick_interval,
+        limits,
+        move |client, peer, registry, limits| {
+            handle_client(client, peer, registry, max_job_size, limits)
+        },
+    )
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum Request {
+    Put {
+        /// Base64 encoded job body
+        body: String,
+        #[serde(default)]
+        delay_ms: u64,
+        #[serde(default)]
+        priority: u32,
+        #[serde(default = "default_ttr_ms")]
+        ttr_ms: u64,
+    },
+    Reserve { timeout_ms: Option<u64> },
+    Cancel { id: String },
+}
+
+fn default_ttr_ms() -> u64 {
+    DEFAULT_TTR_MS
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum Response {
+    Inserted { id: String },
+    Reserved { id: String, body: String },
+    TimedOut,
+    DeadlineSoon,
+    Cancelled,
+    NotFound,
+    Error { error: String },
+}
+
+impl Response {
+    fn error<E: ToString>(error: E) -> Response {
+        Response::Error {
+            error: error.to_string(),
+        }
+    }
+}
+
+/// Returns the longest request line accepted, for puts of bodies of up to `max_job_size` bytes
+fn max_line_len(max_job_size: usize) -> usize {
+    base64::encoded_len(max_job_size) + MAX_REQUEST_OVERHEAD
+}
+
+/// Serves one client until it hangs up or goes idle for longer than `limits` allow, or the
+/// server shuts down
+fn handle_client<S: Read + Write>(
+    mut stream: S,
+    peer: &str,
+    registry: &TubeRegistry,
+    max_job_size: usize,
+    limits: &ConnectionLimits,
+) -> io::Result<()> {
+    info!("Accepted JSON line client connection from: {}", peer);
+    let max_line_len = max_line_len(max_job_size);
+    let mut session = Session::new(registry).with_max_reserved_jobs(limits.max_reserved_jobs);
+    // When the client last sent something, or was last answered
+    let mut last_active = Instant::now();
+    let mut chunk = [0u8; READ_CHUNK_LEN];
+    let mut line: Vec<u8> = vec![];
+    // Set while the rest of an overlong line is skipped, up to its newline
+    let mut skipping = false;
+    loop {
+        if registry.is_closed() && line.is_empty() {
+            info!("Closing client connection for shutdown: {}", peer);
+            return Ok(());
+        }
+        let n = match stream.read(&mut chunk) {
+            Ok(n) => n,
+            Err(ref e) if core::is_read_timeout(e) => {
+                if last_active.elapsed() >= limits.client_idle_timeout {
+                    let released = session.release_all();
+                    info!(
+                        "Closing client connection idle for {:?}, released {} reserved jobs: {}",
+                        limits.client_idle_timeout, released, peer
+                    );
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            info!("Client disconnected: {}", peer);
+            return Ok(());
+        }
+        let mut read = &chunk[..n];
+        while !read.is_empty() {
+            let newline = read.iter().position(|&b| b == b'\n');
+            let (part, rest) = match newline {
+                Some(i) => (&read[..i], &read[i + 1..]),
+                None => (read, &[][..]),
+            };
+            read = rest;
+            if !skipping {
+                line.extend_from_slice(part);
+            }
+            if line.len() > max_line_len {
+                let error = format!("Request line is over {} bytes", max_line_len);
+                write_response(&mut stream, &Response::error(error))?;
+                line.clear();
+                skipping = true;
+            }
+            if newline.is_none() {
+                continue;
+            }
+            if skipping {
+                skipping = false;
+                continue;
+            }
+            if line.iter().all(u8::is_ascii_whitespace) {
+                line.clear();
+                continue;
+            }
+            let response = match parse_request(&line) {
+                Ok(request) => match handle_request(&mut session, request, max_job_size) {
+                    Some(response) => response,
+                    None => {
+                        info!("Closing client connection for shutdown: {}", peer);
+                        return Ok(());
+                    }
+                },
+                Err(response) => response,
+            };
+            line.clear();
+            write_response(&mut stream, &response)?;
+        }
+        // Counted from after the responses, a reserve may have kept the client waiting meanwhile
+        last_active = Instant::now();
+    }
+}
+
+/// Parses a request line, with or without a trailing `\r`
+fn parse_request(line: &[u8]) -> Result<Request, Response> {
+    serde_json::from_slice(line).map_err(|e| Response::error(format!("Malformed request: {}", e)))
+}
+
+/// Carries out `request` for the client of `session`. Returns None if the server started
+/// shutting down while the client waited in reserve.
+fn handle_request(
+    session: &mut Session,
+    request: Request,
+    max_job_size: usize,
+) -> Option<Response> {
+    let response = match request {
+        Request::Put {
+            body,
+            delay_ms,
+            priority,
+            ttr_ms,
+        } => put(session, &body, delay_ms, priority, ttr_ms, max_job_size),
+        Request::Reserve { timeout_ms } => {
+            match session.reserve(timeout_ms.map(Duration::from_millis)) {
+                Reservation::Reserved(job, _) => Response::Reserved {
+                    id: job.get_metadata().get_id().to_string(),
+                    body: base64::encode(job.get_body().as_bytes()),
+                },
+                Reservation::TimedOut => Response::TimedOut,
+                Reservation::DeadlineSoon => Response::DeadlineSoon,
+                Reservation::LimitReached => Response::error("Too many jobs reserved"),
+                Reservation::Closed => return None,
+            }
+        }
+        Request::Cancel { id } => match Uuid::parse_str(&id) {
+            Ok(uuid) => {
+                let id = session.registry().id_of(uuid);
+                match id {
+                    Some(id) if session.delete(id) => Response::Cancelled,
+                    _ => Response::NotFound,
+                }
+            }
+            Err(_) => Response::error(format!("Not a job id: {}", id)),
+        },
+    };
+    Some(response)
+}
+
+/// Schedules a put's base64 encoded `body` `delay_ms` from now
+fn put(
+    session: &Session,
+    body: &str,
+    delay_ms: u64,
+    priority: u32,
+    ttr_ms: u64,
+    max_job_size: usize,
+) -> Response {
+    let body = match base64::decode(body) {
+        Some(b) => b,
+        None => return Response::error("Job body isn't base64"),
+    };
+    if body.len() > max_job_size {
+        return Response::error(format!("Job body is over {} bytes", max_job_size));
+    }
+    let job = session.new_job(priority, delay_ms, ttr_ms, body);
+    let id = job.get_metadata().get_id();
+    match session.put(job) {
+        Ok(_) => Response::Inserted { id: id.to_string() },
+        Err(
+            e @ YaadError::JobRejected {
+                reason: RejectReason::TooFarInFuture { .. },
+            },
+        )
+        | Err(
+            e @ YaadError::JobRejected {
+                reason: RejectReason::Draining,
+            },
+        )
+        | Err(e @ YaadError::Capacity { .. }) => Response::error(e),
+        Err(e) => {
+            error!("Failed to put job on tube {}: {}", session.using(), e);
+            Response::error("Internal error")
+        }
+    }
+}
+
+fn write_response<W: Write>(out: &mut W, response: &Response) -> io::Result<()> {
+    let mut line = serde_json::to_vec(response).expect("Responses always serialize");
+    line.push(b'\n');
+    out.write_all(&line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::io::{BufRead, BufReader};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread;
+
+    fn start_server(max_job_size: usize) -> (SocketAddr, mpsc::Sender<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let registry = Arc::new(TubeRegistry::from_snapshot(
+            vec![],
+            HubConfig::new(beanstalkd::DEFAULT_SPOKE_DURATION_MS),
+        ));
+        let (trigger, shutdown) = mpsc::channel();
+        thread::spawn(move || {
+            let grace = Duration::from_millis(0);
+            let tick_interval = Duration::from_millis(DEFAULT_TICK_INTERVAL_MS);
+            let listeners = vec![listener.into()];
+            let limits = ConnectionLimits::default();
+            serve_until(listeners, registry, &shutdown, grace, max_job_size, tick_interval, limits)
+        });
+        (addr, trigger)
+    }
+
+    fn connect(addr: SocketAddr) -> BufReader<TcpStream> {
+        BufReader::new(TcpStream::connect(addr).unwrap())
+    }
+
+    /// Writes `request` followed by a newline and parses the next response line
+    fn send(client: &mut BufReader<TcpStream>, request: &str) -> Value {
+        client.get_mut().write_all(request.as_bytes()).unwrap();
+        client.get_mut().write_all(b"\n").unwrap();
+        read_response(client)
+    }
+
+    fn read_response(client: &mut BufReader<TcpStream>) -> Value {
+        let mut line = String::new();
+        client.read_line(&mut line).unwrap();
+        assert!(line.ends_with('\n'), "Got: {:?}", line);
+        serde_json::from_str(&line).unwrap()
+    }
+
+    fn put(client: &mut BufReader<TcpStream>, delay_ms: u64, body: &[u8]) -> String {
+        let request = format!(
+            r#"{{"cmd":"put","delay_ms":{},"body":"{}"}}"#,
+            delay_ms,
+            base64::encode(body)
+        );
+        let response = send(client, &request);
+        assert_eq!(response["status"], "inserted", "Got: {}", response);
+        response["id"].as_str().unwrap().to_owned()
+    }
+
+    fn cancel(client: &mut BufReader<TcpStream>, id: &str) -> Value {
+        send(client, &format!(r#"{{"cmd":"cancel","id":"{}"}}"#, id))["status"].clone()
+    }
+
+    #[test]
+    fn puts_reserves_and_cancels_by_uuid() {
+        let (addr, _trigger) = start_server(MAX_JOB_SIZE);
+        let mut client = connect(addr);
+        let body: Vec<u8> = (0..=255).collect();
+        let id = put(&mut client, 0, &body);
+        assert!(Uuid::parse_str(&id).is_ok(), "Not a Uuid: {}", id);
+
+        let reserved = send(&mut client, r#"{"cmd":"reserve","timeout_ms":0}"#);
+        assert_eq!(reserved["status"], "reserved");
+        assert_eq!(reserved["id"], id.as_str());
+        let reserved_body = base64::decode(reserved["body"].as_str().unwrap());
+        assert_eq!(reserved_body, Some(body));
+
+        assert_eq!(cancel(&mut client, &id), "cancelled", "Acknowledges the reserved job");
+        assert_eq!(cancel(&mut client, &id), "not_found");
+        assert_eq!(
+            send(&mut client, r#"{"cmd":"reserve","timeout_ms":0}"#),
+            json_status("timed_out")
+        );
+    }
+
+    fn json_status(status: &str) -> Value {
+        serde_json::from_str(&format!(r#"{{"status":"{}"}}"#, status)).unwrap()
+    }
+
+    #[test]
+    fn holds_delayed_jobs_back() {
+        let (addr, _trigger) = start_server(MAX_JOB_SIZE);
+        let mut producer = connect(addr);
+        let mut consumer = connect(addr);
+        let delayed = put(&mut producer, 1500, b"later");
+        let reserve = r#"{"cmd":"reserve","timeout_ms":0}"#;
+        assert_eq!(send(&mut consumer, reserve), json_status("timed_out"));
+
+        // A consumer waiting without a timeout gets the next job put
+        consumer
+            .get_mut()
+            .write_all(b"{\"cmd\":\"reserve\"}\r\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let due = put(&mut producer, 0, b"now");
+        let reserved = read_response(&mut consumer);
+        assert_eq!(reserved["id"], due.as_str());
+
+        assert_eq!(cancel(&mut producer, &delayed), "cancelled");
+        assert_eq!(cancel(&mut consumer, &delayed), "not_found");
+        let unknown = Uuid::new_v4().to_string();
+        assert_eq!(cancel(&mut consumer, &unknown), "not_found");
+    }
+
+    #[test]
+    fn answers_malformed_requests_and_carries_on() {
+        let max_job_size = 4;
+        let (addr, _trigger) = start_server(max_job_size);
+        let mut client = connect(addr);
+        let overlong = format!(r#"{{"cmd":"put","body":"{}"}}"#, "A".repeat(2000));
+        let cases: &[(&str, &str)] = &[
+            ("not json", "Malformed request"),
+            ("[1, 2]", "Malformed request"),
+            (r#"{"body":"aGk="}"#, "Malformed request"),
+            (r#"{"cmd":"fly"}"#, "Malformed request"),
+            (r#"{"cmd":"put"}"#, "Malformed request"),
+            (r#"{"cmd":"put","delay_ms":-1,"body":"aGk="}"#, "Malformed request"),
+            (r#"{"cmd":"reserve","timeout_ms":"soon"}"#, "Malformed request"),
+            (r#"{"cmd":"put","body":"aGk"}"#, "Job body isn't base64"),
+            (r#"{"cmd":"put","body":"aGVsbG8="}"#, "Job body is over 4 bytes"),
+            (r#"{"cmd":"cancel","id":"42"}"#, "Not a job id: 42"),
+            (&overlong, "Request line is over"),
+        ];
+        for &(request, error) in cases {
+            let response = send(&mut client, request);
+            assert_eq!(response["status"], "error", "Request: {}", request);
+            let reason = response["error"].as_str().unwrap();
+            assert!(reason.starts_with(error), "{} got: {}", request, reason);
+        }
+
+        // Blank lines are skipped rather than answered
+        client.get_mut().write_all(b"\n \r\n").unwrap();
+        let id = put(&mut client, 0, b"hi");
+        let reserved = send(&mut client, r#"{"cmd":"reserve","timeout_ms":0}"#);
+        assert_eq!(reserved["id"], id.as_str());
+    }
+
+    #[test]
+    fn fills_in_put_defaults() {
+        let request = parse_request(br#"{"cmd":"put","body":"aGk=","extra":true}"#);
+        assert_eq!(
+            request,
+            Ok(Request::Put {
+                body: "aGk=".to_owned(),
+                delay_ms: 0,
+                priority: 0,
+                ttr_ms: DEFAULT_TTR_MS,
+            })
+        );
+        let request = parse_request(b"{\"cmd\":\"reserve\"}\r");
+        assert_eq!(request, Ok(Request::Reserve { timeout_ms: None }));
+    }
+
+    #[test]
+    fn hangs_up_on_waiting_clients_at_shutdown() {
+        let (addr, trigger) = start_server(MAX_JOB_SIZE);
+        let mut client = connect(addr);
+        client.get_mut().write_all(b"{\"cmd\":\"reserve\"}\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        trigger.send(()).unwrap();
+        let mut rest = String::new();
+        assert_eq!(client.read_line(&mut rest).unwrap(), 0, "Got: {}", rest);
+    }
+}
@@ -0,0 +1,10 @@
+//! Wire protocols that expose a Hub over the network.
+
+pub mod beanstalkd;
+pub mod core;
+pub mod jsonline;
+
+#[cfg(test)]
+mod end_to_end;
+#[cfg(test)]
+pub mod testutil;
@@ -0,0 +1,229 @@
+//! Support for tests that drive a server over the wire the way a real client would.
+//!
+//! A [`TestServer`] runs the beanstalkd listener in process on an ephemeral port, scheduling on a
+//! hub the test hands it. Given a [`MockClock`], delays and TTRs pass only when the test says so,
+//! so nothing has to sleep for a job to become ready. A [`TestClient`] speaks just enough of the
+//! wire format to put, reserve, delete and read stats, handing back error replies as they were
+//! sent.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use yaad::clock::{Clock, MockClock};
+use yaad::hub::{HubConfig, DEFAULT_TICK_INTERVAL_MS};
+use yaad::Hub;
+
+use super::beanstalkd::{self, CommandOptions, DEFAULT_SPOKE_DURATION_MS, MAX_JOB_SIZE};
+use super::core::{ConnectionLimits, TubeRegistry};
+
+/// Time a mock clock created by [`TestServer::with_mock_clock`] starts at
+pub const MOCK_START_MS: u64 = 1_500_000_000_000;
+
+/// A beanstalkd server listening on 127.0.0.1, shut down when dropped
+pub struct TestServer {
+    addr: SocketAddr,
+    registry: Arc<TubeRegistry>,
+    clock: Option<Arc<MockClock>>,
+    trigger: Option<mpsc::Sender<()>>,
+    server: Option<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl TestServer {
+    /// Starts a server whose default tube is scheduled on `hub`. Tubes created by clients get
+    /// hubs with the same config and clock.
+    pub fn start(hub: Hub) -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind test server");
+        let addr = listener.local_addr().expect("Test server has no local address");
+        let registry = Arc::new(TubeRegistry::new(hub));
+        let server_registry = Arc::clone(&registry);
+        let (trigger, shutdown) = mpsc::channel();
+        let server = thread::spawn(move || {
+            beanstalkd::serve_until(
+                vec![listener.into()],
+                server_registry,
+                &shutdown,
+                Duration::from_millis(0),
+                CommandOptions::new(MAX_JOB_SIZE),
+                Duration::from_millis(DEFAULT_TICK_INTERVAL_MS),
+                ConnectionLimits::default(),
+            )
+        });
+        TestServer {
+            addr,
+            registry,
+            clock: None,
+            trigger: Some(trigger),
+            server: Some(server),
+        }
+    }
+
+    /// Starts a server on a hub with the default spoke duration reading the time off a mock
+    /// clock, starting at [`MOCK_START_MS`]
+    pub fn with_mock_clock() -> TestServer {
+        let clock = Arc::new(MockClock::new(MOCK_START_MS));
+        let hub = Hub::from_config_with_clock(
+            HubConfig::new(DEFAULT_SPOKE_DURATION_MS),
+            Arc::clone(&clock) as Arc<dyn Clock>,
+        );
+        let mut server = TestServer::start(hub);
+        server.clock = Some(clock);
+        server
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn registry(&self) -> &TubeRegistry {
+        &self.registry
+    }
+
+    /// Moves the mock clock `ms` forward and has clients waiting in reserve look again for
+    /// jobs that became ready meanwhile.
+    ///
+    /// # Panics
+    ///
+    /// If the server wasn't started with [`TestServer::with_mock_clock`]
+    pub fn advance(&self, ms: u64) {
+        self.clock
+            .as_ref()
+            .expect("Test server has no mock clock")
+            .advance(ms);
+        self.registry.wake_reservers();
+    }
+
+    /// Opens a new client connection to the server
+    pub fn connect(&self) -> TestClient {
+        TestClient::connect(self.addr).expect("Failed to connect to test server")
+    }
+
+    /// Stops the server and returns how serving ended
+    pub fn shutdown(mut self) -> io::Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        if let Some(trigger) = self.trigger.take() {
+            // The server may have stopped already, in which case there is no one to tell
+            let _ = trigger.send(());
+        }
+        match self.server.take() {
+            Some(server) => server.join().expect("Test server panicked"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            println!("Test server failed: {}", e);
+        }
+    }
+}
+
+/// A blocking beanstalkd client. Replies other than the one a command expects come back as the
+/// `Err` of its result, without the trailing CRLF, e.g. `Err("NOT_FOUND")`.
+pub struct TestClient {
+    stream: BufReader<TcpStream>,
+}
+
+impl TestClient {
+    pub fn connect(addr: SocketAddr) -> io::Result<TestClient> {
+        let stream = TcpStream::connect(addr)?;
+        // A server that stops answering fails the test instead of hanging it
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        Ok(TestClient {
+            stream: BufReader::new(stream),
+        })
+    }
+
+    /// Puts a job on the used tube, to be ready `delay_secs` from now. Returns the job's id.
+    pub fn put(
+        &mut self,
+        priority: u32,
+        delay_secs: u64,
+        ttr_secs: u64,
+        body: &[u8],
+    ) -> Result<u64, String> {
+        let mut request = format!("put {} {} {} {}\r\n", priority, delay_secs, ttr_secs, body.len())
+            .into_bytes();
+        request.extend_from_slice(body);
+        request.extend_from_slice(b"\r\n");
+        let reply = self.send(&request);
+        match reply.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["INSERTED", id] => id.parse().map_err(|_| reply.clone()),
+            _ => Err(reply),
+        }
+    }
+
+    /// Reserves the next ready job, waiting up to `timeout_secs` or forever if it is None.
+    /// Returns the job's id and body.
+    pub fn reserve(&mut self, timeout_secs: Option<u64>) -> Result<(u64, Vec<u8>), String> {
+        let request = match timeout_secs {
+            Some(timeout) => format!("reserve-with-timeout {}\r\n", timeout),
+            None => "reserve\r\n".to_owned(),
+        };
+        let reply = self.send(request.as_bytes());
+        let (id, len) = match reply.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["RESERVED", id, len] => match (id.parse(), len.parse()) {
+                (Ok(id), Ok(len)) => (id, len),
+                _ => return Err(reply.clone()),
+            },
+            _ => return Err(reply),
+        };
+        Ok((id, self.read_data(len)))
+    }
+
+    pub fn delete(&mut self, id: u64) -> Result<(), String> {
+        match self.send(format!("delete {}\r\n", id).as_bytes()).as_str() {
+            "DELETED" => Ok(()),
+            reply => Err(reply.to_owned()),
+        }
+    }
+
+    /// Returns the server's stats by name
+    pub fn stats(&mut self) -> Result<HashMap<String, String>, String> {
+        let reply = self.send(b"stats\r\n");
+        let len = match reply.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["OK", len] => len.parse().map_err(|_| reply.clone())?,
+            _ => return Err(reply),
+        };
+        let yaml = String::from_utf8(self.read_data(len)).expect("Stats aren't UTF-8");
+        Ok(yaml
+            .lines()
+            .skip_while(|l| *l == "---")
+            .filter_map(|l| {
+                let split = l.find(": ")?;
+                Some((l[..split].to_owned(), l[split + 2..].to_owned()))
+            })
+            .collect())
+    }
+
+    /// Writes `request` as is, e.g. a malformed command, and returns the reply line
+    pub fn send(&mut self, request: &[u8]) -> String {
+        self.stream
+            .get_mut()
+            .write_all(request)
+            .expect("Failed to write to test server");
+        let mut line = String::new();
+        self.stream
+            .read_line(&mut line)
+            .expect("Failed to read from test server");
+        line.trim_end_matches("\r\n").to_owned()
+    }
+
+    /// Reads `len` bytes of data and the CRLF ending them
+    fn read_data(&mut self, len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len + 2];
+        self.stream
+            .read_exact(&mut data)
+            .expect("Failed to read from test server");
+        assert!(data.ends_with(b"\r\n"), "Data isn't followed by CRLF");
+        data.truncate(len);
+        data
+    }
+}
@@ -0,0 +1,268 @@
+//! Schedules jobs by delay or wall clock time, for embedders who would rather not deal in
+//! milliseconds since the epoch and job ids.
+//!
+//! A [`Scheduler`] wraps a [`SharedHub`] and hands back a [`JobHandle`] for every job scheduled.
+//! Handles only hold a weak reference to the hub, so handles kept around don't keep a dropped hub
+//! alive - using one afterwards fails with [`ScheduleError::HubDropped`].
+//!
+//! ```
+//! extern crate yaad;
+//!
+//! use std::time::Duration;
+//! use yaad::Scheduler;
+//!
+//! let scheduler = Scheduler::new(1_000);
+//! scheduler.schedule_in(Duration::from_secs(0), "due").unwrap();
+//! let later = scheduler.schedule_in(Duration::from_secs(60), "later").unwrap();
+//! assert_eq!(later.cancel(), Ok(true));
+//!
+//! let ready = scheduler.hub().walk_jobs();
+//! assert_eq!(ready.len(), 1);
+//! assert_eq!(ready[0].get_body().as_bytes(), b"due");
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use errors::YaadError;
+use hub::RescheduleError;
+use job::{Job, JobBody};
+use shared::SharedHub;
+use times;
+use uuid::Uuid;
+
+/// Reasons a job can't be scheduled, cancelled or rescheduled
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScheduleError {
+    /// The hub the job was scheduled on has been dropped
+    HubDropped,
+    /// The hub refused the job
+    Refused(YaadError),
+    /// The job couldn't be moved, e.g. because it was walked or cancelled already
+    Reschedule(RescheduleError),
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ScheduleError::HubDropped => write!(f, "The job's hub has been dropped"),
+            ScheduleError::Refused(ref e) => write!(f, "Job can't be scheduled: {}", e),
+            ScheduleError::Reschedule(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for ScheduleError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ScheduleError::HubDropped => None,
+            ScheduleError::Refused(ref e) => Some(e),
+            ScheduleError::Reschedule(ref e) => Some(e),
+        }
+    }
+}
+
+impl From<YaadError> for ScheduleError {
+    fn from(e: YaadError) -> ScheduleError {
+        ScheduleError::Refused(e)
+    }
+}
+
+impl From<RescheduleError> for ScheduleError {
+    fn from(e: RescheduleError) -> ScheduleError {
+        ScheduleError::Reschedule(e)
+    }
+}
+
+pub struct Scheduler {
+    hub: Arc<SharedHub>,
+}
+
+impl Scheduler {
+    /// Creates a scheduler on a new hub whose spokes each span `spoke_duration_ms`
+    pub fn new(spoke_duration_ms: u64) -> Scheduler {
+        Scheduler::with_hub(Arc::new(SharedHub::new(spoke_duration_ms)))
+    }
+
+    /// Creates a scheduler adding jobs to `hub`
+    pub fn with_hub(hub: Arc<SharedHub>) -> Scheduler {
+        Scheduler { hub }
+    }
+
+    /// Returns the hub jobs are scheduled on, to walk them
+    pub fn hub(&self) -> &Arc<SharedHub> {
+        &self.hub
+    }
+
+    /// Schedules `body` to trigger `delay` from now. A zero delay makes the job due right away.
+    pub fn schedule_in<B: Into<JobBody>>(
+        &self,
+        delay: Duration,
+        body: B,
+    ) -> Result<JobHandle, ScheduleError> {
+        let trigger_at_ms = times::current_time_ms().saturating_add(times::duration_to_ms(delay));
+        self.schedule_at_ms(trigger_at_ms, body.into())
+    }
+
+    /// Schedules `body` to trigger at `at`. Times that have passed make the job due right away.
+    pub fn schedule_at<B: Into<JobBody>>(
+        &self,
+        at: SystemTime,
+        body: B,
+    ) -> Result<JobHandle, ScheduleError> {
+        // Times before the epoch have passed as surely as the epoch itself
+        let trigger_at_ms = times::system_time_to_ms(at.max(UNIX_EPOCH));
+        self.schedule_at_ms(trigger_at_ms, body.into())
+    }
+
+    fn schedule_at_ms(
+        &self,
+        trigger_at_ms: u64,
+        body: JobBody,
+    ) -> Result<JobHandle, ScheduleError> {
+        let job = Job::new_auto_id(trigger_at_ms, body);
+        let id = job.get_metadata().get_id();
+        self.hub.add_job(job)?;
+        Ok(JobHandle {
+            id,
+            trigger_at_ms,
+            hub: Arc::downgrade(&self.hub),
+        })
+    }
+}
+
+/// A job scheduled through a [`Scheduler`]
+#[derive(Debug)]
+pub struct JobHandle {
+    id: Uuid,
+    trigger_at_ms: u64,
+    hub: Weak<SharedHub>,
+}
+
+impl JobHandle {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Returns when the job triggers
+    pub fn trigger_at(&self) -> SystemTime {
+        times::ms_to_system_time(self.trigger_at_ms)
+    }
+
+    /// Cancels the job. Returns false if it was walked or cancelled already.
+    pub fn cancel(self) -> Result<bool, ScheduleError> {
+        Ok(self.hub()?.cancel_job(self.id))
+    }
+
+    /// Moves the job to trigger `delay` from now, returning the handle of the moved job
+    pub fn reschedule(self, delay: Duration) -> Result<JobHandle, ScheduleError> {
+        let hub = self.hub()?;
+        let trigger_at_ms = times::current_time_ms().saturating_add(times::duration_to_ms(delay));
+        hub.reschedule(self.id, trigger_at_ms)?;
+        Ok(JobHandle {
+            trigger_at_ms,
+            ..self
+        })
+    }
+
+    fn hub(&self) -> Result<Arc<SharedHub>, ScheduleError> {
+        self.hub.upgrade().ok_or(ScheduleError::HubDropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spoke::BoundingSpokeTime;
+
+    const TEST_SPOKE_DURATION_MS: u64 = 10;
+
+    fn past_bounds() -> Option<BoundingSpokeTime> {
+        Some(BoundingSpokeTime::new(0, u64::MAX))
+    }
+
+    #[test]
+    fn schedules_due_jobs_on_the_past_spoke() {
+        let scheduler = Scheduler::new(TEST_SPOKE_DURATION_MS);
+        let now = scheduler.schedule_in(Duration::ZERO, "now").unwrap();
+        let epoch = scheduler.schedule_at(UNIX_EPOCH, "epoch").unwrap();
+        let hub = scheduler.hub();
+        assert_eq!(hub.find_job_owner_bst(now.id()), past_bounds());
+        assert_eq!(hub.find_job_owner_bst(epoch.id()), past_bounds());
+        let before_epoch = UNIX_EPOCH - Duration::from_secs(1);
+        let before_epoch = scheduler.schedule_at(before_epoch, "before").unwrap();
+        assert_eq!(before_epoch.trigger_at(), UNIX_EPOCH);
+
+        let walked: Vec<Uuid> = hub
+            .walk_jobs()
+            .iter()
+            .map(|j| j.get_metadata().get_id())
+            .collect();
+        assert_eq!(walked.len(), 3);
+        assert!(walked[..2].contains(&epoch.id()));
+        assert!(walked[..2].contains(&before_epoch.id()));
+        assert_eq!(walked[2], now.id());
+    }
+
+    #[test]
+    fn schedules_at_wall_clock_times() {
+        let scheduler = Scheduler::new(TEST_SPOKE_DURATION_MS);
+        let at = times::ms_to_system_time(times::current_time_ms() + 60_000);
+        let handle = scheduler.schedule_at(at, "later").unwrap();
+        assert_eq!(handle.trigger_at(), at);
+        let owner = scheduler.hub().find_job_owner_bst(handle.id()).unwrap();
+        assert_ne!(Some(owner), past_bounds());
+        assert!(scheduler.hub().walk_jobs().is_empty());
+    }
+
+    #[test]
+    fn cancels_through_the_handle() {
+        let scheduler = Scheduler::new(TEST_SPOKE_DURATION_MS);
+        let later = scheduler
+            .schedule_in(Duration::from_secs(60), "later")
+            .unwrap();
+        let id = later.id();
+        assert_eq!(later.cancel(), Ok(true));
+        assert!(scheduler.hub().find_job_owner_bst(id).is_none());
+
+        let due = scheduler.schedule_in(Duration::ZERO, "due").unwrap();
+        assert_eq!(scheduler.hub().walk_jobs().len(), 1);
+        assert_eq!(due.cancel(), Ok(false), "Job was walked already");
+    }
+
+    #[test]
+    fn reschedules_through_the_handle() {
+        let scheduler = Scheduler::new(TEST_SPOKE_DURATION_MS);
+        let handle = scheduler
+            .schedule_in(Duration::from_secs(60), "moved")
+            .unwrap();
+        let id = handle.id();
+        let handle = handle.reschedule(Duration::ZERO).unwrap();
+        assert!(handle.trigger_at() <= SystemTime::now());
+        let walked = scheduler.hub().walk_jobs();
+        assert_eq!(walked.len(), 1);
+        assert_eq!(walked[0].get_metadata().get_id(), id);
+        assert_eq!(walked[0].get_body().as_bytes(), b"moved");
+
+        assert_eq!(
+            handle.reschedule(Duration::from_secs(1)).unwrap_err(),
+            ScheduleError::Reschedule(RescheduleError::UnknownJob(id)),
+            "Job was walked already"
+        );
+    }
+
+    #[test]
+    fn handles_outlive_their_hub() {
+        let scheduler = Scheduler::new(TEST_SPOKE_DURATION_MS);
+        let first = scheduler.schedule_in(Duration::from_secs(60), "a").unwrap();
+        let second = scheduler.schedule_in(Duration::from_secs(60), "b").unwrap();
+        drop(scheduler);
+
+        assert_eq!(first.cancel(), Err(ScheduleError::HubDropped));
+        let err = second.reschedule(Duration::ZERO).unwrap_err();
+        assert_eq!(err, ScheduleError::HubDropped);
+        assert_eq!(err.to_string(), "The job's hub has been dropped");
+    }
+}
@@ -1,21 +1,170 @@
-use config::{Config, ConfigError, File};
+use config::{Config, ConfigError, Environment, File};
 use std::env;
 
+/// Command line flags and the settings they override
+const FLAGS: &[(&str, &str)] = &[
+    ("--mode", "mode"),
+    ("--addr", "addr"),
+    ("--unix-socket-path", "unix_socket_path"),
+    ("--spoke-duration-ms", "spoke_duration_ms"),
+    ("--count", "count"),
+    ("--jobs-per-second", "jobs_per_second"),
+    ("--body-bytes", "body_bytes"),
+    ("--delay-distribution", "delay_distribution"),
+    ("--producer-threads", "producer_threads"),
+    ("--consumer-threads", "consumer_threads"),
+];
+
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub mode: String,
-    pub count: Option<u16>,
+    pub count: Option<usize>,
+    pub jobs_per_second: Option<u64>,
+    pub body_bytes: Option<usize>,
+    pub delay_distribution: Option<String>,
+    pub producer_threads: Option<usize>,
+    pub consumer_threads: Option<usize>,
+    pub addr: Option<String>,
+    pub unix_socket_path: Option<String>,
+    pub stale_compaction_ratio: Option<f64>,
+    pub hub_shards: Option<usize>,
+    pub id_generation: Option<String>,
+    pub watchdog_quiet_ms: Option<u64>,
+    pub snapshot_path: Option<String>,
+    pub shutdown_grace_ms: Option<u64>,
+    pub log_level: Option<String>,
+    pub spoke_duration_ms: Option<u64>,
+    pub max_future_ms: Option<u64>,
+    pub max_job_body_bytes: Option<usize>,
+    pub statsd_addr: Option<String>,
+    pub metrics_http_addr: Option<String>,
+    pub tick_interval_ms: Option<u64>,
+    pub client_idle_timeout_ms: Option<u64>,
+    pub max_connections: Option<usize>,
+    pub max_reserved_jobs: Option<usize>,
+    pub compress_bodies_over_bytes: Option<usize>,
+    pub max_timeouts: Option<u32>,
+    pub max_pending_jobs: Option<usize>,
+    pub watchdog_max_ms_between_walks: Option<u64>,
+    pub watchdog_max_spokes: Option<usize>,
+    pub watchdog_max_past_jobs: Option<usize>,
+    pub snapshot_command: Option<bool>,
 }
 
 impl Settings {
+    /// Reads the settings for the mode picked by `--mode` or the `RUN_MODE` environment variable,
+    /// see [`Settings::load`]
     pub fn new() -> Result<Self, ConfigError> {
-        let mut s = Config::new();
+        let args: Vec<String> = env::args().skip(1).collect();
+        // Default to 'demo' env
+        let run_mode = env::var("RUN_MODE").unwrap_or("demo".into());
+        Settings::load(&run_mode, &args)
+    }
 
-        // Add in the current environment file
-        // Default to 'consumer' env
-        let env = env::var("RUN_MODE").unwrap_or("demo".into());
-        s.merge(File::with_name(&format!("config/{}", env)).required(true))?;
+    /// Reads `config/<mode>.toml`, then overrides it with `YAAD_*` environment variables, e.g.
+    /// `YAAD_ADDR=0.0.0.0:11300`, and then with the command line flags in `args`. The mode is
+    /// `run_mode` unless `--mode` is given. The file is optional, so everything can be set from
+    /// the environment and the command line.
+    pub fn load(run_mode: &str, args: &[String]) -> Result<Self, ConfigError> {
+        let overrides = parse_args(args)?;
+        let mode = overrides
+            .iter()
+            .find(|o| o.0 == "mode")
+            .map_or(run_mode, |o| o.1.as_str());
+
+        let mut s = Config::new();
+        s.set_default("mode", mode)?;
+        s.merge(File::with_name(&format!("config/{}", mode)).required(false))?;
+        s.merge(Environment::with_prefix("YAAD"))?;
+        for (key, value) in overrides {
+            s.set(key, value)?;
+        }
         // You can deserialize (and thus freeze) the entire configuration as
         s.try_into()
     }
 }
+
+/// Parses `--flag value` and `--flag=value` pairs into the settings they override
+fn parse_args(args: &[String]) -> Result<Vec<(&'static str, String)>, ConfigError> {
+    let mut overrides = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline_value) = match arg.find('=') {
+            Some(i) => (&arg[..i], Some(arg[i + 1..].to_owned())),
+            None => (arg.as_str(), None),
+        };
+        let key = match FLAGS.iter().find(|f| f.0 == flag) {
+            Some(f) => f.1,
+            None => return Err(ConfigError::Message(format!("Unknown flag {}", flag))),
+        };
+        let value = match inline_value.or_else(|| args.next().cloned()) {
+            Some(v) => v,
+            None => return Err(ConfigError::Message(format!("Flag {} needs a value", flag))),
+        };
+        overrides.push((key, value));
+    }
+    Ok(overrides)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_flags() {
+        let overrides = parse_args(&args(&[
+            "--addr",
+            "0.0.0.0:11300",
+            "--spoke-duration-ms=500",
+        ]));
+        assert_eq!(
+            overrides.unwrap(),
+            vec![
+                ("addr", "0.0.0.0:11300".to_owned()),
+                ("spoke_duration_ms", "500".to_owned()),
+            ]
+        );
+        assert!(parse_args(&args(&["--verbose"])).is_err());
+        assert!(parse_args(&args(&["--addr"])).is_err());
+    }
+
+    #[test]
+    fn flags_override_the_config_file() {
+        let s = Settings::load("beanstalkd", &args(&["--spoke-duration-ms", "500"])).unwrap();
+        assert_eq!(s.mode, "beanstalkd");
+        assert_eq!(
+            s.addr,
+            Some("127.0.0.1:11300".to_owned()),
+            "Read from the file"
+        );
+        assert_eq!(s.spoke_duration_ms, Some(500));
+
+        let s =
+            Settings::load("demo", &args(&["--mode", "beanstalkd", "--addr=0.0.0.0:1"])).unwrap();
+        assert_eq!(s.mode, "beanstalkd", "--mode picks the config file too");
+        assert_eq!(s.snapshot_path, Some("yaad.snapshot".to_owned()));
+        assert_eq!(s.addr, Some("0.0.0.0:1".to_owned()));
+    }
+
+    #[test]
+    fn environment_overrides_the_config_file() {
+        // No other test looks at statsd_addr, so setting it doesn't race with them
+        env::set_var("YAAD_STATSD_ADDR", "10.0.0.1:8125");
+        let s = Settings::load("beanstalkd", &[]).unwrap();
+        env::remove_var("YAAD_STATSD_ADDR");
+        assert_eq!(s.statsd_addr, Some("10.0.0.1:8125".to_owned()));
+        assert_eq!(s.shutdown_grace_ms, Some(5000), "Read from the file");
+    }
+
+    #[test]
+    fn runs_without_a_config_file() {
+        let s = Settings::load("no-such-mode", &args(&["--addr", "0.0.0.0:1"])).unwrap();
+        assert_eq!(s.mode, "no-such-mode");
+        assert_eq!(s.addr, Some("0.0.0.0:1".to_owned()));
+        assert_eq!(s.count, None);
+    }
+}
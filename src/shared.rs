@@ -0,0 +1,727 @@
+//! A hub that can be shared between threads without wrapping it in a `Mutex`.
+//!
+//! [`SharedHub`] keeps its spokes like [`Hub`] does, but locks them one at a time: the spoke map
+//! sits behind a `RwLock` that is only written when a spoke is created or pruned, and each spoke
+//! has a `Mutex` of its own. Producers adding jobs to different spokes and a consumer walking the
+//! ready spokes only contend on the spokes they touch.
+//!
+//! The spokes are spread over several such maps, called shards, each behind its own lock. Spokes
+//! take turns: the spoke covering `trigger_at_ms` lives in shard
+//! `(trigger_at_ms / spoke_duration_ms) % shards`, so a whole spoke is always in one shard and
+//! producers creating neighbouring spokes don't wait on the same map lock. Walks, lookups and
+//! stats look at every shard.
+//!
+//! No spoke lock is held while waiting for a map lock, so the two can't deadlock.
+//!
+//! [`SharedHub::snapshot_async`] writes a snapshot the same way, a spoke at a time, so a backup
+//! of a large hub doesn't stall the producers.
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, ErrorKind, Write};
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+use errors::{RejectReason, YaadError};
+use gauges::{self, HubGauges, HubMetrics};
+use hub::{self, Hub, HubStats, RescheduleError, StaleStats, DEFAULT_STALE_COMPACTION_RATIO};
+use job::Job;
+use persistence::{self, SavedState, SnapshotWriter};
+use sink::{JobSink, SINK_RETRY_DELAY_MS};
+use spoke::{self, BoundingSpokeTime, Spoke};
+use times;
+use uuid::Uuid;
+
+/// Number of spoke maps a hub spreads its spokes over unless configured otherwise
+pub const DEFAULT_SHARDS: usize = 8;
+
+/// Ids of the jobs added since a running snapshot was cut
+type AddedSinceCut = Arc<Mutex<HashSet<Uuid>>>;
+
+/// The spokes of one shard, by their bounds
+type SpokeMap = BTreeMap<BoundingSpokeTime, Arc<Mutex<Spoke>>>;
+
+pub struct SharedHub {
+    spoke_duration_ms: u64,
+    /// A power of two of spoke maps, see [`SharedHub::shard_of`]
+    shards: Vec<RwLock<SpokeMap>>,
+    past_spoke: Mutex<Spoke>,
+    stale_compaction_ratio: f64,
+    metrics: Option<Arc<dyn HubMetrics>>,
+    /// Set while [`SharedHub::snapshot_async`] runs. Read locked by every add until the job is
+    /// placed, so the cut falls either before or after each add.
+    cut: RwLock<Option<AddedSinceCut>>,
+}
+
+/// What [`SharedHub::snapshot_async`] wrote
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct OnlineSnapshot {
+    pub jobs: usize,
+    /// When the snapshot was cut, in ms since the epoch
+    pub cut_at_ms: u64,
+}
+
+impl SharedHub {
+    /// Creates a hub whose spokes each span `spoke_duration_ms`, with a past spoke for jobs that
+    /// are already due. The spokes are spread over [`DEFAULT_SHARDS`] shards.
+    pub fn new(spoke_duration_ms: u64) -> SharedHub {
+        SharedHub::with_shards(spoke_duration_ms, DEFAULT_SHARDS)
+    }
+
+    /// Creates a hub like [`SharedHub::new`] that spreads its spokes over `shards` spoke maps.
+    /// More shards let more producers create spokes at once, at the cost of walks and lookups
+    /// visiting every shard.
+    ///
+    /// # Panics
+    ///
+    /// If `shards` isn't a power of two
+    pub fn with_shards(spoke_duration_ms: u64, shards: usize) -> SharedHub {
+        assert!(
+            shards.is_power_of_two(),
+            "Shard count {} isn't a power of two",
+            shards
+        );
+        SharedHub {
+            spoke_duration_ms,
+            shards: (0..shards).map(|_| RwLock::new(BTreeMap::new())).collect(),
+            past_spoke: Mutex::new(Spoke::new_in_namespace(
+                &spoke::default_spoke_namespace(),
+                BoundingSpokeTime::new(0, u64::MAX),
+            )),
+            stale_compaction_ratio: DEFAULT_STALE_COMPACTION_RATIO,
+            metrics: None,
+            cut: RwLock::new(None),
+        }
+    }
+
+    /// Sets the stale ratio above which [`SharedHub::stale_stats`] counts a spoke for compaction
+    pub fn set_stale_compaction_ratio(&mut self, ratio: f64) -> &mut SharedHub {
+        self.stale_compaction_ratio = ratio;
+        self
+    }
+
+    /// Returns the number of spoke maps the hub spreads its spokes over
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the shard holding the spoke bounded by `bst`. Spokes are aligned to multiples of
+    /// their duration, so consecutive spokes go to consecutive shards.
+    fn shard_of(&self, bst: &BoundingSpokeTime) -> &RwLock<SpokeMap> {
+        let spoke_index = bst.get_start_time_ms() / self.spoke_duration_ms;
+        &self.shards[(spoke_index & (self.shards.len() as u64 - 1)) as usize]
+    }
+
+    /// Returns the spokes within `range` across all shards, in time order
+    fn spokes_in<R>(&self, range: R) -> Vec<(BoundingSpokeTime, Arc<Mutex<Spoke>>)>
+    where
+        R: RangeBounds<BoundingSpokeTime> + Clone,
+    {
+        let mut spokes: Vec<(BoundingSpokeTime, Arc<Mutex<Spoke>>)> = vec![];
+        for shard in &self.shards {
+            let shard = shard.read().unwrap();
+            spokes.extend(shard.range(range.clone()).map(|s| (*s.0, Arc::clone(s.1))));
+        }
+        spokes.sort_by_key(|s| s.0);
+        spokes
+    }
+
+    /// Makes [`SharedHub::tick`] report the hub's gauges to `metrics`
+    pub fn set_metrics(&mut self, metrics: Arc<dyn HubMetrics>) -> &mut SharedHub {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Adds a job to the spoke that owns its trigger time, creating the spoke if needed. Fails
+    /// without changing the hub if no spoke can own the job, or with [`RejectReason::Duplicate`]
+    /// if a spoke holds a job with the same id. Producers racing to add the same id to different
+    /// spokes may both succeed, as the spokes are only locked one at a time.
+    pub fn add_job(&self, job: Job) -> Result<(), YaadError> {
+        let id = job.get_metadata().get_id();
+        let cut = self.cut.read().unwrap();
+        if self.find_job_owner_bst(id).is_some() {
+            return Err(RejectReason::Duplicate(id).into());
+        }
+        if let Some(ref added) = *cut {
+            // Recorded before the job can be seen in a spoke, so a snapshot can't pick it up
+            added.lock().unwrap().insert(id);
+        }
+        // Like the hub, jobs due this very millisecond go to the past spoke
+        if job.is_ready_at(times::current_time_ms()) {
+            return self.add_job_to_past(job);
+        }
+        let job_bst = Hub::job_bounding_spoke_time(&job, self.spoke_duration_ms)?;
+        // If the job's spoke exists, only that spoke is locked while offering the job
+        let shard = self.shard_of(&job_bst);
+        let existing = shard.read().unwrap().get(&job_bst).map(Arc::clone);
+        if let Some(s) = existing {
+            if s.lock().unwrap().add_job(&job).is_ok() {
+                return Ok(());
+            }
+        }
+
+        // Create the job's spoke, unless another producer did in the meantime
+        let refused = {
+            let mut spokes = shard.write().unwrap();
+            let spoke = spokes
+                .entry(job_bst)
+                .or_insert_with(|| Arc::new(Mutex::new(Spoke::new_from_bounds(job_bst))));
+            let refused = spoke.lock().unwrap().add_job(&job);
+            refused
+        };
+        match refused {
+            Ok(()) => Ok(()),
+            // The spoke expired while the job was being placed, so the job is due by now
+            Err(_) if job.trigger_at_ms() < times::current_time_ms() => self.add_job_to_past(job),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn add_job_to_past(&self, job: Job) -> Result<(), YaadError> {
+        match self.past_spoke.lock().unwrap().add_job(&job) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                error!("Past spoke refused job {}: {}", job.get_metadata().get_id(), e);
+                Err(YaadError::Internal("Past spoke refused a job"))
+            }
+        }
+    }
+
+    /// Returns the jobs that are ready, sorted like [`Hub::walk_jobs`], and prunes spokes left
+    /// expired and empty
+    pub fn walk_jobs(&self) -> Vec<Job> {
+        let mut walks = {
+            let mut past = self.past_spoke.lock().unwrap();
+            let walked = past.walk();
+            past.release_memory();
+            vec![walked]
+        };
+        // Spokes are ordered by ascending start time, so the ready spokes are a prefix of each
+        // shard's map
+        for (_, s) in self.spokes_in(spoke::started_by(times::current_time_ms())) {
+            walks.push(s.lock().unwrap().walk());
+        }
+        self.prune_spokes();
+        hub::merge_walks(walks)
+    }
+
+    /// Walks the ready jobs and delivers at most `max` of them to `sink`, like
+    /// [`Hub::drain_into`]. Ready jobs past `max` go back to the past spoke for the next call.
+    pub fn drain_into<S: JobSink>(&self, sink: &S, max: usize) -> usize {
+        let retry_at_ms = times::current_time_ms() + SINK_RETRY_DELAY_MS;
+        let mut delivered = 0;
+        let mut jobs = self.walk_jobs().into_iter();
+        for job in jobs.by_ref().take(max) {
+            match sink.deliver(job) {
+                Ok(()) => delivered += 1,
+                Err(job) => self.requeue(job.with_trigger_at_ms(retry_at_ms)),
+            }
+        }
+        jobs.for_each(|job| self.requeue(job));
+        delivered
+    }
+
+    /// Schedules a walked job again, logging it if the hub refuses it
+    fn requeue(&self, job: Job) {
+        let id = job.get_metadata().get_id();
+        if let Err(e) = self.add_job(job) {
+            error!("Failed to re-queue job {} walked for a sink: {}", id, e);
+        }
+    }
+
+    /// Removes every expired spoke with no pending jobs. Only the expired prefix of each shard's
+    /// map is looked at, and a map is only write locked if there is something to prune in it.
+    fn prune_spokes(&self) -> usize {
+        let now_ms = times::current_time_ms();
+        let prunable = |bst: &BoundingSpokeTime, s: &Arc<Mutex<Spoke>>| {
+            bst.is_expired_at(now_ms) && s.lock().unwrap().pending_job_len() == 0
+        };
+        let mut pruned = 0;
+        for shard in &self.shards {
+            let candidates: Vec<BoundingSpokeTime> = shard
+                .read()
+                .unwrap()
+                .range(spoke::started_before(now_ms))
+                .filter(|s| prunable(s.0, s.1))
+                .map(|s| *s.0)
+                .collect();
+            if candidates.is_empty() {
+                continue;
+            }
+            let mut spokes = shard.write().unwrap();
+            // Check again, jobs may have come in while the map wasn't locked
+            for bst in candidates {
+                if spokes.get(&bst).is_some_and(|s| prunable(&bst, s)) {
+                    spokes.remove(&bst);
+                    pruned += 1;
+                }
+            }
+        }
+        pruned
+    }
+
+    /// Housekeeping to call periodically, like [`Hub::tick`]: prunes spent spokes and reports the
+    /// hub's gauges to its metrics
+    pub fn tick(&self) {
+        self.prune_spokes();
+        if let Some(ref metrics) = self.metrics {
+            metrics.record_gauges(&self.gauges());
+        }
+    }
+
+    /// Returns how many spokes and past jobs the hub holds right now, like [`Hub::gauges`]. The
+    /// shared hub doesn't track reservations, so none are counted.
+    pub fn gauges(&self) -> HubGauges {
+        let past_jobs = self.past_spoke.lock().unwrap().pending_job_len();
+        let (mut spokes, mut oldest) = (0, None);
+        for shard in &self.shards {
+            let shard = shard.read().unwrap();
+            spokes += shard.len();
+            oldest = oldest.into_iter().chain(shard.keys().next().cloned()).min();
+        }
+        HubGauges {
+            spokes,
+            oldest_expired_spoke_age_ms: gauges::expired_age_ms(
+                oldest.as_ref(),
+                times::current_time_ms(),
+            ),
+            past_jobs,
+            reserved_jobs: 0,
+        }
+    }
+
+    /// Cancels a job that hasn't been walked yet. Returns false if the hub doesn't hold it.
+    pub fn cancel_job(&self, id: Uuid) -> bool {
+        if self.past_spoke.lock().unwrap().cancel_job(id) {
+            return true;
+        }
+        self.shards.iter().any(|shard| {
+            shard
+                .read()
+                .unwrap()
+                .values()
+                .any(|s| s.lock().unwrap().cancel_job(id))
+        })
+    }
+
+    /// Moves a job that hasn't been walked yet to `new_trigger_at_ms`, like [`Hub::reschedule`].
+    /// The job is out of the hub while it moves, so a walk racing the move misses the job rather
+    /// than handing it out twice.
+    pub fn reschedule(&self, id: Uuid, new_trigger_at_ms: u64) -> Result<(), RescheduleError> {
+        let job = self.take_job(id).ok_or(RescheduleError::UnknownJob(id))?;
+        if let Err(e) = self.add_job(job.clone().with_trigger_at_ms(new_trigger_at_ms)) {
+            // Put the job back at its old time, which its spoke or the past spoke still accepts
+            if let Err(restore) = self.add_job(job) {
+                error!("Lost job {} while rescheduling it: {}", id, restore);
+            }
+            return Err(RescheduleError::Refused(e));
+        }
+        Ok(())
+    }
+
+    /// Takes a job that hasn't been walked yet out of whichever spoke holds it
+    fn take_job(&self, id: Uuid) -> Option<Job> {
+        let take = |s: &mut Spoke| {
+            let job = s.find_job(id)?;
+            s.cancel_job(id);
+            Some(job)
+        };
+        if let Some(job) = take(&mut self.past_spoke.lock().unwrap()) {
+            return Some(job);
+        }
+        self.spokes_in(..)
+            .iter()
+            .find_map(|s| take(&mut s.1.lock().unwrap()))
+    }
+
+    /// Returns the earliest trigger time of any job in the hub, or None if it has no jobs
+    pub fn next_trigger_time_ms(&self) -> Option<u64> {
+        let past = self.past_spoke.lock().unwrap().peek_next_trigger();
+        let next = self
+            .spokes_in(..)
+            .iter()
+            .filter_map(|s| s.1.lock().unwrap().peek_next_trigger())
+            .next();
+        match (past, next) {
+            (Some(p), Some(n)) => Some(p.min(n)),
+            (p, n) => p.or(n),
+        }
+    }
+
+    /// Returns the bounds of the spoke holding a job, or None if the hub doesn't hold it
+    pub fn find_job_owner_bst(&self, id: Uuid) -> Option<BoundingSpokeTime> {
+        {
+            let past = self.past_spoke.lock().unwrap();
+            if past.owns_job(id) {
+                return Some(past.get_bounds());
+            }
+        }
+        // A job is held by a single spoke, so the shards can be searched in any order
+        self.shards.iter().find_map(|shard| {
+            shard
+                .read()
+                .unwrap()
+                .iter()
+                .find(|s| s.1.lock().unwrap().owns_job(id))
+                .map(|s| *s.0)
+        })
+    }
+
+    /// Returns how many jobs and job body bytes each spoke holds, like [`Hub::stats`]
+    pub fn stats(&self) -> HubStats {
+        let past = self.past_spoke.lock().unwrap();
+        let spokes = self.spokes_in(..);
+        let guards: Vec<MutexGuard<Spoke>> = spokes.iter().map(|s| s.1.lock().unwrap()).collect();
+        hub::hub_stats_of(&past, guards.iter().map(|g| &**g))
+    }
+
+    /// Writes the jobs the hub holds to a snapshot at `path`, like [`Hub::snapshot`], without
+    /// stopping producers and consumers. Spokes are copied one at a time, each locked only while
+    /// its jobs are, and written out unlocked. Returns once the file is synced to disk.
+    ///
+    /// The snapshot is cut when it starts. Every job added before the cut is written unless it
+    /// is walked or cancelled before its spoke is copied. Jobs added after the cut are left out,
+    /// rescheduled and re-queued ones included, so no job is written twice. Only one snapshot
+    /// runs at a time, a second one fails with `WouldBlock`.
+    pub fn snapshot_async(&self, path: &Path) -> io::Result<OnlineSnapshot> {
+        let added: AddedSinceCut = Arc::new(Mutex::new(HashSet::new()));
+        let cut_at_ms = {
+            let mut cut = self.cut.write().unwrap();
+            if cut.is_some() {
+                return Err(io::Error::new(
+                    ErrorKind::WouldBlock,
+                    "A snapshot is running already",
+                ));
+            }
+            *cut = Some(Arc::clone(&added));
+            times::current_time_ms()
+        };
+        let written = persistence::save_with(path, |snapshot| self.write_spokes(snapshot, &added));
+        *self.cut.write().unwrap() = None;
+        Ok(OnlineSnapshot {
+            jobs: written?,
+            cut_at_ms,
+        })
+    }
+
+    /// Writes the jobs of the past spoke and then of every other spoke, but those in `added`
+    fn write_spokes<W: Write>(
+        &self,
+        snapshot: &mut SnapshotWriter<W>,
+        added: &Mutex<HashSet<Uuid>>,
+    ) -> io::Result<()> {
+        // Spokes created after this only hold jobs added after the cut
+        let spokes = self.spokes_in(..);
+        let copy = |spoke: &Spoke| -> Vec<Job> {
+            let added = added.lock().unwrap();
+            spoke
+                .jobs()
+                .filter(|j| !added.contains(&j.get_metadata().get_id()))
+                .collect()
+        };
+        let past = copy(&self.past_spoke.lock().unwrap());
+        for job in &past {
+            snapshot.write_job("", job, SavedState::Ready)?;
+        }
+        for (_, s) in spokes {
+            let jobs = copy(&s.lock().unwrap());
+            for job in &jobs {
+                snapshot.write_job("", job, SavedState::Ready)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns stale heap entry totals across all spokes, like [`Hub::stale_stats`]
+    pub fn stale_stats(&self) -> StaleStats {
+        let past = self.past_spoke.lock().unwrap();
+        let spokes = self.spokes_in(..);
+        let guards: Vec<MutexGuard<Spoke>> = spokes.iter().map(|s| s.1.lock().unwrap()).collect();
+        hub::stale_stats_of(
+            Some(&*past).into_iter().chain(guards.iter().map(|g| &**g)),
+            self.stale_compaction_ratio,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::env;
+    use std::fs::{self, File};
+    use std::process;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    const TEST_SPOKE_DURATION_MS: u64 = 10;
+
+    #[test]
+    fn cancels_and_finds_jobs() {
+        let hub = SharedHub::new(TEST_SPOKE_DURATION_MS);
+        let now_ms = times::current_time_ms();
+        let past = Job::new_auto_id(now_ms - 100, "past");
+        let future = Job::new_auto_id(now_ms + 10_000, "future");
+        let (past_id, future_id) = (past.get_metadata().get_id(), future.get_metadata().get_id());
+        hub.add_job(past).unwrap();
+        hub.add_job(future).unwrap();
+
+        assert_eq!(hub.next_trigger_time_ms(), Some(now_ms - 100));
+        assert!(hub.find_job_owner_bst(future_id).is_some());
+        assert!(hub.cancel_job(future_id));
+        assert!(!hub.cancel_job(future_id), "Job is already cancelled");
+        assert!(hub.find_job_owner_bst(future_id).is_none());
+        assert_eq!(hub.stale_stats().total_stale_entries, 1);
+        assert_eq!(hub.stats().total_body_bytes, 4, "Cancelled body isn't counted");
+
+        let walked = hub.walk_jobs();
+        assert_eq!(walked.len(), 1);
+        assert_eq!(walked[0].get_metadata().get_id(), past_id);
+        assert_eq!(hub.next_trigger_time_ms(), None);
+    }
+
+    #[test]
+    fn gauges_count_spokes_and_past_jobs() {
+        let hub = SharedHub::new(TEST_SPOKE_DURATION_MS);
+        let now_ms = times::current_time_ms();
+        hub.add_job(Job::new_auto_id(now_ms - 100, "past")).unwrap();
+        hub.add_job(Job::new_auto_id(now_ms + 10_000, "future"))
+            .unwrap();
+        let gauges = hub.gauges();
+        assert_eq!(gauges.spokes, 1);
+        assert_eq!(gauges.past_jobs, 1);
+        assert_eq!(gauges.oldest_expired_spoke_age_ms, 0);
+        assert_eq!(gauges.reserved_jobs, 0);
+    }
+
+    #[test]
+    fn refuses_duplicate_job_ids() {
+        let hub = SharedHub::new(TEST_SPOKE_DURATION_MS);
+        let now_ms = times::current_time_ms();
+        let job = Job::new_auto_id(now_ms + 10_000, "original");
+        let id = job.get_metadata().get_id();
+        hub.add_job(job).unwrap();
+
+        let same_spoke = Job::new(id, now_ms + 10_001, "same spoke");
+        assert_eq!(hub.add_job(same_spoke), Err(RejectReason::Duplicate(id).into()));
+        let past = Job::new(id, now_ms - 100, "past");
+        assert_eq!(hub.add_job(past), Err(RejectReason::Duplicate(id).into()));
+        assert_eq!(hub.stats().total_body_bytes, 8);
+    }
+
+    #[test]
+    fn producers_and_a_consumer_see_every_job_once() {
+        const PRODUCERS: u64 = 4;
+        const JOBS_PER_PRODUCER: u64 = 250;
+        let hub = Arc::new(SharedHub::new(TEST_SPOKE_DURATION_MS));
+
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|p| {
+                let hub = Arc::clone(&hub);
+                thread::spawn(move || {
+                    let mut ids = vec![];
+                    for i in 0..JOBS_PER_PRODUCER {
+                        // Spread jobs over the past spoke and the next few spokes
+                        let trigger_ms = times::current_time_ms() - 20 + (i * 7 + p) % 100;
+                        let job = Job::new_auto_id(trigger_ms, "job");
+                        ids.push(job.get_metadata().get_id());
+                        hub.add_job(job).unwrap();
+                    }
+                    ids
+                })
+            })
+            .collect();
+
+        let consumer = {
+            let hub = Arc::clone(&hub);
+            thread::spawn(move || {
+                let total = (PRODUCERS * JOBS_PER_PRODUCER) as usize;
+                let deadline_ms = times::current_time_ms() + 10_000;
+                let mut seen = HashSet::new();
+                while seen.len() < total && times::current_time_ms() < deadline_ms {
+                    for j in hub.walk_jobs() {
+                        let id = j.get_metadata().get_id();
+                        assert!(seen.insert(id), "Job {} was walked twice", id);
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                }
+                seen
+            })
+        };
+
+        let mut produced = HashSet::new();
+        for p in producers {
+            produced.extend(p.join().unwrap());
+        }
+        let consumed = consumer.join().unwrap();
+        assert_eq!(consumed, produced, "Every job is walked exactly once");
+        assert!(hub.walk_jobs().is_empty());
+    }
+
+    #[test]
+    fn keeps_jobs_on_either_side_of_shard_boundaries() {
+        const SPOKES: u64 = 8;
+        let hub = SharedHub::with_shards(TEST_SPOKE_DURATION_MS, 4);
+        let first_spoke_ms =
+            times::floor_to(times::current_time_ms(), TEST_SPOKE_DURATION_MS) + 100;
+        let mut ids = vec![];
+        for k in 0..SPOKES {
+            // The last ms of one spoke and the first of the next, which is in the next shard
+            let end_ms = first_spoke_ms + (k + 1) * TEST_SPOKE_DURATION_MS;
+            for trigger_ms in &[end_ms - 1, end_ms] {
+                let job = Job::new_auto_id(*trigger_ms, "job");
+                ids.push((job.get_metadata().get_id(), *trigger_ms));
+                hub.add_job(job).unwrap();
+            }
+        }
+        for (i, shard) in hub.shards.iter().enumerate() {
+            let shard = shard.read().unwrap();
+            assert!(!shard.is_empty());
+            for bst in shard.keys() {
+                let spoke_index = bst.get_start_time_ms() / TEST_SPOKE_DURATION_MS;
+                assert_eq!(spoke_index % 4, i as u64, "Spokes take turns between shards");
+            }
+        }
+        for &(id, trigger_ms) in &ids {
+            let bst = hub.find_job_owner_bst(id).unwrap();
+            assert!(bst.covers(trigger_ms), "Job at {} is in spoke {:?}", trigger_ms, bst);
+            let duplicate = Job::new(id, trigger_ms + TEST_SPOKE_DURATION_MS, "duplicate");
+            assert_eq!(hub.add_job(duplicate), Err(RejectReason::Duplicate(id).into()));
+        }
+        let stats = hub.stats();
+        assert_eq!(stats.total_jobs, ids.len());
+        let starts: Vec<u64> = stats.spokes.iter().map(|s| s.bounds.get_start_time_ms()).collect();
+        let mut sorted = starts.clone();
+        sorted.sort();
+        assert_eq!(starts, sorted, "Stats list spokes in time order across shards");
+        assert_eq!(hub.gauges().spokes, SPOKES as usize + 1);
+        assert_eq!(hub.next_trigger_time_ms(), Some(ids[0].1));
+
+        let (cancelled, _) = ids.remove(3);
+        assert!(hub.cancel_job(cancelled));
+        assert!(hub.find_job_owner_bst(cancelled).is_none());
+
+        // Wait for the last spoke to expire, so it can be pruned once walked
+        let last_ms = ids.last().unwrap().1;
+        while times::current_time_ms() <= last_ms + TEST_SPOKE_DURATION_MS {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let walked: Vec<(Uuid, u64)> = hub
+            .walk_jobs()
+            .iter()
+            .map(|j| (j.get_metadata().get_id(), j.trigger_at_ms()))
+            .collect();
+        assert_eq!(walked, ids, "Every job is walked once, in trigger time order");
+        assert_eq!(hub.gauges().spokes, 0, "Walked spokes are pruned in every shard");
+    }
+
+    #[test]
+    fn sharded_inserts_from_many_threads() {
+        const THREADS: u64 = 8;
+        const JOBS_PER_THREAD: u64 = 2_000;
+        // Far enough ahead that no spoke is walked or expires meanwhile, over 64 spokes
+        let start_ms = times::floor_to(times::current_time_ms(), TEST_SPOKE_DURATION_MS) + 60_000;
+        let insert = |shards: usize| {
+            let hub = Arc::new(SharedHub::with_shards(TEST_SPOKE_DURATION_MS, shards));
+            let threads: Vec<_> = (0..THREADS)
+                .map(|t| {
+                    let hub = Arc::clone(&hub);
+                    thread::spawn(move || {
+                        for i in 0..JOBS_PER_THREAD {
+                            let offset_ms = (i * THREADS + t) % (64 * TEST_SPOKE_DURATION_MS);
+                            hub.add_job(Job::new_auto_id(start_ms + offset_ms, "job"))
+                                .unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for t in threads {
+                t.join().unwrap();
+            }
+            assert_eq!(hub.stats().total_jobs, (THREADS * JOBS_PER_THREAD) as usize);
+            assert_eq!(hub.gauges().spokes, 64);
+            hub
+        };
+        for &shards in &[1, 8] {
+            let hub = insert(shards);
+            for shard in &hub.shards {
+                assert_eq!(
+                    shard.read().unwrap().len(),
+                    64 / shards,
+                    "Consecutive spokes are spread evenly over the shards"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn snapshots_while_producers_keep_adding() {
+        const PRODUCERS: u64 = 4;
+        let hub = Arc::new(SharedHub::new(TEST_SPOKE_DURATION_MS));
+        let now_ms = times::current_time_ms();
+        let mut before_cut = HashSet::new();
+        let past = Job::new_auto_id(now_ms - 100, "past");
+        let past_id = past.get_metadata().get_id();
+        before_cut.insert(past_id);
+        hub.add_job(past).unwrap();
+        for i in 0..500 {
+            let job = Job::new_auto_id(now_ms + 60_000 + i * 13, format!("job {}", i));
+            before_cut.insert(job.get_metadata().get_id());
+            hub.add_job(job).unwrap();
+        }
+
+        let started = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(AtomicBool::new(false));
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|p| {
+                let (hub, started, stop) = (Arc::clone(&hub), started.clone(), stop.clone());
+                thread::spawn(move || {
+                    let (mut all, mut before_cut) = (HashSet::new(), HashSet::new());
+                    let mut i = 0;
+                    while !stop.load(Ordering::SeqCst) {
+                        let trigger_ms = times::current_time_ms() + 60_000 + (i * 7 + p) % 5_000;
+                        let job = Job::new_auto_id(trigger_ms, vec![b'x'; 100]);
+                        let id = job.get_metadata().get_id();
+                        hub.add_job(job).unwrap();
+                        all.insert(id);
+                        // The add returned before the snapshot started, so before the cut
+                        if !started.load(Ordering::SeqCst) {
+                            before_cut.insert(id);
+                        }
+                        i += 1;
+                    }
+                    (all, before_cut)
+                })
+            })
+            .collect();
+
+        thread::sleep(Duration::from_millis(20));
+        let path = env::temp_dir().join(format!("yaad-online-snapshot-{}", process::id()));
+        started.store(true, Ordering::SeqCst);
+        let snapshot = hub.snapshot_async(&path).unwrap();
+        stop.store(true, Ordering::SeqCst);
+        let mut added = before_cut.clone();
+        for p in producers {
+            let (all, produced_before_cut) = p.join().unwrap();
+            added.extend(all);
+            before_cut.extend(produced_before_cut);
+        }
+        assert!(snapshot.cut_at_ms >= now_ms);
+
+        // Restoring fails on a corrupt record or a job written twice
+        let restored = Hub::restore(File::open(&path).unwrap(), 1_000).unwrap();
+        let restored: HashSet<Uuid> = restored
+            .jobs()
+            .iter()
+            .map(|j| j.get_metadata().get_id())
+            .collect();
+        assert_eq!(restored.len(), snapshot.jobs);
+        let missing = before_cut.difference(&restored).count();
+        assert_eq!(missing, 0, "Jobs added before the cut are written");
+        assert!(restored.is_subset(&added));
+        assert!(hub.snapshot_async(&path).is_ok(), "The cut is lifted afterwards");
+        assert!(hub.find_job_owner_bst(past_id).is_some(), "Snapshots leave jobs in the hub");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+//! Tells the process to wind down on SIGTERM or SIGINT, and to drain on SIGUSR1.
+//!
+//! The signal handler only flips a flag. A watcher thread notices it and sends on a channel
+//! outside of signal context, so whoever receives is free to lock mutexes and do IO while it
+//! shuts down.
+
+use libc;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+static TERMINATING: AtomicBool = AtomicBool::new(false);
+static DRAIN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// How often the watcher thread checks whether a termination signal arrived
+const WATCH_INTERVAL_MS: u64 = 100;
+
+extern "C" fn on_signal(_: libc::c_int) {
+    TERMINATING.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_drain_signal(_: libc::c_int) {
+    DRAIN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Installs SIGTERM and SIGINT handlers that send on `trigger` once either signal arrives. The
+/// process keeps running - it's up to the receiver to finish its work and exit.
+pub fn notify_on_terminate(trigger: Sender<()>) -> io::Result<()> {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    for signal in &[libc::SIGTERM, libc::SIGINT] {
+        if unsafe { libc::signal(*signal, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    thread::Builder::new()
+        .name("shutdown".into())
+        .spawn(move || {
+            while !TERMINATING.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(WATCH_INTERVAL_MS));
+            }
+            // The receiver is gone if it already stopped, then there is nothing to notify
+            let _ = trigger.send(());
+        })?;
+    Ok(())
+}
+
+/// Installs a SIGUSR1 handler that asks the process to stop taking new work, like beanstalkd's
+/// drain mode. Check [`drain_requested`] to find out whether it arrived.
+pub fn watch_for_drain() -> io::Result<()> {
+    let handler = on_drain_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGUSR1, handler) } == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns true once SIGUSR1 arrived after [`watch_for_drain`]
+pub fn drain_requested() -> bool {
+    DRAIN_REQUESTED.load(Ordering::SeqCst)
+}
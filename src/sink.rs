@@ -0,0 +1,76 @@
+//! Sinks that walked jobs are pushed into, for embedding services that would rather have ready
+//! jobs delivered to their own pipeline than poll `walk_jobs`.
+//!
+//! A sink hands back the jobs it can't take. [`Hub::drain_into`](::hub::Hub::drain_into)
+//! schedules those again [`SINK_RETRY_DELAY_MS`] later, so a sink that is busy for a moment
+//! doesn't lose jobs.
+
+use std::sync::mpsc::{SyncSender, TrySendError};
+
+use job::Job;
+
+/// How long after a sink refused a job it is offered again
+pub const SINK_RETRY_DELAY_MS: u64 = 100;
+
+/// Takes delivery of walked jobs
+pub trait JobSink {
+    /// Delivers a job, or hands it back if it can't be taken right now
+    fn deliver(&self, job: Job) -> Result<(), Job>;
+}
+
+/// Delivers jobs into a bounded channel. Jobs are handed back while the channel is full or once
+/// its receiver hung up, instead of blocking the hub.
+#[derive(Debug, Clone)]
+pub struct ChannelSink {
+    sender: SyncSender<Job>,
+}
+
+impl ChannelSink {
+    pub fn new(sender: SyncSender<Job>) -> ChannelSink {
+        ChannelSink { sender }
+    }
+}
+
+impl JobSink for ChannelSink {
+    fn deliver(&self, job: Job) -> Result<(), Job> {
+        self.sender.try_send(job).map_err(|e| match e {
+            TrySendError::Full(job) | TrySendError::Disconnected(job) => job,
+        })
+    }
+}
+
+/// Delivers jobs by calling a function, which hands back the jobs it can't take
+pub struct FnSink<F> {
+    deliver: F,
+}
+
+impl<F: Fn(Job) -> Result<(), Job>> FnSink<F> {
+    pub fn new(deliver: F) -> FnSink<F> {
+        FnSink { deliver }
+    }
+}
+
+impl<F: Fn(Job) -> Result<(), Job>> JobSink for FnSink<F> {
+    fn deliver(&self, job: Job) -> Result<(), Job> {
+        (self.deliver)(job)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn channel_sink_hands_back_what_does_not_fit() {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let sink = ChannelSink::new(sender);
+        assert!(sink.deliver(Job::new_auto_id(5, "first")).is_ok());
+        let refused = sink.deliver(Job::new_auto_id(5, "second")).unwrap_err();
+        assert_eq!(refused.get_body().as_bytes(), b"second", "Channel is full");
+
+        assert_eq!(receiver.recv().unwrap().get_body().as_bytes(), b"first");
+        drop(receiver);
+        assert!(sink.deliver(refused).is_err(), "Nobody is listening anymore");
+    }
+}
@@ -5,27 +5,66 @@ use std::cmp::Ordering;
 use std::collections::binary_heap::PeekMut;
 use std::collections::{BinaryHeap, HashMap};
 use std::fmt;
+use std::ops::{RangeTo, RangeToInclusive};
+use std::sync::Arc;
 use times;
-use uuid::Uuid;
+use uuid::{Uuid, NAMESPACE_OID};
 
 // our module
+use clock::{Clock, SystemClock};
+use errors::{RejectReason, YaadError};
 use job::{Job, JobBody, JobMetadata};
 
+/// Returns the namespace spoke ids are derived in when a hub isn't given its own.
+pub fn default_spoke_namespace() -> Uuid {
+    Uuid::new_v5(&NAMESPACE_OID, "yaad.spoke")
+}
+
 /// A Spoke is a time-bound chain of jobs
 ///
 /// Each spoke has a start time and a max Duration (inclusive)
 /// Any job that should trigger in this time bound should be handled
 /// by this spoke.
+///
+/// A spoke's id is derived from its bounds and a namespace, so the same time window always
+/// gets the same id within a hub configuration.
+///
+/// A spoke reads the time off the system clock unless given another one with
+/// [`Spoke::with_clock`].
 #[derive(Debug)]
 pub struct Spoke {
     id: Uuid,
     bst: BoundingSpokeTime,
-    job_id_map: HashMap<Uuid, JobBody>,
+    clock: Arc<dyn Clock>,
+    /// Live jobs, with the metadata of their one live heap entry
+    job_id_map: HashMap<Uuid, (JobMetadata, JobBody)>,
     job_list: BinaryHeap<JobMetadata>,
     // Todo rename to job_queue?
+    /// Total length of the bodies in `job_id_map` as put and as stored, kept up to date as jobs
+    /// come and go
+    body_bytes: usize,
+    stored_body_bytes: usize,
+    /// Bodies of jobs added longer than this are stored compressed
+    compress_bodies_over_bytes: Option<usize>,
 }
 
-#[derive(Debug, Copy, Clone, Eq, Hash)]
+/// How many jobs, and how many bytes of job bodies, a spoke holds
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SpokeStats {
+    pub bounds: BoundingSpokeTime,
+    pub job_count: usize,
+    /// Length of the bodies as they were put
+    pub body_bytes: usize,
+    /// What the bodies take up as stored, less than `body_bytes` if some are compressed
+    pub stored_body_bytes: usize,
+}
+
+/// The window of time a spoke covers, from its start time up to but excluding its end time.
+///
+/// Bounds order chronologically - by start time, then by end time - so a map keyed by bounds
+/// iterates spokes from the soonest to the farthest in the future. See [`started_by`] and
+/// [`started_before`] for `range` queries over such a map.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct BoundingSpokeTime {
     start_time_ms: u64,
     end_time_ms: u64,
@@ -48,55 +87,83 @@ impl BoundingSpokeTime {
         self.end_time_ms
     }
 
+    /// Returns true if `time_ms` falls within these bounds, which include their start but not
+    /// their end
+    #[inline]
+    pub fn covers(&self, time_ms: u64) -> bool {
+        self.start_time_ms <= time_ms && time_ms < self.end_time_ms
+    }
+
     pub fn contains(&self, other: &BoundingSpokeTime) -> bool {
         self.start_time_ms <= other.start_time_ms && self.end_time_ms > other.end_time_ms
     }
 
     #[inline]
     pub fn is_ready(&self) -> bool {
-        self.start_time_ms <= times::current_time_ms()
+        self.is_ready_at(times::current_time_ms())
+    }
+
+    /// Returns true if these bounds start at or before `now_ms`
+    ///
+    /// Both ends of the bounds count as their own time here: bounds are ready from the
+    /// millisecond they start at, and haven't expired yet in the millisecond they end at. That
+    /// leaves a spoke one millisecond after its window before it can be pruned.
+    #[inline]
+    pub fn is_ready_at(&self, now_ms: u64) -> bool {
+        self.start_time_ms <= now_ms
     }
 
     #[inline]
     pub fn is_expired(&self) -> bool {
-        self.end_time_ms < times::current_time_ms()
+        self.is_expired_at(times::current_time_ms())
+    }
+
+    /// Returns true if these bounds end before `now_ms`
+    #[inline]
+    pub fn is_expired_at(&self, now_ms: u64) -> bool {
+        self.end_time_ms < now_ms
     }
 }
 
 impl Spoke {
     /// Constructs a new Spoke - a time bound chain of jobs starting at the current time
-    /// # Example
-    /// Create a spoke that starts at 5 sec, 0 ns from now
-    ///
-    ///```
-    /// use Spoke;
-    /// let s = Spoke::new_from_now(Duration::new(5, 0));
-    /// s.add_job(Job::new(1, 2, 3, "hi");
-    ///```
     #[allow(dead_code)]
     fn new_from_now(duration_ms: u64) -> Spoke {
         Spoke::new(times::current_time_ms(), duration_ms)
     }
 
     pub fn new_from_bounds(bst: BoundingSpokeTime) -> Spoke {
+        Spoke::new_in_namespace(&default_spoke_namespace(), bst)
+    }
+
+    /// Constructs a new Spoke whose id is derived from the given namespace and bounds
+    pub fn new_in_namespace(namespace: &Uuid, bst: BoundingSpokeTime) -> Spoke {
         let job_id_map = HashMap::new();
         let job_list = BinaryHeap::new();
-        let id = Uuid::new_v4();
+        let id = Spoke::derive_id(namespace, &bst);
         Spoke {
             id,
             bst,
+            clock: Arc::new(SystemClock),
             job_id_map,
             job_list,
+            body_bytes: 0,
+            stored_body_bytes: 0,
+            compress_bodies_over_bytes: None,
         }
     }
-    /// Constructs a new Spoke - a time bound chain of jobs starting at the current time
+    /// Constructs a new Spoke - a time bound chain of jobs starting at `start_time_ms`
     /// # Example
-    /// Create a spoke that starts at 5 sec, 0 ns from now
+    /// Create a spoke that starts now and lasts 5 seconds
     ///
     ///```
-    /// use Spoke;
-    /// let s = Spoke::new(Duration::new(5, 0));
-    /// s.add_job(Job::new(1, 2, 3, "hi");
+    /// use yaad::job::Job;
+    /// use yaad::spoke::Spoke;
+    /// use yaad::times;
+    ///
+    /// let now_ms = times::current_time_ms();
+    /// let mut s = Spoke::new(now_ms, 5_000);
+    /// assert!(s.add_job(&Job::new_auto_id(now_ms + 2_000, "hi")).is_ok());
     ///```
     pub fn new(start_time_ms: u64, duration_ms: u64) -> Spoke {
         let end_time_ms = start_time_ms + duration_ms;
@@ -107,89 +174,329 @@ impl Spoke {
         Spoke::new_from_bounds(bst)
     }
 
-    /// Add a new job into the Spoke - the job is optionally returned if the Spoke is not the right
-    /// one to take the job's responsibility.
+    /// Makes the spoke read the time off `clock`, e.g. so a test can move it forward
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Spoke {
+        self.clock = clock;
+        self
+    }
+
+    /// Makes the spoke compress the bodies of jobs added from now on that are longer than
+    /// `threshold_bytes`, or stop compressing them if it is None
+    pub fn with_compress_bodies_over_bytes(mut self, threshold_bytes: Option<usize>) -> Spoke {
+        self.compress_bodies_over_bytes = threshold_bytes;
+        self
+    }
+
+    /// Returns the deterministic id of a spoke with these bounds in the given namespace
+    pub fn derive_id(namespace: &Uuid, bst: &BoundingSpokeTime) -> Uuid {
+        Uuid::new_v5(
+            namespace,
+            &format!("{}-{}", bst.start_time_ms, bst.end_time_ms),
+        )
+    }
+
+    #[inline]
+    pub fn get_id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Returns the first 8 hex characters of the spoke's id, for logs and diagrams
+    pub fn short_id(&self) -> String {
+        self.id.simple().to_string()[..8].to_owned()
+    }
+
+    /// Add a new job into the Spoke - fails with the reason if the Spoke is not the right one to
+    /// take the job's responsibility. The spoke keeps a copy of the job, so a refused one is still
+    /// the caller's to place elsewhere.
     ///
     /// A Spoke is `responsible` for a job if that job's trigger time lies in the Spoke's
-    /// time bounds
-    pub fn add_job(&mut self, job: Job) -> Option<Job> {
+    /// time bounds. An expired spoke refuses every job with [`YaadError::Expired`]. A job whose
+    /// id the spoke holds already is refused as a [`RejectReason::Duplicate`], leaving the held
+    /// job as it was.
+    pub fn add_job(&mut self, job: &Job) -> Result<(), YaadError> {
         if self.is_expired() {
-            return Option::from(job);
+            return Err(YaadError::Expired { bounds: self.bst });
         }
-        if self.bst.start_time_ms <= job.trigger_at_ms()
-            && job.trigger_at_ms() < self.bst.end_time_ms
-        {
+        let id = job.get_metadata().get_id();
+        if self.job_id_map.contains_key(&id) {
+            return Err(RejectReason::Duplicate(id).into());
+        }
+        if !self.bst.covers(job.trigger_at_ms()) {
             // Only accept jobs that are this spoke's responsibility
-            let jm = job.get_metadata();
-
-            println!("Inserting jm: {:?}", jm);
-            self.job_id_map.insert(jm.get_id(), job.get_body());
-            self.job_list.push(jm);
-            return Option::None;
-        } else {
-            // Return jobs that you don't want to accept
-            return Option::from(job);
+            return Err(RejectReason::OutOfBounds {
+                trigger_at_ms: job.trigger_at_ms(),
+                bounds: self.bst,
+            }
+            .into());
         }
+        let jm = job.get_metadata();
+        trace_job!(
+            "Spoke {} {:?} accepted job {} triggering at {}",
+            self.short_id(),
+            self.bst,
+            jm.get_id(),
+            jm.trigger_at_ms()
+        );
+        let body = match self.compress_bodies_over_bytes {
+            Some(threshold_bytes) => job.body().clone().compressed_over(threshold_bytes),
+            None => job.body().clone(),
+        };
+        self.count_body_in(&body);
+        self.job_id_map.insert(jm.get_id(), (jm, body));
+        self.job_list.push(jm);
+        Ok(())
     }
 
-    /// Walk returns an iterator that returns jobs in trigger order
+    /// Walk returns an iterator that returns jobs in trigger order, jobs triggering together in id
+    /// order
     ///
     /// Call walk in a loop like an iterator on this spoke
     /// # Example
     /// ```
-    ///
-    /// use Spoke;
-    /// use times;
+    /// use yaad::job::Job;
+    /// use yaad::spoke::Spoke;
+    /// use yaad::times;
     ///
     /// let c = times::current_time_ms();
-    /// let s = Spoke.new_from_now(Duration::from_millis(10_000, 0));
-    /// s.add_job(Job::new(1, 1, c + 2500, "hello world");
-    /// s.add_job(Job::new(2, 2, c + 5500, "hello world again");
-    /// let i = s.walk().iter()
-    /// for j in i {
+    /// let mut s = Spoke::new(c - 1_000, 10_000);
+    /// s.add_job(&Job::new_auto_id(c - 500, "hello world")).unwrap();
+    /// s.add_job(&Job::new_auto_id(c + 5_500, "hello world again")).unwrap();
+    /// for j in s.walk() {
     ///   println!("Job: {:?}", j)
     /// }
     /// ```
     pub fn walk(&mut self) -> Vec<Job> {
-        let mut ready_jobs: Vec<Job> = vec![];
+        self.drain_ready().collect()
+    }
 
-        while let Some(peeked) = self.job_list.peek_mut() {
-            if peeked.is_ready() {
+    /// Walks like [`Spoke::walk`], but stops after `max` ready jobs. The jobs left over stay in
+    /// the spoke for the next walk.
+    pub fn walk_limit(&mut self, max: usize) -> Vec<Job> {
+        self.drain_ready().take(max).collect()
+    }
+
+    /// Returns the ready jobs in walk order like [`Spoke::walk`], taking each off the spoke only
+    /// once the iterator gets to it. Jobs the iterator doesn't get to stay in the spoke, so a
+    /// caller can stop early and walk the rest later. Whether a job is ready is judged by the
+    /// time the iterator was created.
+    pub fn drain_ready(&mut self) -> DrainReady<'_> {
+        let now_ms = self.clock.now_ms();
+        DrainReady {
+            spoke: self,
+            now_ms,
+        }
+    }
+
+    /// Takes the next job ready at `now_ms` off the spoke, dropping the tombstones ahead of it. A
+    /// live heap entry without a job behind it is taken off too, and reported as
+    /// [`YaadError::Internal`].
+    pub(crate) fn next_ready_at(&mut self, now_ms: u64) -> Option<Result<Job, YaadError>> {
+        loop {
+            let peeked = self.job_list.peek_mut()?;
+            if !is_live(&self.job_id_map, &peeked) {
+                // Cancelled or moved job - drop its tombstone whether it is ready or not
+                PeekMut::pop(peeked);
+            } else if peeked.is_ready_at(now_ms) {
                 let jm = PeekMut::pop(peeked);
-                match self.job_id_map.remove(&jm.get_id()) {
-                    Some(b) => ready_jobs.push(Job::new_from_metadata(jm, b)),
-                    None => (),
-                }
+                return Some(match self.job_id_map.remove(&jm.get_id()) {
+                    Some((jm, b)) => {
+                        self.count_body_out(&b);
+                        // Consumers get the body as it was put
+                        Ok(Job::new_from_metadata(jm, b.decompressed()))
+                    }
+                    None => Err(YaadError::Internal("Spoke holds metadata without a body")),
+                });
             } else {
-                break;
+                return None;
+            }
+        }
+    }
+
+    /// Returns the trigger time of the next job due in this spoke without walking it. Tombstones
+    /// of cancelled jobs at the top of the heap are dropped on the way.
+    pub fn peek_next_trigger(&mut self) -> Option<u64> {
+        self.peek_next_job().map(|jm| jm.trigger_at_ms())
+    }
+
+    /// Returns the metadata of the next job this spoke would walk, ready or not, without walking
+    /// it. Tombstones are dropped like in [`Spoke::peek_next_trigger`].
+    pub fn peek_next_job(&mut self) -> Option<JobMetadata> {
+        while let Some(peeked) = self.job_list.peek_mut() {
+            if is_live(&self.job_id_map, &peeked) {
+                return Some(*peeked);
             }
+            PeekMut::pop(peeked);
+        }
+        None
+    }
+
+    /// Returns the next live job this spoke would walk among those matching `pred`, ready or not.
+    /// Unlike [`Spoke::peek_next_job`] this leaves the heap alone, so tombstones are skipped but
+    /// not dropped.
+    pub fn peek_job_where<P: Fn(&JobMetadata) -> bool>(&self, pred: P) -> Option<JobMetadata> {
+        let wanted = |jm: &&JobMetadata| is_live(&self.job_id_map, jm) && pred(jm);
+        match self.job_list.peek() {
+            // The heap top is the next job of all, so it is the next wanted one too
+            Some(top) if wanted(&top) => Some(*top),
+            _ => self.job_list.iter().filter(wanted).max().cloned(),
         }
-        ready_jobs
     }
 
     pub fn cancel_job(&mut self, id: Uuid) -> bool {
         // Try to delete using internal id then
         match self.job_id_map.remove(&id) {
-            Some(_) => true, // This does not remove from job list atm
-            //when walking it will just not point to anything
+            Some((_, b)) => {
+                // This does not remove from job list atm
+                //when walking it will just not point to anything
+                self.count_body_out(&b);
+                true
+            }
             None => false,
         }
     }
 
+    /// Takes every live job out of this spoke, in no particular order, and drops the tombstones
+    pub fn drain_jobs(&mut self) -> Vec<Job> {
+        self.body_bytes = 0;
+        self.stored_body_bytes = 0;
+        self.job_list.clear();
+        self.job_id_map
+            .drain()
+            .map(|(_, (jm, b))| Job::new_from_metadata(jm, b))
+            .collect()
+    }
+
+    /// Returns copies of the live jobs in this spoke, in no particular order
+    pub fn jobs<'a>(&'a self) -> impl Iterator<Item = Job> + 'a {
+        self.job_id_map
+            .values()
+            .map(|&(jm, ref b)| Job::new_from_metadata(jm, b.clone()))
+    }
+
+    /// Returns the metadata and body of each live job in this spoke without copying the bodies,
+    /// in no particular order. Heap entries left behind by cancelled jobs aren't included.
+    pub fn live_jobs<'a>(&'a self) -> impl Iterator<Item = (JobMetadata, &'a JobBody)> + 'a {
+        self.job_id_map.values().map(|&(jm, ref b)| (jm, b))
+    }
+
+    /// Returns a copy of a live job in this spoke, or None if the spoke doesn't own it
+    pub fn find_job(&self, id: Uuid) -> Option<Job> {
+        self.peek_job(id)
+            .map(|(jm, body)| Job::new_from_metadata(jm, body.clone()))
+    }
+
+    /// Returns the metadata of a live job in this spoke and a reference to its body, or None if the
+    /// spoke doesn't own it
+    pub fn peek_job(&self, id: Uuid) -> Option<(JobMetadata, &JobBody)> {
+        self.job_id_map.get(&id).map(|&(jm, ref b)| (jm, b))
+    }
+
+    /// Returns the number of live jobs in this spoke that are due by `now_ms`
+    pub fn due_job_len(&self, now_ms: u64) -> usize {
+        self.job_id_map
+            .values()
+            .filter(|e| e.0.trigger_at_ms() <= now_ms)
+            .count()
+    }
+
     pub fn owns_job(&self, id: Uuid) -> bool {
         self.job_id_map.contains_key(&id)
     }
 
-    /// Returns the number of jobs pending in this spoke
+    /// Returns the ids of the live jobs in this spoke, in no particular order
+    pub fn job_ids<'a>(&'a self) -> impl Iterator<Item = Uuid> + 'a {
+        self.job_id_map.keys().cloned()
+    }
+
+    /// Returns the number of jobs pending in this spoke. Heap entries left behind by cancelled
+    /// jobs are not counted, so a spoke whose jobs were all cancelled is pending nothing.
     #[inline]
     pub fn pending_job_len(&self) -> usize {
-        self.job_list.len()
+        self.job_list.len() - self.stale_entry_len()
+    }
+
+    /// Returns the number of jobs in this spoke that still have a body - cancelled jobs are not
+    /// counted.
+    #[inline]
+    pub fn live_job_len(&self) -> usize {
+        self.job_id_map.len()
+    }
+
+    fn count_body_in(&mut self, body: &JobBody) {
+        self.body_bytes += body.raw_len();
+        self.stored_body_bytes += body.stored_len();
+    }
+
+    fn count_body_out(&mut self, body: &JobBody) {
+        self.body_bytes -= body.raw_len();
+        self.stored_body_bytes -= body.stored_len();
+    }
+
+    /// Returns how many live jobs this spoke holds and the total length of their bodies
+    pub fn stats(&self) -> SpokeStats {
+        SpokeStats {
+            bounds: self.bst,
+            job_count: self.live_job_len(),
+            body_bytes: self.body_bytes,
+            stored_body_bytes: self.stored_body_bytes,
+        }
+    }
+
+    /// Returns the number of heap entries that outlived their job body (cancelled jobs). These
+    /// are dead weight until the spoke is walked past them or compacted.
+    #[inline]
+    pub fn stale_entry_len(&self) -> usize {
+        self.job_list.len().saturating_sub(self.job_id_map.len())
+    }
+
+    /// Returns the fraction of heap entries that are stale, between 0.0 and 1.0.
+    pub fn stale_ratio(&self) -> f64 {
+        if self.job_list.is_empty() {
+            return 0.0;
+        }
+        self.stale_entry_len() as f64 / self.job_list.len() as f64
+    }
+
+    /// Drops heap entries whose job was cancelled and returns the number of entries removed.
+    pub fn compact(&mut self) -> usize {
+        let before = self.job_list.len();
+        let job_id_map = &self.job_id_map;
+        let live: Vec<JobMetadata> = self
+            .job_list
+            .drain()
+            .filter(|jm| is_live(job_id_map, jm))
+            .collect();
+        self.job_list = BinaryHeap::from(live);
+        before - self.job_list.len()
+    }
+
+    /// Hands the heap and the job map back to the allocator once the spoke holds no live jobs,
+    /// and shrinks them once they are mostly empty. A past spoke lives as long as its hub, so
+    /// without this it would hold on to the capacity of its biggest burst of late jobs for good.
+    pub fn release_memory(&mut self) {
+        if self.job_id_map.is_empty() {
+            // Tombstones are dropped too, nothing is left for them to stand in for
+            self.job_list = BinaryHeap::new();
+            self.job_id_map = HashMap::new();
+            return;
+        }
+        if self.job_list.len() < self.job_list.capacity() / 4 {
+            self.job_list.shrink_to_fit();
+        }
+        if self.job_id_map.len() < self.job_id_map.capacity() / 4 {
+            self.job_id_map.shrink_to_fit();
+        }
+    }
+
+    /// Returns how many heap entries the spoke has room for without allocating
+    pub fn heap_capacity(&self) -> usize {
+        self.job_list.capacity()
     }
 
     /// Returns true if this Spoke's start time is now or in the past
     #[inline]
     pub fn is_ready(&self) -> bool {
-        self.bst.is_ready()
+        self.bst.is_ready_at(self.clock.now_ms())
     }
 
     #[inline]
@@ -203,16 +510,25 @@ impl Spoke {
     /// from an expired Spoke.
     #[inline]
     pub fn is_expired(&self) -> bool {
-        self.bst.is_expired()
+        self.bst.is_expired_at(self.clock.now_ms())
     }
 }
 
+/// Returns true if `jm` is the heap entry of a job `job_id_map` still holds. Cancelled jobs, and
+/// jobs moved to another trigger time since, leave a tombstone behind in the heap.
+#[inline]
+fn is_live(job_id_map: &HashMap<Uuid, (JobMetadata, JobBody)>, jm: &JobMetadata) -> bool {
+    job_id_map
+        .get(&jm.get_id())
+        .is_some_and(|e| e.0.trigger_at_ms() == jm.trigger_at_ms())
+}
+
 impl fmt::Display for Spoke {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "(Id: {:?}\nStart time:\n{:?}, Duration:\n{:?} sec, NumJobs:\n{}, JobList:\n{:?})",
-            self.id,
+            "(Id: {}, Bounds: [{}, {}), NumJobs: {}, JobList: {:?})",
+            self.short_id(),
             self.bst.start_time_ms,
             self.bst.end_time_ms,
             self.job_list.len(),
@@ -222,8 +538,7 @@ impl fmt::Display for Spoke {
 }
 
 impl Ord for Spoke {
-    /// A Spoke is greater than another spoke if it's start time is nearer in the future
-    /// and it's end time is strictly less than the other's start time.
+    /// Spokes order like their bounds, the spoke starting first being the smaller one
     fn cmp(&self, other: &Spoke) -> Ordering {
         self.bst.cmp(&other.bst)
     }
@@ -244,34 +559,43 @@ impl PartialEq for Spoke {
     }
 }
 
-impl Ord for BoundingSpokeTime {
-    /// A BoundingSpokeTime is greater than another spoke if it's start time is nearer in the future
-    /// and it's end time is strictly less than the other's start time.
-    fn cmp(&self, other: &BoundingSpokeTime) -> Ordering {
-        // Flip ordering
-        self.start_time_ms
-            .cmp(&other.start_time_ms)
-            .then(self.end_time_ms.cmp(&other.end_time_ms))
-    }
+/// The ready jobs of a spoke, taken off it one by one, see [`Spoke::drain_ready`]
+#[derive(Debug)]
+pub struct DrainReady<'a> {
+    spoke: &'a mut Spoke,
+    now_ms: u64,
 }
 
-impl PartialOrd for BoundingSpokeTime {
-    fn partial_cmp(&self, other: &BoundingSpokeTime) -> Option<Ordering> {
-        Some(self.cmp(other))
+impl<'a> Iterator for DrainReady<'a> {
+    type Item = Job;
+
+    /// Skips the jobs the spoke can't hand out, logging why
+    fn next(&mut self) -> Option<Job> {
+        loop {
+            match self.spoke.next_ready_at(self.now_ms)? {
+                Ok(job) => return Some(job),
+                Err(e) => error!("Spoke {} skipped a job: {}", self.spoke.short_id(), e),
+            }
+        }
     }
 }
 
-impl PartialEq for BoundingSpokeTime {
-    fn eq(&self, other: &BoundingSpokeTime) -> bool {
-        self.start_time_ms.eq(&other.start_time_ms) & &self.end_time_ms.eq(&other.end_time_ms)
-    }
+/// Keys of the spokes starting at or before `time_ms`, i.e. the spokes ready by then, in a map
+/// keyed by bounds
+pub fn started_by(time_ms: u64) -> RangeToInclusive<BoundingSpokeTime> {
+    ..=BoundingSpokeTime::new(time_ms, u64::MAX)
+}
+
+/// Keys of the spokes starting before `time_ms` in a map keyed by bounds. Spokes don't overlap,
+/// so all of them but the last one have expired by then.
+pub fn started_before(time_ms: u64) -> RangeTo<BoundingSpokeTime> {
+    ..BoundingSpokeTime::new(time_ms, 0)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread;
-    use std::time::Duration;
+    use clock::MockClock;
 
     #[test]
     fn can_create_spoke() {
@@ -283,9 +607,9 @@ mod tests {
     fn can_add_jobs() {
         let current_ms = times::current_time_ms();
         let mut s: Spoke = Spoke::new_from_now(10_000);
-        s.add_job(Job::new_auto_id(current_ms + 4000, "Hello Second Job!"));
+        s.add_job(&Job::new_auto_id(current_ms + 4000, "Hello Second Job!")).unwrap();
         assert_eq!(s.job_list.len(), 1);
-        s.add_job(Job::new_auto_id(current_ms + 6000, "Hello Second Job!"));
+        s.add_job(&Job::new_auto_id(current_ms + 6000, "Hello Second Job!")).unwrap();
         assert_eq!(s.job_list.len(), 2);
     }
 
@@ -295,7 +619,7 @@ mod tests {
         let mut s: Spoke = Spoke::new_from_now(10_000);
         let j = Job::new_auto_id(current_ms + 4000, "Hello Second Job!");
         let id = j.get_metadata().get_id();
-        s.add_job(j);
+        s.add_job(&j).unwrap();
         assert_eq!(
             s.job_list.len(),
             1,
@@ -319,11 +643,13 @@ mod tests {
     #[test]
     fn walk_spoke_with_jobs() {
         let current_time = times::current_time_ms();
-        let mut s: Spoke = Spoke::new(current_time, 1000);
-        s.add_job(Job::new_auto_id(current_time + 300, "I am Job"));
-        s.add_job(Job::new_auto_id(current_time + 523, "I am Job"));
-        // wait 750 for jobs to be active
-        thread::park_timeout(Duration::from_millis(750));
+        let clock = Arc::new(MockClock::new(current_time));
+        let mut s: Spoke = Spoke::new(current_time, 1000).with_clock(clock.clone());
+        s.add_job(&Job::new_auto_id(current_time + 300, "I am Job")).unwrap();
+        s.add_job(&Job::new_auto_id(current_time + 523, "I am Job")).unwrap();
+        assert!(s.walk().is_empty(), "No job is due yet");
+        // move 750 on for jobs to be active
+        clock.advance(750);
         let res = s.walk();
         assert_eq!(res.len(), 2, "Test should have found 2 jobs ready")
     }
@@ -331,15 +657,16 @@ mod tests {
     #[test]
     fn walk_spoke_with_jobs_idempotent() {
         let current_time = times::current_time_ms();
-        let mut s: Spoke = Spoke::new(current_time, 10_000);
+        let clock = Arc::new(MockClock::new(current_time));
+        let mut s: Spoke = Spoke::new(current_time, 10_000).with_clock(clock.clone());
         println!("Spoke list idempotent: {:p}", &s);
 
-        s.add_job(Job::new_auto_id(current_time + 500, "I am Job"));
+        s.add_job(&Job::new_auto_id(current_time + 500, "I am Job")).unwrap();
         println!("Spoke list idempotent: {:p}", &s);
 
-        s.add_job(Job::new_auto_id(current_time + 500, "I am Job"));
-        // wait 3/4 sec
-        thread::park_timeout(Duration::from_millis(750));
+        s.add_job(&Job::new_auto_id(current_time + 500, "I am Job")).unwrap();
+        // move 3/4 sec on
+        clock.advance(750);
 
         let first_job_set = s.walk();
         assert_eq!(
@@ -358,6 +685,72 @@ mod tests {
         println!("Walk 2 done, pending job len: {:?}", s.pending_job_len());
     }
 
+    #[test]
+    fn walks_urgent_jobs_first_within_a_millisecond() {
+        let current_time = times::current_time_ms();
+        let mut s = Spoke::new(current_time - 1_000, 10_000);
+        s.add_job(&Job::new_auto_id(current_time - 500, "relaxed").with_priority(1_024)).unwrap();
+        s.add_job(&Job::new_auto_id(current_time - 500, "urgent").with_priority(0)).unwrap();
+
+        let walked = s.walk();
+        assert_eq!(walked.len(), 2);
+        assert_eq!(walked[0].get_body().as_bytes(), b"urgent");
+        assert_eq!(walked[1].get_body().as_bytes(), b"relaxed");
+    }
+
+    #[test]
+    fn walk_limit_leaves_the_rest_for_later() {
+        let current_time = times::current_time_ms();
+        let mut s = Spoke::new(current_time - 1_000, 10_000);
+        let cancelled = Job::new_auto_id(current_time - 900, "cancelled");
+        let cancelled_id = cancelled.get_metadata().get_id();
+        s.add_job(&cancelled).unwrap();
+        s.cancel_job(cancelled_id);
+        for offset in &[300, 200, 100] {
+            s.add_job(&Job::new_auto_id(current_time - offset, "ready")).unwrap();
+        }
+        s.add_job(&Job::new_auto_id(current_time + 5_000, "later")).unwrap();
+
+        let first = s.walk_limit(2);
+        assert_eq!(first.len(), 2);
+        assert_eq!(first[0].trigger_at_ms(), current_time - 300);
+        assert_eq!(first[1].trigger_at_ms(), current_time - 200);
+        assert_eq!(s.pending_job_len(), 2);
+
+        let rest = s.walk_limit(2);
+        assert_eq!(rest.len(), 1, "Jobs that aren't ready are left alone");
+        assert_eq!(rest[0].trigger_at_ms(), current_time - 100);
+        assert!(s.walk_limit(0).is_empty());
+    }
+
+    #[test]
+    fn drain_ready_takes_jobs_off_only_as_they_are_iterated() {
+        let current_time = times::current_time_ms();
+        let clock = Arc::new(MockClock::new(current_time));
+        let mut s = Spoke::new(current_time - 1_000, 10_000).with_clock(clock.clone());
+        assert!(s.drain_ready().next().is_none(), "Empty spoke drains nothing");
+        assert_eq!(s.heap_capacity(), 0, "Draining an empty spoke doesn't allocate");
+
+        for offset in &[300, 200, 100] {
+            s.add_job(&Job::new_auto_id(current_time - offset, "ready")).unwrap();
+        }
+        s.add_job(&Job::new_auto_id(current_time + 5_000, "later")).unwrap();
+        {
+            let mut ready = s.drain_ready();
+            let first = ready.next().unwrap();
+            assert_eq!(first.trigger_at_ms(), current_time - 300);
+        }
+        assert_eq!(s.pending_job_len(), 3, "Jobs not iterated over stay scheduled");
+
+        let rest: Vec<u64> = s.drain_ready().map(|j| j.trigger_at_ms()).collect();
+        assert_eq!(rest, vec![current_time - 200, current_time - 100]);
+        assert!(s.drain_ready().next().is_none(), "Each job is drained once");
+
+        clock.advance(6_000);
+        assert_eq!(s.drain_ready().count(), 1);
+        assert_eq!(s.pending_job_len(), 0);
+    }
+
     #[test]
     fn reject_outoftimebounds_jobs() {
         let current_time = times::current_time_ms();
@@ -373,28 +766,46 @@ mod tests {
         let jj_reject: Job = Job::new_auto_id(current_time - 2_000, "before spoke duration");
 
         assert!(
-            s.add_job(j_accept).is_none(),
+            s.add_job(&j_accept).is_ok(),
             "Should accept jobs in spoke span"
         );
         assert!(
-            s.add_job(jj_accept).is_none(),
+            s.add_job(&jj_accept).is_ok(),
             "Should accept jobs in spoke span"
         );
+        let bounds = s.get_bounds();
+        match s.add_job(&j_reject) {
+            Err(YaadError::JobRejected { reason }) => assert_eq!(
+                reason,
+                RejectReason::OutOfBounds {
+                    trigger_at_ms: current_time + 44_000,
+                    bounds
+                },
+                "Should reject jobs beyond spoke span"
+            ),
+            other => panic!("Expected the job to be out of bounds, got {:?}", other),
+        }
         assert!(
-            s.add_job(j_reject).is_some(),
-            "Should reject jobs beyond spoke span"
-        );
-        assert!(
-            s.add_job(jj_reject).is_some(),
+            s.add_job(&jj_reject).is_err(),
             "Should reject jobs before spoke span"
         );
     }
 
+    #[test]
+    fn expired_spokes_refuse_jobs() {
+        let clock = Arc::new(MockClock::new(10_000));
+        let mut s = Spoke::new(5_000, 1_000).with_clock(clock as Arc<dyn Clock>);
+        let bounds = s.get_bounds();
+        let e = s.add_job(&Job::new_auto_id(5_500, "late")).unwrap_err();
+        assert_eq!(e, YaadError::Expired { bounds });
+        assert_eq!(s.pending_job_len(), 0);
+    }
+
     #[test]
     fn spoke_ordering() {
-        let one = Spoke::new_from_now(5);
-        thread::park_timeout(Duration::from_millis(5));
-        let two = Spoke::new_from_now(5);
+        let current_ms = times::current_time_ms();
+        let one = Spoke::new(current_ms, 5);
+        let two = Spoke::new(current_ms + 5, 5);
         assert!(
             one < two,
             "Spoke with time interval closer to now should be smaller"
@@ -410,6 +821,39 @@ mod tests {
         assert!(spoke.get_bounds().contains(&bst));
     }
 
+    #[test]
+    fn spoke_ids_are_derived_from_bounds() {
+        let bst = BoundingSpokeTime::new(500, 800);
+        let one = Spoke::new_from_bounds(bst);
+        let two = Spoke::new_from_bounds(bst);
+        assert_eq!(one.get_id(), two.get_id(), "Same bounds map to the same id");
+        assert_eq!(
+            one.get_id(),
+            Spoke::new(500, 300).get_id(),
+            "Constructor used doesn't change the id"
+        );
+
+        let other = Spoke::new_from_bounds(BoundingSpokeTime::new(500, 801));
+        assert_ne!(one.get_id(), other.get_id(), "Different bounds get different ids");
+
+        let namespace = Uuid::new_v5(&NAMESPACE_OID, "another hub");
+        let namespaced = Spoke::new_in_namespace(&namespace, bst);
+        assert_ne!(one.get_id(), namespaced.get_id(), "Namespaces keep ids apart");
+        assert_eq!(
+            namespaced.get_id(),
+            Spoke::new_in_namespace(&namespace, bst).get_id()
+        );
+    }
+
+    #[test]
+    fn display_includes_short_id_and_bounds() {
+        let s = Spoke::new(500, 300);
+        let shown = format!("{}", s);
+        assert!(shown.contains(&s.short_id()), "{}", shown);
+        assert!(shown.contains("[500, 800)"), "{}", shown);
+        assert_eq!(s.short_id().len(), 8);
+    }
+
     #[test]
     fn can_cancel_job() {
         let current_ms = times::current_time_ms();
@@ -419,8 +863,8 @@ mod tests {
         let j_two = Job::new_auto_id(current_ms + 700, "two");
 
         let j_one_id = j_one.get_metadata().get_id();
-        s.add_job(j_one);
-        s.add_job(j_two);
+        s.add_job(&j_one).unwrap();
+        s.add_job(&j_two).unwrap();
 
         assert_eq!(s.pending_job_len(), 2);
 
@@ -429,4 +873,245 @@ mod tests {
         // Job is gone, more cancels are idempotent
         assert!(!s.cancel_job(j_one_id));
     }
+
+    #[test]
+    fn cancelled_jobs_are_not_pending() {
+        let current_ms = times::current_time_ms();
+        let clock = Arc::new(MockClock::new(current_ms));
+        let mut s: Spoke = Spoke::new(current_ms, 20).with_clock(clock.clone());
+
+        let mut ids = vec![];
+        for i in 0..3 {
+            let j = Job::new_auto_id(current_ms + 5 + i, "job");
+            ids.push(j.get_metadata().get_id());
+            s.add_job(&j).unwrap();
+        }
+        s.cancel_job(ids[0]);
+        s.cancel_job(ids[2]);
+        assert_eq!(s.pending_job_len(), 1, "Cancelled jobs are not pending");
+        assert_eq!(s.stale_entry_len(), 2);
+
+        assert!(!s.is_expired());
+        clock.advance(30);
+        assert!(s.is_expired());
+        let walked = s.walk();
+        assert_eq!(walked.len(), 1);
+        assert_eq!(walked[0].get_metadata().get_id(), ids[1]);
+        assert_eq!(s.pending_job_len(), 0);
+        assert_eq!(s.stale_entry_len(), 0, "Walk drops tombstones");
+    }
+
+    #[test]
+    fn readded_jobs_only_fire_at_their_new_time() {
+        let current_ms = times::current_time_ms();
+        let clock = Arc::new(MockClock::new(current_ms));
+        let mut s = Spoke::new(current_ms, 1_000).with_clock(clock.clone());
+        let j = Job::new_auto_id(current_ms + 500, "moved");
+        let id = j.get_metadata().get_id();
+        s.add_job(&j).unwrap();
+        s.cancel_job(id);
+        s.add_job(&j.with_trigger_at_ms(current_ms + 800)).unwrap();
+        assert_eq!(s.stale_entry_len(), 1, "The entry at the old time is a tombstone");
+
+        clock.advance(600);
+        assert!(s.walk().is_empty());
+        assert_eq!(s.pending_job_len(), 1);
+        clock.advance(200);
+        assert_eq!(s.walk()[0].trigger_at_ms(), current_ms + 800);
+    }
+
+    #[test]
+    fn refuses_jobs_it_holds_already() {
+        let current_ms = times::current_time_ms();
+        let mut s = Spoke::new(current_ms, 1_000);
+        let j = Job::new_auto_id(current_ms + 500, "first");
+        let id = j.get_metadata().get_id();
+        assert!(s.add_job(&j).is_ok());
+        let e = s.add_job(&Job::new(id, current_ms + 600, "second")).unwrap_err();
+        assert_eq!(e, RejectReason::Duplicate(id).into());
+        assert_eq!(s.pending_job_len(), 1);
+        assert_eq!(s.body_bytes, 5, "Held body is untouched");
+        assert_eq!(s.find_job(id).unwrap().trigger_at_ms(), current_ms + 500);
+    }
+
+    #[test]
+    fn releases_memory_of_walked_jobs() {
+        let current_ms = times::current_time_ms();
+        let mut s = Spoke::new(0, u64::MAX - 1);
+        for i in 0..1_000 {
+            s.add_job(&Job::new_auto_id(current_ms - 1_000 + i, "late")).unwrap();
+        }
+        let first = s.peek_next_job().unwrap().get_id();
+        s.cancel_job(first);
+        assert_eq!(s.walk_limit(900).len(), 900);
+        s.release_memory();
+        assert!(s.heap_capacity() < 1_000, "Mostly empty heap is shrunk");
+        assert_eq!(s.pending_job_len(), 99);
+
+        assert_eq!(s.walk().len(), 99);
+        s.release_memory();
+        assert_eq!(s.heap_capacity(), 0);
+        assert_eq!(s.stats().body_bytes, 0);
+    }
+
+    #[test]
+    fn peeks_next_trigger() {
+        let current_ms = times::current_time_ms();
+        let mut s: Spoke = Spoke::new_from_now(10_000);
+        assert_eq!(s.peek_next_trigger(), None, "Empty spoke has nothing due");
+
+        let j_one = Job::new_auto_id(current_ms + 600, "one");
+        let j_one_id = j_one.get_metadata().get_id();
+        s.add_job(&Job::new_auto_id(current_ms + 700, "two")).unwrap();
+        s.add_job(&j_one).unwrap();
+        assert_eq!(s.peek_next_trigger(), Some(current_ms + 600));
+        assert_eq!(s.pending_job_len(), 2, "Peeking doesn't walk");
+
+        s.cancel_job(j_one_id);
+        assert_eq!(s.peek_next_trigger(), Some(current_ms + 700), "Tombstones are skipped");
+    }
+
+    #[test]
+    fn peeks_jobs_without_touching_the_heap() {
+        let current_ms = times::current_time_ms();
+        let mut s = Spoke::new(current_ms - 1_000, 10_000);
+        let due = Job::new_auto_id(current_ms - 500, "due");
+        let due_id = due.get_metadata().get_id();
+        s.add_job(&due).unwrap();
+        s.add_job(&Job::new_auto_id(current_ms + 600, "later")).unwrap();
+        s.add_job(&Job::new_auto_id(current_ms + 700, "latest")).unwrap();
+
+        let ready = s.peek_job_where(|jm| jm.is_ready()).unwrap();
+        assert_eq!(ready.get_id(), due_id);
+        let delayed = s.peek_job_where(|jm| !jm.is_ready()).unwrap();
+        assert_eq!(delayed.trigger_at_ms(), current_ms + 600, "Delayed job due first");
+        assert_eq!(s.peek_job(due_id).unwrap().1.as_bytes(), b"due");
+
+        s.cancel_job(due_id);
+        assert_eq!(s.peek_job_where(|jm| jm.is_ready()), None, "Tombstones are skipped");
+        assert_eq!(s.stale_entry_len(), 1, "but left in place");
+        assert!(s.peek_job(due_id).is_none());
+        assert_eq!(s.pending_job_len(), 2);
+    }
+
+    #[test]
+    fn walk_drops_tombstones_ahead_of_future_jobs() {
+        let current_ms = times::current_time_ms();
+        let mut s: Spoke = Spoke::new_from_now(10_000);
+        let j_one = Job::new_auto_id(current_ms + 600, "one");
+        let j_one_id = j_one.get_metadata().get_id();
+        s.add_job(&j_one).unwrap();
+        s.add_job(&Job::new_auto_id(current_ms + 700, "two")).unwrap();
+        s.cancel_job(j_one_id);
+
+        assert_eq!(s.walk().len(), 0, "Nothing is ready yet");
+        assert_eq!(s.stale_entry_len(), 0, "Tombstone at the heap top was dropped");
+        assert_eq!(s.pending_job_len(), 1);
+    }
+
+    #[test]
+    fn drains_live_jobs() {
+        let current_ms = times::current_time_ms();
+        let mut s: Spoke = Spoke::new_from_now(10_000);
+        let cancelled = Job::new_auto_id(current_ms + 600, "cancelled");
+        let cancelled_id = cancelled.get_metadata().get_id();
+        s.add_job(&cancelled).unwrap();
+        s.add_job(&Job::new_auto_id(current_ms + 700, "kept")).unwrap();
+        s.cancel_job(cancelled_id);
+
+        let drained = s.drain_jobs();
+        assert_eq!(drained.len(), 1);
+        assert_eq!(drained[0].get_body().as_bytes(), b"kept");
+        assert_eq!(s.pending_job_len(), 0);
+        assert_eq!(s.stale_entry_len(), 0, "Tombstones are dropped");
+    }
+
+    #[test]
+    fn tracks_body_bytes() {
+        let current_ms = times::current_time_ms();
+        let mut s = Spoke::new(current_ms - 1_000, 10_000);
+        assert_eq!(s.stats().body_bytes, 0);
+        let cancelled = Job::new_auto_id(current_ms + 600, "cancelled");
+        let cancelled_id = cancelled.get_metadata().get_id();
+        s.add_job(&cancelled).unwrap();
+        s.add_job(&Job::new_auto_id(current_ms - 500, "due")).unwrap();
+        s.add_job(&Job::new_auto_id(current_ms + 700, "later")).unwrap();
+        assert_eq!(s.stats().body_bytes, 17);
+        assert_eq!(s.stats().job_count, 3);
+
+        s.cancel_job(cancelled_id);
+        assert_eq!(s.stats().body_bytes, 8);
+        assert_eq!(s.walk().len(), 1);
+        let stats = s.stats();
+        assert_eq!((stats.job_count, stats.body_bytes), (1, 5));
+        assert_eq!(stats.bounds, s.get_bounds());
+
+        let again = Job::new_auto_id(current_ms + 800, "later, again");
+        let again_id = again.get_metadata().get_id();
+        s.add_job(&again).unwrap();
+        assert!(s.add_job(&Job::new(again_id, current_ms + 800, "duplicate")).is_err());
+        assert_eq!(s.stats().body_bytes, 17, "A refused duplicate isn't counted");
+        s.drain_jobs();
+        assert_eq!(s.stats().body_bytes, 0);
+    }
+
+    #[test]
+    fn compresses_bodies_over_the_threshold() {
+        let current_ms = times::current_time_ms();
+        let mut s =
+            Spoke::new(current_ms - 1_000, 10_000).with_compress_bodies_over_bytes(Some(100));
+        let large = "x".repeat(10_000);
+        let exact = "y".repeat(100);
+        s.add_job(&Job::new_auto_id(current_ms - 500, large.as_str())).unwrap();
+        s.add_job(&Job::new_auto_id(current_ms - 400, exact.as_str())).unwrap();
+        s.add_job(&Job::new_auto_id(current_ms + 5_000, "small")).unwrap();
+
+        let stats = s.stats();
+        assert_eq!(stats.body_bytes, 10_105);
+        if cfg!(feature = "compression") {
+            assert!(stats.stored_body_bytes < 1_000, "Stored {}", stats.stored_body_bytes);
+        } else {
+            assert_eq!(stats.stored_body_bytes, 10_105);
+        }
+        let compressed: Vec<bool> = s.jobs().map(|j| j.body().is_compressed()).collect();
+        assert_eq!(
+            compressed.iter().filter(|&&c| c).count(),
+            if cfg!(feature = "compression") { 1 } else { 0 },
+            "Only the body over the threshold is compressed"
+        );
+
+        let walked = s.walk();
+        assert_eq!(walked.len(), 2);
+        assert_eq!(walked[0].body().as_bytes(), large.as_bytes());
+        assert_eq!(walked[1].body().as_bytes(), exact.as_bytes());
+        let stats = s.stats();
+        assert_eq!((stats.body_bytes, stats.stored_body_bytes), (5, 5));
+    }
+
+    #[test]
+    fn tracks_stale_entries() {
+        let current_ms = times::current_time_ms();
+        let mut s: Spoke = Spoke::new_from_now(10_000);
+        assert_eq!(s.stale_ratio(), 0.0, "Empty spoke has no stale entries");
+
+        let mut ids = vec![];
+        for i in 0..4 {
+            let j = Job::new_auto_id(current_ms + 600 + i, "job");
+            ids.push(j.get_metadata().get_id());
+            s.add_job(&j).unwrap();
+        }
+        s.cancel_job(ids[0]);
+        s.cancel_job(ids[1]);
+        s.cancel_job(ids[2]);
+
+        assert_eq!(s.live_job_len(), 1);
+        assert_eq!(s.stale_entry_len(), 3);
+        assert_eq!(s.stale_ratio(), 0.75);
+
+        assert_eq!(s.compact(), 3, "Compaction drops every stale entry");
+        assert_eq!(s.stale_entry_len(), 0);
+        assert_eq!(s.pending_job_len(), 1);
+        assert!(s.owns_job(ids[3]), "Compaction keeps live jobs");
+        assert_eq!(s.compact(), 0, "Compaction is idempotent");
+    }
 }
@@ -0,0 +1,179 @@
+//! Counters for monitoring a hub and whatever serves it.
+//!
+//! A [`Stats`] collector is shared behind an `Arc`: a `Hub` counts the jobs added to and walked
+//! off it and the spokes it keeps, and a protocol front end counts its client connections.
+//! Several hubs can share one collector to report totals across all of them.
+//!
+//! Walked jobs are also timed: how long after its trigger time each job was handed out. Jobs
+//! walked off the past spoke were late by the time they were added or their spoke expired, so
+//! they are counted apart from the jobs walked off their own spoke on time.
+//!
+//! Counters are updated with relaxed atomics - they are for monitoring, not for synchronisation,
+//! so a reader may briefly see one counter ahead of another.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    jobs_added: AtomicUsize,
+    jobs_walked: AtomicUsize,
+    on_time_deliveries: AtomicUsize,
+    on_time_delivery_lag_ms: AtomicU64,
+    late_deliveries: AtomicUsize,
+    late_delivery_lag_ms: AtomicU64,
+    max_delivery_lag_ms: AtomicU64,
+    jobs_auto_buried: AtomicUsize,
+    stalls: AtomicUsize,
+    internal_errors: AtomicUsize,
+    spokes_live: AtomicUsize,
+    connections_open: AtomicUsize,
+    connections_total: AtomicUsize,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    pub fn record_job_added(&self) {
+        self.record_jobs_added(1);
+    }
+
+    pub fn record_jobs_added(&self, n: usize) {
+        self.jobs_added.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn record_jobs_walked(&self, n: usize) {
+        self.jobs_walked.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Records a job walked off its own spoke `lag_ms` after its trigger time
+    pub fn record_on_time_delivery(&self, lag_ms: u64) {
+        self.on_time_deliveries.fetch_add(1, Ordering::Relaxed);
+        self.on_time_delivery_lag_ms.fetch_add(lag_ms, Ordering::Relaxed);
+        self.max_delivery_lag_ms.fetch_max(lag_ms, Ordering::Relaxed);
+    }
+
+    /// Records a job walked off the past spoke `lag_ms` after its trigger time
+    pub fn record_late_delivery(&self, lag_ms: u64) {
+        self.late_deliveries.fetch_add(1, Ordering::Relaxed);
+        self.late_delivery_lag_ms.fetch_add(lag_ms, Ordering::Relaxed);
+        self.max_delivery_lag_ms.fetch_max(lag_ms, Ordering::Relaxed);
+    }
+
+    /// Records a job buried because its TTR ran out too often
+    pub fn record_job_auto_buried(&self) {
+        self.jobs_auto_buried.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a hub tripping its watchdog
+    pub fn record_stall(&self) {
+        self.stalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records an inconsistency a hub found in its own state, see
+    /// [`YaadError::Internal`](::errors::YaadError::Internal)
+    pub fn record_internal_error(&self) {
+        self.internal_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_spokes_created(&self, n: usize) {
+        self.spokes_live.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn record_spokes_pruned(&self, n: usize) {
+        self.spokes_live.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    pub fn record_connection_opened(&self) {
+        self.connections_open.fetch_add(1, Ordering::Relaxed);
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_connection_closed(&self) {
+        self.connections_open.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of jobs ever added. Jobs put back after a reservation ran out, or
+    /// released or kicked, are not counted again.
+    pub fn jobs_added(&self) -> usize {
+        self.jobs_added.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of jobs ever walked off a hub
+    pub fn jobs_walked(&self) -> usize {
+        self.jobs_walked.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of jobs walked off their own spoke
+    pub fn on_time_deliveries(&self) -> usize {
+        self.on_time_deliveries.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total time jobs walked off their own spoke waited past their trigger time
+    pub fn on_time_delivery_lag_ms(&self) -> u64 {
+        self.on_time_delivery_lag_ms.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of jobs walked off the past spoke
+    pub fn late_deliveries(&self) -> usize {
+        self.late_deliveries.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total time jobs walked off the past spoke waited past their trigger time
+    pub fn late_delivery_lag_ms(&self) -> u64 {
+        self.late_delivery_lag_ms.load(Ordering::Relaxed)
+    }
+
+    /// Returns the longest any walked job waited past its trigger time
+    pub fn max_delivery_lag_ms(&self) -> u64 {
+        self.max_delivery_lag_ms.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of jobs ever buried because their TTR ran out too often, see
+    /// [`HubConfig::max_timeouts`](::hub::HubConfig::max_timeouts)
+    pub fn jobs_auto_buried(&self) -> usize {
+        self.jobs_auto_buried.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of times a hub tripped its [`watchdog`](::watchdog)
+    pub fn stalls(&self) -> usize {
+        self.stalls.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of inconsistencies hubs found in their own state. Anything but 0 is a
+    /// bug worth reporting.
+    pub fn internal_errors(&self) -> usize {
+        self.internal_errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of spokes currently kept, past spokes not included
+    pub fn spokes_live(&self) -> usize {
+        self.spokes_live.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of client connections currently open
+    pub fn connections_open(&self) -> usize {
+        self.connections_open.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of client connections ever opened
+    pub fn connections_total(&self) -> usize {
+        self.connections_total.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_connections() {
+        let stats = Stats::new();
+        stats.record_connection_opened();
+        stats.record_connection_opened();
+        stats.record_connection_closed();
+        assert_eq!(stats.connections_open(), 1);
+        assert_eq!(stats.connections_total(), 2);
+    }
+}
@@ -1,21 +1,47 @@
 use std::ops::Add;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use chrono::{TimeZone, Utc};
 
+/// The latest time [`current_time_ms`] returned, in ms since the epoch
+static LATEST_MS: AtomicU64 = AtomicU64::new(0);
+
 #[inline]
 /// Returns current time in ms - drops `nanosec` precision
+///
+/// Never returns less than it did before: when the system clock is stepped back, e.g. by NTP, the
+/// time stays where it was until the system clock catches up again.
 pub fn current_time_ms() -> u64 {
-    system_time_to_ms(SystemTime::now())
+    not_before_latest(&LATEST_MS, system_time_to_ms(SystemTime::now()))
+}
+
+#[inline]
+/// Returns `now_ms`, or the latest time recorded in `latest_ms` if that is later, and records the
+/// one it returns
+pub fn not_before_latest(latest_ms: &AtomicU64, now_ms: u64) -> u64 {
+    latest_ms.fetch_max(now_ms, Ordering::SeqCst).max(now_ms)
 }
 
 #[inline]
 pub fn floor_ms_from_epoch(ms: u64) -> u64 {
-    (ms / 10) * 10
+    floor_to(ms, 10)
+}
+
+#[inline]
+/// Rounds `ms` down to a multiple of `granularity` - a granularity of 0 leaves it unchanged
+pub fn floor_to(ms: u64, granularity: u64) -> u64 {
+    match granularity {
+        0 => ms,
+        g => (ms / g) * g,
+    }
 }
 
 #[inline]
 pub fn duration_to_ms(d: Duration) -> u64 {
-    (d.as_secs() * 1000) as u64 + (d.subsec_nanos() as u64 / 1_000_000)
+    // Saturates instead of overflowing for absurdly long durations
+    d.as_secs()
+        .saturating_mul(1000)
+        .saturating_add(d.subsec_nanos() as u64 / 1_000_000)
 }
 
 #[inline]
@@ -48,4 +74,27 @@ mod tests {
 
         assert_eq!(now_ms, now_no_nanos_ms);
     }
+
+    #[test]
+    fn time_never_goes_back() {
+        let latest_ms = AtomicU64::new(0);
+        assert_eq!(not_before_latest(&latest_ms, 1_000), 1_000);
+        assert_eq!(not_before_latest(&latest_ms, 700), 1_000, "Stepped back 300ms");
+        assert_eq!(not_before_latest(&latest_ms, 1_000), 1_000);
+        assert_eq!(not_before_latest(&latest_ms, 1_001), 1_001, "Caught up again");
+
+        let before = current_time_ms();
+        assert!(current_time_ms() >= before);
+    }
+
+    #[test]
+    fn floors_to_granularity() {
+        assert_eq!(floor_to(12_345, 10_000), 10_000);
+        assert_eq!(floor_to(10_000, 10_000), 10_000);
+        assert_eq!(floor_to(9_999, 10_000), 0);
+        assert_eq!(floor_to(12_345, 1), 12_345);
+        assert_eq!(floor_to(12_345, 0), 12_345);
+        assert_eq!(floor_to(u64::MAX, 2), u64::MAX - 1);
+        assert_eq!(floor_ms_from_epoch(12_345), 12_340);
+    }
 }
@@ -0,0 +1,206 @@
+//! A self-check that notices when a hub is getting sick before its consumers do.
+//!
+//! A [`Watchdog`] is fed by the hub it guards - every walk, add and prune records when it
+//! happened - and is evaluated on every [`Hub::tick`](::hub::Hub::tick) against the
+//! [`WatchdogThresholds`] set in the hub's [`HubConfig`](::hub::HubConfig). A hub trips the
+//! watchdog when nothing walked it for too long, when its spoke map keeps growing, or when due
+//! jobs pile up in the past spoke faster than they are walked off.
+//!
+//! A tripped check is reported once, when it trips, rather than on every tick it stays tripped:
+//! the hub logs the [`StallReport`] at error level, counts it in its
+//! [`Stats`](::stats::Stats) and hands it to the callback set with
+//! [`Hub::set_on_stall`](::hub::Hub::set_on_stall), if any, so an embedding service can page
+//! someone.
+
+use std::fmt;
+use std::mem;
+
+use gauges::HubGauges;
+
+/// Limits a healthy hub stays within. A limit left None isn't checked, so the default checks
+/// nothing.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct WatchdogThresholds {
+    /// Longest a hub may go without being walked
+    pub max_ms_between_walks: Option<u64>,
+    /// Most spokes a hub may keep, not counting the past spoke. Ticks create the spokes of the
+    /// next [`PREALLOCATED_SPOKES`](::hub::PREALLOCATED_SPOKES) spoke durations ahead of time, so
+    /// keep this well above that.
+    pub max_spokes: Option<usize>,
+    /// Most due jobs that may wait in the past spoke
+    pub max_past_jobs: Option<usize>,
+}
+
+/// A threshold a hub went over
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Stall {
+    /// Nothing walked the hub for `ms_since_walk`, longer than `max_ms`
+    WalkOverdue { ms_since_walk: u64, max_ms: u64 },
+    /// The hub keeps `spokes` spokes, more than `max`
+    TooManySpokes { spokes: usize, max: usize },
+    /// `past_jobs` due jobs wait in the past spoke, more than `max`
+    PastBacklog { past_jobs: usize, max: usize },
+}
+
+impl fmt::Display for Stall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Stall::WalkOverdue {
+                ms_since_walk,
+                max_ms,
+            } => write!(f, "not walked for {}ms, over {}ms", ms_since_walk, max_ms),
+            Stall::TooManySpokes { spokes, max } => write!(f, "{} spokes, over {}", spokes, max),
+            Stall::PastBacklog { past_jobs, max } => {
+                write!(f, "{} past jobs, over {}", past_jobs, max)
+            }
+        }
+    }
+}
+
+/// What a hub looked like when it tripped its watchdog
+#[derive(Debug, Clone, PartialEq)]
+pub struct StallReport {
+    /// When the watchdog was evaluated, in ms since the epoch
+    pub at_ms: u64,
+    /// Every threshold the hub is over at `at_ms`, including ones that tripped on an earlier tick
+    pub stalls: Vec<Stall>,
+    pub gauges: HubGauges,
+    /// When the hub was last walked, or created if it never was
+    pub last_walk_ms: u64,
+    /// When a job was last added, if ever
+    pub last_add_ms: Option<u64>,
+    /// When spent spokes were last pruned, if ever
+    pub last_prune_ms: Option<u64>,
+}
+
+impl fmt::Display for StallReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stalls: Vec<String> = self.stalls.iter().map(|s| s.to_string()).collect();
+        let or_never = |ms: Option<u64>| ms.map_or("never".to_owned(), |ms| ms.to_string());
+        write!(
+            f,
+            "stalls=[{}] spokes={} past_jobs={} reserved_jobs={} last_walk_ms={} \
+             last_add_ms={} last_prune_ms={}",
+            stalls.join("; "),
+            self.gauges.spokes,
+            self.gauges.past_jobs,
+            self.gauges.reserved_jobs,
+            self.last_walk_ms,
+            or_never(self.last_add_ms),
+            or_never(self.last_prune_ms)
+        )
+    }
+}
+
+/// Watches a hub for the stalls its thresholds describe
+pub struct Watchdog {
+    thresholds: WatchdogThresholds,
+    last_walk_ms: u64,
+    last_add_ms: Option<u64>,
+    last_prune_ms: Option<u64>,
+    /// What the last evaluation found, so a stall is only reported when it trips
+    tripped: Vec<Stall>,
+    on_stall: Option<Box<dyn Fn(StallReport) + Send>>,
+}
+
+impl Watchdog {
+    /// Creates a watchdog for a hub created at `now_ms`. A hub that is never walked trips the
+    /// walk check `max_ms_between_walks` after it was created.
+    pub fn new(thresholds: WatchdogThresholds, now_ms: u64) -> Watchdog {
+        Watchdog {
+            thresholds,
+            last_walk_ms: now_ms,
+            last_add_ms: None,
+            last_prune_ms: None,
+            tripped: vec![],
+            on_stall: None,
+        }
+    }
+
+    pub fn thresholds(&self) -> WatchdogThresholds {
+        self.thresholds
+    }
+
+    /// Makes reports go to `on_stall` as well
+    pub fn set_on_stall(&mut self, on_stall: Box<dyn Fn(StallReport) + Send>) {
+        self.on_stall = Some(on_stall);
+    }
+
+    pub fn record_walk(&mut self, now_ms: u64) {
+        self.last_walk_ms = now_ms;
+    }
+
+    pub fn record_add(&mut self, now_ms: u64) {
+        self.last_add_ms = Some(now_ms);
+    }
+
+    pub fn record_prune(&mut self, now_ms: u64) {
+        self.last_prune_ms = Some(now_ms);
+    }
+
+    /// Checks `gauges`, sampled at `now_ms`, against the thresholds. Returns a report if a
+    /// threshold tripped since the last check, after handing it to the stall callback.
+    pub fn check(&mut self, now_ms: u64, gauges: &HubGauges) -> Option<StallReport> {
+        let t = self.thresholds;
+        let ms_since_walk = now_ms.saturating_sub(self.last_walk_ms);
+        let stalls: Vec<Stall> = vec![
+            t.max_ms_between_walks
+                .filter(|max_ms| ms_since_walk > *max_ms)
+                .map(|max_ms| Stall::WalkOverdue {
+                    ms_since_walk,
+                    max_ms,
+                }),
+            t.max_spokes
+                .filter(|max| gauges.spokes > *max)
+                .map(|max| Stall::TooManySpokes {
+                    spokes: gauges.spokes,
+                    max,
+                }),
+            t.max_past_jobs
+                .filter(|max| gauges.past_jobs > *max)
+                .map(|max| Stall::PastBacklog {
+                    past_jobs: gauges.past_jobs,
+                    max,
+                }),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+
+        let was_tripped = |s: &Stall| {
+            self.tripped
+                .iter()
+                .any(|t| mem::discriminant(t) == mem::discriminant(s))
+        };
+        let newly_tripped = stalls.iter().any(|s| !was_tripped(s));
+        self.tripped = stalls.clone();
+        if !newly_tripped {
+            return None;
+        }
+        let report = StallReport {
+            at_ms: now_ms,
+            stalls,
+            gauges: *gauges,
+            last_walk_ms: self.last_walk_ms,
+            last_add_ms: self.last_add_ms,
+            last_prune_ms: self.last_prune_ms,
+        };
+        if let Some(ref on_stall) = self.on_stall {
+            on_stall(report.clone());
+        }
+        Some(report)
+    }
+}
+
+impl fmt::Debug for Watchdog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Watchdog")
+            .field("thresholds", &self.thresholds)
+            .field("last_walk_ms", &self.last_walk_ms)
+            .field("last_add_ms", &self.last_add_ms)
+            .field("last_prune_ms", &self.last_prune_ms)
+            .field("tripped", &self.tripped)
+            .field("on_stall", &self.on_stall.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,318 @@
+//! A versioned serde schema for jobs, for snapshots, the JSON line protocol and replication.
+//!
+//! [`SerializedJob`] flattens a [`Job`] into the fields that make up its schedule, so the layout
+//! of [`Job`] and [`JobMetadata`](::job::JobMetadata) can change without breaking data that was
+//! stored or sent with an older build. Every record carries the [`VERSION`] of the schema it was
+//! written with; fields added in later versions fall back to their defaults when an older record
+//! lacks them.
+//!
+//! | version | fields                                      |
+//! |---------|---------------------------------------------|
+//! | 0       | `id`, `trigger_at_ms`, `body`               |
+//! | 1       | adds `priority` and `ttr_ms`                |
+//!
+//! Ids serialize as hyphenated strings in human readable formats, like JSON, and as their 16
+//! bytes in binary ones. Bodies serialize as their original bytes, even when the hub keeps them
+//! compressed.
+//!
+//! Needs the `serialization` feature, which the `server` feature turns on.
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, Serializer};
+use uuid::Uuid;
+
+use job::{Job, JobBody, DEFAULT_PRIORITY, DEFAULT_TTR_MS};
+
+/// Schema version of the records this build writes
+pub const VERSION: u8 = 1;
+
+/// A job as it is stored and sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SerializedJob {
+    /// Records written before the schema was versioned lack it, and are version 0
+    #[serde(default)]
+    pub version: u8,
+    #[serde(with = "uuid_format")]
+    pub id: Uuid,
+    pub trigger_at_ms: u64,
+    /// Since version 1
+    #[serde(default = "default_priority")]
+    pub priority: u32,
+    /// Since version 1
+    #[serde(default = "default_ttr_ms")]
+    pub ttr_ms: u64,
+    pub body: JobBody,
+}
+
+fn default_priority() -> u32 {
+    DEFAULT_PRIORITY
+}
+
+fn default_ttr_ms() -> u64 {
+    DEFAULT_TTR_MS
+}
+
+/// Reasons a [`SerializedJob`] can't be turned back into a [`Job`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum SerializedJobError {
+    /// The record was written with a newer schema than this build reads
+    UnsupportedVersion(u8),
+    /// Jobs only take uuid v4 ids
+    InvalidId(Uuid),
+}
+
+impl fmt::Display for SerializedJobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SerializedJobError::UnsupportedVersion(version) => write!(
+                f,
+                "Job record has schema version {}, newer than {}",
+                version, VERSION
+            ),
+            SerializedJobError::InvalidId(id) => write!(f, "Job id {} isn't a uuid v4", id),
+        }
+    }
+}
+
+impl Error for SerializedJobError {}
+
+impl<'a> From<&'a Job> for SerializedJob {
+    fn from(job: &'a Job) -> SerializedJob {
+        SerializedJob {
+            version: VERSION,
+            id: job.get_metadata().get_id(),
+            trigger_at_ms: job.trigger_at_ms(),
+            priority: job.priority(),
+            ttr_ms: job.ttr_ms(),
+            body: job.get_body(),
+        }
+    }
+}
+
+/// The job is created anew, so its creation time is when it was read and its reserve, release
+/// and timeout counts start at 0
+impl TryFrom<SerializedJob> for Job {
+    type Error = SerializedJobError;
+
+    fn try_from(record: SerializedJob) -> Result<Job, SerializedJobError> {
+        if record.version > VERSION {
+            return Err(SerializedJobError::UnsupportedVersion(record.version));
+        }
+        let id = record.id;
+        let job = Job::try_new(id, record.trigger_at_ms, record.body)
+            .map_err(|_| SerializedJobError::InvalidId(id))?;
+        Ok(job.with_priority(record.priority).with_ttr_ms(record.ttr_ms))
+    }
+}
+
+impl Serialize for JobBody {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.to_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for JobBody {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<JobBody, D::Error> {
+        deserializer.deserialize_byte_buf(BodyVisitor)
+    }
+}
+
+/// Takes bodies as bytes, as the sequence of numbers JSON writes bytes as, or as text
+struct BodyVisitor;
+
+impl<'de> Visitor<'de> for BodyVisitor {
+    type Value = JobBody;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a job body as bytes or text")
+    }
+
+    fn visit_bytes<E: de::Error>(self, body: &[u8]) -> Result<JobBody, E> {
+        Ok(body.into())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, body: Vec<u8>) -> Result<JobBody, E> {
+        Ok(body.into())
+    }
+
+    fn visit_str<E: de::Error>(self, body: &str) -> Result<JobBody, E> {
+        Ok(body.into())
+    }
+
+    fn visit_string<E: de::Error>(self, body: String) -> Result<JobBody, E> {
+        Ok(body.into())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<JobBody, A::Error> {
+        let mut body = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            body.push(byte);
+        }
+        Ok(body.into())
+    }
+}
+
+/// Serializes a uuid as its hyphenated string in human readable formats and as its 16 bytes in
+/// binary ones, for `#[serde(with = "::wire::uuid_format")]`
+pub mod uuid_format {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(id: &Uuid, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&id.hyphenated().to_string())
+        } else {
+            serializer.serialize_bytes(id.as_bytes())
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Uuid, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(UuidVisitor)
+        } else {
+            deserializer.deserialize_bytes(UuidVisitor)
+        }
+    }
+
+    struct UuidVisitor;
+
+    impl<'de> Visitor<'de> for UuidVisitor {
+        type Value = Uuid;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a uuid as a hyphenated string or 16 bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, id: &str) -> Result<Uuid, E> {
+            Uuid::parse_str(id).map_err(|e| E::custom(format!("Invalid uuid {}: {}", id, e)))
+        }
+
+        fn visit_bytes<E: de::Error>(self, id: &[u8]) -> Result<Uuid, E> {
+            Uuid::from_bytes(id).map_err(|_| E::invalid_length(id.len(), &self))
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Uuid, A::Error> {
+            let mut id = Vec::with_capacity(16);
+            while let Some(byte) = seq.next_element::<u8>()? {
+                id.push(byte);
+            }
+            self.visit_bytes(&id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate bincode;
+    extern crate serde_json;
+
+    use super::*;
+
+    fn job() -> Job {
+        Job::new_auto_id(1_500, vec![0u8, 159, 146, 150])
+            .with_priority(7)
+            .with_ttr_ms(30_000)
+    }
+
+    fn assert_same_schedule(a: &Job, b: &Job) {
+        assert_eq!(a.get_metadata().get_id(), b.get_metadata().get_id());
+        assert_eq!(a.trigger_at_ms(), b.trigger_at_ms());
+        assert_eq!(a.priority(), b.priority());
+        assert_eq!(a.ttr_ms(), b.ttr_ms());
+        assert_eq!(a.get_body().as_bytes(), b.get_body().as_bytes());
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let job = job();
+        let json = serde_json::to_value(SerializedJob::from(&job)).unwrap();
+        assert_eq!(json["version"], VERSION);
+        assert_eq!(
+            json["id"],
+            job.get_metadata().get_id().hyphenated().to_string(),
+            "Ids are strings in JSON"
+        );
+
+        let record: SerializedJob = serde_json::from_value(json).unwrap();
+        assert_same_schedule(&Job::try_from(record).unwrap(), &job);
+    }
+
+    #[test]
+    fn round_trips_through_bincode() {
+        let job = job();
+        let bytes = bincode::serialize(&SerializedJob::from(&job)).unwrap();
+        let id = job.get_metadata().get_id();
+        assert!(
+            bytes.windows(16).any(|w| w == id.as_bytes()),
+            "Ids are their 16 bytes in binary formats"
+        );
+
+        let record: SerializedJob = bincode::deserialize(&bytes).unwrap();
+        assert_same_schedule(&Job::try_from(record).unwrap(), &job);
+    }
+
+    #[test]
+    fn serializes_compressed_bodies_as_put() {
+        let body = JobBody::from(vec![b'a'; 1_000]).compressed_over(0);
+        let json = serde_json::to_string(&body).unwrap();
+        let read: JobBody = serde_json::from_str(&json).unwrap();
+        assert!(!read.is_compressed());
+        assert_eq!(read.as_bytes(), &[b'a'; 1_000][..]);
+
+        let text: JobBody = serde_json::from_str(r#""hello""#).unwrap();
+        assert_eq!(text.as_bytes(), b"hello", "Text bodies are taken too");
+    }
+
+    #[test]
+    fn reads_version_0_records_with_defaults() {
+        let id = Uuid::new_v4();
+        let record: SerializedJob = serde_json::from_str(&format!(
+            r#"{{"id": "{}", "trigger_at_ms": 1500, "body": [104, 105]}}"#,
+            id
+        ))
+        .unwrap();
+        assert_eq!(record.version, 0);
+        assert_eq!(record.priority, DEFAULT_PRIORITY);
+        assert_eq!(record.ttr_ms, DEFAULT_TTR_MS);
+
+        let job = Job::try_from(record).unwrap();
+        assert_eq!(job.get_metadata().get_id(), id);
+        assert_eq!(job.trigger_at_ms(), 1_500);
+        assert_eq!(job.get_body().as_bytes(), b"hi");
+    }
+
+    #[test]
+    fn refuses_records_it_cannot_read() {
+        let mut record = SerializedJob::from(&job());
+        record.version = VERSION + 1;
+        assert_eq!(
+            Job::try_from(record.clone()),
+            Err(SerializedJobError::UnsupportedVersion(VERSION + 1))
+        );
+
+        record.version = VERSION;
+        record.id = Uuid::new_v5(&::uuid::NAMESPACE_DNS, "yaad");
+        assert_eq!(
+            Job::try_from(record.clone()),
+            Err(SerializedJobError::InvalidId(record.id))
+        );
+    }
+
+    #[test]
+    fn metadata_round_trips() {
+        let metadata = job().get_metadata();
+        let json = serde_json::to_string(&metadata).unwrap();
+        let read: ::job::JobMetadata = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.get_id(), metadata.get_id());
+        assert_eq!(read.trigger_at_ms(), metadata.trigger_at_ms());
+        assert_eq!(read.created_at_ms(), metadata.created_at_ms());
+
+        let bytes = bincode::serialize(&metadata).unwrap();
+        let read: ::job::JobMetadata = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(read.get_id(), metadata.get_id());
+        assert_eq!(read.created_at_ms(), metadata.created_at_ms());
+    }
+}